@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate clap;
 extern crate failure;
 
@@ -10,6 +11,7 @@ use std::process;
 use clap::{App, Arg};
 
 use riboprof::bam_suppress_duplicates::*;
+use riboprof::umi::*;
 
 fn main() {
     match wrapper() {
@@ -32,7 +34,7 @@ fn get_cli() -> Result<CLI, failure::Error> {
         .version("0.1.0")
         .author("Nick Ingolia <ingolia@berkeley.edu>")
         .about("Suppress likely PCR duplicates based on UMIs embedded in sequence names")
-        .long_about("Identifies and removes likely PCR duplicates. Duplicates are identified based on a nucleotide tag at the end of the read name stored in the BAM file, separated from the rest of the read name by a \"#\". Reads with no tag are not subject to deduplication. When multiple reads aligning to the same position share the same nucleotide tag, one is selected arbitrarily and written as the \"unique\" representative and, if specified, the rest are written to the file of duplicates. Optionally, the unique representative can be tagged with a \"ZD\" tag indicating the total number of duplicate reads (always 2 or more) at that position. Optionally, a table of duplicate suppression statistics can be written as a tab-separated file, tabulating the duplicate status of each distinct mapping site. In this statistics file, the first column is the total number of reads aligned to the site, the second is the number of unique reads, and the third is the count of distinct sites. Thus, \"1  1  234\" would indicate 234 distinct positions with a single unique read, \"2  2  17\" would indicate 17 distinct positions with two unique reads, and \"2  1  5\" would indicate 5 positions with a single duplicated read (2 reads total, 1 unique).")
+        .long_about("Identifies and removes likely PCR duplicates. Duplicates are identified based on a nucleotide tag, or UMI, stored with each read. By default the tag is at the end of the read name, separated from the rest of the read name by a \"#\"; the other UMI encodings written by fastx-split are also supported, including an RX SAM tag. UMIs from other pipelines can be read from the end of the read name after a chosen delimiter, or from any SAM tag such as UB. Reads with no tag are not subject to deduplication. When multiple reads aligning to the same position share the same nucleotide tag, one is selected arbitrarily and written as the \"unique\" representative and, if specified, the rest are written to the file of duplicates. Optionally, tags that differ by a few sequencing errors can be clustered and treated as the same tag, either by merging all tags within a maximum edit distance or by the directional method, where a tag is merged into a similar tag with at least twice as many reads; the representative is then chosen from the most abundant tag in the cluster. Optionally, the unique representative can be tagged with a \"ZD\" tag indicating the total number of duplicate reads (always 2 or more) at that position. Optionally, a table of duplicate suppression statistics can be written as a tab-separated file, tabulating the duplicate status of each distinct mapping site. In this statistics file, the first column is the total number of reads aligned to the site, the second is the number of unique reads, and the third is the count of distinct sites. Thus, \"1  1  234\" would indicate 234 distinct positions with a single unique read, \"2  2  17\" would indicate 17 distinct positions with two unique reads, and \"2  1  5\" would indicate 5 positions with a single duplicated read (2 reads total, 1 unique). When UMIs are clustered, a final comment line, \"# merged_reads\" followed by a count, gives the number of reads merged into the cluster of another UMI.")
         .arg(
             Arg::with_name("bam_input")
                 .short("i")
@@ -75,6 +77,52 @@ fn get_cli() -> Result<CLI, failure::Error> {
                 .long("annotate")
                 .help("Annotate deduplicated reads"),
         )
+        .arg(
+            Arg::with_name("umi_encoding")
+                .long("umi-encoding")
+                .value_name("ENCODING")
+                .help("Convention for the UMI: \"hash\" for read#UMI, \"underscore\" for read_UMI, \"colon\" for read:UMI, or \"sam\" for an RX SAM tag")
+                .takes_value(true)
+                .possible_values(UmiEncoding::names())
+                .default_value("hash"),
+        )
+        .arg(
+            Arg::with_name("umi_delimiter")
+                .long("umi-delimiter")
+                .value_name("CHAR")
+                .help("Read the UMI from the end of the read name after the last CHAR, instead of using --umi-encoding")
+                .takes_value(true)
+                .conflicts_with("umi_tag"),
+        )
+        .arg(
+            Arg::with_name("umi_tag")
+                .long("umi-tag")
+                .value_name("TAG")
+                .help("Read the UMI from the SAM tag TAG, e.g., RX or UB, instead of using --umi-encoding; alignments without the tag are untagged")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("umi_clustering")
+                .long("umi-clustering")
+                .value_name("METHOD")
+                .help("Method for merging UMIs with sequencing errors at the same site: \"exact\" for no merging, \"distance\" for UMIs within --umi-distance edits, or \"directional\" for UMIs within --umi-distance edits of a UMI with at least twice as many reads")
+                .takes_value(true)
+                .possible_values(UmiClustering::names())
+                .default_value("exact"),
+        )
+        .arg(
+            Arg::with_name("umi_distance")
+                .long("umi-distance")
+                .value_name("EDITS")
+                .help("Maximum edit distance between UMIs merged by --umi-clustering")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("collapsed")
+                .long("collapsed")
+                .help("Count each alignment as the number of identical reads it stands for, given as _x<count> in the read name by fastx-split --collapse"),
+        )
         .get_matches();
 
     Ok(CLI {
@@ -83,5 +131,11 @@ fn get_cli() -> Result<CLI, failure::Error> {
         bam_dups: matches.value_of_lossy("bam_dups").map(|a| a.to_string()),
         stats: matches.value_of_lossy("stats").map(|a| a.to_string()),
         annotate: matches.is_present("annotate"),
+        umi_encoding: value_t!(matches.value_of("umi_encoding"), UmiEncoding)?,
+        umi_delimiter: matches.value_of_lossy("umi_delimiter").map(|a| a.to_string()),
+        umi_tag: matches.value_of_lossy("umi_tag").map(|a| a.to_string()),
+        collapsed: matches.is_present("collapsed"),
+        umi_clustering: value_t!(matches.value_of("umi_clustering"), UmiClustering)?,
+        umi_distance: value_t!(matches.value_of("umi_distance"), usize)?,
     })
 }
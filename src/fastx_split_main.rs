@@ -11,6 +11,7 @@ use std::process;
 use clap::{App, Arg};
 
 use riboprof::fastx_split::*;
+use riboprof::umi::*;
 
 fn main() {
     match wrapper() {
@@ -32,7 +33,7 @@ fn get_cli() -> Result<CLI, failure::Error> {
     let matches = App::new("fastx-split")
         .version("0.1.0")
         .author("Nick Ingolia <ingolia@berkeley.edu>")
-        .about("Split FastQ file using index and random nucleotides")
+        .about("Split FastQ or FastA file using index and random nucleotides. Input format is detected automatically and gzip and BGZF inputs are decompressed.")
         .arg(
             Arg::with_name("output_dir")
                 .short("o")
@@ -56,7 +57,7 @@ fn get_cli() -> Result<CLI, failure::Error> {
                 .short("p")
                 .long("prefix")
                 .value_name("PREFIX")
-                .help("Prefix format string")
+                .help("Prefix format string, with I for sample index, N for UMI, A/C/G/T for constant and X for skipped bases")
                 .takes_value(true)
                 .default_value(""),
         )
@@ -69,15 +70,151 @@ fn get_cli() -> Result<CLI, failure::Error> {
                 .takes_value(true)
                 .default_value(""),
         )
+        .arg(
+            Arg::with_name("max_constant_mismatch")
+                .long("max-constant-mismatch")
+                .value_name("MISMATCHES")
+                .help("Maximum number of mismatches in the constant linker bases; reads with more are written to badlinker")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("mate2")
+                .short("2")
+                .long("mate2")
+                .value_name("MATE2")
+                .help("Mate 2 input for paired-end reads, given once for each input in the same order")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("prefix2")
+                .long("prefix2")
+                .value_name("PREFIX2")
+                .help("Mate 2 prefix format string for paired-end reads")
+                .takes_value(true)
+                .default_value(""),
+        )
+        .arg(
+            Arg::with_name("suffix2")
+                .long("suffix2")
+                .value_name("SUFFIX2")
+                .help("Mate 2 suffix format string for paired-end reads")
+                .takes_value(true)
+                .default_value(""),
+        )
+        .arg(
+            Arg::with_name("adapter")
+                .short("a")
+                .long("adapter")
+                .value_name("ADAPTER")
+                .help("3\' adapter sequence to locate and remove, along with everything after it, before splitting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("adapter_mismatch_rate")
+                .long("adapter-mismatch-rate")
+                .value_name("RATE")
+                .help("Maximum fraction of mismatches in an adapter match")
+                .takes_value(true)
+                .default_value("0.1"),
+        )
+        .arg(
+            Arg::with_name("adapter_min_overlap")
+                .long("adapter-min-overlap")
+                .value_name("BASES")
+                .help("Minimum length of a partial adapter match at the end of a read")
+                .takes_value(true)
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("trim_quality")
+                .long("trim-quality")
+                .value_name("QUALITY")
+                .help("Trim low-quality bases from the 3\' end of each insert, after linker removal and before the minimum insert length is checked, using this Phred quality threshold")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trim_mode")
+                .long("trim-mode")
+                .value_name("MODE")
+                .help("Quality trimming algorithm: \"bwa\" to trim where the summed shortfall from the threshold is largest, or \"window\" to trim until a window of bases at the 3\' end reaches the threshold on average")
+                .takes_value(true)
+                .possible_values(QualityTrimMode::names())
+                .default_value("bwa"),
+        )
+        .arg(
+            Arg::with_name("trim_window")
+                .long("trim-window")
+                .value_name("BASES")
+                .help("Window length for \"window\" quality trimming")
+                .takes_value(true)
+                .default_value("4"),
+        )
+        .arg(
+            Arg::with_name("trim_poly_a")
+                .long("trim-poly-a")
+                .value_name("BASES")
+                .help("Strip a poly(A) tail of at least this many bases from the 3\' end of each insert, or of mate 1 for read pairs, after quality trimming")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trim_poly_n")
+                .long("trim-poly-n")
+                .help("Strip any run of N bases from the 3\' end of each insert, or of mate 1 for read pairs, before poly(A) stripping"),
+        )
+        .arg(
+            Arg::with_name("max_reads")
+                .long("max-reads")
+                .value_name("READS")
+                .help("Maximum number of reads written for each sample, unless given in the sample sheet. Reads with an unknown index are not capped. Processing stops once every sample in the sample sheet has reached its maximum")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("subsample")
+                .long("subsample")
+                .value_name("FRACTION")
+                .help("Randomly keep this fraction of the input reads, skipping the rest")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Random number seed for subsampling")
+                .takes_value(true)
+                .default_value("1"),
+        )
         .arg(
             Arg::with_name("sample_sheet")
                 .short("s")
                 .long("sample-sheet")
                 .value_name("SAMPLESHEET.CSV")
-                .help("File name of CSV-format sample sheet")
+                .help("File name of CSV-format sample sheet, either name,index lines or a header line naming columns among name, index, mismatches, index2, mismatches2, output, description, prefix, suffix, prefix2, suffix2 and max_reads")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("max_mismatch")
+                .long("max-mismatch")
+                .value_name("MISMATCHES")
+                .help("Maximum number of mismatches, including N, between a read index and a sample index")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("max_mismatch2")
+                .long("max-mismatch2")
+                .value_name("MISMATCHES")
+                .help("Maximum number of mismatches in the second index, for sample sheets with an index2 column [default: --max-mismatch]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reduce_mismatch")
+                .long("reduce-mismatch")
+                .help("Lower the mismatch tolerance for samples whose indices are too similar, instead of failing"),
+        )
         .arg(
             Arg::with_name("progress")
                 .long("progress")
@@ -86,16 +223,189 @@ fn get_cli() -> Result<CLI, failure::Error> {
                 .takes_value(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("gzip")
+                .short("z")
+                .long("gzip")
+                .help("Write gzip-compressed output files, which cannot be combined with --bam")
+                .conflicts_with("bam"),
+        )
+        .arg(
+            Arg::with_name("compression_level")
+                .long("compression-level")
+                .value_name("LEVEL")
+                .help("Gzip compression level for output files, 0 (fastest) to 9 (smallest)")
+                .takes_value(true)
+                .default_value("6"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("N")
+                .help("Number of worker threads; output is identical for any number of threads")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("umi_encoding")
+                .long("umi-encoding")
+                .value_name("ENCODING")
+                .help("Convention for recording the UMI: \"hash\" for read#UMI, \"underscore\" for read_UMI, \"colon\" for read:UMI, or \"sam\" for an RX:Z:UMI tag in the description")
+                .takes_value(true)
+                .possible_values(UmiEncoding::names())
+                .default_value("hash"),
+        )
+        .arg(
+            Arg::with_name("malformed")
+                .long("malformed")
+                .value_name("POLICY")
+                .help("Handling of malformed records: \"skip\" to count and discard them, \"reject\" to count them and write them to rejected.fastq, or \"abort\" to stop; after a record that cannot be parsed, reading resumes at the next header line, except that an unparseable mate or index record always stops the run")
+                .takes_value(true)
+                .possible_values(MalformedPolicy::names())
+                .default_value("abort"),
+        )
+        .arg(
+            Arg::with_name("index1")
+                .long("index1")
+                .value_name("I1")
+                .help("First index read input (i7), given once for each input in the same order")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("index2")
+                .long("index2")
+                .value_name("I2")
+                .help("Second index read input (i5), given once for each input in the same order")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("header_index")
+                .long("header-index")
+                .help("Take the index sequences from Illumina read descriptions, as in 1:N:0:ACGTAC+GGTTAA"),
+        )
+        .arg(
+            Arg::with_name("index1_spec")
+                .long("index1-spec")
+                .value_name("SPEC")
+                .help("Format string for the start of the first index sequence, with I for sample index and N for UMI bases placed before those from the linker")
+                .takes_value(true)
+                .default_value(""),
+        )
+        .arg(
+            Arg::with_name("index2_spec")
+                .long("index2-spec")
+                .value_name("SPEC")
+                .help("Format string for the start of the second index sequence, as for --index1-spec")
+                .takes_value(true)
+                .default_value(""),
+        )
+        .arg(
+            Arg::with_name("contaminants")
+                .long("contaminants")
+                .value_name("FASTA")
+                .help("Contaminant sequences, such as rRNA and tRNA; reads whose insert matches them are written to <name>_contam for each sample")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("contaminant_kmer")
+                .long("contaminant-kmer")
+                .value_name("K")
+                .help("K-mer length for matching inserts against contaminant sequences")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .arg(
+            Arg::with_name("contaminant_fraction")
+                .long("contaminant-fraction")
+                .value_name("FRACTION")
+                .help("Fraction of insert k-mers found in the contaminant sequences for an insert to match")
+                .takes_value(true)
+                .default_value("0.5"),
+        )
+        .arg(
+            Arg::with_name("collapse")
+                .long("collapse")
+                .help("Collapse identical reads with the same UMI in each sample into one record, adding _x<count> to the read name before the UMI, and write <name>_collapsed.txt. One record for each distinct insert and UMI is held in memory until all reads are processed, so memory use grows with the number of distinct reads"),
+        )
+        .arg(
+            Arg::with_name("bam")
+                .long("bam")
+                .help("Write each sample as unaligned BAM, <name>.bam, with the UMI in the RX tag, UMI quality in QX, sample index in BC, and a read group per sample"),
+        )
         .arg(Arg::with_name("input").multiple(true).required(true))
         .get_matches();
 
     Ok(CLI {
         fastx_inputs: matches.values_of_lossy("input").unwrap(),
+        mate2_inputs: matches
+            .values_of_lossy("mate2")
+            .unwrap_or_else(|| Vec::new()),
         output_dir: matches.value_of("output_dir").unwrap().to_string(),
         min_insert: value_t!(matches.value_of("min_insert"), usize)?,
         prefix: matches.value_of("prefix").unwrap().to_string(),
         suffix: matches.value_of("suffix").unwrap().to_string(),
+        prefix2: matches.value_of("prefix2").unwrap().to_string(),
+        suffix2: matches.value_of("suffix2").unwrap().to_string(),
+        adapter: matches.value_of_lossy("adapter").map(|a| a.to_string()),
+        adapter_mismatch_rate: value_t!(matches.value_of("adapter_mismatch_rate"), f64)?,
+        adapter_min_overlap: value_t!(matches.value_of("adapter_min_overlap"), usize)?,
         sample_sheet: matches.value_of("sample_sheet").unwrap().to_string(),
+        max_mismatch: value_t!(matches.value_of("max_mismatch"), usize)?,
+        max_mismatch2: if matches.is_present("max_mismatch2") {
+            Some(value_t!(matches.value_of("max_mismatch2"), usize)?)
+        } else {
+            None
+        },
+        reduce_mismatch: matches.is_present("reduce_mismatch"),
         progress: value_t!(matches.value_of("progress"), usize)?,
+        gzip: matches.is_present("gzip"),
+        compression_level: value_t!(matches.value_of("compression_level"), u32)?,
+        threads: value_t!(matches.value_of("threads"), usize)?,
+        umi_encoding: value_t!(matches.value_of("umi_encoding"), UmiEncoding)?,
+        malformed: value_t!(matches.value_of("malformed"), MalformedPolicy)?,
+        index1_inputs: matches
+            .values_of_lossy("index1")
+            .unwrap_or_else(|| Vec::new()),
+        index2_inputs: matches
+            .values_of_lossy("index2")
+            .unwrap_or_else(|| Vec::new()),
+        header_index: matches.is_present("header_index"),
+        index1_spec: matches.value_of("index1_spec").unwrap().to_string(),
+        index2_spec: matches.value_of("index2_spec").unwrap().to_string(),
+        max_constant_mismatch: value_t!(matches.value_of("max_constant_mismatch"), usize)?,
+        contaminants: matches.value_of("contaminants").map(|c| c.to_string()),
+        contaminant_kmer: value_t!(matches.value_of("contaminant_kmer"), usize)?,
+        contaminant_fraction: value_t!(matches.value_of("contaminant_fraction"), f64)?,
+        collapse: matches.is_present("collapse"),
+        unaligned_bam: matches.is_present("bam"),
+        trim_quality: if matches.is_present("trim_quality") {
+            Some(value_t!(matches.value_of("trim_quality"), u8)?)
+        } else {
+            None
+        },
+        trim_mode: value_t!(matches.value_of("trim_mode"), QualityTrimMode)?,
+        trim_window: value_t!(matches.value_of("trim_window"), usize)?,
+        trim_poly_a: if matches.is_present("trim_poly_a") {
+            Some(value_t!(matches.value_of("trim_poly_a"), usize)?)
+        } else {
+            None
+        },
+        trim_poly_n: matches.is_present("trim_poly_n"),
+        max_reads: if matches.is_present("max_reads") {
+            Some(value_t!(matches.value_of("max_reads"), usize)?)
+        } else {
+            None
+        },
+        subsample: if matches.is_present("subsample") {
+            Some(value_t!(matches.value_of("subsample"), f64)?)
+        } else {
+            None
+        },
+        seed: value_t!(matches.value_of("seed"), u64)?,
     })
 }
@@ -101,6 +101,11 @@ fn get_cli() -> Result<CLI, failure::Error> {
                 .help("Write output BAM file annotated wiht framing information")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("collapsed")
+                .long("collapsed")
+                .help("Count each alignment as the number of identical reads it stands for, given as _x<count> in the read name by fastx-split --collapse")
+        )
         .arg(Arg::with_name("input").value_name("INPUT.BAM").required(true))
         .get_matches();
 
@@ -115,6 +120,7 @@ fn get_cli() -> Result<CLI, failure::Error> {
         lengths: matches.value_of("lengths").unwrap().to_string(),
         count_multi: matches.is_present("count-multi"),
         annotate: matches.value_of_lossy("annotate").map(|a| a.to_string()),
+        collapsed: matches.is_present("collapsed"),
         input: matches.value_of("input").unwrap().to_string(),
     })
 }
@@ -10,10 +10,18 @@ use rust_htslib::bam::Read as BamRead;
 mod record_class;
 mod record_group;
 mod stats;
+mod umi_cluster;
+mod umi_source;
 
 use bam_suppress_duplicates::record_class::*;
 use bam_suppress_duplicates::record_group::*;
 use bam_suppress_duplicates::stats::*;
+use bam_suppress_duplicates::umi_cluster::*;
+use bam_suppress_duplicates::umi_source::*;
+use umi::*;
+
+pub use bam_suppress_duplicates::umi_cluster::UmiClustering;
+pub use bam_suppress_duplicates::umi_source::UmiSource;
 
 pub struct CLI {
     pub bam_input: String,
@@ -21,6 +29,12 @@ pub struct CLI {
     pub bam_dups: Option<String>,
     pub stats: Option<String>,
     pub annotate: bool,
+    pub umi_encoding: UmiEncoding,
+    pub umi_delimiter: Option<String>,
+    pub umi_tag: Option<String>,
+    pub collapsed: bool,
+    pub umi_clustering: UmiClustering,
+    pub umi_distance: usize,
 }
 
 pub struct Config {
@@ -29,6 +43,9 @@ pub struct Config {
     dups_output: Option<bam::Writer>,
     stat_file: Option<PathBuf>,
     annotate: bool,
+    umi_source: UmiSource,
+    collapsed: bool,
+    clusterer: UmiClusterer,
     stats: Stats,
 }
 
@@ -56,63 +73,139 @@ impl Config {
 
         let stats = Stats::new(DEFAULT_NLIM);
 
+        let umi_source = match (&cli.umi_delimiter, &cli.umi_tag) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!(
+                    "UMI delimiter and UMI tag cannot both be given"
+                ));
+            }
+            (Some(delimiter), None) => UmiSource::from_delimiter(delimiter)?,
+            (None, Some(tag)) => UmiSource::from_tag_name(tag)?,
+            (None, None) => UmiSource::from_encoding(cli.umi_encoding),
+        };
+
         Ok(Config {
             input: input,
             uniq_output: uniq_out,
             dups_output: dups_out,
             stat_file: cli.stats.as_ref().map(|s| Path::new(&s).to_path_buf()),
             annotate: cli.annotate,
+            umi_source: umi_source,
+            collapsed: cli.collapsed,
+            clusterer: UmiClusterer::new(cli.umi_clustering, cli.umi_distance),
             stats: stats,
         })
     }
 }
 
-pub fn read_tag(r1: &bam::Record) -> Option<&[u8]> {
-    if let Some(delim_pos) = r1.qname().iter().position(|&ch| ch == b'#') {
-        Some(r1.qname().split_at(delim_pos + 1).1)
-    } else {
-        None
-    }
+pub fn read_tag(umi_source: UmiSource, r1: &bam::Record) -> Option<&[u8]> {
+    umi_source.record_umi(r1)
 }
 
-// N.B. No read tag => never a duplicate!
-pub fn same_tag(r0: &bam::Record, r1: &bam::Record) -> bool {
-    if let Some(tag0) = read_tag(r0) {
-        if let Some(tag1) = read_tag(r1) {
-            (tag0 == tag1)
-        } else {
-            false
-        }
-    } else {
-        false
-    }
+/// Returns the key for grouping records by read tag, so that records
+/// are grouped when their tags are identical. Records with no read
+/// tag have no key and are never duplicates.
+pub fn tag_key(umi_source: UmiSource, r1: &bam::Record) -> Option<Vec<u8>> {
+    read_tag(umi_source, r1).map(|tag| tag.to_vec())
 }
 
-pub fn same_cigar(r0: &bam::Record, r1: &bam::Record) -> bool {
-    r0.raw_cigar() == r1.raw_cigar()
+/// Returns the key for grouping records by CIGAR, so that records
+/// are grouped when their raw CIGAR operations are identical.
+pub fn cigar_key(r1: &bam::Record) -> Vec<u32> {
+    r1.raw_cigar().to_vec()
+}
+
+// Clusters the tagged record classes at one site by UMI. Returns, for
+// each class, the classes in the cluster that starts with it, parent
+// class first, or `None` for untagged classes and for classes merged
+// into an earlier cluster.
+fn cluster_classes(
+    clusterer: &UmiClusterer,
+    umi_source: UmiSource,
+    collapsed: bool,
+    classes: &[Vec<bam::Record>],
+) -> Vec<Option<Vec<usize>>> {
+    let tagged: Vec<(usize, &[u8])> = classes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, class)| read_tag(umi_source, class.first().unwrap()).map(|tag| (i, tag)))
+        .collect();
+    let umis: Vec<&[u8]> = tagged.iter().map(|(_, tag)| *tag).collect();
+    let counts: Vec<usize> = tagged
+        .iter()
+        .map(|(i, _)| {
+            classes[*i]
+                .iter()
+                .map(|r| read_multiplicity(collapsed, r))
+                .sum()
+        })
+        .collect();
+
+    let mut starts = vec![None; classes.len()];
+    for cluster in clusterer.clusters(&umis, &counts) {
+        let members: Vec<usize> = cluster.iter().map(|pos| tagged[*pos].0).collect();
+        let first = *members.iter().min().unwrap();
+        starts[first] = Some(members);
+    }
+    starts
 }
 
 pub fn bam_suppress_duplicates(mut config: Config) -> Result<(), failure::Error> {
     let loc_groups = RecordGroups::new_by_location(&mut config.input)?;
+    let umi_source = config.umi_source;
+    let collapsed = config.collapsed;
+    let umi_tag_key = |r1: &bam::Record| tag_key(umi_source, r1);
+    // Every record has a CIGAR, so every record has a CIGAR key
+    let cigar_class_key = |r1: &bam::Record| Some(cigar_key(r1));
 
     for loc_group_res in loc_groups {
         let loc_group = loc_group_res?;
-        let mut cigar_classes = RecordClass::new(&same_cigar);
+        let mut cigar_classes = RecordClass::new(&cigar_class_key);
         cigar_classes.insert_all(loc_group.into_iter());
         for cigar_class in cigar_classes.classes() {
-            let mut tag_classes = RecordClass::new(&same_tag);
+            let mut tag_classes = RecordClass::new(&umi_tag_key);
             tag_classes.insert_all(cigar_class.into_iter());
+            let tag_classes = tag_classes.classes();
+            let clusters = cluster_classes(&config.clusterer, umi_source, collapsed, &tag_classes);
+            let mut tag_classes: Vec<Option<Vec<bam::Record>>> =
+                tag_classes.into_iter().map(Some).collect();
 
             let mut n_total = 0;
             let mut n_unique = 0;
 
-            for mut tag_class in tag_classes.classes() {
-                if read_tag(tag_class.first().unwrap()).is_none() {
+            for (class_idx, cluster) in clusters.into_iter().enumerate() {
+                let untagged = tag_classes[class_idx].as_ref().map_or(false, |tag_class| {
+                    read_tag(umi_source, tag_class.first().unwrap()).is_none()
+                });
+
+                if untagged {
+                    let tag_class = tag_classes[class_idx].take().unwrap();
                     assert!(tag_class.len() == 1);
                     config.uniq_output.write(tag_class.first().unwrap())?;
-                    config.stats.tally_untagged();
-                } else {
-                    let tag_class_len = tag_class.len();
+                    config
+                        .stats
+                        .tally_untagged(read_multiplicity(collapsed, tag_class.first().unwrap()));
+                } else if let Some(members) = cluster {
+                    // The first record of the parent class represents
+                    // the whole cluster
+                    let mut tag_class = Vec::new();
+                    for (member_idx, member) in members.into_iter().enumerate() {
+                        let member_class = tag_classes[member].take().unwrap();
+                        if member_idx > 0 {
+                            config.stats.tally_merged(
+                                member_class
+                                    .iter()
+                                    .map(|r| read_multiplicity(collapsed, r))
+                                    .sum(),
+                            );
+                        }
+                        tag_class.extend(member_class);
+                    }
+
+                    let tag_class_len: usize = tag_class
+                        .iter()
+                        .map(|r| read_multiplicity(collapsed, r))
+                        .sum();
                     n_total += tag_class_len;
                     n_unique += 1;
 
@@ -128,6 +221,9 @@ pub fn bam_suppress_duplicates(mut config: Config) -> Result<(), failure::Error>
                             out.write(dup)?;
                         }
                     }
+                } else {
+                    // Merged into an earlier cluster
+                    continue;
                 }
 
                 config.stats.tally(n_total, n_unique);
@@ -138,6 +234,13 @@ pub fn bam_suppress_duplicates(mut config: Config) -> Result<(), failure::Error>
     if let Some(ref stats_file) = config.stat_file {
         let mut stats_out = fs::File::create(stats_file)?;
         stats_out.write_all(config.stats.dedup_table().as_bytes())?;
+        if config.clusterer.clustering() != UmiClustering::Exact {
+            write!(
+                stats_out,
+                "# merged_reads\t{}\n",
+                config.stats.merged_reads()
+            )?;
+        }
     }
 
     eprintln!(
@@ -151,6 +254,13 @@ pub fn bam_suppress_duplicates(mut config: Config) -> Result<(), failure::Error>
         config.stats.dupl_reads(),
         config.stats.dupl_sites()
     );
+    if config.clusterer.clustering() != UmiClustering::Exact {
+        eprintln!(
+            "Merged {} reads with likely UMI errors by {} clustering",
+            config.stats.merged_reads(),
+            config.clusterer.clustering()
+        );
+    }
     eprintln!(
         "{:>4.1}% unique",
         100.0 * (config.stats.unique_reads() as f64) / (config.stats.total_reads() as f64)
@@ -158,3 +268,120 @@ pub fn bam_suppress_duplicates(mut config: Config) -> Result<(), failure::Error>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn record(name: &str) -> bam::Record {
+        let mut rec = bam::Record::new();
+        rec.set(
+            name.as_bytes(),
+            &CigarString(vec![Cigar::Match(4)]),
+            b"ACGT",
+            &[30; 4],
+        );
+        rec.set_tid(0);
+        rec.set_pos(100);
+        rec
+    }
+
+    fn class_names(names: &[&str]) -> Vec<bam::Record> {
+        names.iter().map(|name| record(*name)).collect()
+    }
+
+    #[test]
+    fn cluster_tagged_classes() {
+        let umi_source = UmiSource::from_encoding(UmiEncoding::Hash);
+        let classes = vec![
+            class_names(&["a_x5#AAAA"]),
+            class_names(&["b#AAAT", "c#AAAT", "d#AAAT"]),
+            class_names(&["e"]),
+            class_names(&["f#CCCC"]),
+        ];
+
+        // AAAT has more reads and absorbs AAAA, so its class leads the
+        // cluster listed at the earlier class
+        let directional = UmiClusterer::new(UmiClustering::Directional, 1);
+        assert!(
+            cluster_classes(&directional, umi_source, false, &classes)
+                == vec![Some(vec![1, 0]), None, None, Some(vec![3])]
+        );
+
+        // Counting the multiplicity of collapsed reads, AAAA has more
+        // reads and absorbs AAAT
+        assert!(
+            cluster_classes(&directional, umi_source, true, &classes)
+                == vec![Some(vec![0, 1]), None, None, Some(vec![3])]
+        );
+
+        let exact = UmiClusterer::new(UmiClustering::Exact, 1);
+        assert!(
+            cluster_classes(&exact, umi_source, false, &classes)
+                == vec![Some(vec![0]), Some(vec![1]), None, Some(vec![3])]
+        );
+    }
+
+    #[test]
+    fn merged_representative() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("bam_suppress_duplicates_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut header = bam::Header::new();
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", &"chr1")
+                .push_tag(b"LN", &1000),
+        );
+        {
+            let mut writer = bam::Writer::from_path(&path("input.bam"), &header).unwrap();
+            for name in ["a#AAAA", "b#AAAT", "c#AAAT", "d#AAAT"].iter() {
+                writer.write(&record(name)).unwrap();
+            }
+        }
+
+        let cli = CLI {
+            bam_input: path("input.bam"),
+            bam_output: path("uniq.bam"),
+            bam_dups: Some(path("dups.bam")),
+            stats: Some(path("stats.txt")),
+            annotate: false,
+            umi_encoding: UmiEncoding::Hash,
+            umi_delimiter: None,
+            umi_tag: None,
+            collapsed: false,
+            umi_clustering: UmiClustering::Directional,
+            umi_distance: 1,
+        };
+        bam_suppress_duplicates(Config::new(&cli).unwrap()).unwrap();
+
+        let names = |name: &str| -> Vec<Vec<u8>> {
+            bam::Reader::from_path(&path(name))
+                .unwrap()
+                .records()
+                .map(|r| r.unwrap().qname().to_vec())
+                .collect()
+        };
+
+        // The record written comes from AAAT, the most abundant UMI,
+        // although AAAA is seen first
+        assert!(names("uniq.bam") == vec![b"b#AAAT".to_vec()]);
+        assert!(
+            names("dups.bam") == vec![b"c#AAAT".to_vec(), b"d#AAAT".to_vec(), b"a#AAAA".to_vec()]
+        );
+        assert!(
+            fs::read_to_string(path("stats.txt")).unwrap()
+                == "ttl\tuniq\tcount\n4\t1\t1\n# merged_reads\t1\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
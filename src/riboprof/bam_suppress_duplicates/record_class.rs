@@ -1,27 +1,32 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
 use rust_htslib::bam;
 
-/// Group BAM records according to an equivalence function. All
-/// records are read and classified, and this function does not
-/// require the reads to be sorted in any way. The number of
-/// equivalence comparisons, and the minimum running time, scales
-/// according to _N*M_, where _N_ is the number of records and _M_ is
-/// the number of groups.
-pub struct RecordClass<'a> {
+/// Group BAM records according to a class key. All records are read
+/// and classified, and this function does not require the reads to
+/// be sorted in any way. Records are looked up by hashing their keys,
+/// so the running time scales linearly with the number of records.
+/// Records with no key are never grouped with any other record.
+pub struct RecordClass<'a, K> {
     classes: Vec<Vec<bam::Record>>,
-    same_class: &'a Fn(&bam::Record, &bam::Record) -> bool,
+    class_index: HashMap<K, usize>,
+    class_key: &'a Fn(&bam::Record) -> Option<K>,
 }
 
-impl<'a> RecordClass<'a> {
+impl<'a, K: Eq + Hash> RecordClass<'a, K> {
     /// Create a new BAM record classifier.
     ///
     /// # Arguments
     ///
-    /// * `same_class` specifies an equivalence function over BAM
-    /// records for grouping.
-    pub fn new(same_class: &'a Fn(&bam::Record, &bam::Record) -> bool) -> Self {
+    /// * `class_key` specifies a key for grouping BAM records, which
+    /// are in the same class when their keys are equal, or `None`
+    /// when the record is in a class of its own.
+    pub fn new(class_key: &'a Fn(&bam::Record) -> Option<K>) -> Self {
         RecordClass {
             classes: Vec::new(),
-            same_class: same_class,
+            class_index: HashMap::new(),
+            class_key: class_key,
         }
     }
 
@@ -31,9 +36,11 @@ impl<'a> RecordClass<'a> {
     ///
     /// * `r` is the record to be added
     pub fn insert(&mut self, r: bam::Record) {
-        for c in self.classes.iter_mut() {
-            if (self.same_class)(c.first().unwrap(), &r) {
-                c.push(r);
+        if let Some(key) = (self.class_key)(&r) {
+            let next_class = self.classes.len();
+            let class_idx = *self.class_index.entry(key).or_insert(next_class);
+            if class_idx < next_class {
+                self.classes[class_idx].push(r);
                 return;
             }
         }
@@ -57,8 +64,59 @@ impl<'a> RecordClass<'a> {
     }
 
     /// Record classification. Returns a `Vec` of record classes, each
-    /// of which is a non-empty `Vec`.
+    /// of which is a non-empty `Vec`. Classes are in order of their
+    /// first record, and records within a class are in the order they
+    /// were inserted.
     pub fn classes(self) -> Vec<Vec<bam::Record>> {
         self.classes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_htslib::bam::record::CigarString;
+
+    fn record(name: &str) -> bam::Record {
+        let mut rec = bam::Record::new();
+        rec.set(name.as_bytes(), &CigarString(Vec::new()), b"ACGT", &[30; 4]);
+        rec
+    }
+
+    #[test]
+    fn classify_by_key() {
+        // Names are "<key>_<serial>", with no key for "-"
+        let name_key = |r: &bam::Record| -> Option<Vec<u8>> {
+            let key = r.qname().split(|&b| b == b'_').next().unwrap().to_vec();
+            if key == b"-" {
+                None
+            } else {
+                Some(key)
+            }
+        };
+
+        let mut classes = RecordClass::new(&name_key);
+        classes.insert_all(
+            ["a_1", "b_2", "-_3", "a_4", "-_5", "c_6", "b_7", "a_8"]
+                .iter()
+                .map(|name| record(*name)),
+        );
+
+        let classes = classes.classes();
+        let names: Vec<Vec<&[u8]>> = classes
+            .iter()
+            .map(|class| class.iter().map(|r| r.qname()).collect())
+            .collect();
+        assert!(
+            names
+                == vec![
+                    vec![&b"a_1"[..], &b"a_4"[..], &b"a_8"[..]],
+                    vec![&b"b_2"[..], &b"b_7"[..]],
+                    vec![&b"-_3"[..]],
+                    vec![&b"-_5"[..]],
+                    vec![&b"c_6"[..]],
+                ]
+        );
+    }
+}
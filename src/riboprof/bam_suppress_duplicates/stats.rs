@@ -9,6 +9,7 @@ pub struct Stats {
     total_sites_count: u64,
     dupl_sites_count: u64,
     untagged_count: u64,
+    merged_count: u64,
 }
 
 impl Stats {
@@ -21,6 +22,7 @@ impl Stats {
             total_sites_count: 0,
             dupl_sites_count: 0,
             untagged_count: 0,
+            merged_count: 0,
         }
     }
 
@@ -54,6 +56,9 @@ impl Stats {
     pub fn dupl_sites(&self) -> u64 {
         self.dupl_sites_count
     }
+    pub fn merged_reads(&self) -> u64 {
+        self.merged_count
+    }
 
     pub fn tally(&mut self, ntotal: usize, nunique: usize) {
         let idx = self.index(ntotal, nunique);
@@ -64,10 +69,17 @@ impl Stats {
         self.dupl_sites_count += if ntotal > nunique { 1 } else { 0 };
     }
 
-    pub fn tally_untagged(&mut self) {
-        self.untagged_count += 1;
+    pub fn tally_untagged(&mut self, nreads: usize) {
+        self.untagged_count += nreads as u64;
+    }
+
+    pub fn tally_merged(&mut self, nreads: usize) {
+        self.merged_count += nreads as u64;
     }
 
+    // Untagged reads are listed with 0 total and 0 unique. Reads
+    // merged into the cluster of another UMI are not in the table,
+    // which stays numeric, but follow it in a comment line.
     pub fn dedup_table(&self) -> String {
         let mut table = "ttl\tuniq\tcount\n".to_string();
 
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use failure;
+
+/// Method for deciding which distinct UMIs at one site stand for the
+/// same original molecule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UmiClustering {
+    /// Only identical UMIs are the same molecule
+    Exact,
+    /// UMIs within the maximum edit distance are the same molecule,
+    /// following chains of neighboring UMIs
+    Distance,
+    /// A UMI within the maximum edit distance of a UMI with at least
+    /// twice as many reads (less one) is an error copy of it, as in
+    /// the UMI-tools directional method
+    Directional,
+}
+
+impl UmiClustering {
+    /// Returns the name strings accepted by `from_str`
+    pub fn names() -> &'static [&'static str] {
+        &["exact", "distance", "directional"]
+    }
+}
+
+impl FromStr for UmiClustering {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "exact" => Ok(UmiClustering::Exact),
+            "distance" => Ok(UmiClustering::Distance),
+            "directional" => Ok(UmiClustering::Directional),
+            _ => Err(format_err!(
+                "Unknown UMI clustering \"{}\", expected one of {}",
+                name,
+                UmiClustering::names().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for UmiClustering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            UmiClustering::Exact => "exact",
+            UmiClustering::Distance => "distance",
+            UmiClustering::Directional => "directional",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Groups the distinct UMIs found at one site into clusters that are
+/// each treated as a single original molecule.
+#[derive(Debug, Clone, Copy)]
+pub struct UmiClusterer {
+    clustering: UmiClustering,
+    max_distance: usize,
+}
+
+impl UmiClusterer {
+    /// Creates a UMI clusterer
+    ///
+    /// # Arguments
+    ///
+    /// * `clustering` is the clustering method
+    ///
+    /// * `max_distance` is the largest edit distance between UMIs
+    /// that are merged, when the method is not exact
+    pub fn new(clustering: UmiClustering, max_distance: usize) -> Self {
+        UmiClusterer {
+            clustering: clustering,
+            max_distance: max_distance,
+        }
+    }
+
+    /// Returns the clustering method
+    pub fn clustering(&self) -> UmiClustering {
+        self.clustering
+    }
+
+    /// Clusters distinct UMIs. Each cluster lists positions in `umis`,
+    /// starting with its parent UMI, which has the most reads in the
+    /// cluster and is listed first among ties, followed by the other
+    /// UMIs in order. Clusters are in order of their first UMI.
+    ///
+    /// # Arguments
+    ///
+    /// * `umis` are the distinct UMI sequences
+    ///
+    /// * `counts` gives the number of reads with each UMI
+    pub fn clusters(&self, umis: &[&[u8]], counts: &[usize]) -> Vec<Vec<usize>> {
+        if self.clustering == UmiClustering::Exact || self.max_distance == 0 {
+            return (0..umis.len()).map(|i| vec![i]).collect();
+        }
+
+        // Directed edges from each UMI to the UMIs it absorbs
+        let mut edges = vec![Vec::new(); umis.len()];
+        for i in 0..umis.len() {
+            for j in (i + 1)..umis.len() {
+                if !within_distance(umis[i], umis[j], self.max_distance) {
+                    continue;
+                }
+                match self.clustering {
+                    UmiClustering::Directional => {
+                        if counts[i] + 1 >= 2 * counts[j] {
+                            edges[i].push(j);
+                        }
+                        if counts[j] + 1 >= 2 * counts[i] {
+                            edges[j].push(i);
+                        }
+                    }
+                    _ => {
+                        edges[i].push(j);
+                        edges[j].push(i);
+                    }
+                }
+            }
+        }
+
+        // Each cluster grows from the most abundant unclustered UMI
+        let mut by_count: Vec<usize> = (0..umis.len()).collect();
+        by_count.sort_by(|&i, &j| counts[j].cmp(&counts[i]).then(i.cmp(&j)));
+
+        let mut clustered = vec![false; umis.len()];
+        let mut clusters = Vec::new();
+        for parent in by_count {
+            if clustered[parent] {
+                continue;
+            }
+            clustered[parent] = true;
+
+            let mut members = Vec::new();
+            let mut queue = VecDeque::new();
+            queue.push_back(parent);
+            while let Some(curr) = queue.pop_front() {
+                for &next in edges[curr].iter() {
+                    if !clustered[next] {
+                        clustered[next] = true;
+                        members.push(next);
+                        queue.push_back(next);
+                    }
+                }
+            }
+
+            members.sort();
+            members.insert(0, parent);
+            clusters.push(members);
+        }
+
+        clusters.sort_by_key(|members| *members.iter().min().unwrap());
+        clusters
+    }
+}
+
+// True when the edit distance between two UMIs is at most
+// `max_distance`
+fn within_distance(umi1: &[u8], umi2: &[u8], max_distance: usize) -> bool {
+    let length_diff = if umi1.len() > umi2.len() {
+        umi1.len() - umi2.len()
+    } else {
+        umi2.len() - umi1.len()
+    };
+    length_diff <= max_distance && edit_distance(umi1, umi2) <= max_distance
+}
+
+/// Returns the edit (Levenshtein) distance between two sequences,
+/// counting substitutions, insertions and deletions.
+pub fn edit_distance(seq1: &[u8], seq2: &[u8]) -> usize {
+    let mut prev: Vec<usize> = (0..=seq2.len()).collect();
+    let mut curr = vec![0; seq2.len() + 1];
+
+    for (i, b1) in seq1.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, b2) in seq2.iter().enumerate() {
+            let subst = prev[j] + if b1 == b2 { 0 } else { 1 };
+            curr[j + 1] = subst.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        mem::swap(&mut prev, &mut curr);
+    }
+
+    prev[seq2.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances() {
+        assert!(edit_distance(b"ACGT", b"ACGT") == 0);
+        assert!(edit_distance(b"ACGT", b"ACCT") == 1);
+        assert!(edit_distance(b"ACGT", b"AGT") == 1);
+        assert!(edit_distance(b"ACGT", b"TGCA") == 4);
+        assert!(edit_distance(b"", b"ACG") == 3);
+    }
+
+    #[test]
+    fn cluster_modes() {
+        let umis: Vec<&[u8]> = vec![
+            &b"AAAA"[..],
+            &b"AAAT"[..],
+            &b"CCCC"[..],
+            &b"AATT"[..],
+            &b"CCCG"[..],
+        ];
+        let counts = vec![10, 2, 3, 1, 3];
+
+        let exact = UmiClusterer::new(UmiClustering::Exact, 1);
+        assert!(
+            exact.clusters(&umis, &counts) == vec![vec![0], vec![1], vec![2], vec![3], vec![4]]
+        );
+
+        let distance = UmiClusterer::new(UmiClustering::Distance, 1);
+        assert!(distance.clusters(&umis, &counts) == vec![vec![0, 1, 3], vec![2, 4]]);
+
+        // AATT (1 read) is absorbed by AAAT (2 reads), and CCCC and
+        // CCCG are too similar in abundance to merge
+        let directional = UmiClusterer::new(UmiClustering::Directional, 1);
+        assert!(directional.clusters(&umis, &counts) == vec![vec![0, 1, 3], vec![2], vec![4]]);
+
+        let none = UmiClusterer::new(UmiClustering::Directional, 0);
+        assert!(none.clusters(&umis, &counts).len() == 5);
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+
+use failure;
+
+use rust_htslib::bam;
+
+use umi::*;
+
+/// Location of the UMI in an alignment record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UmiSource {
+    /// UMI in the read name, following one of the read name encodings
+    /// written by fastx-split
+    Name(UmiEncoding),
+    /// UMI at the end of the read name after the last occurrence of a
+    /// delimiter
+    NameDelimiter(u8),
+    /// UMI in a string-valued SAM tag
+    Tag([u8; 2]),
+}
+
+impl UmiSource {
+    /// Returns the UMI source for a UMI encoding written by
+    /// fastx-split, where the SAM tag encoding becomes an `RX` tag on
+    /// the alignment.
+    pub fn from_encoding(umi_encoding: UmiEncoding) -> Self {
+        match umi_encoding {
+            UmiEncoding::SamTag => UmiSource::Tag([UMI_SAM_TAG[0], UMI_SAM_TAG[1]]),
+            _ => UmiSource::Name(umi_encoding),
+        }
+    }
+
+    /// Returns the UMI source for a read name delimiter
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned unless `delimiter` is a single
+    /// ASCII character.
+    pub fn from_delimiter(delimiter: &str) -> Result<Self, failure::Error> {
+        match delimiter.as_bytes() {
+            [delim] if delim.is_ascii() => Ok(UmiSource::NameDelimiter(*delim)),
+            _ => Err(format_err!(
+                "UMI delimiter \"{}\" is not a single ASCII character",
+                delimiter
+            )),
+        }
+    }
+
+    /// Returns the UMI source for a named SAM tag
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned unless `tag` is a valid SAM tag
+    /// name, i.e., a letter followed by a letter or digit.
+    pub fn from_tag_name(tag: &str) -> Result<Self, failure::Error> {
+        match tag.as_bytes() {
+            [tag1, tag2] if tag1.is_ascii_alphabetic() && tag2.is_ascii_alphanumeric() => {
+                Ok(UmiSource::Tag([*tag1, *tag2]))
+            }
+            _ => Err(format_err!(
+                "UMI tag \"{}\" is not a valid SAM tag name",
+                tag
+            )),
+        }
+    }
+
+    /// Extracts the UMI from an alignment record, returning `None`
+    /// when the record has no UMI, including when the tag is missing
+    /// or does not hold a string.
+    ///
+    /// # Arguments
+    ///
+    /// * `r1` is the alignment record
+    pub fn record_umi<'a>(&self, r1: &'a bam::Record) -> Option<&'a [u8]> {
+        match self {
+            UmiSource::Name(umi_encoding) => umi_encoding.name_umi(r1.qname()),
+            UmiSource::NameDelimiter(delimiter) => {
+                let name = r1.qname();
+                let delim_pos = name.iter().rposition(|ch| ch == delimiter)?;
+                Some(name.split_at(delim_pos + 1).1)
+            }
+            UmiSource::Tag(tag) => match r1.aux(tag) {
+                Some(bam::record::Aux::String(umi)) => Some(umi),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for UmiSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UmiSource::Name(umi_encoding) => write!(f, "read name ({})", umi_encoding),
+            UmiSource::NameDelimiter(delimiter) => {
+                write!(f, "read name after \"{}\"", *delimiter as char)
+            }
+            UmiSource::Tag(tag) => write!(f, "{} tag", String::from_utf8_lossy(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_htslib::bam::record::CigarString;
+
+    fn record(name: &str, tags: &[(&[u8], &[u8])]) -> bam::Record {
+        let mut rec = bam::Record::new();
+        rec.set(name.as_bytes(), &CigarString(Vec::new()), b"ACGT", &[30; 4]);
+        for (tag, value) in tags.iter() {
+            rec.push_aux(tag, &bam::record::Aux::String(value)).unwrap();
+        }
+        rec
+    }
+
+    #[test]
+    fn umi_sources() {
+        let hash = UmiSource::from_encoding(UmiEncoding::Hash);
+        assert!(hash.record_umi(&record("read1#ACGT", &[])) == Some(&b"ACGT"[..]));
+        assert!(hash.record_umi(&record("read1", &[])).is_none());
+
+        let delim = UmiSource::from_delimiter("|").unwrap();
+        assert!(delim.record_umi(&record("read1|x|GGCC", &[])) == Some(&b"GGCC"[..]));
+        assert!(delim.record_umi(&record("read1#ACGT", &[])).is_none());
+        assert!(UmiSource::from_delimiter("||").is_err());
+
+        let rx = UmiSource::from_encoding(UmiEncoding::SamTag);
+        assert!(rx == UmiSource::from_tag_name("RX").unwrap());
+        let ub = UmiSource::from_tag_name("UB").unwrap();
+        let tagged = record(
+            "read1",
+            &[(&b"RX"[..], &b"TTAA"[..]), (&b"UB"[..], &b"CCGG"[..])],
+        );
+        assert!(rx.record_umi(&tagged) == Some(&b"TTAA"[..]));
+        assert!(ub.record_umi(&tagged) == Some(&b"CCGG"[..]));
+        assert!(ub
+            .record_umi(&record("read1#ACGT", &[(&b"RX"[..], &b"TTAA"[..])]))
+            .is_none());
+
+        assert!(UmiSource::from_tag_name("U").is_err());
+        assert!(UmiSource::from_tag_name("1B").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::str;
+
+use failure;
+
+/// 3' adapter sequence that is located in a read by ungapped
+/// approximate matching. The adapter may be only partly present at
+/// the very end of the read, when the insert is nearly as long as
+/// the read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adapter {
+    sequence: Vec<u8>,
+    max_mismatch_rate: f64,
+    min_overlap: usize,
+}
+
+impl Adapter {
+    /// Create a new adapter specification
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` is the adapter nucleotide sequence
+    ///
+    /// * `max_mismatch_rate` is the largest fraction of mismatched
+    /// bases allowed in a match, over the overlap between the read
+    /// and the adapter
+    ///
+    /// * `min_overlap` is the shortest partial match to the start of
+    /// the adapter that is accepted at the end of a read
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned for an empty adapter, for
+    /// characters other than `ACGT`, or for a mismatch rate outside
+    /// of 0 to 1.
+    pub fn new(
+        sequence: &str,
+        max_mismatch_rate: f64,
+        min_overlap: usize,
+    ) -> Result<Self, failure::Error> {
+        let sequence = sequence.to_ascii_uppercase().into_bytes();
+
+        if sequence.is_empty() {
+            return Err(failure::err_msg("Empty adapter sequence"));
+        }
+        if let Some(bad) = sequence.iter().find(|&nt| !b"ACGT".contains(nt)) {
+            return Err(format_err!(
+                "Bad adapter sequence char \'{}\'",
+                *bad as char
+            ));
+        }
+        if !(max_mismatch_rate >= 0.0 && max_mismatch_rate <= 1.0) {
+            return Err(format_err!(
+                "Adapter mismatch rate {} out of range 0-1",
+                max_mismatch_rate
+            ));
+        }
+
+        Ok(Adapter {
+            sequence: sequence,
+            max_mismatch_rate: max_mismatch_rate,
+            min_overlap: min_overlap.max(1),
+        })
+    }
+
+    /// Returns the adapter sequence
+    pub fn sequence(&self) -> &[u8] {
+        &self.sequence
+    }
+
+    /// Finds the start of the adapter in a read sequence, which is
+    /// also the length of the read remaining after the adapter and
+    /// everything after it are removed. The leftmost acceptable match
+    /// is reported. Partial matches, where the read ends within the
+    /// adapter, must cover at least the minimum overlap. An `N` in
+    /// the read always counts as a mismatch.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` is the read sequence
+    ///
+    /// Returns `None` when no adapter is found.
+    pub fn find(&self, seq: &[u8]) -> Option<usize> {
+        for start in 0..seq.len() {
+            let overlap = (seq.len() - start).min(self.sequence.len());
+            if overlap < self.min_overlap {
+                break;
+            }
+
+            let max_mismatch = (overlap as f64 * self.max_mismatch_rate).floor() as usize;
+            if self.matches_within(&seq[start..(start + overlap)], max_mismatch) {
+                return Some(start);
+            }
+        }
+
+        None
+    }
+
+    fn matches_within(&self, read_part: &[u8], max_mismatch: usize) -> bool {
+        let mut mismatch = 0;
+        for (read_nt, adapter_nt) in read_part.iter().zip(self.sequence.iter()) {
+            if read_nt.to_ascii_uppercase() != *adapter_nt {
+                mismatch += 1;
+                if mismatch > max_mismatch {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Adapter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", str::from_utf8(&self.sequence).unwrap_or("???"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &str = "CTGTAGGCACCATCAAT";
+
+    #[test]
+    fn adapter_full_match() {
+        let adapter = Adapter::new(ADAPTER, 0.1, 3).unwrap();
+        assert!(adapter.find(b"ACGTACGTACGTCTGTAGGCACCATCAATAAAA") == Some(12));
+        assert!(adapter.find(b"CTGTAGGCACCATCAATAAAA") == Some(0));
+        assert!(adapter.find(b"ACGTACGTACGTACGTACGTACGT") == None);
+    }
+
+    #[test]
+    fn adapter_mismatch() {
+        let adapter = Adapter::new(ADAPTER, 0.1, 3).unwrap();
+        assert!(adapter.find(b"ACGTACGTACGTCTGTAGGCAGCATCAATAAAA") == Some(12));
+        assert!(adapter.find(b"ACGTACGTACGTCTGTAGGNACCATCAATAAAA") == Some(12));
+        assert!(adapter.find(b"ACGTACGTACGTCTCTAGGCAGCATCAATAAAA") == None);
+
+        let exact = Adapter::new(ADAPTER, 0.0, 3).unwrap();
+        assert!(exact.find(b"ACGTACGTACGTCTGTAGGCAGCATCAATAAAA") == None);
+    }
+
+    #[test]
+    fn adapter_partial_match() {
+        let adapter = Adapter::new(ADAPTER, 0.1, 3).unwrap();
+        assert!(adapter.find(b"ACGTACGTACGTCTGTAGG") == Some(12));
+        assert!(adapter.find(b"ACGTACGTACGTCTG") == Some(12));
+        assert!(adapter.find(b"ACGTACGTACGTACT") == None);
+    }
+
+    #[test]
+    fn adapter_bad_spec() {
+        assert!(Adapter::new("", 0.1, 3).is_err());
+        assert!(Adapter::new("ACGTX", 0.1, 3).is_err());
+        assert!(Adapter::new(ADAPTER, 1.5, 3).is_err());
+        assert!(Adapter::new("acgt", 0.1, 3).unwrap().sequence() == b"ACGT");
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::str;
+
+use failure;
+
+use fastx_split::linkers::*;
+use fastx_split::sample_writer::*;
+use umi::*;
+
+/// Insert sequence of one mate after linker removal, with its quality
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CollapsedMate {
+    id: String,
+    desc: Option<String>,
+    qual: Vec<u8>,
+}
+
+/// Count of identical reads, or read pairs, with the same UMI, along
+/// with the name, description, quality, and barcode of the first of
+/// them, which stands for all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CollapsedRead {
+    order: usize,
+    count: usize,
+    barcode: Barcode,
+    mates: Vec<CollapsedMate>,
+}
+
+/// Collapses identical reads, or read pairs, that share the same
+/// insert sequences and UMI into one record carrying the number of
+/// identical reads. Records are written in the order of their first
+/// appearance once all reads are collected.
+///
+/// Reads are keyed on their insert sequences and UMI, and only one
+/// representative read is kept for each key, but the key and its
+/// representative stay in memory until they are written. Memory use
+/// therefore grows with the number of distinct reads, which can
+/// approach the number of reads in a library of low duplication.
+#[derive(Debug, Clone, Default)]
+pub struct Collapser {
+    reads: HashMap<(Vec<Vec<u8>>, Vec<u8>), CollapsedRead>,
+}
+
+impl Collapser {
+    /// Creates a collapser holding no reads
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one read, or one read pair, after linker removal
+    ///
+    /// # Arguments
+    ///
+    /// * `barcode` holds the UMI and sample index
+    ///
+    /// * `mates` gives the read name, description, insert sequence,
+    /// and insert quality of each mate
+    pub fn insert(&mut self, barcode: &Barcode, mates: &[(&str, Option<&str>, &[u8], &[u8])]) {
+        let key = (
+            mates.iter().map(|(_, _, seq, _)| seq.to_vec()).collect(),
+            barcode.umi().to_vec(),
+        );
+        let order = self.reads.len();
+        let read = self.reads.entry(key).or_insert_with(|| CollapsedRead {
+            order: order,
+            count: 0,
+            barcode: barcode.clone(),
+            mates: mates
+                .iter()
+                .map(|(id, desc, _, qual)| CollapsedMate {
+                    id: id.to_string(),
+                    desc: desc.map(|desc| desc.to_string()),
+                    qual: qual.to_vec(),
+                })
+                .collect(),
+        });
+        read.count += 1;
+    }
+
+    /// Returns the number of distinct reads
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    /// Writes one record for each distinct read, or one record for
+    /// each mate of each distinct read pair, in order of first
+    /// appearance. The multiplicity is added to the read name, as by
+    /// `collapsed_id`, before the UMI is recorded. Returns a
+    /// tab-separated table with a header line giving the read name,
+    /// UMI, and multiplicity of each record written.
+    ///
+    /// # Arguments
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs
+    ///
+    /// * `dest` is the sample output
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the records.
+    pub fn write_all(
+        &self,
+        umi_encoding: UmiEncoding,
+        dest: &mut SampleWriter,
+    ) -> Result<String, failure::Error> {
+        let mut reads: Vec<_> = self.reads.iter().collect();
+        reads.sort_by_key(|(_, read)| read.order);
+
+        let mut table = "read\tumi\tcount\n".to_string();
+
+        for ((seqs, umi), read) in reads {
+            let umi = str::from_utf8(umi)?;
+            let paired = read.mates.len() > 1;
+            for (i, (mate, seq)) in read.mates.iter().zip(seqs.iter()).enumerate() {
+                let position = match (paired, i) {
+                    (false, _) => Mate::Single,
+                    (true, 0) => Mate::First,
+                    (true, _) => Mate::Second,
+                };
+                dest.write(
+                    umi_encoding,
+                    position,
+                    &collapsed_id(&mate.id, read.count),
+                    mate.desc.as_ref().map(String::as_str),
+                    seq,
+                    &mate.qual,
+                    &read.barcode,
+                )?;
+            }
+
+            if let Some(mate) = read.mates.first() {
+                table.push_str(&format!(
+                    "{}\t{}\t{}\n",
+                    collapsed_id(&mate.id, read.count),
+                    umi,
+                    read.count
+                ));
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fastx_split::compress::*;
+    use fastx_split::fastx::*;
+
+    #[test]
+    fn collapse() {
+        let mut collapser = Collapser::new();
+        let spec = LinkerSpec::new("NN", "").unwrap();
+        let ac = spec.barcode(b"ACGATTACA", b"").unwrap();
+        let gt = spec.barcode(b"GTGATTACA", b"").unwrap();
+        collapser.insert(&ac, &[("r1", None, b"GATTACA", b"IIIIIII")]);
+        collapser.insert(&ac, &[("r2", None, b"GATTACA", b"#######")]);
+        collapser.insert(&gt, &[("r3", Some("x"), b"GATTACA", b"IIIIIII")]);
+        collapser.insert(&ac, &[("r4", None, b"TTAGGC", b"IIIIII")]);
+        collapser.insert(&ac, &[("r5", None, b"GATTACA", b"IIIIIII")]);
+        assert!(collapser.len() == 3);
+
+        let buffer = SharedBuffer::new();
+        let mut dest = SampleWriter::fastx(FastxFormat::Fasta, buffer.clone());
+        let table = collapser.write_all(UmiEncoding::Hash, &mut dest).unwrap();
+        drop(dest);
+
+        assert!(
+            table
+                == "read\tumi\tcount\n\
+                    r1_x3\tAC\t3\n\
+                    r3_x1\tGT\t1\n\
+                    r4_x1\tAC\t1\n"
+        );
+        assert!(
+            String::from_utf8(buffer.contents()).unwrap()
+                == ">r1_x3#AC\nGATTACA\n>r3_x1#GT x\nGATTACA\n>r4_x1#AC\nTTAGGC\n"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use failure;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Leading bytes of any gzip stream. BGZF files are a series of
+/// gzip members and begin with the same magic number.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Opens an input file, or standard input for `-`, and transparently
+/// decompresses it when it starts with the gzip magic number. Both
+/// plain gzip and block gzip (BGZF) inputs are handled.
+///
+/// # Arguments
+///
+/// * `input_name` is the input file name, or `-` for standard input
+///
+/// # Errors
+///
+/// An error variant is returned when the input cannot be opened or
+/// its first bytes cannot be read.
+pub fn open_input<P: AsRef<Path>>(input_name: P) -> Result<Box<BufRead + Send>, failure::Error> {
+    let raw_reader: Box<Read + Send> = if input_name.as_ref() == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(&input_name)?)
+    };
+
+    let mut buf_reader = BufReader::new(raw_reader);
+    let is_gzip = buf_reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(buf_reader))))
+    } else {
+        Ok(Box::new(buf_reader))
+    }
+}
+
+/// Output writer that must be finished explicitly, e.g., to write
+/// the gzip trailer, so that errors in completing the output are
+/// reported rather than lost when it is dropped.
+pub trait FinishWrite: Write {
+    /// Flushes all buffered data and completes the output. No more
+    /// data should be written afterwards.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the remaining data cannot
+    /// be written.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl FinishWrite for fs::File {}
+
+impl FinishWrite for Vec<u8> {}
+
+impl FinishWrite for io::Sink {}
+
+impl<W: FinishWrite> FinishWrite for GzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finish()
+    }
+}
+
+impl<W: FinishWrite + ?Sized> FinishWrite for Box<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// In-memory output for tests. Clones share one buffer, so the
+/// output can be read after a clone is handed to the code under test.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the output written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl FinishWrite for SharedBuffer {}
+
+/// Compression applied to output files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputCompression {
+    Plain,
+    Gzip(u32),
+}
+
+impl OutputCompression {
+    /// Highest compression level accepted for gzip output.
+    pub const MAX_LEVEL: u32 = 9;
+
+    /// Create an output compression setting.
+    ///
+    /// # Arguments
+    ///
+    /// * `gzip` is true when outputs should be gzip compressed
+    ///
+    /// * `level` is the gzip compression level, from 0 (none) to 9 (best)
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the compression level is out of range.
+    pub fn new(gzip: bool, level: u32) -> Result<Self, failure::Error> {
+        if level > Self::MAX_LEVEL {
+            return Err(format_err!(
+                "Compression level {} out of range 0-{}",
+                level,
+                Self::MAX_LEVEL
+            ));
+        }
+
+        Ok(if gzip {
+            OutputCompression::Gzip(level)
+        } else {
+            OutputCompression::Plain
+        })
+    }
+
+    /// Returns the file name extension for an output file of the
+    /// given base type, e.g., `fastq` or `fastq.gz`.
+    pub fn extension(&self, base: &str) -> String {
+        match self {
+            OutputCompression::Plain => base.to_string(),
+            OutputCompression::Gzip(_) => format!("{}.gz", base),
+        }
+    }
+
+    /// Creates an output file, wrapped in a gzip encoder when
+    /// needed. The output must be finished to complete the gzip
+    /// stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` is the output file path
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the file cannot be created.
+    pub fn create<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Box<FinishWrite + Send>, failure::Error> {
+        let file = fs::File::create(path)?;
+        Ok(match self {
+            OutputCompression::Plain => Box::new(file),
+            OutputCompression::Gzip(level) => {
+                Box::new(GzEncoder::new(file, Compression::new(*level)))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use flate2::read::GzDecoder;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_input(path: &Path) -> Vec<u8> {
+        let mut contents = Vec::new();
+        open_input(path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn compressed_input() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("fastx_split_compress_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let plain_path = dir.join("plain.fastq");
+        fs::write(&plain_path, b"@r1\nACGT\n+\nIIII\n").unwrap();
+        assert!(read_input(&plain_path) == b"@r1\nACGT\n+\nIIII\n");
+
+        let gzip_path = dir.join("gzip.fastq.gz");
+        fs::write(&gzip_path, gzip(b"@r1\nACGT\n+\nIIII\n")).unwrap();
+        assert!(read_input(&gzip_path) == b"@r1\nACGT\n+\nIIII\n");
+
+        // BGZF files are a series of gzip members, all of which are read
+        let mut members = gzip(b"@r1\nACGT\n+\nIIII\n");
+        members.extend(gzip(b"@r2\nTTGC\n+\n####\n"));
+        let bgzf_path = dir.join("bgzf.fastq.gz");
+        fs::write(&bgzf_path, members).unwrap();
+        assert!(read_input(&bgzf_path) == b"@r1\nACGT\n+\nIIII\n@r2\nTTGC\n+\n####\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_output() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("fastx_split_compress_out_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let compression = OutputCompression::new(true, 6).unwrap();
+        assert!(compression == OutputCompression::Gzip(6));
+        assert!(compression.extension("fastq") == "fastq.gz");
+
+        let output_path = dir.join("out.fastq.gz");
+        {
+            let mut writer = compression.create(&output_path).unwrap();
+            writer.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+            writer.finish().unwrap();
+        }
+
+        // A single gzip decoder reads exactly one member, which fails
+        // without a complete trailer
+        let compressed = fs::read(&output_path).unwrap();
+        assert!(compressed.starts_with(&GZIP_MAGIC));
+        let mut contents = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert!(contents == b"@r1\nACGT\n+\nIIII\n");
+
+        let plain = OutputCompression::new(false, 6).unwrap();
+        assert!(plain == OutputCompression::Plain);
+        assert!(plain.extension("fastq") == "fastq");
+
+        assert!(OutputCompression::new(true, 10).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use failure;
+
+use bio::alphabets::dna;
+use bio::io::fasta;
+
+use fastx_split::compress::*;
+
+/// Longest k-mer that can be packed into a `u64`
+const MAX_KMER_LENGTH: usize = 32;
+
+/// Index of the k-mers in contaminant sequences, such as rRNA and
+/// tRNA, used to identify inserts derived from them. K-mers from both
+/// strands of each contaminant are indexed, and k-mers containing any
+/// base other than A, C, G, or T are ignored.
+#[derive(Debug, Clone)]
+pub struct ContaminantIndex {
+    kmer_length: usize,
+    min_fraction: f64,
+    kmers: HashSet<u64>,
+    sequences: usize,
+}
+
+impl ContaminantIndex {
+    /// Creates an empty contaminant index
+    ///
+    /// # Arguments
+    ///
+    /// * `kmer_length` is the k-mer length, from 1 to 32
+    ///
+    /// * `min_fraction` is the fraction of insert k-mers that must be
+    /// found in the index for an insert to match
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the k-mer length or the
+    /// fraction is out of range.
+    pub fn new(kmer_length: usize, min_fraction: f64) -> Result<Self, failure::Error> {
+        if kmer_length == 0 || kmer_length > MAX_KMER_LENGTH {
+            return Err(format_err!(
+                "Contaminant k-mer length {} is not between 1 and {}",
+                kmer_length,
+                MAX_KMER_LENGTH
+            ));
+        }
+
+        if !(min_fraction > 0.0 && min_fraction <= 1.0) {
+            return Err(format_err!(
+                "Contaminant k-mer fraction {} is not above 0 and at most 1",
+                min_fraction
+            ));
+        }
+
+        Ok(ContaminantIndex {
+            kmer_length: kmer_length,
+            min_fraction: min_fraction,
+            kmers: HashSet::new(),
+            sequences: 0,
+        })
+    }
+
+    /// Creates a contaminant index from the sequences in a FastA
+    /// file, which may be compressed
+    ///
+    /// # Arguments
+    ///
+    /// * `fasta_file` is the name of the contaminant FastA file
+    ///
+    /// * `kmer_length` is the k-mer length, from 1 to 32
+    ///
+    /// * `min_fraction` is the fraction of insert k-mers that must be
+    /// found in the index for an insert to match
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the parameters are out of
+    /// range or the FastA file cannot be read.
+    pub fn from_fasta<P: AsRef<Path>>(
+        fasta_file: P,
+        kmer_length: usize,
+        min_fraction: f64,
+    ) -> Result<Self, failure::Error> {
+        let mut index = ContaminantIndex::new(kmer_length, min_fraction)?;
+        let reader = fasta::Reader::new(open_input(&fasta_file)?);
+        for rec_res in reader.records() {
+            let rec = rec_res
+                .map_err(|err| format_err!("{}: {}", fasta_file.as_ref().to_string_lossy(), err))?;
+            index.add_sequence(rec.seq());
+        }
+        Ok(index)
+    }
+
+    /// Adds the k-mers of a contaminant sequence, and of its reverse
+    /// complement, to the index
+    pub fn add_sequence(&mut self, seq: &[u8]) {
+        let upper = seq.to_ascii_uppercase();
+        let kmers = &mut self.kmers;
+        for_each_kmer(&upper, self.kmer_length, |kmer| {
+            kmers.insert(kmer);
+        });
+        for_each_kmer(&dna::revcomp(&upper), self.kmer_length, |kmer| {
+            kmers.insert(kmer);
+        });
+        self.sequences += 1;
+    }
+
+    /// Returns the k-mer length
+    pub fn kmer_length(&self) -> usize {
+        self.kmer_length
+    }
+
+    /// Returns the fraction of insert k-mers needed for a match
+    pub fn min_fraction(&self) -> f64 {
+        self.min_fraction
+    }
+
+    /// Returns the number of contaminant sequences indexed
+    pub fn sequences(&self) -> usize {
+        self.sequences
+    }
+
+    /// Returns true when an insert matches the contaminants: at
+    /// least the minimum fraction of its k-mers are found in the
+    /// index. Inserts with no k-mers never match.
+    ///
+    /// # Arguments
+    ///
+    /// * `insert` is the insert sequence after linker removal
+    pub fn matches(&self, insert: &[u8]) -> bool {
+        let mut total = 0;
+        let mut found = 0;
+        for_each_kmer(insert, self.kmer_length, |kmer| {
+            total += 1;
+            if self.kmers.contains(&kmer) {
+                found += 1;
+            }
+        });
+        total > 0 && (found as f64) >= self.min_fraction * (total as f64)
+    }
+}
+
+fn nt_code(nt: u8) -> Option<u64> {
+    match nt {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+// Calls `f` with each k-mer of `seq` packed two bits per base,
+// skipping k-mers that contain an ambiguous base
+fn for_each_kmer<F: FnMut(u64)>(seq: &[u8], kmer_length: usize, mut f: F) {
+    let mask = if kmer_length >= MAX_KMER_LENGTH {
+        !0
+    } else {
+        (1u64 << (2 * kmer_length)) - 1
+    };
+
+    let mut kmer = 0;
+    let mut valid = 0;
+    for &nt in seq.iter() {
+        match nt_code(nt) {
+            Some(code) => {
+                kmer = ((kmer << 2) | code) & mask;
+                valid += 1;
+                if valid >= kmer_length {
+                    f(kmer);
+                }
+            }
+            None => valid = 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kmers() {
+        let mut kmers = Vec::new();
+        for_each_kmer(b"ACGTNACGA", 3, |kmer| kmers.push(kmer));
+        assert!(kmers == vec![0b000110, 0b011011, 0b000110, 0b011000]);
+    }
+
+    #[test]
+    fn contaminant_matches() {
+        let mut index = ContaminantIndex::new(4, 0.5).unwrap();
+        index.add_sequence(b"acgtaccgatgg");
+        assert!(index.sequences() == 1);
+
+        assert!(index.matches(b"CGTACCGA"));
+        assert!(index.matches(b"TCGGTACG"));
+        assert!(index.matches(b"ACGTACTTT"));
+        assert!(!index.matches(b"ACGTACTTTT"));
+        assert!(!index.matches(b"ACG"));
+        assert!(!index.matches(b"NNNNNNNN"));
+
+        assert!(ContaminantIndex::new(0, 0.5).is_err());
+        assert!(ContaminantIndex::new(33, 0.5).is_err());
+        assert!(ContaminantIndex::new(20, 0.0).is_err());
+    }
+}
@@ -0,0 +1,737 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use failure;
+
+use fastx_split::fastx::*;
+use fastx_split::index_reads::*;
+use fastx_split::linkers::*;
+use fastx_split::processor::*;
+use fastx_split::sample_sheet::*;
+use fastx_split::subsample::*;
+
+/// Sample settings used by a `Demultiplexer` when assigning reads.
+/// The default methods suit samples that use the default linker.
+pub trait DemuxSample {
+    /// Returns the sample name, which keys the per-sample counts
+    fn name(&self) -> &str;
+
+    /// Returns the linker specification for single-end reads from
+    /// the sample, if it differs from the one used to find the sample
+    /// index.
+    fn linker_spec(&self) -> Option<&LinkerSpec> {
+        None
+    }
+
+    /// Returns the linker specification for read pairs from the
+    /// sample, if it differs from the one used to find the sample
+    /// index.
+    fn paired_spec(&self) -> Option<&PairedLinkerSpec> {
+        None
+    }
+}
+
+impl DemuxSample for String {
+    fn name(&self) -> &str {
+        self
+    }
+}
+
+impl<'a> DemuxSample for &'a str {
+    fn name(&self) -> &str {
+        self
+    }
+}
+
+/// Result of assigning a read, or a read pair, to a sample. The split
+/// `S` is a `LinkerSplit` for single-end reads and a `PairedSplit`
+/// for read pairs. Reads whose index matches no sample are assigned
+/// to the sample for unknown indices.
+pub enum Assignment<T, S> {
+    /// The read is assigned to a sample. The split holds the insert
+    /// sequence and quality after linker removal and 3' trimming,
+    /// along with the UMI and the sample index.
+    Sample(Rc<RefCell<T>>, S),
+    /// The read is assigned to a sample, as for `Sample`, but its
+    /// insert matches the contaminant sequences
+    Contaminant(Rc<RefCell<T>>, S),
+    /// The insert is too short, with the sample and its insert length
+    /// when the sample index could be found
+    TooShort(Option<(Rc<RefCell<T>>, usize)>),
+    /// No 3' adapter was found
+    NoAdapter,
+    /// The constant bases in the linker of the sample have too many
+    /// mismatches
+    BadLinker,
+    /// The record is malformed, with an error describing the record
+    Malformed(MalformedReason, failure::Error),
+    /// The read was skipped by random subsampling
+    Subsampled,
+}
+
+/// Counts of reads, or read pairs in paired-end mode, by their
+/// assignment.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SplitCounts {
+    total: usize,
+    assigned: HashMap<String, usize>,
+    contaminant: HashMap<String, usize>,
+    tooshort: usize,
+    noadapter: usize,
+    badlinker: usize,
+    malformed: BTreeMap<MalformedReason, usize>,
+    subsampled: usize,
+    unknown_indices: HashMap<Vec<u8>, usize>,
+}
+
+impl SplitCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the total number of reads processed
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the number of reads assigned to a sample, by sample
+    /// name. Reads for unknown indices are counted under the name of
+    /// the sample for unknown indices, and contaminant reads are not
+    /// counted.
+    pub fn assigned(&self, name: &str) -> usize {
+        self.assigned.get(name).map_or(0, |count| *count)
+    }
+
+    /// Returns the number of reads assigned to a sample whose insert
+    /// matches the contaminant sequences, by sample name
+    pub fn contaminant(&self, name: &str) -> usize {
+        self.contaminant.get(name).map_or(0, |count| *count)
+    }
+
+    /// Returns the number of reads with an insert that is too short
+    pub fn tooshort(&self) -> usize {
+        self.tooshort
+    }
+
+    /// Returns the number of reads where no 3' adapter was found
+    pub fn noadapter(&self) -> usize {
+        self.noadapter
+    }
+
+    /// Returns the number of reads with too many mismatches in the
+    /// constant linker bases
+    pub fn badlinker(&self) -> usize {
+        self.badlinker
+    }
+
+    /// Returns the number of malformed reads for one reason
+    pub fn malformed(&self, reason: MalformedReason) -> usize {
+        self.malformed.get(&reason).map_or(0, |count| *count)
+    }
+
+    /// Returns the number of reads skipped by random subsampling
+    pub fn subsampled(&self) -> usize {
+        self.subsampled
+    }
+
+    /// Returns the number of reads for each sample index sequence
+    /// that is not assigned to a sample. Every read whose index is
+    /// found is counted, including reads that are then too short,
+    /// contaminants, or fail the linker check.
+    pub fn unknown_indices(&self) -> &HashMap<Vec<u8>, usize> {
+        &self.unknown_indices
+    }
+
+    /// Returns the most common unassigned sample index sequences,
+    /// with their read counts, in descending order of abundance. Ties
+    /// are broken by index sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` is the maximum number of index sequences returned
+    pub fn top_unknown_indices(&self, n: usize) -> Vec<(&[u8], usize)> {
+        let mut indices: Vec<(&[u8], usize)> = self
+            .unknown_indices
+            .iter()
+            .map(|(index, count)| (index.as_slice(), *count))
+            .collect();
+        indices.sort_by(|(idx1, ct1), (idx2, ct2)| ct2.cmp(ct1).then(idx1.cmp(idx2)));
+        indices.truncate(n);
+        indices
+    }
+
+    fn tally_malformed(&mut self, reason: MalformedReason) {
+        *self.malformed.entry(reason).or_insert(0) += 1;
+    }
+
+    // Tallies a read assigned to a sample, either as a sample read or
+    // as a contaminant
+    fn tally_sample<T: DemuxSample, S>(
+        &mut self,
+        assignment: &Assignment<T, S>,
+    ) -> Result<(), failure::Error> {
+        let (tally, sample_rc) = match assignment {
+            Assignment::Sample(sample_rc, _) => (&mut self.assigned, sample_rc),
+            Assignment::Contaminant(sample_rc, _) => (&mut self.contaminant, sample_rc),
+            _ => return Ok(()),
+        };
+        let sample = sample_rc.try_borrow()?;
+        if let Some(count) = tally.get_mut(sample.name()) {
+            *count += 1;
+            return Ok(());
+        }
+        tally.insert(sample.name().to_string(), 1);
+        Ok(())
+    }
+
+    // Tallies the sample index of a read when it is assigned to the
+    // thing for unknown indices
+    fn tally_unknown<T>(&mut self, sample_map: &SampleMap<T>, sample_index: &[u8]) {
+        if sample_map.find(sample_index).is_none() {
+            *self
+                .unknown_indices
+                .entry(sample_index.to_vec())
+                .or_insert(0) += 1;
+        }
+    }
+}
+
+/// Assigns reads, or read pairs, to samples without writing any
+/// output. Each read is split using the linker of its sample and
+/// returned as an `Assignment`, leaving the caller to write or count
+/// it. Every read is also tallied in the `SplitCounts`, including the
+/// number of reads and contaminants assigned to each sample.
+///
+/// Read fates can be determined in parallel by the shared
+/// `ReadProcessor` and then assigned in input order, or reads can be
+/// demultiplexed one at a time with `demux_read` and `demux_pair`.
+///
+/// Samples are shared with the caller as `Rc<RefCell<T>>`, so a
+/// demultiplexer is not `Send` and must be used on a single thread.
+/// Only fate determination by the `ReadProcessor` runs in parallel.
+pub struct Demultiplexer<T> {
+    processor: Arc<ReadProcessor>,
+    sample_map: SampleMap<T>,
+    subsampler: Option<Subsampler>,
+    counts: SplitCounts,
+}
+
+impl<T: DemuxSample> Demultiplexer<T> {
+    /// Creates a demultiplexer for single-end reads, with no 3'
+    /// adapter and no minimum insert length
+    ///
+    /// # Arguments
+    ///
+    /// * `linker_spec` is the linker specification for the reads
+    ///
+    /// * `sample_map` maps sample indices to samples
+    pub fn new(linker_spec: LinkerSpec, sample_map: SampleMap<T>) -> Self {
+        Demultiplexer::with_processor(ReadProcessor::new(linker_spec, None, None, 0), sample_map)
+    }
+
+    /// Creates a demultiplexer using any read processing parameters
+    ///
+    /// # Arguments
+    ///
+    /// * `processor` determines read fates and splits reads
+    ///
+    /// * `sample_map` maps sample indices to samples, with the
+    /// sample index length of the processor
+    pub fn with_processor(processor: ReadProcessor, sample_map: SampleMap<T>) -> Self {
+        Demultiplexer {
+            processor: Arc::new(processor),
+            sample_map: sample_map,
+            subsampler: None,
+            counts: SplitCounts::new(),
+        }
+    }
+
+    /// Returns the read processing parameters
+    pub fn processor(&self) -> &ReadProcessor {
+        &self.processor
+    }
+
+    /// Returns the read processor shared with worker threads that
+    /// determine read fates
+    pub fn shared_processor(&self) -> Arc<ReadProcessor> {
+        self.processor.clone()
+    }
+
+    /// Returns the sample map
+    pub fn sample_map(&self) -> &SampleMap<T> {
+        &self.sample_map
+    }
+
+    /// Returns the counts of reads by their assignment
+    pub fn counts(&self) -> &SplitCounts {
+        &self.counts
+    }
+
+    /// Returns the random subsampling of reads, if any
+    pub fn subsampler(&self) -> Option<&Subsampler> {
+        self.subsampler.as_ref()
+    }
+
+    /// Sets random subsampling of reads. Malformed records are never
+    /// skipped, so that they are always reported.
+    pub fn set_subsampler(&mut self, subsampler: Option<Subsampler>) {
+        self.subsampler = subsampler;
+    }
+
+    /// Determines the fate of a single-end read and assigns it to a
+    /// sample. Index bases from read headers are used when the
+    /// processor takes them from headers, but index read inputs
+    /// require `assign_read`.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the sample index has the
+    /// wrong length for the sample map or the sample is in use.
+    pub fn demux_read<'a, R: FastxRecord>(
+        &mut self,
+        fq: &'a R,
+    ) -> Result<Assignment<T, LinkerSplit<'a>>, failure::Error> {
+        let (fate, barcode) = self
+            .processor
+            .indexed_read_fate(fq, &IndexReads::default())?;
+        self.assign_read(fq, fate, &barcode)
+    }
+
+    /// Determines the fate of a read pair and assigns it to a
+    /// sample, as for `demux_read`.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the processor has no paired
+    /// linker specification, as well as for `demux_read`.
+    pub fn demux_pair<'a, R: FastxRecord>(
+        &mut self,
+        fq1: &'a R,
+        fq2: &'a R,
+    ) -> Result<Assignment<T, PairedSplit<'a>>, failure::Error> {
+        let (fate, barcode) = self
+            .processor
+            .indexed_pair_fate(fq1, fq2, &IndexReads::default())?;
+        self.assign_pair(fq1, fq2, fate, &barcode)
+    }
+
+    /// Assigns a single-end read to a sample according to its fate,
+    /// with the barcode from any separately sequenced indices.
+    /// Samples with their own linker specification are split again
+    /// after the sample index is found, and reads that are then too
+    /// short are assigned as short reads. The constant bases are
+    /// checked in the linker of the sample, and the insert from that
+    /// linker is checked again against any contaminant sequences.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the sample index has the
+    /// wrong length for the sample map or the sample is in use.
+    pub fn assign_read<'a, R: FastxRecord>(
+        &mut self,
+        fq: &'a R,
+        fate: ReadFate,
+        barcode: &Barcode,
+    ) -> Result<Assignment<T, LinkerSplit<'a>>, failure::Error> {
+        self.counts.total += 1;
+        if self.subsample_skips(fate) {
+            return Ok(Assignment::Subsampled);
+        }
+
+        let processor = &self.processor;
+        let assignment = match fate {
+            ReadFate::Malformed(reason) => {
+                self.counts.tally_malformed(reason);
+                Assignment::Malformed(
+                    reason,
+                    format_err!("Malformed record \"{}\": {}", fq.id(), reason),
+                )
+            }
+            ReadFate::NoAdapter => {
+                self.counts.noadapter += 1;
+                Assignment::NoAdapter
+            }
+            ReadFate::TooShort(end) => {
+                self.counts.tooshort += 1;
+                match processor.try_split_read(fq, end, barcode) {
+                    Some(split) => {
+                        let sample_rc = self.sample_map.lookup(split.sample_index())?;
+                        self.counts
+                            .tally_unknown(&self.sample_map, split.sample_index());
+                        let length = Self::short_read_length(
+                            processor,
+                            &*sample_rc.try_borrow()?,
+                            fq,
+                            end,
+                            split.sequence().len(),
+                        );
+                        Assignment::TooShort(length.map(|length| (sample_rc, length)))
+                    }
+                    None => Assignment::TooShort(None),
+                }
+            }
+            ReadFate::Split(end) | ReadFate::Contaminant(end) => {
+                let split = match processor.split_read(fq, end, barcode) {
+                    Ok(split) => split,
+                    Err(err) => {
+                        self.counts.tally_malformed(MalformedReason::SplitFailed);
+                        return Ok(Assignment::Malformed(MalformedReason::SplitFailed, err));
+                    }
+                };
+                let sample_rc = self.sample_map.lookup(split.sample_index())?;
+                self.counts
+                    .tally_unknown(&self.sample_map, split.sample_index());
+                let insert_length = split.sequence().len();
+                let (sample_split, resplit) = match sample_rc.try_borrow()?.linker_spec() {
+                    Some(linker_spec) => {
+                        (processor.resplit_read(linker_spec, fq, end, barcode), true)
+                    }
+                    None => (Some(split), false),
+                };
+                match sample_split {
+                    Some(ref sample_split)
+                        if sample_split.constant_mismatches()
+                            > processor.max_constant_mismatch() =>
+                    {
+                        self.counts.badlinker += 1;
+                        Assignment::BadLinker
+                    }
+                    Some(sample_split) => {
+                        // The insert from the sample's own linker is
+                        // checked again for contaminants
+                        let contaminant = if resplit {
+                            processor.is_contaminant(sample_split.sequence())
+                        } else {
+                            fate == ReadFate::Contaminant(end)
+                        };
+                        if contaminant {
+                            Assignment::Contaminant(sample_rc, sample_split)
+                        } else {
+                            Assignment::Sample(sample_rc, sample_split)
+                        }
+                    }
+                    None => {
+                        self.counts.tooshort += 1;
+                        let length = Self::short_read_length(
+                            processor,
+                            &*sample_rc.try_borrow()?,
+                            fq,
+                            end,
+                            insert_length,
+                        );
+                        Assignment::TooShort(length.map(|length| (sample_rc, length)))
+                    }
+                }
+            }
+        };
+
+        self.counts.tally_sample(&assignment)?;
+        Ok(assignment)
+    }
+
+    /// Assigns a read pair to a sample according to its fate, as for
+    /// single-end reads.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the processor has no paired
+    /// linker specification, as well as for `assign_read`.
+    pub fn assign_pair<'a, R: FastxRecord>(
+        &mut self,
+        fq1: &'a R,
+        fq2: &'a R,
+        fate: ReadFate,
+        barcode: &Barcode,
+    ) -> Result<Assignment<T, PairedSplit<'a>>, failure::Error> {
+        self.counts.total += 1;
+        if self.subsample_skips(fate) {
+            return Ok(Assignment::Subsampled);
+        }
+
+        let processor = &self.processor;
+        let assignment = match fate {
+            ReadFate::Malformed(reason) => {
+                self.counts.tally_malformed(reason);
+                Assignment::Malformed(
+                    reason,
+                    format_err!(
+                        "Malformed record pair \"{}\" / \"{}\": {}",
+                        fq1.id(),
+                        fq2.id(),
+                        reason
+                    ),
+                )
+            }
+            ReadFate::NoAdapter => {
+                self.counts.noadapter += 1;
+                Assignment::NoAdapter
+            }
+            ReadFate::TooShort(end1) => {
+                self.counts.tooshort += 1;
+                match processor.try_split_pair(fq1, end1, fq2, barcode) {
+                    Some(split) => {
+                        let sample_rc = self.sample_map.lookup(split.sample_index())?;
+                        self.counts
+                            .tally_unknown(&self.sample_map, split.sample_index());
+                        let length = Self::short_pair_length(
+                            processor,
+                            &*sample_rc.try_borrow()?,
+                            fq1,
+                            end1,
+                            fq2,
+                            split.mate1().sequence().len(),
+                        );
+                        Assignment::TooShort(length.map(|length| (sample_rc, length)))
+                    }
+                    None => Assignment::TooShort(None),
+                }
+            }
+            ReadFate::Split(end1) | ReadFate::Contaminant(end1) => {
+                let split = match processor.split_pair(fq1, end1, fq2, barcode) {
+                    Ok(split) => split,
+                    Err(err) => {
+                        self.counts.tally_malformed(MalformedReason::SplitFailed);
+                        return Ok(Assignment::Malformed(MalformedReason::SplitFailed, err));
+                    }
+                };
+                let sample_rc = self.sample_map.lookup(split.sample_index())?;
+                self.counts
+                    .tally_unknown(&self.sample_map, split.sample_index());
+                let insert_length = split.mate1().sequence().len();
+                let (sample_split, resplit) = match sample_rc.try_borrow()?.paired_spec() {
+                    Some(paired_spec) => (
+                        processor.resplit_pair(paired_spec, fq1, end1, fq2, barcode),
+                        true,
+                    ),
+                    None => (Some(split), false),
+                };
+                match sample_split {
+                    Some(ref sample_split)
+                        if sample_split.constant_mismatches()
+                            > processor.max_constant_mismatch() =>
+                    {
+                        self.counts.badlinker += 1;
+                        Assignment::BadLinker
+                    }
+                    Some(sample_split) => {
+                        let contaminant = if resplit {
+                            processor.is_contaminant(sample_split.mate1().sequence())
+                        } else {
+                            fate == ReadFate::Contaminant(end1)
+                        };
+                        if contaminant {
+                            Assignment::Contaminant(sample_rc, sample_split)
+                        } else {
+                            Assignment::Sample(sample_rc, sample_split)
+                        }
+                    }
+                    None => {
+                        self.counts.tooshort += 1;
+                        let length = Self::short_pair_length(
+                            processor,
+                            &*sample_rc.try_borrow()?,
+                            fq1,
+                            end1,
+                            fq2,
+                            insert_length,
+                        );
+                        Assignment::TooShort(length.map(|length| (sample_rc, length)))
+                    }
+                }
+            }
+        };
+
+        self.counts.tally_sample(&assignment)?;
+        Ok(assignment)
+    }
+
+    /// Tallies a single-end read that could not be parsed and so is
+    /// never assigned. Unparsed reads are not subsampled.
+    pub fn tally_unparsed(&mut self) {
+        self.counts.total += 1;
+        self.counts.tally_malformed(MalformedReason::Unparseable);
+    }
+
+    // Returns true, tallying the read, when a read is skipped by
+    // subsampling
+    fn subsample_skips(&mut self, fate: ReadFate) -> bool {
+        if let ReadFate::Malformed(_) = fate {
+            return false;
+        }
+
+        match self.subsampler {
+            Some(ref mut subsampler) if !subsampler.keep() => {
+                self.counts.subsampled += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Reads with a short insert are tallied in the insert length
+    // histogram of their sample, after 3' trimming, using the linker
+    // of the sample when it differs from the default linker
+    fn short_read_length<R: FastxRecord>(
+        processor: &ReadProcessor,
+        sample: &T,
+        fq: &R,
+        end: usize,
+        insert_length: usize,
+    ) -> Option<usize> {
+        match sample.linker_spec() {
+            Some(linker_spec) => linker_spec.split_record_at(fq, end).map(|mut split| {
+                processor.trim_read(&mut split);
+                split.sequence().len()
+            }),
+            None => Some(insert_length),
+        }
+    }
+
+    fn short_pair_length<R: FastxRecord>(
+        processor: &ReadProcessor,
+        sample: &T,
+        fq1: &R,
+        end1: usize,
+        fq2: &R,
+        insert_length: usize,
+    ) -> Option<usize> {
+        match sample.paired_spec() {
+            Some(paired_spec) => paired_spec.split_pair_at(fq1, end1, fq2).map(|mut split| {
+                processor.trim_pair(&mut split);
+                split.mate1().sequence().len()
+            }),
+            None => Some(insert_length),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::io::fastq;
+
+    use fastx_split::contaminant::*;
+
+    fn sample_name<S>(assignment: &Assignment<String, S>) -> Option<String> {
+        match assignment {
+            Assignment::Sample(sample_rc, _) => Some(sample_rc.borrow().clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn demux_reads() {
+        let mut sample_map = SampleMap::new(2, "unknown".to_string());
+        sample_map
+            .insert(b"AC".to_vec(), 0, "first".to_string())
+            .unwrap();
+        sample_map
+            .insert(b"GT".to_vec(), 0, "second".to_string())
+            .unwrap();
+        let mut demux = Demultiplexer::new(LinkerSpec::new("NII", "").unwrap(), sample_map);
+
+        let first = fastq::Record::with_attrs("first", None, b"TACGGATT", b"IIIIIIII");
+        let assignment = demux.demux_read(&first).unwrap();
+        assert!(sample_name(&assignment) == Some("first".to_string()));
+        if let Assignment::Sample(_, split) = assignment {
+            assert!(split.sequence() == b"GGATT");
+            assert!(split.umi() == b"T");
+        }
+
+        let second = fastq::Record::with_attrs("second", None, b"CGTAAAA", b"IIIIIII");
+        let assignment = demux.demux_read(&second).unwrap();
+        assert!(sample_name(&assignment) == Some("second".to_string()));
+
+        let unknown = fastq::Record::with_attrs("unknown", None, b"CTTAAAA", b"IIIIIII");
+        let assignment = demux.demux_read(&unknown).unwrap();
+        assert!(sample_name(&assignment) == Some("unknown".to_string()));
+
+        let bad = fastq::Record::with_attrs("bad", None, b"CGTAAAA", b"III");
+        match demux.demux_read(&bad).unwrap() {
+            Assignment::Malformed(reason, _) => assert!(reason == MalformedReason::BadQuality),
+            _ => panic!("Malformed read was assigned"),
+        }
+
+        let again = fastq::Record::with_attrs("again", None, b"AACAAAA", b"IIIIIII");
+        let assignment = demux.demux_read(&again).unwrap();
+        assert!(sample_name(&assignment) == Some("first".to_string()));
+
+        assert!(demux.counts().total() == 5);
+        assert!(demux.counts().assigned("first") == 2);
+        assert!(demux.counts().assigned("second") == 1);
+        assert!(demux.counts().assigned("unknown") == 1);
+        assert!(demux.counts().assigned("other") == 0);
+        assert!(demux.counts().contaminant("first") == 0);
+        assert!(demux.counts().malformed(MalformedReason::BadQuality) == 1);
+        assert!(demux.counts().tooshort() == 0);
+    }
+
+    #[test]
+    fn demux_short() {
+        let mut sample_map = SampleMap::new(1, "unknown");
+        sample_map.insert(b"A".to_vec(), 0, "first").unwrap();
+        let processor = ReadProcessor::new(LinkerSpec::new("NI", "").unwrap(), None, None, 3);
+        let mut demux = Demultiplexer::with_processor(processor, sample_map);
+
+        let short = fastq::Record::with_attrs("short", None, b"TACG", b"IIII");
+        match demux.demux_read(&short).unwrap() {
+            Assignment::TooShort(Some((sample_rc, length))) => {
+                assert!(*sample_rc.borrow() == "first");
+                assert!(length == 2);
+            }
+            _ => panic!("Short read was not assigned as short"),
+        }
+        assert!(demux.counts().tooshort() == 1);
+    }
+
+    struct LinkerSample(&'static str, Option<LinkerSpec>);
+
+    impl DemuxSample for LinkerSample {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn linker_spec(&self) -> Option<&LinkerSpec> {
+            self.1.as_ref()
+        }
+    }
+
+    #[test]
+    fn demux_contaminant() {
+        let mut sample_map = SampleMap::new(1, LinkerSample("unknown", None));
+        let own_linker = LinkerSpec::new("INNN", "").unwrap();
+        sample_map
+            .insert(b"A".to_vec(), 0, LinkerSample("own", Some(own_linker)))
+            .unwrap();
+        sample_map
+            .insert(b"C".to_vec(), 0, LinkerSample("default", None))
+            .unwrap();
+        let mut processor = ReadProcessor::new(LinkerSpec::new("I", "").unwrap(), None, None, 3);
+        let mut contaminants = ContaminantIndex::new(4, 1.0).unwrap();
+        contaminants.add_sequence(b"ACGTACCG");
+        processor.set_contaminants(Some(contaminants));
+        let mut demux = Demultiplexer::with_processor(processor, sample_map);
+
+        // The default linker leaves TTT on the insert, so only the
+        // insert from the sample's own linker is a contaminant
+        let own = fastq::Record::with_attrs("own", None, b"ATTTACGTACCG", b"IIIIIIIIIIII");
+        match demux.demux_read(&own).unwrap() {
+            Assignment::Contaminant(_, split) => assert!(split.sequence() == b"ACGTACCG"),
+            _ => panic!("Contaminant was not assigned as a contaminant"),
+        }
+
+        let default = fastq::Record::with_attrs("default", None, b"CTTTACGTACCG", b"IIIIIIIIIIII");
+        match demux.demux_read(&default).unwrap() {
+            Assignment::Sample(_, split) => assert!(split.sequence() == b"TTTACGTACCG"),
+            _ => panic!("Read was not assigned to its sample"),
+        }
+
+        assert!(demux.counts().contaminant("own") == 1);
+        assert!(demux.counts().assigned("own") == 0);
+        assert!(demux.counts().contaminant("default") == 0);
+        assert!(demux.counts().assigned("default") == 1);
+    }
+}
@@ -0,0 +1,695 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use failure;
+
+use fastx_split::compress::*;
+use fastx_split::demux::*;
+use fastx_split::fastx::*;
+use fastx_split::linkers::*;
+use fastx_split::pipeline::*;
+use fastx_split::processor::*;
+use fastx_split::sample::*;
+use fastx_split::sample_sheet::*;
+use fastx_split::sample_writer::*;
+use fastx_split::ubam::*;
+use umi::*;
+
+/// Creates output files in one output directory, all with the same
+/// sequence format and compression. Threaded outputs are written and
+/// compressed on their own threads. Sample outputs may instead be
+/// written as unaligned BAM.
+#[derive(Debug, Clone)]
+pub struct OutputFiles {
+    output_dir: PathBuf,
+    format: FastxFormat,
+    compression: OutputCompression,
+    threaded: bool,
+    unaligned_bam: bool,
+}
+
+impl OutputFiles {
+    pub fn new(
+        output_dir: &Path,
+        format: FastxFormat,
+        compression: OutputCompression,
+        threaded: bool,
+    ) -> Self {
+        OutputFiles {
+            output_dir: output_dir.to_path_buf(),
+            format: format,
+            compression: compression,
+            threaded: threaded,
+            unaligned_bam: false,
+        }
+    }
+
+    /// Returns the output sequence format
+    pub fn format(&self) -> FastxFormat {
+        self.format
+    }
+
+    /// Sets whether sample outputs, including contaminant outputs,
+    /// are written as unaligned BAM rather than in the output
+    /// sequence format. Other outputs are unaffected.
+    pub fn set_unaligned_bam(&mut self, unaligned_bam: bool) {
+        self.unaligned_bam = unaligned_bam;
+    }
+
+    // Appends the extension to the base name, so that names containing
+    // a '.' keep their full text and remain distinct
+    fn output_path(&self, name: &str, extension: &str) -> PathBuf {
+        let mut output_path = self.output_dir.clone();
+        output_path.push(format!("{}.{}", name, extension));
+        output_path
+    }
+
+    /// Creates an output file in the output directory, adding the
+    /// extension for the sequence format and compression.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` is the base name of the output file
+    pub fn create(&self, name: &str) -> Result<Box<FinishWrite>, failure::Error> {
+        let output_path =
+            self.output_path(name, &self.compression.extension(self.format.extension()));
+        let writer = self.compression.create(output_path.as_path())?;
+        if self.threaded {
+            Ok(Box::new(ThreadedWriter::new(writer)))
+        } else {
+            Ok(writer)
+        }
+    }
+
+    /// Creates the output for the processed records of a sample. An
+    /// unaligned BAM output is written to `<output_name>.bam` with a
+    /// read group for the sample. Otherwise, paired-end samples write
+    /// mate 1 and mate 2 to `<output_name>_R1` and `<output_name>_R2`.
+    ///
+    /// # Arguments
+    ///
+    /// * `output_name` is the base name for the output files
+    ///
+    /// * `read_group` is the read group for unaligned BAM output
+    ///
+    /// * `paired` is true for paired-end samples
+    pub fn create_sample_writer(
+        &self,
+        output_name: &str,
+        read_group: ReadGroup,
+        paired: bool,
+    ) -> Result<SampleWriter, failure::Error> {
+        if self.unaligned_bam {
+            let output_path = self.output_path(output_name, "bam");
+            Ok(SampleWriter::Bam(UnalignedBamWriter::from_path(
+                &output_path,
+                read_group,
+            )?))
+        } else if paired {
+            let dest1 = self.create(&format!("{}_R1", output_name))?;
+            let dest2 = self.create(&format!("{}_R2", output_name))?;
+            Ok(SampleWriter::fastx_paired(self.format, dest1, dest2))
+        } else {
+            Ok(SampleWriter::fastx(self.format, self.create(output_name)?))
+        }
+    }
+
+    /// Creates a sample with its output files, as described for
+    /// `create_sample_writer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` is the sample name
+    ///
+    /// * `output_name` is the base name for the sample output files
+    ///
+    /// * `index` is the sample index sequence
+    ///
+    /// * `description` is the sample description, if any
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs in read names
+    ///
+    /// * `paired` is true for paired-end samples
+    pub fn create_sample(
+        &self,
+        name: &str,
+        output_name: &str,
+        index: Vec<u8>,
+        description: Option<&str>,
+        umi_encoding: UmiEncoding,
+        paired: bool,
+    ) -> Result<Sample, failure::Error> {
+        let read_group = ReadGroup::new(name, &index, description);
+        let dest = self.create_sample_writer(output_name, read_group, paired)?;
+        let mut sample = Sample::with_writer(name.to_string(), index, umi_encoding, dest);
+        sample.set_description(description);
+        Ok(sample)
+    }
+
+    /// Creates the contaminant outputs for a sample,
+    /// `<output_name>_contam` as described for
+    /// `create_sample_writer`, with the read group of the sample.
+    pub fn create_contaminant_outputs(
+        &self,
+        sample: &mut Sample,
+        output_name: &str,
+        paired: bool,
+    ) -> Result<(), failure::Error> {
+        let read_group = ReadGroup::new(sample.name(), sample.index(), sample.description());
+        let dest =
+            self.create_sample_writer(&format!("{}_contam", output_name), read_group, paired)?;
+        sample.set_contam_dest(dest);
+        Ok(())
+    }
+}
+
+/// Output for reads that are not assigned to any sample, with a
+/// second output for mate 2 of paired-end reads.
+pub struct FateOutput {
+    mate1: FastxWriter,
+    mate2: Option<FastxWriter>,
+}
+
+impl FateOutput {
+    /// Creates the output files, `<name>` for single-end reads or
+    /// `<name>_R1` and `<name>_R2` for paired-end reads.
+    pub fn create(files: &OutputFiles, name: &str, paired: bool) -> Result<Self, failure::Error> {
+        if paired {
+            let dest1 = files.create(&format!("{}_R1", name))?;
+            let dest2 = files.create(&format!("{}_R2", name))?;
+            Ok(FateOutput {
+                mate1: FastxWriter::new(files.format(), dest1),
+                mate2: Some(FastxWriter::new(files.format(), dest2)),
+            })
+        } else {
+            Ok(FateOutput {
+                mate1: FastxWriter::new(files.format(), files.create(name)?),
+                mate2: None,
+            })
+        }
+    }
+
+    /// Writes a single-end read
+    pub fn write_record<R: FastxRecord>(&mut self, fq: &R) -> Result<(), failure::Error> {
+        self.mate1.write_record(fq)
+    }
+
+    /// Writes both mates of a read pair
+    pub fn write_pair<R: FastxRecord>(&mut self, fq1: &R, fq2: &R) -> Result<(), failure::Error> {
+        self.mate1.write_record(fq1)?;
+        if let Some(ref mut mate2) = self.mate2 {
+            mate2.write_record(fq2)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the original text of a single-end read that could not
+    /// be parsed
+    pub fn write_unparsed(&mut self, unparsed: &Unparsed) -> Result<(), failure::Error> {
+        self.mate1.write_unparsed(unparsed)
+    }
+
+    /// Finishes the output files after all reads have been written
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        self.mate1.finish()?;
+        if let Some(ref mut mate2) = self.mate2 {
+            mate2.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Handling of malformed records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MalformedPolicy {
+    /// Count and discard malformed records
+    Skip,
+    /// Count malformed records and write them to a rejected output
+    Reject,
+    /// Stop with an error at the first malformed record
+    Abort,
+}
+
+impl MalformedPolicy {
+    /// Returns the name strings accepted by `from_str`
+    pub fn names() -> &'static [&'static str] {
+        &["skip", "reject", "abort"]
+    }
+}
+
+impl FromStr for MalformedPolicy {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "skip" => Ok(MalformedPolicy::Skip),
+            "reject" => Ok(MalformedPolicy::Reject),
+            "abort" => Ok(MalformedPolicy::Abort),
+            _ => Err(format_err!(
+                "Unknown malformed record policy \"{}\", expected one of {}",
+                name,
+                MalformedPolicy::names().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MalformedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MalformedPolicy::Skip => "skip",
+            MalformedPolicy::Reject => "reject",
+            MalformedPolicy::Abort => "abort",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Handles reads, in input order, once their fates have been
+/// determined by a `ReadProcessor`. Reads are assigned to samples by
+/// a `Demultiplexer`, then written to the sample or fate outputs and
+/// tallied in the sample statistics.
+pub struct Dispatcher {
+    demux: Demultiplexer<Sample>,
+    short_file: FateOutput,
+    noadapter_file: Option<FateOutput>,
+    badlinker_file: Option<FateOutput>,
+    malformed_policy: MalformedPolicy,
+    rejected_file: Option<FateOutput>,
+    finished: bool,
+    capped: usize,
+}
+
+impl Dispatcher {
+    /// Creates a new read dispatcher
+    ///
+    /// # Arguments
+    ///
+    /// * `demux` assigns reads to samples
+    ///
+    /// * `short_file` is the output for reads with a short insert
+    ///
+    /// * `noadapter_file` is the output for reads with no 3' adapter
+    ///
+    /// * `badlinker_file` is the output for reads with mismatches in
+    /// the constant linker bases, used when any linker has constant
+    /// bases
+    ///
+    /// * `malformed_policy` is the handling of malformed records
+    ///
+    /// * `rejected_file` is the output for malformed records, used
+    /// only with `MalformedPolicy::Reject`
+    pub fn new(
+        demux: Demultiplexer<Sample>,
+        short_file: FateOutput,
+        noadapter_file: Option<FateOutput>,
+        badlinker_file: Option<FateOutput>,
+        malformed_policy: MalformedPolicy,
+        rejected_file: Option<FateOutput>,
+    ) -> Self {
+        Dispatcher {
+            demux: demux,
+            short_file: short_file,
+            noadapter_file: noadapter_file,
+            badlinker_file: badlinker_file,
+            malformed_policy: malformed_policy,
+            rejected_file: if malformed_policy == MalformedPolicy::Reject {
+                rejected_file
+            } else {
+                None
+            },
+            finished: false,
+            capped: 0,
+        }
+    }
+
+    /// Returns the demultiplexer that assigns reads to samples
+    pub fn demux(&self) -> &Demultiplexer<Sample> {
+        &self.demux
+    }
+
+    /// Returns the read processing parameters
+    pub fn processor(&self) -> &ReadProcessor {
+        self.demux.processor()
+    }
+
+    /// Returns the handling of malformed records
+    pub fn malformed_policy(&self) -> MalformedPolicy {
+        self.malformed_policy
+    }
+
+    /// Returns true when constant linker bases are checked
+    pub fn checks_constants(&self) -> bool {
+        self.badlinker_file.is_some()
+    }
+
+    /// Returns true when any sample has a maximum number of reads
+    pub fn caps_samples(&self) -> bool {
+        self.sample_map().things().iter().any(|sample_rc| {
+            sample_rc
+                .try_borrow()
+                .map_or(false, |sample| sample.max_reads().is_some())
+        })
+    }
+
+    /// Returns true once every sample in the sample sheet has reached
+    /// its maximum number of reads, so that no further reads need to
+    /// be handled. This is never true when any sample is uncapped.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the sample map
+    pub fn sample_map(&self) -> &SampleMap<Sample> {
+        self.demux.sample_map()
+    }
+
+    /// Returns the counts of reads that are not assigned to a sample
+    pub fn counts(&self) -> &SplitCounts {
+        self.demux.counts()
+    }
+
+    /// Returns the number of reads dropped because their sample had
+    /// already reached its maximum number of reads
+    pub fn capped(&self) -> usize {
+        self.capped
+    }
+
+    /// Finishes all sample and fate outputs after the last read has
+    /// been handled. Outputs that are dropped without being finished
+    /// are completed on a best-effort basis, with errors discarded.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in completing
+    /// any output.
+    pub fn finish_outputs(&mut self) -> Result<(), failure::Error> {
+        for sample_rc in self.sample_map().things() {
+            sample_rc.try_borrow_mut()?.finish()?;
+        }
+
+        self.short_file.finish()?;
+        let fate_files = self
+            .noadapter_file
+            .iter_mut()
+            .chain(self.badlinker_file.iter_mut())
+            .chain(self.rejected_file.iter_mut());
+        for fate_file in fate_files {
+            fate_file.finish()?;
+        }
+        Ok(())
+    }
+
+    // Returns the output for a malformed record if it should be
+    // written, or the error if the run should stop.
+    fn malformed(
+        &mut self,
+        err: failure::Error,
+    ) -> Result<Option<&mut FateOutput>, failure::Error> {
+        if self.malformed_policy == MalformedPolicy::Abort {
+            return Err(err);
+        }
+
+        Ok(self.rejected_file.as_mut())
+    }
+
+    // Called when a sample becomes full. Reads with an unknown index
+    // never stop the run early.
+    fn update_finished(&mut self) {
+        let unknown = self.sample_map().unknown();
+        self.finished = self
+            .sample_map()
+            .things()
+            .iter()
+            .filter(|sample_rc| !Rc::ptr_eq(sample_rc, &unknown))
+            .all(|sample_rc| {
+                sample_rc
+                    .try_borrow()
+                    .map_or(false, |sample| sample.is_full())
+            });
+    }
+
+    /// Handles a single-end read according to its fate, with the
+    /// barcode from any separately sequenced indices, after assigning
+    /// it to a sample. Contaminant reads are written to the
+    /// contaminant output of their sample. Reads for a sample that
+    /// is already full are dropped, but contaminant reads are not
+    /// capped.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the read cannot be written,
+    /// or when it is malformed and malformed records abort the run.
+    pub fn handle_read<R: FastxRecord>(
+        &mut self,
+        fq: &R,
+        fate: ReadFate,
+        barcode: &Barcode,
+    ) -> Result<(), failure::Error> {
+        let mut filled = false;
+
+        match self.demux.assign_read(fq, fate, barcode)? {
+            Assignment::Malformed(_, err) => {
+                if let Some(rejected_file) = self.malformed(err)? {
+                    rejected_file.write_record(fq)?;
+                }
+            }
+            Assignment::NoAdapter => {
+                if let Some(ref mut noadapter_file) = self.noadapter_file {
+                    noadapter_file.write_record(fq)?;
+                }
+            }
+            Assignment::BadLinker => {
+                if let Some(ref mut badlinker_file) = self.badlinker_file {
+                    badlinker_file.write_record(fq)?;
+                }
+            }
+            Assignment::TooShort(sample_length) => {
+                if let Some((sample_rc, length)) = sample_length {
+                    sample_rc.try_borrow_mut()?.tally_insert_length(length);
+                }
+                self.short_file.write_record(fq)?;
+            }
+            Assignment::Contaminant(sample_rc, split) => {
+                sample_rc
+                    .try_borrow_mut()?
+                    .handle_contaminant_read(fq, &split)?;
+            }
+            Assignment::Sample(sample_rc, split) => {
+                let mut sample = sample_rc.try_borrow_mut()?;
+                if sample.is_full() {
+                    self.capped += 1;
+                } else {
+                    sample.handle_split_read(fq, &split)?;
+                    filled = sample.is_full();
+                }
+            }
+            Assignment::Subsampled => (),
+        }
+
+        if filled {
+            self.update_finished();
+        }
+
+        Ok(())
+    }
+
+    /// Handles a read pair according to its fate, as for single-end
+    /// reads.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the pair cannot be written,
+    /// or when it is malformed and malformed records abort the run.
+    pub fn handle_pair<R: FastxRecord>(
+        &mut self,
+        fq1: &R,
+        fq2: &R,
+        fate: ReadFate,
+        barcode: &Barcode,
+    ) -> Result<(), failure::Error> {
+        let mut filled = false;
+
+        match self.demux.assign_pair(fq1, fq2, fate, barcode)? {
+            Assignment::Malformed(_, err) => {
+                if let Some(rejected_file) = self.malformed(err)? {
+                    rejected_file.write_pair(fq1, fq2)?;
+                }
+            }
+            Assignment::NoAdapter => {
+                if let Some(ref mut noadapter_file) = self.noadapter_file {
+                    noadapter_file.write_pair(fq1, fq2)?;
+                }
+            }
+            Assignment::BadLinker => {
+                if let Some(ref mut badlinker_file) = self.badlinker_file {
+                    badlinker_file.write_pair(fq1, fq2)?;
+                }
+            }
+            Assignment::TooShort(sample_length) => {
+                if let Some((sample_rc, length)) = sample_length {
+                    sample_rc.try_borrow_mut()?.tally_insert_length(length);
+                }
+                self.short_file.write_pair(fq1, fq2)?;
+            }
+            Assignment::Contaminant(sample_rc, split) => {
+                sample_rc
+                    .try_borrow_mut()?
+                    .handle_contaminant_pair(fq1, fq2, &split)?;
+            }
+            Assignment::Sample(sample_rc, split) => {
+                let mut sample = sample_rc.try_borrow_mut()?;
+                if sample.is_full() {
+                    self.capped += 1;
+                } else {
+                    sample.handle_split_pair(fq1, fq2, &split)?;
+                    filled = sample.is_full();
+                }
+            }
+            Assignment::Subsampled => (),
+        }
+
+        if filled {
+            self.update_finished();
+        }
+
+        Ok(())
+    }
+
+    /// Handles a single-end read that could not be parsed, which is
+    /// counted as malformed and written unchanged to the rejected
+    /// output when malformed records are rejected.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the text cannot be written,
+    /// or when malformed records abort the run.
+    pub fn handle_unparsed_read(&mut self, unparsed: &Unparsed) -> Result<(), failure::Error> {
+        self.demux.tally_unparsed();
+        let err = format_err!("Unparseable record at {}", unparsed);
+        if let Some(rejected_file) = self.malformed(err)? {
+            rejected_file.write_unparsed(unparsed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::io::fastq;
+
+    use fastx_split::index_reads::*;
+
+    // Creates a sample writing FastQ records to a shared buffer
+    fn capped_sample(name: &str, index: &[u8], max_reads: Option<usize>) -> (Sample, SharedBuffer) {
+        let outbuf = SharedBuffer::new();
+        let mut sample = Sample::new(
+            name.to_string(),
+            index.to_vec(),
+            FastxFormat::Fastq,
+            UmiEncoding::Hash,
+            outbuf.clone(),
+        );
+        sample.set_max_reads(max_reads);
+        (sample, outbuf)
+    }
+
+    fn fate_output() -> FateOutput {
+        FateOutput {
+            mate1: FastxWriter::new(FastxFormat::Fastq, Box::new(SharedBuffer::new())),
+            mate2: None,
+        }
+    }
+
+    fn handle(dispatcher: &mut Dispatcher, seq: &[u8]) {
+        let fq = fastq::Record::with_attrs("read", None, seq, &vec![b'I'; seq.len()]);
+        let (fate, barcode) = dispatcher
+            .processor()
+            .indexed_read_fate(&fq, &IndexReads::default())
+            .unwrap();
+        dispatcher.handle_read(&fq, fate, &barcode).unwrap();
+    }
+
+    #[test]
+    fn capped_samples() {
+        let (unknown, _) = capped_sample("UnknownIndex", b"NN", None);
+        let (one, one_out) = capped_sample("one", b"AC", Some(2));
+        let (two, two_out) = capped_sample("two", b"GT", Some(1));
+        let mut sample_map = SampleMap::new(2, unknown);
+        sample_map.insert(b"AC".to_vec(), 0, one).unwrap();
+        sample_map.insert(b"GT".to_vec(), 0, two).unwrap();
+
+        let processor = ReadProcessor::new(LinkerSpec::new("II", "").unwrap(), None, None, 2);
+        let mut dispatcher = Dispatcher::new(
+            Demultiplexer::with_processor(processor, sample_map),
+            fate_output(),
+            None,
+            None,
+            MalformedPolicy::Skip,
+            None,
+        );
+        assert!(dispatcher.caps_samples());
+
+        handle(&mut dispatcher, b"ACGGAA");
+        handle(&mut dispatcher, b"ACGGTT");
+        assert!(dispatcher.capped() == 0);
+        assert!(!dispatcher.is_finished());
+
+        // The third read for the full sample is dropped
+        handle(&mut dispatcher, b"ACGGCC");
+        assert!(dispatcher.capped() == 1);
+        assert!(!dispatcher.is_finished());
+
+        // Reads with an unknown index are never capped
+        for _ in 0..3 {
+            handle(&mut dispatcher, b"TTGGAA");
+        }
+        assert!(dispatcher.capped() == 1);
+        assert!(!dispatcher.is_finished());
+
+        handle(&mut dispatcher, b"GTGGAA");
+        assert!(dispatcher.is_finished());
+
+        dispatcher.finish_outputs().unwrap();
+        let records =
+            |outbuf: &SharedBuffer| outbuf.contents().iter().filter(|&&ch| ch == b'\n').count() / 4;
+        assert!(records(&one_out) == 2);
+        assert!(records(&two_out) == 1);
+        // The demultiplexer counts the capped read as assigned
+        assert!(dispatcher.counts().total() == 7);
+        assert!(dispatcher.counts().assigned("one") == 3);
+        assert!(dispatcher.counts().assigned("UnknownIndex") == 3);
+    }
+
+    #[test]
+    fn uncapped_samples() {
+        let (unknown, _) = capped_sample("UnknownIndex", b"NN", None);
+        let (one, _) = capped_sample("one", b"AC", Some(1));
+        let (two, _) = capped_sample("two", b"GT", None);
+        let mut sample_map = SampleMap::new(2, unknown);
+        sample_map.insert(b"AC".to_vec(), 0, one).unwrap();
+        sample_map.insert(b"GT".to_vec(), 0, two).unwrap();
+
+        let processor = ReadProcessor::new(LinkerSpec::new("II", "").unwrap(), None, None, 2);
+        let mut dispatcher = Dispatcher::new(
+            Demultiplexer::with_processor(processor, sample_map),
+            fate_output(),
+            None,
+            None,
+            MalformedPolicy::Skip,
+            None,
+        );
+
+        // An uncapped sample keeps the run going after the capped
+        // sample is full
+        handle(&mut dispatcher, b"ACGGAA");
+        handle(&mut dispatcher, b"ACGGTT");
+        handle(&mut dispatcher, b"GTGGAA");
+        assert!(dispatcher.capped() == 1);
+        assert!(!dispatcher.is_finished());
+    }
+}
@@ -0,0 +1,878 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+
+use failure;
+
+use bio::io::{fasta, fastq};
+
+use fastx_split::compress::*;
+
+/// Offset of Phred quality scores in FastQ quality strings
+pub const PHRED_OFFSET: u8 = 33;
+
+/// Sequence file format for inputs and outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FastxFormat {
+    Fasta,
+    Fastq,
+}
+
+impl FastxFormat {
+    /// Detects the format of a sequence file from its first
+    /// non-whitespace character, `>` for FastA and `@` for
+    /// FastQ. Leading whitespace is consumed but the first record is
+    /// left intact. An empty input is treated as FastQ.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is the buffered input stream
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the input cannot be read or
+    /// begins with any other character.
+    pub fn detect<R: BufRead + ?Sized>(input: &mut R) -> Result<Self, failure::Error> {
+        loop {
+            let (skip, first) = {
+                let buf = input.fill_buf()?;
+                if buf.is_empty() {
+                    return Ok(FastxFormat::Fastq);
+                }
+                match buf.iter().position(|ch| !ch.is_ascii_whitespace()) {
+                    Some(pos) => (pos, Some(buf[pos])),
+                    None => (buf.len(), None),
+                }
+            };
+            input.consume(skip);
+
+            match first {
+                Some(b'>') => return Ok(FastxFormat::Fasta),
+                Some(b'@') => return Ok(FastxFormat::Fastq),
+                Some(ch) => {
+                    return Err(format_err!(
+                        "Unrecognized sequence format, starting with \'{}\'",
+                        ch as char
+                    ))
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Returns the conventional file name extension for the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FastxFormat::Fasta => "fasta",
+            FastxFormat::Fastq => "fastq",
+        }
+    }
+}
+
+/// Opened sequence input whose format has been detected.
+pub struct FastxInput {
+    name: PathBuf,
+    format: FastxFormat,
+    reader: Box<BufRead + Send>,
+}
+
+impl FastxInput {
+    /// Opens a sequence input, decompressing it if needed, and
+    /// detects its format.
+    ///
+    /// # Arguments
+    ///
+    /// * `input_name` is the input file name, or `-` for standard input
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the input cannot be opened
+    /// or its format cannot be determined.
+    pub fn open<P: AsRef<Path>>(input_name: P) -> Result<Self, failure::Error> {
+        let mut reader = open_input(&input_name)?;
+        let format = FastxFormat::detect(&mut reader)
+            .map_err(|e| format_err!("{}: {}", input_name.as_ref().to_string_lossy(), e))?;
+        Ok(FastxInput {
+            name: input_name.as_ref().to_path_buf(),
+            format: format,
+            reader: reader,
+        })
+    }
+
+    /// Returns the input file name
+    pub fn name(&self) -> &Path {
+        &self.name
+    }
+
+    /// Returns the detected sequence format
+    pub fn format(&self) -> FastxFormat {
+        self.format
+    }
+
+    /// Returns the buffered (and decompressed) input stream
+    pub fn into_reader(self) -> Box<BufRead + Send> {
+        self.reader
+    }
+}
+
+/// Common interface to FastA and FastQ sequence records. FastA
+/// records have an empty quality string.
+pub trait FastxRecord {
+    /// Returns the record identifier
+    fn id(&self) -> &str;
+
+    /// Returns the record description, if any
+    fn desc(&self) -> Option<&str>;
+
+    /// Returns the record sequence
+    fn seq(&self) -> &[u8];
+
+    /// Returns the record quality, which is empty for FastA records
+    fn qual(&self) -> &[u8];
+
+    /// Returns true when the record format has a quality string, even
+    /// if it is empty
+    fn has_qual(&self) -> bool;
+}
+
+impl FastxRecord for fastq::Record {
+    fn id(&self) -> &str {
+        fastq::Record::id(self)
+    }
+
+    fn desc(&self) -> Option<&str> {
+        fastq::Record::desc(self)
+    }
+
+    fn seq(&self) -> &[u8] {
+        fastq::Record::seq(self)
+    }
+
+    fn qual(&self) -> &[u8] {
+        fastq::Record::qual(self)
+    }
+
+    fn has_qual(&self) -> bool {
+        true
+    }
+}
+
+impl FastxRecord for fasta::Record {
+    fn id(&self) -> &str {
+        fasta::Record::id(self)
+    }
+
+    fn desc(&self) -> Option<&str> {
+        fasta::Record::desc(self)
+    }
+
+    fn seq(&self) -> &[u8] {
+        fasta::Record::seq(self)
+    }
+
+    fn qual(&self) -> &[u8] {
+        &[]
+    }
+
+    fn has_qual(&self) -> bool {
+        false
+    }
+}
+
+/// Returns a read `id` without any trailing `/1` or `/2` mate suffix.
+pub fn mate_base_id(id: &str) -> &str {
+    if id.ends_with("/1") || id.ends_with("/2") {
+        &id[..(id.len() - 2)]
+    } else {
+        id
+    }
+}
+
+/// Text of a record that could not be parsed, kept so that the
+/// record can be written unchanged to the rejected output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unparsed {
+    line: usize,
+    text: Vec<u8>,
+    problem: &'static str,
+}
+
+impl Unparsed {
+    /// Returns the line number where the record starts
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the original text of the record, with line endings
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// Returns a description of the parse failure
+    pub fn problem(&self) -> &str {
+        self.problem
+    }
+}
+
+impl fmt::Display for Unparsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.problem)
+    }
+}
+
+/// A parsed record, or the text of a record that could not be parsed
+pub type Parsed<R> = Result<R, Unparsed>;
+
+// Lines of an input, with one line of look-ahead that can be put
+// back when it starts the next record
+struct InputLines<B> {
+    input: B,
+    line: usize,
+    pending: Option<Vec<u8>>,
+}
+
+impl<B: BufRead> InputLines<B> {
+    fn new(input: B) -> Self {
+        InputLines {
+            input: input,
+            line: 0,
+            pending: None,
+        }
+    }
+
+    // Returns the next line, including its line ending
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(line) = self.pending.take() {
+            self.line += 1;
+            return Ok(Some(line));
+        }
+
+        let mut line = Vec::new();
+        if self.input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        Ok(Some(line))
+    }
+
+    // Returns a line to the input, to be read again as the next line
+    fn put_back(&mut self, line: Vec<u8>) {
+        self.line -= 1;
+        self.pending = Some(line);
+    }
+
+    // Returns the next line that is not blank
+    fn next_nonblank(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while let Some(line) = self.next_line()? {
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns the next line unless it starts a new record, which is
+    // put back
+    fn next_in_record(&mut self, header: u8) -> io::Result<Option<Vec<u8>>> {
+        match self.next_line()? {
+            Some(ref line) if line.first() == Some(&header) => {
+                self.put_back(line.clone());
+                Ok(None)
+            }
+            next => Ok(next),
+        }
+    }
+
+    // Adds lines to the text of an unparsed record up to the start
+    // of the next record
+    fn skip_record(&mut self, header: u8, text: &mut Vec<u8>) -> io::Result<()> {
+        while let Some(line) = self.next_in_record(header)? {
+            text.extend_from_slice(&line);
+        }
+        Ok(())
+    }
+}
+
+// Strips the line ending, either `\n` or `\r\n`
+fn line_content(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    if end > 0 && line[end - 1] == b'\n' {
+        end -= 1;
+    }
+    if end > 0 && line[end - 1] == b'\r' {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+// Splits a header line, after its initial `>` or `@`, into the
+// record identifier and any description
+fn parse_header(line: &[u8]) -> Option<(String, Option<String>)> {
+    let header = str::from_utf8(&line_content(line)[1..]).ok()?;
+    let mut fields = header.trim().splitn(2, char::is_whitespace);
+    let id = fields.next().unwrap_or("").to_string();
+    let desc = fields
+        .next()
+        .map(|desc| desc.trim().to_string())
+        .filter(|desc| !desc.is_empty());
+    Some((id, desc))
+}
+
+/// Iterator over the records of a FastQ input. A record that cannot
+/// be parsed is returned as `Unparsed`, and parsing resumes at the
+/// next line starting with `@`, so that a corrupt record does not
+/// hide the records after it. The iterator ends with an error when
+/// the input cannot be read.
+pub struct FastqRecords<B> {
+    lines: InputLines<B>,
+    failed: bool,
+}
+
+impl<B: BufRead> FastqRecords<B> {
+    /// Creates an iterator over FastQ records
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is the buffered input stream
+    pub fn new(input: B) -> Self {
+        FastqRecords {
+            lines: InputLines::new(input),
+            failed: false,
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Parsed<fastq::Record>>> {
+        let header = match self.lines.next_nonblank()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut unparsed = Unparsed {
+            line: self.lines.line,
+            text: header.clone(),
+            problem: "truncated record",
+        };
+
+        if header[0] != b'@' {
+            self.lines.skip_record(b'@', &mut unparsed.text)?;
+            unparsed.problem = "expected a header line starting with '@'";
+            return Ok(Some(Err(unparsed)));
+        }
+
+        // A line starting with `@` in place of the sequence or the
+        // `+` separator begins the next record
+        let seq = match self.lines.next_in_record(b'@')? {
+            Some(seq) => seq,
+            None => return Ok(Some(Err(unparsed))),
+        };
+        unparsed.text.extend_from_slice(&seq);
+
+        match self.lines.next_in_record(b'@')? {
+            Some(ref sep) if sep.first() == Some(&b'+') => unparsed.text.extend_from_slice(sep),
+            Some(sep) => {
+                unparsed.text.extend_from_slice(&sep);
+                self.lines.skip_record(b'@', &mut unparsed.text)?;
+                unparsed.problem = "expected a separator line starting with '+'";
+                return Ok(Some(Err(unparsed)));
+            }
+            None => return Ok(Some(Err(unparsed))),
+        }
+
+        // Quality strings may start with `@`, so a quality line only
+        // begins the next record when it has the wrong length
+        let qual = match self.lines.next_line()? {
+            Some(qual) => qual,
+            None => return Ok(Some(Err(unparsed))),
+        };
+        if qual.first() == Some(&b'@') && line_content(&qual).len() != line_content(&seq).len() {
+            self.lines.put_back(qual);
+            return Ok(Some(Err(unparsed)));
+        }
+        unparsed.text.extend_from_slice(&qual);
+
+        match parse_header(&header) {
+            Some((id, desc)) => Ok(Some(Ok(fastq::Record::with_attrs(
+                &id,
+                desc.as_ref().map(String::as_str),
+                line_content(&seq),
+                line_content(&qual),
+            )))),
+            None => {
+                unparsed.problem = "header is not valid UTF-8";
+                Ok(Some(Err(unparsed)))
+            }
+        }
+    }
+}
+
+impl<B: BufRead> Iterator for FastqRecords<B> {
+    type Item = Result<Parsed<fastq::Record>, failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        match self.next_record() {
+            Ok(next) => next.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err.into()))
+            }
+        }
+    }
+}
+
+/// Iterator over the records of a FastA input, as for
+/// `FastqRecords`. Sequences may span several lines, and parsing
+/// resumes at the next line starting with `>` after a record that
+/// cannot be parsed.
+pub struct FastaRecords<B> {
+    lines: InputLines<B>,
+    failed: bool,
+}
+
+impl<B: BufRead> FastaRecords<B> {
+    /// Creates an iterator over FastA records
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is the buffered input stream
+    pub fn new(input: B) -> Self {
+        FastaRecords {
+            lines: InputLines::new(input),
+            failed: false,
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Parsed<fasta::Record>>> {
+        let header = match self.lines.next_nonblank()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut unparsed = Unparsed {
+            line: self.lines.line,
+            text: header.clone(),
+            problem: "expected a header line starting with '>'",
+        };
+
+        let mut seq = Vec::new();
+        while let Some(line) = self.lines.next_in_record(b'>')? {
+            unparsed.text.extend_from_slice(&line);
+            seq.extend_from_slice(line_content(&line));
+        }
+
+        if header[0] != b'>' {
+            return Ok(Some(Err(unparsed)));
+        }
+
+        match parse_header(&header) {
+            Some((id, desc)) => Ok(Some(Ok(fasta::Record::with_attrs(
+                &id,
+                desc.as_ref().map(String::as_str),
+                &seq,
+            )))),
+            None => {
+                unparsed.problem = "header is not valid UTF-8";
+                Ok(Some(Err(unparsed)))
+            }
+        }
+    }
+}
+
+impl<B: BufRead> Iterator for FastaRecords<B> {
+    type Item = Result<Parsed<fasta::Record>, failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        match self.next_record() {
+            Ok(next) => next.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err.into()))
+            }
+        }
+    }
+}
+
+/// Iterator over read pairs drawn from separate mate 1 and mate 2
+/// record iterators. The iterator ends with an error at the first
+/// record in either input that cannot be parsed, since resuming after
+/// a corrupt record may skip different numbers of records in the two
+/// inputs and leave every later pair out of step. It also ends with
+/// an error when either input cannot be read. The inputs must have
+/// the same number of records, but the mate names are not checked
+/// here.
+pub struct MatePairs<I1, I2> {
+    mate1: I1,
+    mate2: I2,
+    failed: bool,
+}
+
+impl<I1, I2> MatePairs<I1, I2> {
+    /// Creates an iterator over read pairs
+    ///
+    /// # Arguments
+    ///
+    /// * `mate1` yields the mate 1 records
+    ///
+    /// * `mate2` yields the mate 2 records
+    pub fn new(mate1: I1, mate2: I2) -> Self {
+        MatePairs {
+            mate1: mate1,
+            mate2: mate2,
+            failed: false,
+        }
+    }
+}
+
+impl<R, I1, I2> Iterator for MatePairs<I1, I2>
+where
+    R: FastxRecord,
+    I1: Iterator<Item = Result<Parsed<R>, failure::Error>>,
+    I2: Iterator<Item = Result<Parsed<R>, failure::Error>>,
+{
+    type Item = Result<(R, R), failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next = match (self.mate1.next(), self.mate2.next()) {
+            (None, None) => return None,
+            (Some(Ok(Ok(rec1))), Some(Ok(Ok(rec2)))) => Ok((rec1, rec2)),
+            (Some(Err(err)), _) | (_, Some(Err(err))) => Err(err),
+            (Some(Ok(Err(unparsed))), _) => {
+                Err(format_err!("Unparseable mate 1 record at {}", unparsed))
+            }
+            (_, Some(Ok(Err(unparsed)))) => {
+                Err(format_err!("Unparseable mate 2 record at {}", unparsed))
+            }
+            _ => Err(failure::err_msg(
+                "Mate 1 and mate 2 inputs have different numbers of records",
+            )),
+        };
+        self.failed = next.is_err();
+        Some(next)
+    }
+}
+
+/// Writer for either FastA or FastQ output. Quality information is
+/// discarded when writing FastA. Records are written directly, rather
+/// than through the `bio` writers, so that the underlying output can
+/// be finished explicitly.
+pub struct FastxWriter {
+    format: FastxFormat,
+    dest: io::BufWriter<Box<FinishWrite>>,
+}
+
+impl FastxWriter {
+    /// Creates a new sequence writer
+    ///
+    /// # Arguments
+    ///
+    /// * `format` is the output sequence format
+    ///
+    /// * `dest` is the underlying output writer
+    pub fn new(format: FastxFormat, dest: Box<FinishWrite>) -> Self {
+        FastxWriter {
+            format: format,
+            dest: io::BufWriter::new(dest),
+        }
+    }
+
+    /// Writes one sequence record.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the record to the output.
+    pub fn write(
+        &mut self,
+        id: &str,
+        desc: Option<&str>,
+        seq: &[u8],
+        qual: &[u8],
+    ) -> Result<(), failure::Error> {
+        let header = match self.format {
+            FastxFormat::Fasta => b'>',
+            FastxFormat::Fastq => b'@',
+        };
+        self.dest.write_all(&[header])?;
+        self.dest.write_all(id.as_bytes())?;
+        if let Some(desc) = desc {
+            self.dest.write_all(b" ")?;
+            self.dest.write_all(desc.as_bytes())?;
+        }
+        self.dest.write_all(b"\n")?;
+        self.dest.write_all(seq)?;
+        self.dest.write_all(b"\n")?;
+        if self.format == FastxFormat::Fastq {
+            self.dest.write_all(b"+\n")?;
+            self.dest.write_all(qual)?;
+            self.dest.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Writes an existing sequence record unchanged.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the record to the output.
+    pub fn write_record<R: FastxRecord>(&mut self, rec: &R) -> Result<(), failure::Error> {
+        self.write(rec.id(), rec.desc(), rec.seq(), rec.qual())
+    }
+
+    /// Writes the original text of a record that could not be
+    /// parsed, ending it with a newline if needed.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the text to the output.
+    pub fn write_unparsed(&mut self, unparsed: &Unparsed) -> Result<(), failure::Error> {
+        self.dest.write_all(unparsed.text())?;
+        if !unparsed.text().ends_with(b"\n") {
+            self.dest.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a parsed record, or the original text of a record that
+    /// could not be parsed.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the record to the output.
+    pub fn write_parsed<R: FastxRecord>(
+        &mut self,
+        parsed: &Parsed<R>,
+    ) -> Result<(), failure::Error> {
+        match parsed {
+            Ok(rec) => self.write_record(rec),
+            Err(unparsed) => self.write_unparsed(unparsed),
+        }
+    }
+
+    /// Writes all buffered records and finishes the underlying
+    /// output.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the remaining records or completing the output.
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        self.dest.flush()?;
+        self.dest.get_mut().finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format() {
+        let mut fasta_in: &[u8] = b">seq1\nACGT\n";
+        assert!(FastxFormat::detect(&mut fasta_in).unwrap() == FastxFormat::Fasta);
+        assert!(fasta_in == b">seq1\nACGT\n");
+
+        let mut fastq_in: &[u8] = b"\n\n@seq1\nACGT\n+\nIIII\n";
+        assert!(FastxFormat::detect(&mut fastq_in).unwrap() == FastxFormat::Fastq);
+        assert!(fastq_in == b"@seq1\nACGT\n+\nIIII\n");
+
+        let mut empty_in: &[u8] = b"";
+        assert!(FastxFormat::detect(&mut empty_in).unwrap() == FastxFormat::Fastq);
+
+        let mut bad_in: &[u8] = b"ACGT\n";
+        assert!(FastxFormat::detect(&mut bad_in).is_err());
+    }
+
+    fn records(ids: &[&str]) -> Vec<Result<Parsed<fasta::Record>, failure::Error>> {
+        ids.iter()
+            .map(|id| Ok(Ok(fasta::Record::with_attrs(id, None, b"ACGT"))))
+            .collect()
+    }
+
+    #[test]
+    fn mate_pairs() {
+        let pairs: Vec<_> = MatePairs::new(
+            records(&["a/1", "b"]).into_iter(),
+            records(&["a/2", "b"]).into_iter(),
+        )
+        .collect();
+        assert!(pairs.len() == 2);
+        assert!(pairs.iter().all(|res| res.is_ok()));
+
+        let mut uneven = MatePairs::new(
+            records(&["a"]).into_iter(),
+            records(&["a", "b"]).into_iter(),
+        );
+        assert!(uneven.next().unwrap().is_ok());
+        assert!(uneven.next().unwrap().is_err());
+
+        // An unparsed mate ends the pairs with an error
+        let mut mate2 = records(&["a"]);
+        mate2.push(Ok(Err(Unparsed {
+            line: 3,
+            text: b"b\n".to_vec(),
+            problem: "bad",
+        })));
+        mate2.append(&mut records(&["c"]));
+        let unparsed: Vec<_> =
+            MatePairs::new(records(&["a", "b", "c"]).into_iter(), mate2.into_iter()).collect();
+        assert!(unparsed.len() == 2);
+        assert!(unparsed[0].is_ok());
+        match unparsed[1] {
+            Err(ref err) => assert!(err.to_string() == "Unparseable mate 2 record at line 3: bad"),
+            Ok(_) => panic!("Expected unparsed mate 2"),
+        }
+
+        let mut mate2 = records(&["a"]);
+        mate2.push(Err(io::Error::new(io::ErrorKind::Other, "Bad input").into()));
+        mate2.append(&mut records(&["c"]));
+        let broken: Vec<_> =
+            MatePairs::new(records(&["a", "b", "c"]).into_iter(), mate2.into_iter()).collect();
+        assert!(broken.len() == 2);
+        assert!(broken[0].is_ok());
+        assert!(broken[1].is_err());
+    }
+
+    #[test]
+    fn fastq_mate_pairs() {
+        // Mate 1 of r2 has no separator and its quality line starts
+        // with '@', so mate 1 yields two unparsed records for the one
+        // unparsed record in mate 2
+        let mate1: &[u8] = b"@r1\nACGT\n+\nIIII\n\
+                             @r2\nACGT\n@III\n\
+                             @r3\nACGT\n+\nIIII\n";
+        let mate2: &[u8] = b"@r1\nTTGC\n+\nIIII\n\
+                             @r2\nTTGC\n+\nIIII\n\
+                             @r3\nTTGC\n+\nIIII\n";
+        let pairs: Vec<_> =
+            MatePairs::new(FastqRecords::new(mate1), FastqRecords::new(mate2)).collect();
+        assert!(pairs.len() == 2);
+        match pairs[0] {
+            Ok((ref rec1, ref rec2)) => {
+                assert!(rec1.id() == "r1");
+                assert!(rec2.id() == "r1");
+            }
+            Err(_) => panic!("Expected read pair r1"),
+        }
+        match pairs[1] {
+            Err(ref err) => {
+                assert!(err.to_string() == "Unparseable mate 1 record at line 5: truncated record")
+            }
+            Ok(_) => panic!("Expected unparsed mate 1"),
+        }
+    }
+
+    #[test]
+    fn fastq_records() {
+        // The second record is cut short after its header, the fourth
+        // has no separator line, and the records after each are read
+        let corrupt: &[u8] = b"@r1 x y\nACGT\n+\nIIII\n\
+                               @r2\n\
+                               @r3\nACGT\n+\n@III\n\
+                               @r4\nACGT\nIIII\nACGT\n\
+                               \n\
+                               @r5\nAC\n+r5\nII";
+        let parsed: Vec<_> = FastqRecords::new(corrupt).map(Result::unwrap).collect();
+        assert!(parsed.len() == 5);
+        match parsed[0] {
+            Ok(ref rec) => {
+                assert!(rec.id() == "r1");
+                assert!(rec.desc() == Some("x y"));
+                assert!(rec.seq() == b"ACGT");
+                assert!(rec.qual() == b"IIII");
+            }
+            Err(_) => panic!("Expected record r1"),
+        }
+        match parsed[1] {
+            Err(ref unparsed) => {
+                assert!(unparsed.line() == 5);
+                assert!(unparsed.text() == b"@r2\n");
+                assert!(unparsed.to_string() == "line 5: truncated record");
+            }
+            Ok(_) => panic!("Expected unparsed record"),
+        }
+        assert!(parsed[2]
+            .as_ref()
+            .map_or(false, |rec| rec.qual() == b"@III"));
+        match parsed[3] {
+            Err(ref unparsed) => {
+                assert!(unparsed.line() == 10);
+                assert!(unparsed.text() == b"@r4\nACGT\nIIII\nACGT\n\n");
+            }
+            Ok(_) => panic!("Expected unparsed record"),
+        }
+        assert!(parsed[4]
+            .as_ref()
+            .map_or(false, |rec| rec.id() == "r5" && rec.qual() == b"II"));
+
+        let garbage: &[u8] = b"ACGT\nIIII\n@r1\nAC\n+\nII\n";
+        let parsed: Vec<_> = FastqRecords::new(garbage).map(Result::unwrap).collect();
+        assert!(parsed.len() == 2);
+        assert!(parsed[0].as_ref().err().map(Unparsed::text) == Some(&b"ACGT\nIIII\n"[..]));
+        assert!(parsed[1].is_ok());
+    }
+
+    #[test]
+    fn fasta_records() {
+        let input: &[u8] = b">r1 desc\nACGT\nGG\nCC\n>r2\n\n>r3\nTT";
+        let parsed: Vec<_> = FastaRecords::new(input).map(Result::unwrap).collect();
+        assert!(parsed.len() == 3);
+        assert!(parsed[0]
+            .as_ref()
+            .map_or(false, |rec| rec.seq() == b"ACGTGGCC"));
+        assert!(parsed[0]
+            .as_ref()
+            .map_or(false, |rec| rec.desc() == Some("desc")));
+        assert!(parsed[1].as_ref().map_or(false, |rec| rec.seq().is_empty()));
+        assert!(parsed[2].as_ref().map_or(false, |rec| rec.seq() == b"TT"));
+
+        let garbage: &[u8] = b"ACGT\n>r1\nAC\n";
+        let parsed: Vec<_> = FastaRecords::new(garbage).map(Result::unwrap).collect();
+        assert!(parsed.len() == 2);
+        assert!(parsed[0].as_ref().err().map(Unparsed::line) == Some(1));
+        assert!(parsed[1].as_ref().map_or(false, |rec| rec.id() == "r1"));
+    }
+
+    #[test]
+    fn write_records() {
+        let fasta_out = SharedBuffer::new();
+        let mut writer = FastxWriter::new(FastxFormat::Fasta, Box::new(fasta_out.clone()));
+        writer.write("r1", Some("x y"), b"ACGT", b"IIII").unwrap();
+        writer.write("r2", None, b"GG", b"##").unwrap();
+        writer.finish().unwrap();
+        assert!(fasta_out.contents() == &b">r1 x y\nACGT\n>r2\nGG\n"[..]);
+
+        let fastq_out = SharedBuffer::new();
+        let mut writer = FastxWriter::new(FastxFormat::Fastq, Box::new(fastq_out.clone()));
+        writer.write("r1", None, b"ACGT", b"IIII").unwrap();
+        writer.finish().unwrap();
+        assert!(fastq_out.contents() == &b"@r1\nACGT\n+\nIIII\n"[..]);
+    }
+
+    #[test]
+    fn write_unparsed() {
+        let out = SharedBuffer::new();
+        let mut writer = FastxWriter::new(FastxFormat::Fastq, Box::new(out.clone()));
+        let unparsed: Parsed<fastq::Record> = Err(Unparsed {
+            line: 1,
+            text: b"@r1\nAC".to_vec(),
+            problem: "truncated record",
+        });
+        writer.write_parsed(&unparsed).unwrap();
+        writer.finish().unwrap();
+        assert!(out.contents() == &b"@r1\nAC\n"[..]);
+    }
+}
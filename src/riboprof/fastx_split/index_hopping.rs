@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::str;
+
+use failure;
+
+use fastx_split::sample_sheet::*;
+
+/// Index hopping and index failure counts for dual indices. Every
+/// combination of a first index and a second index from the sample
+/// sheet has a read count: assigned combinations count the reads of
+/// their sample, and other combinations count reads where each index
+/// matched a sample but the pair did not, typically due to index
+/// hopping.
+#[derive(Debug, Clone)]
+pub struct DualIndexStats {
+    index1s: Vec<Vec<u8>>,
+    index2s: Vec<Vec<u8>>,
+    reads: Vec<Vec<usize>>,
+    assigned: Vec<Vec<bool>>,
+    failed_index1: usize,
+    failed_index2: usize,
+    failed_both: usize,
+    hopped: usize,
+}
+
+impl DualIndexStats {
+    /// Tallies dual index statistics, or returns `None` when the
+    /// sample map does not use dual indices.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_map` maps index sequences to samples
+    ///
+    /// * `unknown_counts` gives the number of reads for each index
+    /// sequence that was not assigned to a sample
+    ///
+    /// * `sample_reads` gives the number of reads for a sample
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when a sample cannot be borrowed.
+    pub fn new<T, F>(
+        sample_map: &SampleMap<T>,
+        unknown_counts: &HashMap<Vec<u8>, usize>,
+        sample_reads: F,
+    ) -> Result<Option<Self>, failure::Error>
+    where
+        F: Fn(&T) -> usize,
+    {
+        let index1_length = match sample_map.index1_length() {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let samples: Vec<(&[u8], Rc<RefCell<T>>)> = sample_map.sample_indices();
+
+        let mut index1s: Vec<Vec<u8>> = Vec::new();
+        let mut index2s: Vec<Vec<u8>> = Vec::new();
+        for (index, _) in samples.iter() {
+            let (index1, index2) = index.split_at(index1_length);
+            if !index1s.iter().any(|known| known.as_slice() == index1) {
+                index1s.push(index1.to_vec());
+            }
+            if !index2s.iter().any(|known| known.as_slice() == index2) {
+                index2s.push(index2.to_vec());
+            }
+        }
+
+        let mut stats = DualIndexStats {
+            reads: vec![vec![0; index2s.len()]; index1s.len()],
+            assigned: vec![vec![false; index2s.len()]; index1s.len()],
+            index1s: index1s,
+            index2s: index2s,
+            failed_index1: 0,
+            failed_index2: 0,
+            failed_both: 0,
+            hopped: 0,
+        };
+
+        for (index, thing) in samples.iter() {
+            let (index1, index2) = index.split_at(index1_length);
+            let (i1, i2) = stats.position(index1, index2).unwrap();
+            stats.reads[i1][i2] += sample_reads(&*thing.try_borrow()?);
+            stats.assigned[i1][i2] = true;
+        }
+
+        for (index, count) in unknown_counts.iter() {
+            match sample_map.index_failure(index) {
+                Some(IndexFailure::Index1) => stats.failed_index1 += count,
+                Some(IndexFailure::Index2) => stats.failed_index2 += count,
+                Some(IndexFailure::Both) => stats.failed_both += count,
+                Some(IndexFailure::Hopped(index1, index2)) => {
+                    if let Some((i1, i2)) = stats.position(&index1, &index2) {
+                        stats.reads[i1][i2] += count;
+                    }
+                    stats.hopped += count;
+                }
+                None => (),
+            }
+        }
+
+        Ok(Some(stats))
+    }
+
+    // Row and column of a combination in the matrix
+    fn position(&self, index1: &[u8], index2: &[u8]) -> Option<(usize, usize)> {
+        let i1 = self
+            .index1s
+            .iter()
+            .position(|idx| idx.as_slice() == index1)?;
+        let i2 = self
+            .index2s
+            .iter()
+            .position(|idx| idx.as_slice() == index2)?;
+        Some((i1, i2))
+    }
+
+    /// Returns the distinct first indices, in sample sheet order
+    pub fn index1s(&self) -> &[Vec<u8>] {
+        &self.index1s
+    }
+
+    /// Returns the distinct second indices, in sample sheet order
+    pub fn index2s(&self) -> &[Vec<u8>] {
+        &self.index2s
+    }
+
+    /// Returns the number of reads whose first index matched no
+    /// sample, although the second index did
+    pub fn failed_index1(&self) -> usize {
+        self.failed_index1
+    }
+
+    /// Returns the number of reads whose second index matched no
+    /// sample, although the first index did
+    pub fn failed_index2(&self) -> usize {
+        self.failed_index2
+    }
+
+    /// Returns the number of reads where neither index matched a
+    /// sample
+    pub fn failed_both(&self) -> usize {
+        self.failed_both
+    }
+
+    /// Returns the number of reads where each index matched a sample
+    /// but the combination was not assigned to any sample
+    pub fn hopped(&self) -> usize {
+        self.hopped
+    }
+
+    /// Returns the unassigned index combinations with at least one
+    /// read, with their read counts, in descending order of abundance
+    pub fn hopped_combinations(&self) -> Vec<(&[u8], &[u8], usize)> {
+        let mut combinations = Vec::new();
+        for (i1, index1) in self.index1s.iter().enumerate() {
+            for (i2, index2) in self.index2s.iter().enumerate() {
+                if !self.assigned[i1][i2] && self.reads[i1][i2] > 0 {
+                    combinations.push((index1.as_slice(), index2.as_slice(), self.reads[i1][i2]));
+                }
+            }
+        }
+        combinations.sort_by(|(_, _, ct1), (_, _, ct2)| ct2.cmp(ct1));
+        combinations
+    }
+
+    /// Returns a tab-separated matrix of read counts with a row for
+    /// each first index and a column for each second index. Counts
+    /// for combinations not assigned to any sample are marked with a
+    /// trailing `*`.
+    pub fn matrix_table(&self) -> String {
+        let mut table = "index1".to_string();
+        for index2 in self.index2s.iter() {
+            table.push('\t');
+            table.push_str(str::from_utf8(index2).unwrap_or("???"));
+        }
+        table.push('\n');
+
+        for (i1, index1) in self.index1s.iter().enumerate() {
+            table.push_str(str::from_utf8(index1).unwrap_or("???"));
+            for (reads, assigned) in self.reads[i1].iter().zip(self.assigned[i1].iter()) {
+                table.push_str(&format!("\t{}{}", reads, if *assigned { "" } else { "*" }));
+            }
+            table.push('\n');
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hopping_matrix() {
+        let mut sample_map = SampleMap::new_dual(4, 4, 0);
+        sample_map
+            .insert_with_tolerances(b"AAAACCCC".to_vec(), &[1, 1], 100)
+            .unwrap();
+        sample_map
+            .insert_with_tolerances(b"GGGGTTTT".to_vec(), &[1, 1], 50)
+            .unwrap();
+
+        let mut unknown_counts = HashMap::new();
+        unknown_counts.insert(b"AAAATTTT".to_vec(), 3);
+        unknown_counts.insert(b"AAATTTTA".to_vec(), 2);
+        unknown_counts.insert(b"GGGGACGT".to_vec(), 4);
+        unknown_counts.insert(b"ACGTCCCC".to_vec(), 5);
+        unknown_counts.insert(b"ACGTACGT".to_vec(), 6);
+
+        let stats = DualIndexStats::new(&sample_map, &unknown_counts, |reads| *reads)
+            .unwrap()
+            .unwrap();
+        assert!(stats.hopped() == 5);
+        assert!(stats.failed_index1() == 5);
+        assert!(stats.failed_index2() == 4);
+        assert!(stats.failed_both() == 6);
+        assert!(stats.hopped_combinations() == vec![(&b"AAAA"[..], &b"TTTT"[..], 5)]);
+        assert!(
+            stats.matrix_table()
+                == "index1\tCCCC\tTTTT\n\
+                    AAAA\t100\t5*\n\
+                    GGGG\t0*\t50\n"
+        );
+
+        let single = SampleMap::new(4, 0);
+        assert!(
+            DualIndexStats::new(&single, &unknown_counts, |reads| *reads)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
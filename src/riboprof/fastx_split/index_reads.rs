@@ -0,0 +1,603 @@
+use std::fmt;
+
+use failure;
+
+use fastx_split::fastx::*;
+use fastx_split::linkers::*;
+use fastx_split::processor::*;
+
+/// Source of sample index and UMI bases that are sequenced apart from
+/// the insert read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexSource {
+    /// Separate index read inputs, such as Illumina I1 and I2 files
+    Reads,
+    /// The index field of an Illumina read description, as in
+    /// `1:N:0:ACGTAC+GGTTAA`
+    Header,
+}
+
+impl fmt::Display for IndexSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IndexSource::Reads => "reads",
+            IndexSource::Header => "header",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Name, sequence, and quality of one record from an index read
+/// input. The quality is empty for FastA inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndexRead {
+    id: String,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl IndexRead {
+    /// Copies the name, sequence, and quality of an index read record
+    pub fn new<R: FastxRecord>(rec: &R) -> Self {
+        IndexRead {
+            id: rec.id().to_string(),
+            seq: rec.seq().to_vec(),
+            qual: rec.qual().to_vec(),
+        }
+    }
+
+    /// Returns the index read name
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the index read sequence
+    pub fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    /// Returns the index read quality
+    pub fn qual(&self) -> &[u8] {
+        &self.qual
+    }
+}
+
+/// Index reads accompanying one read or read pair, which are absent
+/// when there are no index read inputs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct IndexReads {
+    index1: Option<IndexRead>,
+    index2: Option<IndexRead>,
+}
+
+impl IndexReads {
+    /// Returns the first index read (I1), if any
+    pub fn index1(&self) -> Option<&IndexRead> {
+        self.index1.as_ref()
+    }
+
+    /// Returns the second index read (I2), if any
+    pub fn index2(&self) -> Option<&IndexRead> {
+        self.index2.as_ref()
+    }
+}
+
+/// Records from one index read input
+pub type IndexReadRecords = Box<Iterator<Item = Result<Parsed<IndexRead>, failure::Error>> + Send>;
+
+/// Reads index records from an input in either sequence format. The
+/// index reads need not share the format of the insert reads.
+///
+/// # Arguments
+///
+/// * `input` is the opened index read input
+pub fn index_read_records(input: FastxInput) -> IndexReadRecords {
+    match input.format() {
+        FastxFormat::Fastq => Box::new(
+            FastqRecords::new(input.into_reader())
+                .map(|res| res.map(|parsed| parsed.map(|rec| IndexRead::new(&rec)))),
+        ),
+        FastxFormat::Fasta => Box::new(
+            FastaRecords::new(input.into_reader())
+                .map(|res| res.map(|parsed| parsed.map(|rec| IndexRead::new(&rec)))),
+        ),
+    }
+}
+
+/// Reads, or read pairs, that are joined with index reads by
+/// `IndexedRecords`
+pub trait IndexedItem {
+    /// Returns the text of a read that could not be parsed
+    fn unparsed(&self) -> Option<&Unparsed>;
+}
+
+impl<R> IndexedItem for Parsed<R> {
+    fn unparsed(&self) -> Option<&Unparsed> {
+        self.as_ref().err()
+    }
+}
+
+// Both mates of a read pair have always been parsed
+impl<R> IndexedItem for (R, R) {
+    fn unparsed(&self) -> Option<&Unparsed> {
+        None
+    }
+}
+
+/// Iterator joining reads, or read pairs, with the corresponding
+/// records from index read inputs. The iterator ends with an error at
+/// the first index record that cannot be parsed, as for `MatePairs`,
+/// or when an index input cannot be read. It also ends with an error
+/// at the first read that cannot be parsed when there are index
+/// inputs, because the read parser may resynchronize over several
+/// records and the index reads would no longer line up. The index
+/// inputs must have the same number of records as the reads, but the
+/// read names are not checked here.
+pub struct IndexedRecords<I> {
+    records: I,
+    index1: Option<IndexReadRecords>,
+    index2: Option<IndexReadRecords>,
+    failed: bool,
+}
+
+impl<I> IndexedRecords<I> {
+    /// Creates an iterator over reads joined with their index reads
+    ///
+    /// # Arguments
+    ///
+    /// * `records` yields the reads or read pairs
+    ///
+    /// * `index1` yields the first index reads, if any
+    ///
+    /// * `index2` yields the second index reads, if any
+    pub fn new(
+        records: I,
+        index1: Option<IndexReadRecords>,
+        index2: Option<IndexReadRecords>,
+    ) -> Self {
+        IndexedRecords {
+            records: records,
+            index1: index1,
+            index2: index2,
+            failed: false,
+        }
+    }
+
+    // Outer `None` when there is no index input, inner `None` when
+    // the index input has ended
+    fn next_index(
+        index: &mut Option<IndexReadRecords>,
+    ) -> Option<Option<Result<Parsed<IndexRead>, failure::Error>>> {
+        index.as_mut().map(|records| records.next())
+    }
+
+    fn index_read(
+        next: Option<Option<Result<Parsed<IndexRead>, failure::Error>>>,
+        index: usize,
+    ) -> Result<Option<IndexRead>, failure::Error> {
+        match next {
+            None => Ok(None),
+            Some(None) => Err(failure::err_msg(
+                "Index read inputs have fewer records than the read inputs",
+            )),
+            Some(Some(Err(err))) => Err(format_err!("Unreadable index input: {}", err)),
+            Some(Some(Ok(Err(unparsed)))) => Err(format_err!(
+                "Unparseable index {} record at {}",
+                index,
+                unparsed
+            )),
+            Some(Some(Ok(Ok(index_read)))) => Ok(Some(index_read)),
+        }
+    }
+
+    fn next_indexed<T: IndexedItem>(
+        &mut self,
+        next: Option<Result<T, failure::Error>>,
+    ) -> Option<Result<(T, IndexReads), failure::Error>> {
+        let next1 = Self::next_index(&mut self.index1);
+        let next2 = Self::next_index(&mut self.index2);
+
+        let t = match next {
+            None => {
+                let more1 = next1.map_or(false, |next| next.is_some());
+                let more2 = next2.map_or(false, |next| next.is_some());
+                return if more1 || more2 {
+                    Some(Err(failure::err_msg(
+                        "Index read inputs have more records than the read inputs",
+                    )))
+                } else {
+                    None
+                };
+            }
+            Some(Err(err)) => return Some(Err(err)),
+            Some(Ok(t)) => t,
+        };
+
+        if next1.is_some() || next2.is_some() {
+            if let Some(unparsed) = t.unparsed() {
+                return Some(Err(format_err!(
+                    "Unparseable record at {} with index read inputs",
+                    unparsed
+                )));
+            }
+        }
+
+        let index1 = match Self::index_read(next1, 1) {
+            Ok(index1) => index1,
+            Err(err) => return Some(Err(err)),
+        };
+        let index2 = match Self::index_read(next2, 2) {
+            Ok(index2) => index2,
+            Err(err) => return Some(Err(err)),
+        };
+
+        Some(Ok((
+            t,
+            IndexReads {
+                index1: index1,
+                index2: index2,
+            },
+        )))
+    }
+}
+
+impl<T, I> Iterator for IndexedRecords<I>
+where
+    T: IndexedItem,
+    I: Iterator<Item = Result<T, failure::Error>>,
+{
+    type Item = Result<(T, IndexReads), failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next = self.records.next();
+        let next = self.next_indexed(next);
+        self.failed = next.as_ref().map_or(false, Result::is_err);
+        next
+    }
+}
+
+/// Returns the first and second index sequences from the index field
+/// of an Illumina read description, such as `1:N:0:ACGTAC+GGTTAA`,
+/// or `None` when the last field is not made up of nucleotides. The
+/// second index is empty for single-index runs.
+///
+/// # Arguments
+///
+/// * `desc` is the read description
+pub fn header_indices(desc: &str) -> Option<(&[u8], &[u8])> {
+    let field = desc.rsplit(':').next()?.as_bytes();
+    let is_nt = |ch: &u8| match ch {
+        b'A' | b'C' | b'G' | b'T' | b'N' => true,
+        _ => false,
+    };
+
+    let (index1, index2) = match field.iter().position(|&ch| ch == b'+') {
+        Some(pos) => (&field[..pos], &field[(pos + 1)..]),
+        None => (field, &field[field.len()..]),
+    };
+
+    if !index1.is_empty() && index1.iter().all(is_nt) && index2.iter().all(is_nt) {
+        Some((index1, index2))
+    } else {
+        None
+    }
+}
+
+/// Specification of the sample index and UMI bases taken from
+/// separately sequenced indices. The index bases are placed before
+/// any index bases from the read linker, first index before second
+/// index, and likewise for the UMI bases.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExternalIndex {
+    source: IndexSource,
+    index1: LinkerSpec,
+    index2: LinkerSpec,
+}
+
+impl ExternalIndex {
+    /// Creates a new specification for separately sequenced indices
+    ///
+    /// # Arguments
+    ///
+    /// * `source` is the source of the index sequences
+    ///
+    /// * `index1_spec` describes the start of the first index
+    /// sequence, as for a linker prefix
+    ///
+    /// * `index2_spec` describes the start of the second index
+    /// sequence, as for a linker prefix
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when a specification cannot be
+    /// parsed or has constant bases, which are not checked in index
+    /// sequences, or when neither specification has any bases.
+    pub fn new(
+        source: IndexSource,
+        index1_spec: &str,
+        index2_spec: &str,
+    ) -> Result<Self, failure::Error> {
+        let index1 = LinkerSpec::new(index1_spec, "")?;
+        let index2 = LinkerSpec::new(index2_spec, "")?;
+
+        for (index_spec, index) in [(index1_spec, &index1), (index2_spec, &index2)].iter() {
+            if index.constant_length() > 0 {
+                return Err(format_err!(
+                    "Index read specification \"{}\" has constant bases, which are not checked",
+                    index_spec
+                ));
+            }
+        }
+
+        if index1.linker_length() + index2.linker_length() == 0 {
+            return Err(failure::err_msg(
+                "Index read specifications have no index or UMI bases",
+            ));
+        }
+
+        Ok(ExternalIndex {
+            source: source,
+            index1: index1,
+            index2: index2,
+        })
+    }
+
+    /// Returns the source of the index sequences
+    pub fn source(&self) -> IndexSource {
+        self.source
+    }
+
+    /// Returns the specification for the first index
+    pub fn index1(&self) -> &LinkerSpec {
+        &self.index1
+    }
+
+    /// Returns the specification for the second index
+    pub fn index2(&self) -> &LinkerSpec {
+        &self.index2
+    }
+
+    /// Returns the number of sample index bases taken from the indices
+    pub fn sample_index_length(&self) -> usize {
+        self.index1.sample_index_length() + self.index2.sample_index_length()
+    }
+
+    /// Returns the number of sample index bases taken from the first
+    /// and from the second index
+    pub fn index_segments(&self) -> Vec<usize> {
+        vec![
+            self.index1.sample_index_length(),
+            self.index2.sample_index_length(),
+        ]
+    }
+
+    /// Extracts the sample index and UMI bases for a read, or for mate
+    /// 1 of a read pair, from its index reads or its description.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq` is the read
+    ///
+    /// * `index_reads` are the index reads accompanying the read
+    ///
+    /// # Errors
+    ///
+    /// `MalformedReason::MateMismatch` is returned when an index read
+    /// name differs from the read name, and `MalformedReason::BadIndex`
+    /// when an index sequence is missing or too short.
+    pub fn barcode<R: FastxRecord>(
+        &self,
+        fq: &R,
+        index_reads: &IndexReads,
+    ) -> Result<Barcode, MalformedReason> {
+        // Header indices have no quality information
+        let ((seq1, qual1), (seq2, qual2)) = match self.source {
+            IndexSource::Reads => (
+                Self::index_read_seq(fq, index_reads.index1())?,
+                Self::index_read_seq(fq, index_reads.index2())?,
+            ),
+            IndexSource::Header => {
+                let (seq1, seq2) = fq
+                    .desc()
+                    .and_then(header_indices)
+                    .ok_or(MalformedReason::BadIndex)?;
+                ((seq1, &b""[..]), (seq2, &b""[..]))
+            }
+        };
+
+        let mut barcode = self
+            .index1
+            .barcode(seq1, qual1)
+            .ok_or(MalformedReason::BadIndex)?;
+        barcode.extend(
+            &self
+                .index2
+                .barcode(seq2, qual2)
+                .ok_or(MalformedReason::BadIndex)?,
+        );
+        Ok(barcode)
+    }
+
+    // A missing index read is treated as an empty sequence, which is
+    // only valid when no bases are taken from it
+    fn index_read_seq<'a, R: FastxRecord>(
+        fq: &R,
+        index_read: Option<&'a IndexRead>,
+    ) -> Result<(&'a [u8], &'a [u8]), MalformedReason> {
+        match index_read {
+            Some(index_read) => {
+                if mate_base_id(index_read.id()) == mate_base_id(fq.id()) {
+                    Ok((index_read.seq(), index_read.qual()))
+                } else {
+                    Err(MalformedReason::MateMismatch)
+                }
+            }
+            None => Ok((&[], &[])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use bio::io::{fasta, fastq};
+
+    fn index_records(ids: &[&str], seq: &[u8]) -> IndexReadRecords {
+        let records: Vec<Result<Parsed<IndexRead>, failure::Error>> = ids
+            .iter()
+            .map(|id| {
+                Ok(Ok(IndexRead::new(&fasta::Record::with_attrs(
+                    id, None, seq,
+                ))))
+            })
+            .collect();
+        Box::new(records.into_iter())
+    }
+
+    #[test]
+    fn header() {
+        assert!(header_indices("1:N:0:ACGTAC+GGTTAA") == Some((&b"ACGTAC"[..], &b"GGTTAA"[..])));
+        assert!(header_indices("1:N:0:ACGTAC") == Some((&b"ACGTAC"[..], &b""[..])));
+        assert!(header_indices("1:N:0:1") == None);
+        assert!(header_indices("") == None);
+    }
+
+    #[test]
+    fn external_barcode() {
+        let header = ExternalIndex::new(IndexSource::Header, "IIII", "NNI").unwrap();
+        assert!(header.sample_index_length() == 5);
+        assert!(header.index_segments() == vec![4, 1]);
+
+        let fq = fastq::Record::with_attrs("read", Some("1:N:0:ACGTAC+GGTTAA"), b"ACGT", b"IIII");
+        let barcode = header.barcode(&fq, &IndexReads::default()).unwrap();
+        assert!(barcode.sample_index() == b"ACGTT");
+        assert!(barcode.umi() == b"GG");
+
+        let single = fastq::Record::with_attrs("read", Some("1:N:0:ACGTAC"), b"ACGT", b"IIII");
+        assert!(header.barcode(&single, &IndexReads::default()) == Err(MalformedReason::BadIndex));
+
+        let reads = ExternalIndex::new(IndexSource::Reads, "IINN", "").unwrap();
+        let index_reads = IndexReads {
+            index1: Some(IndexRead::new(&fasta::Record::with_attrs(
+                "read", None, b"CCGGA",
+            ))),
+            index2: None,
+        };
+        let barcode = reads.barcode(&fq, &index_reads).unwrap();
+        assert!(barcode.sample_index() == b"CC");
+        assert!(barcode.umi() == b"GG");
+
+        let other = fastq::Record::with_attrs("other", None, b"ACGT", b"IIII");
+        assert!(reads.barcode(&other, &index_reads) == Err(MalformedReason::MateMismatch));
+
+        assert!(ExternalIndex::new(IndexSource::Reads, "", "").is_err());
+        assert!(ExternalIndex::new(IndexSource::Reads, "IIAC", "").is_err());
+        assert!(ExternalIndex::new(IndexSource::Header, "II", "NNG").is_err());
+    }
+
+    #[test]
+    fn indexed_records() {
+        let reads = |ids: &[&str]| -> Vec<Result<Parsed<IndexRead>, failure::Error>> {
+            ids.iter()
+                .map(|id| {
+                    Ok(Ok(IndexRead::new(&fasta::Record::with_attrs(
+                        id, None, b"ACGT",
+                    ))))
+                })
+                .collect()
+        };
+
+        let joined: Vec<_> = IndexedRecords::new(
+            reads(&["a", "b"]).into_iter(),
+            Some(index_records(&["a", "b"], b"ACGT")),
+            None,
+        )
+        .collect();
+        assert!(joined.len() == 2);
+        match joined[1] {
+            Ok((Ok(ref read), ref index_reads)) => {
+                assert!(read.id() == "b");
+                assert!(index_reads.index1().map(IndexRead::id) == Some("b"));
+                assert!(index_reads.index2().is_none());
+            }
+            _ => panic!("Expected indexed record"),
+        }
+
+        let mut short = IndexedRecords::new(
+            reads(&["a", "b"]).into_iter(),
+            Some(index_records(&["a"], b"ACGT")),
+            None,
+        );
+        assert!(short.next().unwrap().is_ok());
+        assert!(short.next().unwrap().is_err());
+
+        let mut long = IndexedRecords::new(
+            reads(&["a"]).into_iter(),
+            None,
+            Some(index_records(&["a", "b"], b"ACGT")),
+        );
+        assert!(long.next().unwrap().is_ok());
+        assert!(long.next().unwrap().is_err());
+
+        // The truncated first index record ends the records with an
+        // error
+        let truncated: &'static [u8] = b"@a\n@b\nACGT\n+\nIIII\n";
+        let mut unparsed = IndexedRecords::new(
+            reads(&["a", "b"]).into_iter(),
+            None,
+            Some(Box::new(FastqRecords::new(truncated).map(|res| {
+                res.map(|parsed| parsed.map(|rec| IndexRead::new(&rec)))
+            }))),
+        );
+        match unparsed.next() {
+            Some(Err(ref err)) => {
+                assert!(err.to_string() == "Unparseable index 2 record at line 1: truncated record")
+            }
+            _ => panic!("Expected unparsed index record"),
+        }
+        assert!(unparsed.next().is_none());
+
+        // The corrupt read is followed by a resynchronized read, which
+        // would otherwise be joined with the wrong index read
+        let corrupt: &'static [u8] = b"@a\nACGT\n@b\nACGT\n+\nIIII\n";
+        let mut resync = IndexedRecords::new(
+            FastqRecords::new(corrupt),
+            Some(index_records(&["a", "b"], b"ACGT")),
+            None,
+        );
+        match resync.next() {
+            Some(Err(ref err)) => assert!(
+                err.to_string()
+                    == "Unparseable record at line 1: truncated record with index read inputs"
+            ),
+            _ => panic!("Expected unparsed read"),
+        }
+        assert!(resync.next().is_none());
+
+        // Unparsed reads are passed on when there are no index inputs
+        let mut plain = IndexedRecords::new(FastqRecords::new(corrupt), None, None);
+        match plain.next() {
+            Some(Ok((Err(_), ref index_reads))) => assert!(index_reads.index1().is_none()),
+            _ => panic!("Expected unparsed read"),
+        }
+
+        let broken: Vec<Result<Parsed<IndexRead>, failure::Error>> =
+            vec![Err(io::Error::new(io::ErrorKind::Other, "Bad index").into())];
+        let mut unreadable = IndexedRecords::new(
+            reads(&["a"]).into_iter(),
+            Some(Box::new(broken.into_iter())),
+            None,
+        );
+        match unreadable.next() {
+            Some(Err(ref err)) => assert!(err.to_string() == "Unreadable index input: Bad index"),
+            _ => panic!("Expected unreadable index input"),
+        }
+    }
+}
@@ -3,14 +3,18 @@ use std::fmt;
 
 use failure;
 
-use bio::io::fastq;
+use fastx_split::fastx::*;
+use fastx_split::trim::*;
 
-/// Nucleotide type in the linker, either a unique molecule identifier
-/// (UMI) base or a part of the sample index.
+/// Nucleotide type in the linker: a unique molecule identifier (UMI)
+/// base, a part of the sample index, a constant base that is checked
+/// and removed, or a base that is removed without checking.
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 enum LinkerNtSpec {
     UMI,
     SampleIndex,
+    Constant(u8),
+    Skip,
 }
 
 impl LinkerNtSpec {
@@ -20,6 +24,8 @@ impl LinkerNtSpec {
     /// * `ch` is the specification character
     ///   * `N` specifies a UMI character
     ///   * `I` specifies a sample index character
+    ///   * `A`, `C`, `G`, or `T` specifies a constant character
+    ///   * `X` specifies a character that is skipped
     ///
     /// # Errors
     /// An error variant is returned for any other character.
@@ -27,6 +33,8 @@ impl LinkerNtSpec {
         match ch {
             'N' => Ok(LinkerNtSpec::UMI),
             'I' => Ok(LinkerNtSpec::SampleIndex),
+            'A' | 'C' | 'G' | 'T' => Ok(LinkerNtSpec::Constant(ch as u8)),
+            'X' => Ok(LinkerNtSpec::Skip),
             _ => Err(LinkerError::BadSpecChar(ch).into()),
         }
     }
@@ -37,6 +45,8 @@ impl fmt::Display for LinkerNtSpec {
         match self {
             LinkerNtSpec::UMI => write!(f, "N"),
             LinkerNtSpec::SampleIndex => write!(f, "I"),
+            LinkerNtSpec::Constant(nt) => write!(f, "{}", *nt as char),
+            LinkerNtSpec::Skip => write!(f, "X"),
         }
     }
 }
@@ -94,6 +104,16 @@ impl LinkerSpec {
         })
     }
 
+    /// Returns the prefix specification string
+    pub fn prefix_spec(&self) -> String {
+        self.prefix.iter().map(LinkerNtSpec::to_string).collect()
+    }
+
+    /// Returns the suffix specification string
+    pub fn suffix_spec(&self) -> String {
+        self.suffix.iter().map(LinkerNtSpec::to_string).collect()
+    }
+
     /// Returns the length of the prefix, the number of bases that
     /// will be removed from the beginning of the raw read
     #[allow(dead_code)]
@@ -121,6 +141,17 @@ impl LinkerSpec {
         self.sample_index_length
     }
 
+    /// Returns the number of sample index bases in the prefix and in
+    /// the suffix, which are joined to form the sample index
+    pub fn index_segments(&self) -> Vec<usize> {
+        let count_index = |nts: &[LinkerNtSpec]| {
+            nts.iter()
+                .filter(|&nt| *nt == LinkerNtSpec::SampleIndex)
+                .count()
+        };
+        vec![count_index(&self.prefix), count_index(&self.suffix)]
+    }
+
     /// Returns the length in bases of the UMI sequence that will be
     /// constructed from the linker
     #[allow(dead_code)]
@@ -128,46 +159,143 @@ impl LinkerSpec {
         self.umi_length
     }
 
+    /// Returns the number of constant bases in the linker, which are
+    /// checked against the read
+    pub fn constant_length(&self) -> usize {
+        self.prefix
+            .iter()
+            .chain(self.suffix.iter())
+            .filter(|&nt| match nt {
+                LinkerNtSpec::Constant(_) => true,
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Returns the number of mismatches between the constant bases of
+    /// the linker and a sequence, counting the prefix from the start
+    /// of the sequence and the suffix from its end. Bases beyond the
+    /// end of a sequence that is too short are not counted.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` is the sequence holding the linker bases
+    pub fn constant_mismatches(&self, sequence: &[u8]) -> usize {
+        let suffix_start = sequence.len().saturating_sub(self.suffix.len());
+        self.prefix
+            .iter()
+            .zip(sequence.iter())
+            .chain(self.suffix.iter().zip(sequence[suffix_start..].iter()))
+            .filter(|&(nt, base)| match nt {
+                LinkerNtSpec::Constant(constant) => constant != base,
+                _ => false,
+            })
+            .count()
+    }
+
     /// Split a fastq record sequence according to the linker
     /// specification. If the sequence is too short to split -- if its
     /// total length is less than the total linker length -- then
-    /// `None` is returned.
+    /// `None` is returned. FastA records, which have no quality
+    /// information, yield a split with an empty quality.
     ///
     /// # Arguments
     ///
-    /// * `fq` is a FastQ record
-    pub fn split_record<'a>(&self, fq: &'a fastq::Record) -> Option<LinkerSplit<'a>> {
-        let sequence = fq.seq();
+    /// * `fq` is a FastQ or FastA record
+    pub fn split_record<'a, R: FastxRecord>(&self, fq: &'a R) -> Option<LinkerSplit<'a>> {
+        self.split_record_at(fq, fq.seq().len())
+    }
 
-        if sequence.len() >= self.prefix.len() + self.suffix.len() {
-            let mut umi = Vec::new();
-            let mut sample_index = Vec::new();
+    /// Split a fastq record sequence according to the linker
+    /// specification, considering only the first `end` bases of the
+    /// record. The suffix is taken from the bases just before
+    /// `end`. This is used after a 3' adapter has been located, so
+    /// that the adapter and everything after it are discarded. If
+    /// the truncated sequence is too short to split, or if `end`
+    /// lies beyond the end of the record, then `None` is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq` is a FastQ or FastA record
+    ///
+    /// * `end` is the length of the record to be split
+    pub fn split_record_at<'a, R: FastxRecord>(
+        &self,
+        fq: &'a R,
+        end: usize,
+    ) -> Option<LinkerSplit<'a>> {
+        if end > fq.seq().len() {
+            return None;
+        }
 
-            for i in 0..self.prefix.len() {
-                match self.prefix[i] {
-                    LinkerNtSpec::UMI => umi.push(sequence[i]),
-                    LinkerNtSpec::SampleIndex => sample_index.push(sequence[i]),
-                };
-            }
+        let sequence = &fq.seq()[..end];
+        let quality = if fq.qual().is_empty() {
+            fq.qual()
+        } else {
+            &fq.qual()[..end]
+        };
 
+        if let Some(barcode) = self.barcode(sequence, quality) {
             let suffix_start = sequence.len() - self.suffix.len();
-            for i in 0..self.suffix.len() {
-                match self.suffix[i] {
-                    LinkerNtSpec::UMI => umi.push(sequence[suffix_start + i]),
-                    LinkerNtSpec::SampleIndex => sample_index.push(sequence[suffix_start + i]),
-                };
-            }
 
             Some(LinkerSplit {
-                umi: umi,
-                sample_index: sample_index,
+                barcode: barcode,
+                constant_mismatches: self.constant_mismatches(sequence),
                 sequence: &sequence[self.prefix.len()..suffix_start],
-                quality: &fq.qual()[self.prefix.len()..suffix_start],
+                quality: if quality.is_empty() {
+                    quality
+                } else {
+                    &quality[self.prefix.len()..suffix_start]
+                },
+                insert_trim: InsertTrim::default(),
             })
         } else {
             None
         }
     }
+
+    /// Extract the UMI and sample index bases from a sequence
+    /// according to the linker specification, without splitting off
+    /// an insert. This is used for separately sequenced index reads,
+    /// where any bases between the prefix and the suffix are
+    /// ignored. If the sequence is shorter than the total linker
+    /// length, then `None` is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` is the sequence holding the linker bases
+    ///
+    /// * `quality` is the quality for the sequence, which is empty
+    /// when there is no quality information
+    pub fn barcode(&self, sequence: &[u8], quality: &[u8]) -> Option<Barcode> {
+        if sequence.len() < self.prefix.len() + self.suffix.len() {
+            return None;
+        }
+
+        let mut barcode = Barcode::default();
+
+        let suffix_start = sequence.len() - self.suffix.len();
+        let positions = self.prefix.iter().enumerate().chain(
+            self.suffix
+                .iter()
+                .enumerate()
+                .map(|(i, nt)| (suffix_start + i, nt)),
+        );
+        for (i, nt) in positions {
+            match nt {
+                LinkerNtSpec::UMI => {
+                    barcode.umi.push(sequence[i]);
+                    if let Some(qual) = quality.get(i) {
+                        barcode.umi_quality.push(*qual);
+                    }
+                }
+                LinkerNtSpec::SampleIndex => barcode.sample_index.push(sequence[i]),
+                LinkerNtSpec::Constant(_) | LinkerNtSpec::Skip => (),
+            };
+        }
+
+        Some(barcode)
+    }
 }
 
 impl fmt::Display for LinkerSpec {
@@ -184,25 +312,165 @@ impl fmt::Display for LinkerSpec {
     }
 }
 
+/// Linker specifications for the two mates of a paired-end read. The
+/// sample index and the UMI are assembled from the mate 1 bases
+/// followed by the mate 2 bases.
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct PairedLinkerSpec {
+    mate1: LinkerSpec,
+    mate2: LinkerSpec,
+}
+
+impl PairedLinkerSpec {
+    /// Create a new paired-end linker specification from the
+    /// specifications for each mate.
+    pub fn new(mate1: LinkerSpec, mate2: LinkerSpec) -> Self {
+        PairedLinkerSpec {
+            mate1: mate1,
+            mate2: mate2,
+        }
+    }
+
+    /// Returns the linker specification for mate 1
+    pub fn mate1(&self) -> &LinkerSpec {
+        &self.mate1
+    }
+
+    /// Returns the linker specification for mate 2
+    pub fn mate2(&self) -> &LinkerSpec {
+        &self.mate2
+    }
+
+    /// Returns the combined length in bases of the sample index
+    /// constructed from both mates
+    pub fn sample_index_length(&self) -> usize {
+        self.mate1.sample_index_length() + self.mate2.sample_index_length()
+    }
+
+    /// Split both mates of a read pair according to their linker
+    /// specifications. If either mate is too short to split, then
+    /// `None` is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq1` is the mate 1 record
+    ///
+    /// * `fq2` is the mate 2 record
+    pub fn split_pair<'a, R: FastxRecord>(
+        &self,
+        fq1: &'a R,
+        fq2: &'a R,
+    ) -> Option<PairedSplit<'a>> {
+        self.split_pair_at(fq1, fq1.seq().len(), fq2)
+    }
+
+    /// Split both mates of a read pair, considering only the first
+    /// `end1` bases of mate 1, as in `LinkerSpec::split_record_at`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq1` is the mate 1 record
+    ///
+    /// * `end1` is the length of mate 1 to be split
+    ///
+    /// * `fq2` is the mate 2 record
+    pub fn split_pair_at<'a, R: FastxRecord>(
+        &self,
+        fq1: &'a R,
+        end1: usize,
+        fq2: &'a R,
+    ) -> Option<PairedSplit<'a>> {
+        let mate1 = self.mate1.split_record_at(fq1, end1)?;
+        let mate2 = self.mate2.split_record(fq2)?;
+
+        let mut barcode = mate1.barcode().clone();
+        barcode.extend(mate2.barcode());
+
+        Some(PairedSplit {
+            barcode: barcode,
+            mate1: mate1,
+            mate2: mate2,
+        })
+    }
+}
+
+impl fmt::Display for PairedLinkerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mate 1 {}; mate 2 {}", self.mate1, self.mate2)
+    }
+}
+
+/// UMI and sample index bases taken from a linker or from a
+/// separately sequenced index read. The UMI quality is shorter than
+/// the UMI when some UMI bases have no quality information.
+#[derive(Debug, Clone, Default, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct Barcode {
+    umi: Vec<u8>,
+    umi_quality: Vec<u8>,
+    sample_index: Vec<u8>,
+}
+
+impl Barcode {
+    /// Returns the UMI sequence
+    pub fn umi(&self) -> &[u8] {
+        &self.umi
+    }
+
+    /// Returns the quality of the UMI bases
+    pub fn umi_quality(&self) -> &[u8] {
+        &self.umi_quality
+    }
+
+    /// Returns the sample index sequence
+    pub fn sample_index(&self) -> &[u8] {
+        &self.sample_index
+    }
+
+    /// Appends the UMI and sample index bases of another barcode
+    pub fn extend(&mut self, other: &Barcode) {
+        self.umi.extend_from_slice(&other.umi);
+        self.umi_quality.extend_from_slice(&other.umi_quality);
+        self.sample_index.extend_from_slice(&other.sample_index);
+    }
+
+    // Places the bases of another barcode before those of this one
+    fn prepend(&mut self, other: &Barcode) {
+        let mut joined = other.clone();
+        joined.extend(self);
+        *self = joined;
+    }
+}
+
 /// Represents the split sequence (and quality) information from a
 /// FastQ record along with the sample index and UMI sequences.
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct LinkerSplit<'a> {
-    umi: Vec<u8>,
-    sample_index: Vec<u8>,
+    barcode: Barcode,
+    constant_mismatches: usize,
     sequence: &'a [u8],
     quality: &'a [u8],
+    insert_trim: InsertTrim,
 }
 
 impl<'a> LinkerSplit<'a> {
     /// Returns the UMI sequence
     pub fn umi<'b>(&'b self) -> &'b [u8] {
-        &self.umi
+        self.barcode.umi()
     }
 
     /// Returns the sample index sequence
     pub fn sample_index<'b>(&'b self) -> &'b [u8] {
-        &self.sample_index
+        self.barcode.sample_index()
+    }
+
+    /// Returns the UMI, UMI quality, and sample index
+    pub fn barcode<'b>(&'b self) -> &'b Barcode {
+        &self.barcode
+    }
+
+    /// Returns the number of mismatches in the constant linker bases
+    pub fn constant_mismatches(&self) -> usize {
+        self.constant_mismatches
     }
 
     /// Returns the non-linker portion of the raw input sequence
@@ -214,6 +482,84 @@ impl<'a> LinkerSplit<'a> {
     pub fn quality(&self) -> &'a [u8] {
         self.quality
     }
+
+    /// Places the UMI and sample index bases of a barcode, such as
+    /// one from separate index reads, before those from the linker
+    pub fn prepend_barcode(&mut self, barcode: &Barcode) {
+        self.barcode.prepend(barcode);
+    }
+
+    /// Returns the bases trimmed from the 3' end of the insert
+    pub fn insert_trim(&self) -> InsertTrim {
+        self.insert_trim
+    }
+
+    /// Removes bases from the 3' end of the non-linker sequence, and
+    /// of its quality, after quality trimming and tail stripping
+    pub fn trim_insert(&mut self, insert_trim: InsertTrim) {
+        let length = self.sequence.len().saturating_sub(insert_trim.total());
+        self.sequence = &self.sequence[..length];
+        if !self.quality.is_empty() {
+            self.quality = &self.quality[..length];
+        }
+        self.insert_trim = insert_trim;
+    }
+}
+
+/// Represents the split information for both mates of a paired-end
+/// read, along with the combined sample index and UMI sequences.
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct PairedSplit<'a> {
+    barcode: Barcode,
+    mate1: LinkerSplit<'a>,
+    mate2: LinkerSplit<'a>,
+}
+
+impl<'a> PairedSplit<'a> {
+    /// Returns the combined UMI sequence
+    pub fn umi<'b>(&'b self) -> &'b [u8] {
+        self.barcode.umi()
+    }
+
+    /// Returns the combined sample index sequence
+    pub fn sample_index<'b>(&'b self) -> &'b [u8] {
+        self.barcode.sample_index()
+    }
+
+    /// Returns the combined UMI, UMI quality, and sample index
+    pub fn barcode<'b>(&'b self) -> &'b Barcode {
+        &self.barcode
+    }
+
+    /// Returns the combined number of mismatches in the constant
+    /// linker bases of both mates
+    pub fn constant_mismatches(&self) -> usize {
+        self.mate1.constant_mismatches() + self.mate2.constant_mismatches()
+    }
+
+    /// Returns the split information for mate 1
+    pub fn mate1<'b>(&'b self) -> &'b LinkerSplit<'a> {
+        &self.mate1
+    }
+
+    /// Returns the split information for mate 2
+    pub fn mate2<'b>(&'b self) -> &'b LinkerSplit<'a> {
+        &self.mate2
+    }
+
+    /// Places the UMI and sample index bases of a barcode, such as
+    /// one from separate index reads, before the combined bases from
+    /// both mates
+    pub fn prepend_barcode(&mut self, barcode: &Barcode) {
+        self.barcode.prepend(barcode);
+    }
+
+    /// Removes bases from the 3' end of the insert of each mate, as
+    /// for `LinkerSplit::trim_insert`
+    pub fn trim_inserts(&mut self, insert_trim1: InsertTrim, insert_trim2: InsertTrim) {
+        self.mate1.trim_insert(insert_trim1);
+        self.mate2.trim_insert(insert_trim2);
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -235,6 +581,8 @@ impl error::Error for LinkerError {}
 mod tests {
     use super::*;
 
+    use bio::io::{fasta, fastq};
+
     const SEQ1: &[u8] = b"ACGTACGTACGTACGT";
     const SEQ2: &[u8] = b"AAAACCCCGGGGTTTT";
     const SEQ3: &[u8] = b"CGATCGATCGATCGATCG";
@@ -361,6 +709,57 @@ mod tests {
         assert!(spec.umi_length() == 4);
     }
 
+    #[test]
+    fn test_fasta() {
+        let rec = fasta::Record::with_attrs("test_record", None, SEQ1);
+        let spec = LinkerSpec::new("IINN", "IINN").unwrap();
+        let split = spec.split_record(&rec).unwrap();
+
+        assert!(split.umi() == b"GTGT");
+        assert!(split.sample_index() == b"ACAC");
+        assert!(split.sequence() == b"ACGTACGT");
+        assert!(split.quality().is_empty());
+    }
+
+    #[test]
+    fn test_paired() {
+        let spec = PairedLinkerSpec::new(
+            LinkerSpec::new("NNII", "").unwrap(),
+            LinkerSpec::new("IN", "N").unwrap(),
+        );
+        assert!(spec.sample_index_length() == 3);
+        assert!(spec.mate1().umi_length() + spec.mate2().umi_length() == 4);
+
+        let rec1 = fastq(SEQ1);
+        let rec2 = fastq(SEQ2);
+        let split = spec.split_pair(&rec1, &rec2).unwrap();
+
+        assert!(split.umi() == b"ACAT");
+        assert!(split.sample_index() == b"GTA");
+        assert!(split.mate1().sequence() == b"ACGTACGTACGT");
+        assert!(split.mate2().sequence() == b"AACCCCGGGGTTT");
+        assert!(split.mate2().quality()[0] == 2 + 32);
+
+        let short = fastq(b"A");
+        assert!(spec.split_pair(&rec1, &short) == None);
+    }
+
+    #[test]
+    fn test_split_at() {
+        let rec = fastq(SEQ2);
+        let spec = LinkerSpec::new("NN", "IIN").unwrap();
+        let split = spec.split_record_at(&rec, 12).unwrap();
+
+        assert!(split.umi() == b"AAG");
+        assert!(split.sample_index() == b"GG");
+        assert!(split.sequence() == b"AACCCCG");
+        assert!(split.quality().len() == 7);
+        assert!(split.quality()[0] == 2 + 32);
+
+        assert!(spec.split_record_at(&rec, 4) == None);
+        assert!(spec.split_record_at(&rec, 17) == None);
+    }
+
     const SEQ10: &[u8] = b"ACACAGTGTG";
     const SEQ11: &[u8] = b"TGCATGCATGC";
     const SEQ12: &[u8] = b"CCCTTTGGGAAA";
@@ -387,4 +786,50 @@ mod tests {
         assert!(split12.as_ref().map(LinkerSplit::sample_index) == Some(b"CCCTT"));
         assert!(split12.as_ref().map(LinkerSplit::sequence) == Some(b"T"));
     }
+
+    #[test]
+    fn barcode() {
+        let spec = LinkerSpec::new("IIN", "NI").unwrap();
+        let barcode = spec.barcode(b"ACGTTTCA", b"ABCDEFGH").unwrap();
+        assert!(barcode.sample_index() == b"ACA");
+        assert!(barcode.umi() == b"GC");
+        assert!(barcode.umi_quality() == b"CG");
+        assert!(spec.barcode(b"ACGT", b"") == None);
+        assert!(spec
+            .barcode(b"ACGTTTCA", b"")
+            .unwrap()
+            .umi_quality()
+            .is_empty());
+
+        let rec = fastq(SEQ2);
+        let mut split = LinkerSpec::new("NI", "")
+            .unwrap()
+            .split_record(&rec)
+            .unwrap();
+        split.prepend_barcode(&barcode);
+        assert!(split.sample_index() == b"ACAA");
+        assert!(split.umi() == b"GCA");
+        assert!(split.barcode().umi_quality() == b"CG ");
+        assert!(split.sequence() == b"AACCCCGGGGTTTT");
+    }
+
+    #[test]
+    fn test_constant() {
+        assert_split(SEQ1, "IACN", "XT", b"T", b"A", b"ACGTACGTAC", 4 + 32);
+
+        let spec = LinkerSpec::new("IACN", "XT").unwrap();
+        assert!(spec.linker_length() == 6);
+        assert!(spec.sample_index_length() == 1);
+        assert!(spec.umi_length() == 1);
+        assert!(spec.constant_length() == 3);
+        assert!(spec.prefix_spec() == "IACN");
+        assert!(spec.suffix_spec() == "XT");
+
+        let rec = fastq(SEQ1);
+        assert!(spec.split_record(&rec).unwrap().constant_mismatches() == 2);
+        assert!(spec.constant_mismatches(b"GACATCGT") == 0);
+        assert!(spec.constant_mismatches(b"GGGATCGA") == 3);
+
+        assert!(LinkerSpec::new("IAZ", "").is_err());
+    }
 }
@@ -1,82 +1,376 @@
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::str;
 
 use failure;
+use serde_json;
 
-use bio::io::fastq;
-
+mod adapter;
+mod collapse;
+mod compress;
+mod contaminant;
+mod demux;
+mod dispatch;
+mod fastx;
+mod index_hopping;
+mod index_reads;
 mod linkers;
+mod pipeline;
+mod processor;
 mod sample;
 mod sample_sheet;
+mod sample_writer;
+mod subsample;
+mod summary;
+mod trim;
+mod ubam;
+mod umi_stats;
+mod unknown_index;
 
+use fastx_split::adapter::*;
+use fastx_split::compress::*;
+use fastx_split::contaminant::*;
+use fastx_split::demux::*;
+use fastx_split::dispatch::*;
+use fastx_split::fastx::*;
+use fastx_split::index_reads::*;
 use fastx_split::linkers::*;
+use fastx_split::pipeline::*;
+use fastx_split::processor::*;
 use fastx_split::sample::*;
 use fastx_split::sample_sheet::*;
+use fastx_split::subsample::*;
+use fastx_split::summary::*;
+use fastx_split::trim::*;
+use fastx_split::unknown_index::*;
+use umi::*;
+
+pub use fastx_split::dispatch::MalformedPolicy;
+pub use fastx_split::trim::QualityTrimMode;
+
+// Library interface for demultiplexing without the fastx-split outputs
+pub use fastx_split::adapter::Adapter;
+pub use fastx_split::contaminant::ContaminantIndex;
+pub use fastx_split::demux::{Assignment, Demultiplexer, DemuxSample, SplitCounts};
+pub use fastx_split::fastx::FastxRecord;
+pub use fastx_split::index_hopping::DualIndexStats;
+pub use fastx_split::index_reads::{ExternalIndex, IndexSource};
+pub use fastx_split::linkers::{Barcode, LinkerSpec, LinkerSplit, PairedLinkerSpec, PairedSplit};
+pub use fastx_split::processor::{MalformedReason, ReadFate, ReadProcessor};
+pub use fastx_split::sample_sheet::{IndexFailure, SampleMap};
+pub use fastx_split::subsample::Subsampler;
+pub use fastx_split::trim::InsertTrimmer;
+
+/// Longest UMI for which the table of reads for every possible UMI
+/// is written, as the table has 5^n rows for n-base UMIs
+const MAX_UMI_TABLE_LENGTH: usize = 8;
+
+/// Output names used for reads that are not assigned to a sample
+const RESERVED_OUTPUTS: &[&str] = &[
+    "UnknownIndex",
+    "tooshort",
+    "noadapter",
+    "badlinker",
+    "rejected",
+];
 
 pub struct CLI {
     pub fastx_inputs: Vec<String>,
+    pub mate2_inputs: Vec<String>,
     pub output_dir: String,
     pub min_insert: usize,
     pub prefix: String,
     pub suffix: String,
+    pub prefix2: String,
+    pub suffix2: String,
+    pub adapter: Option<String>,
+    pub adapter_mismatch_rate: f64,
+    pub adapter_min_overlap: usize,
     pub sample_sheet: String,
+    pub max_mismatch: usize,
+    pub max_mismatch2: Option<usize>,
+    pub reduce_mismatch: bool,
     pub progress: usize,
+    pub gzip: bool,
+    pub compression_level: u32,
+    pub threads: usize,
+    pub umi_encoding: UmiEncoding,
+    pub malformed: MalformedPolicy,
+    pub index1_inputs: Vec<String>,
+    pub index2_inputs: Vec<String>,
+    pub header_index: bool,
+    pub index1_spec: String,
+    pub index2_spec: String,
+    pub max_constant_mismatch: usize,
+    pub contaminants: Option<String>,
+    pub contaminant_kmer: usize,
+    pub contaminant_fraction: f64,
+    pub collapse: bool,
+    pub unaligned_bam: bool,
+    pub trim_quality: Option<u8>,
+    pub trim_mode: QualityTrimMode,
+    pub trim_window: usize,
+    pub trim_poly_a: Option<usize>,
+    pub trim_poly_n: bool,
+    pub max_reads: Option<usize>,
+    pub subsample: Option<f64>,
+    pub seed: u64,
 }
 
 pub struct Config {
-    fastx_inputs: Vec<PathBuf>,
+    fastx_inputs: Vec<FastxInput>,
+    mate2_inputs: Vec<FastxInput>,
+    index1_inputs: Vec<FastxInput>,
+    index2_inputs: Vec<FastxInput>,
+    input_names: Vec<PathBuf>,
+    mate2_names: Vec<PathBuf>,
+    index1_names: Vec<PathBuf>,
+    index2_names: Vec<PathBuf>,
+    contaminants_name: Option<PathBuf>,
     output_dir: PathBuf,
-    min_insert: usize,
-    linker_spec: LinkerSpec,
-    sample_map: SampleMap<Sample>,
-    short_file: fastq::Writer<fs::File>,
+    unaligned_bam: bool,
+    max_reads: Option<usize>,
+    dispatcher: Dispatcher,
+    threads: usize,
     progress: Option<usize>,
 }
 
 impl Config {
     pub fn new(cli: &CLI) -> Result<Self, failure::Error> {
         let linker_spec = LinkerSpec::new(&cli.prefix, &cli.suffix)?;
-        let index_length = linker_spec.sample_index_length();
+        let paired_spec = if cli.mate2_inputs.is_empty() {
+            None
+        } else if cli.mate2_inputs.len() == cli.fastx_inputs.len() {
+            let mate2_spec = LinkerSpec::new(&cli.prefix2, &cli.suffix2)?;
+            Some(PairedLinkerSpec::new(linker_spec.clone(), mate2_spec))
+        } else {
+            return Err(format_err!(
+                "{} mate 1 inputs but {} mate 2 inputs",
+                cli.fastx_inputs.len(),
+                cli.mate2_inputs.len()
+            ));
+        };
+        let paired = paired_spec.is_some();
+
+        let adapter = match cli.adapter {
+            Some(ref adapter_seq) => Some(Adapter::new(
+                adapter_seq,
+                cli.adapter_mismatch_rate,
+                cli.adapter_min_overlap,
+            )?),
+            None => None,
+        };
+
+        let mut processor = ReadProcessor::new(linker_spec, paired_spec, adapter, cli.min_insert);
+        processor.set_external_index(Config::external_index(cli)?);
+        processor.set_max_constant_mismatch(cli.max_constant_mismatch);
+        processor.set_trimmer(Config::insert_trimmer(cli)?);
+        processor.set_contaminants(match cli.contaminants {
+            Some(ref contaminants) => Some(ContaminantIndex::from_fasta(
+                contaminants,
+                cli.contaminant_kmer,
+                cli.contaminant_fraction,
+            )?),
+            None => None,
+        });
+        let filters_contaminants = processor.contaminants().is_some();
+        let index_length = processor.sample_index_length();
+        let compression = OutputCompression::new(cli.gzip, cli.compression_level)?;
+
+        let fastx_inputs = Config::open_inputs(&cli.fastx_inputs)?;
+        let mate2_inputs = Config::open_inputs(&cli.mate2_inputs)?;
+        let index1_inputs = Config::open_inputs(&cli.index1_inputs)?;
+        let index2_inputs = Config::open_inputs(&cli.index2_inputs)?;
+        let format = Config::output_format(fastx_inputs.iter().chain(mate2_inputs.iter()))?;
 
         let output_dir = Path::new(&cli.output_dir).to_path_buf();
         fs::DirBuilder::new()
             .recursive(true)
             .create(output_dir.as_path())?;
 
-        let unknown_sample = Sample::new(
-            "UnknownIndex".to_string(),
+        let threads = if cli.threads > 0 { cli.threads } else { 1 };
+        let mut output_files = OutputFiles::new(&output_dir, format, compression, threads > 1);
+        output_files.set_unaligned_bam(cli.unaligned_bam);
+
+        let sample_sheet_txt = fs::read_to_string(&cli.sample_sheet)?;
+        let samples = parse_sample_sheet(&sample_sheet_txt)?;
+        let tolerances = Config::mismatch_tolerances(&samples, cli)?;
+        let index1_length = Config::index1_length(&samples, index_length)?;
+
+        let mut unknown_sample = output_files.create_sample(
+            "UnknownIndex",
+            "UnknownIndex",
             vec![b'N'; index_length],
-            Config::create_writer(&output_dir, "UnknownIndex")?,
-        );
+            None,
+            cli.umi_encoding,
+            paired,
+        )?;
+        if filters_contaminants {
+            output_files.create_contaminant_outputs(&mut unknown_sample, "UnknownIndex", paired)?;
+        }
+        if cli.collapse {
+            unknown_sample.collapse_identical();
+        }
 
-        let mut sample_map = SampleMap::new(index_length, unknown_sample);
+        let mut sample_map = match index1_length {
+            Some(index1_length) => {
+                SampleMap::new_dual(index1_length, index_length - index1_length, unknown_sample)
+            }
+            None => SampleMap::new(index_length, unknown_sample),
+        };
 
-        let sample_sheet_txt = fs::read_to_string(&cli.sample_sheet)?;
-        for (name, index) in parse_sample_sheet(&sample_sheet_txt)?.into_iter() {
-            let output_file = Config::create_writer(&output_dir, &name)?;
-            let sample = Sample::new(
-                name.to_string(),
-                index.to_string().into_bytes(),
-                output_file,
-            );
-            sample_map.insert(index.into_bytes(), true, sample)?;
+        let mut checks_constants = match processor.paired_spec() {
+            Some(paired_spec) => {
+                paired_spec.mate1().constant_length() + paired_spec.mate2().constant_length() > 0
+            }
+            None => processor.linker_spec().constant_length() > 0,
+        };
+
+        for (spec, tolerance) in samples.iter().zip(tolerances.into_iter()) {
+            if RESERVED_OUTPUTS.contains(&spec.output_name()) {
+                return Err(format_err!(
+                    "Sample sheet line {}: output name \"{}\" is reserved",
+                    spec.line(),
+                    spec.output_name()
+                ));
+            }
+
+            if filters_contaminants && spec.output_name().ends_with("_contam") {
+                return Err(format_err!(
+                    "Sample sheet line {}: output name \"{}\" ends with \"_contam\", which is used for contaminant outputs",
+                    spec.line(),
+                    spec.output_name()
+                ));
+            }
+
+            let mut sample = output_files.create_sample(
+                spec.name(),
+                spec.output_name(),
+                spec.full_index().into_bytes(),
+                spec.description(),
+                cli.umi_encoding,
+                paired,
+            )?;
+            if filters_contaminants {
+                output_files.create_contaminant_outputs(&mut sample, spec.output_name(), paired)?;
+            }
+            if cli.collapse {
+                sample.collapse_identical();
+            }
+            sample.set_max_reads(spec.max_reads().or(cli.max_reads));
+
+            let linker_spec = Config::sample_linker_spec(
+                spec,
+                (spec.prefix(), spec.suffix()),
+                (&cli.prefix, &cli.suffix),
+                processor.linker_spec(),
+            )?;
+            match processor.paired_spec() {
+                Some(paired_spec) => {
+                    let mate2_spec = Config::sample_linker_spec(
+                        spec,
+                        (spec.prefix2(), spec.suffix2()),
+                        (&cli.prefix2, &cli.suffix2),
+                        paired_spec.mate2(),
+                    )?;
+                    if linker_spec.is_some() || mate2_spec.is_some() {
+                        let sample_spec = PairedLinkerSpec::new(
+                            linker_spec.unwrap_or_else(|| paired_spec.mate1().clone()),
+                            mate2_spec.unwrap_or_else(|| paired_spec.mate2().clone()),
+                        );
+                        checks_constants |= sample_spec.mate1().constant_length()
+                            + sample_spec.mate2().constant_length()
+                            > 0;
+                        sample.set_paired_spec(sample_spec);
+                    }
+                }
+                None => {
+                    if spec.prefix2().is_some() || spec.suffix2().is_some() {
+                        return Err(format_err!(
+                            "Sample sheet line {}: prefix2 and suffix2 need paired-end reads",
+                            spec.line()
+                        ));
+                    }
+                    if let Some(linker_spec) = linker_spec {
+                        checks_constants |= linker_spec.constant_length() > 0;
+                        sample.set_linker_spec(linker_spec);
+                    }
+                }
+            }
+
+            sample_map
+                .insert_with_tolerances(spec.full_index().into_bytes(), &tolerance, sample)
+                .map_err(|err| format_err!("Sample sheet line {}: {}", spec.line(), err))?;
         }
 
-        let short_file = fastq::Writer::new(Config::create_writer(&output_dir, "tooshort")?);
+        let short_file = FateOutput::create(&output_files, "tooshort", paired)?;
+
+        let noadapter_file = if processor.adapter().is_some() {
+            Some(FateOutput::create(&output_files, "noadapter", paired)?)
+        } else {
+            None
+        };
+
+        let badlinker_file = if checks_constants {
+            Some(FateOutput::create(&output_files, "badlinker", paired)?)
+        } else {
+            None
+        };
+
+        let rejected_file = if cli.malformed == MalformedPolicy::Reject {
+            Some(FateOutput::create(&output_files, "rejected", paired)?)
+        } else {
+            None
+        };
+
+        let mut demux = Demultiplexer::with_processor(processor, sample_map);
+        demux.set_subsampler(match cli.subsample {
+            Some(fraction) => Some(Subsampler::new(fraction, cli.seed)?),
+            None => None,
+        });
+
+        let dispatcher = Dispatcher::new(
+            demux,
+            short_file,
+            noadapter_file,
+            badlinker_file,
+            cli.malformed,
+            rejected_file,
+        );
 
         let mut mapping_file = output_dir.clone();
         mapping_file.push("mapping.txt");
-        fs::write(&mapping_file, sample_map.mapping_table())?;
+        fs::write(&mapping_file, dispatcher.sample_map().mapping_table())?;
 
         Ok(Config {
-            fastx_inputs: cli.fastx_inputs.iter().map(PathBuf::from).collect(),
+            input_names: fastx_inputs
+                .iter()
+                .map(|i| i.name().to_path_buf())
+                .collect(),
+            mate2_names: mate2_inputs
+                .iter()
+                .map(|i| i.name().to_path_buf())
+                .collect(),
+            index1_names: index1_inputs
+                .iter()
+                .map(|i| i.name().to_path_buf())
+                .collect(),
+            index2_names: index2_inputs
+                .iter()
+                .map(|i| i.name().to_path_buf())
+                .collect(),
+            contaminants_name: cli.contaminants.as_ref().map(PathBuf::from),
+            fastx_inputs: fastx_inputs,
+            mate2_inputs: mate2_inputs,
+            index1_inputs: index1_inputs,
+            index2_inputs: index2_inputs,
             output_dir: output_dir,
-            min_insert: cli.min_insert,
-            linker_spec: linker_spec,
-            sample_map: sample_map,
-            short_file: short_file,
+            unaligned_bam: cli.unaligned_bam,
+            max_reads: cli.max_reads,
+            dispatcher: dispatcher,
+            threads: threads,
             progress: if cli.progress > 0 {
                 Some(cli.progress)
             } else {
@@ -85,101 +379,748 @@ impl Config {
         })
     }
 
-    fn create_writer(output_dir: &Path, name: &str) -> Result<fs::File, failure::Error> {
-        let mut output_path = output_dir.to_path_buf();
-        output_path.push(Path::new(name));
-        output_path.set_extension("fastq");
-        fs::File::create(output_path.as_path()).map_err(::std::convert::Into::into)
+    // Index bases come either from index read inputs, one for each
+    // read input, or from the read descriptions. An index input with
+    // no bases taken from it is an error, as it is likely a mistake.
+    fn external_index(cli: &CLI) -> Result<Option<ExternalIndex>, failure::Error> {
+        let has_index_reads = !cli.index1_inputs.is_empty() || !cli.index2_inputs.is_empty();
+
+        let source = if cli.header_index {
+            if has_index_reads {
+                return Err(failure::err_msg(
+                    "Index read inputs cannot be combined with header indices",
+                ));
+            }
+            IndexSource::Header
+        } else if has_index_reads {
+            IndexSource::Reads
+        } else if cli.index1_spec.is_empty() && cli.index2_spec.is_empty() {
+            return Ok(None);
+        } else {
+            return Err(failure::err_msg(
+                "Index read specifications require index read inputs or header indices",
+            ));
+        };
+
+        let external_index = ExternalIndex::new(source, &cli.index1_spec, &cli.index2_spec)?;
+
+        if source == IndexSource::Reads {
+            let index_inputs = [
+                ("first", &cli.index1_inputs, external_index.index1()),
+                ("second", &cli.index2_inputs, external_index.index2()),
+            ];
+            for (which, inputs, spec) in index_inputs.iter() {
+                if spec.linker_length() == 0 && !inputs.is_empty() {
+                    return Err(format_err!(
+                        "No bases are taken from the {} index reads",
+                        which
+                    ));
+                } else if spec.linker_length() > 0 && inputs.len() != cli.fastx_inputs.len() {
+                    return Err(format_err!(
+                        "{} inputs but {} {} index read inputs",
+                        cli.fastx_inputs.len(),
+                        inputs.len(),
+                        which
+                    ));
+                }
+            }
+        }
+
+        Ok(Some(external_index))
+    }
+
+    // Dual indices split the sample index after the first index,
+    // which must have the same length for every sample
+    fn index1_length(
+        samples: &[SampleSpec],
+        index_length: usize,
+    ) -> Result<Option<usize>, failure::Error> {
+        let index1_length = match samples.first() {
+            Some(spec) if spec.index2().is_some() => spec.index().len(),
+            _ => return Ok(None),
+        };
+
+        for spec in samples.iter() {
+            let index2_length = spec.index2().map_or(0, str::len);
+            if spec.index().len() != index1_length || index1_length + index2_length != index_length
+            {
+                return Err(format_err!(
+                    "Sample sheet line {}: dual index lengths {}+{} do not match {}+{}",
+                    spec.line(),
+                    spec.index().len(),
+                    index2_length,
+                    index1_length,
+                    index_length.saturating_sub(index1_length)
+                ));
+            }
+        }
+
+        Ok(Some(index1_length))
+    }
+
+    // Index conflicts are always reported. They are an error unless
+    // the tolerance may be reduced for the samples involved.
+    fn mismatch_tolerances(
+        samples: &[SampleSpec],
+        cli: &CLI,
+    ) -> Result<Vec<Vec<usize>>, failure::Error> {
+        let max_mismatch2 = cli.max_mismatch2.unwrap_or(cli.max_mismatch);
+        let requested: Vec<Vec<usize>> = samples
+            .iter()
+            .map(|spec| {
+                let mut tolerance = vec![spec.max_mismatch().unwrap_or(cli.max_mismatch)];
+                if spec.index2().is_some() {
+                    tolerance.push(spec.max_mismatch2().unwrap_or(max_mismatch2));
+                }
+                tolerance
+            })
+            .collect();
+        let conflicts = index_conflicts(samples, &requested);
+
+        if conflicts.is_empty() {
+            Ok(requested)
+        } else if cli.reduce_mismatch {
+            let tolerances = reduced_tolerances(samples, &requested, &conflicts)?;
+            for conflict in conflicts.iter() {
+                eprintln!("Index conflict: {}", conflict);
+            }
+            for ((spec, tolerance), req) in
+                samples.iter().zip(tolerances.iter()).zip(requested.iter())
+            {
+                if tolerance != req {
+                    let parts: Vec<String> = tolerance.iter().map(|t| t.to_string()).collect();
+                    eprintln!(
+                        "Reduced mismatch tolerance for {} to {}",
+                        spec.name(),
+                        parts.join("+")
+                    );
+                }
+            }
+            Ok(tolerances)
+        } else {
+            Err(SampleError::IndexConflicts(conflicts).into())
+        }
+    }
+
+    // Trimming of insert 3' ends, which removes no bases unless
+    // quality trimming or tail stripping is requested
+    fn insert_trimmer(cli: &CLI) -> Result<InsertTrimmer, failure::Error> {
+        let mut trimmer = InsertTrimmer::new();
+        if let Some(threshold) = cli.trim_quality {
+            trimmer.set_quality_trim(cli.trim_mode, threshold, cli.trim_window)?;
+        }
+        if let Some(min_length) = cli.trim_poly_a {
+            trimmer.set_poly_a(min_length);
+        }
+        trimmer.set_poly_n(cli.trim_poly_n);
+        Ok(trimmer)
+    }
+
+    // A per-sample prefix or suffix replaces the default for that
+    // sample, or for mate 1 of read pairs, and a per-sample prefix2
+    // or suffix2 replaces the mate 2 default. The sample index is
+    // still found with the default linker, so the lengths must agree.
+    fn sample_linker_spec(
+        spec: &SampleSpec,
+        (prefix, suffix): (Option<&str>, Option<&str>),
+        (default_prefix, default_suffix): (&str, &str),
+        default_spec: &LinkerSpec,
+    ) -> Result<Option<LinkerSpec>, failure::Error> {
+        if prefix.is_none() && suffix.is_none() {
+            return Ok(None);
+        }
+
+        let linker_spec = LinkerSpec::new(
+            prefix.unwrap_or(default_prefix),
+            suffix.unwrap_or(default_suffix),
+        )
+        .map_err(|err| format_err!("Sample sheet line {}: {}", spec.line(), err))?;
+
+        let default_length = default_spec.sample_index_length();
+        if linker_spec.sample_index_length() != default_length {
+            return Err(format_err!(
+                "Sample sheet line {}: linker has {} index bases but default linker has {}",
+                spec.line(),
+                linker_spec.sample_index_length(),
+                default_length
+            ));
+        }
+
+        Ok(Some(linker_spec))
+    }
+
+    fn open_inputs(input_names: &[String]) -> Result<Vec<FastxInput>, failure::Error> {
+        input_names.iter().map(FastxInput::open).collect()
+    }
+
+    // Output records are written in the same format as the input,
+    // so all inputs must share one format.
+    fn output_format<'a, I>(fastx_inputs: I) -> Result<FastxFormat, failure::Error>
+    where
+        I: Iterator<Item = &'a FastxInput>,
+    {
+        let mut first: Option<&FastxInput> = None;
+        for input in fastx_inputs {
+            match first {
+                None => first = Some(input),
+                Some(first_input) => {
+                    if input.format() != first_input.format() {
+                        return Err(format_err!(
+                            "Mixed input formats: {} is {:?} but {} is {:?}",
+                            first_input.name().to_string_lossy(),
+                            first_input.format(),
+                            input.name().to_string_lossy(),
+                            input.format()
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(first.map_or(FastxFormat::Fastq, FastxInput::format))
     }
 }
 
-pub fn split_file<P: AsRef<Path>>(
+pub fn split_file(
     config: &mut Config,
-    input_name: P,
-) -> Result<(usize, usize), failure::Error> {
-    let mut total = 0;
-    let mut tooshort = 0;
+    input: FastxInput,
+    index1: Option<IndexReadRecords>,
+    index2: Option<IndexReadRecords>,
+) -> Result<(), failure::Error> {
+    let input_name = input.name().to_path_buf();
+    match input.format() {
+        FastxFormat::Fastq => split_records(
+            config,
+            &input_name,
+            IndexedRecords::new(FastqRecords::new(input.into_reader()), index1, index2),
+        ),
+        FastxFormat::Fasta => split_records(
+            config,
+            &input_name,
+            IndexedRecords::new(FastaRecords::new(input.into_reader()), index1, index2),
+        ),
+    }
+}
 
-    let input_reader: Box<Read> = if input_name.as_ref() == Path::new("-") {
-        Box::new(io::stdin())
-    } else {
-        Box::new(fs::File::open(&input_name)?)
-    };
+// Read fates are determined in parallel by the worker threads, and
+// reads are then written in input order by the dispatcher. Reading
+// stops once the dispatcher is finished. An unparsed read has no
+// fate and is handled apart.
+fn split_records<R, I>(
+    config: &mut Config,
+    input_name: &Path,
+    records: I,
+) -> Result<(), failure::Error>
+where
+    R: FastxRecord + Send,
+    I: Iterator<Item = Result<(Parsed<R>, IndexReads), failure::Error>> + Send,
+{
+    let processor = config.dispatcher.demux().shared_processor();
+    let dispatcher = &mut config.dispatcher;
+    let progress = config.progress;
+    let mut file_total = 0;
 
-    for fqres in fastq::Reader::new(input_reader).records() {
-        let fq = fqres?;
+    run_pipeline(
+        config.threads,
+        records,
+        |&(ref fq, ref index_reads)| match fq {
+            Ok(fq) => processor.indexed_read_fate(fq, index_reads),
+            Err(_) => Ok(unparseable_fate()),
+        },
+        |(fq, _), analysis| {
+            let (fate, barcode) = analysis?;
+            match fq {
+                Ok(fq) => dispatcher.handle_read(&fq, fate, &barcode)?,
+                Err(unparsed) => dispatcher.handle_unparsed_read(&unparsed)?,
+            }
 
-        total += 1;
+            file_total += 1;
+            if progress.map_or(false, |nprog| file_total % nprog == 0) {
+                print!(
+                    "{:7} reads from {}\n",
+                    file_total,
+                    input_name.to_str().unwrap_or("???")
+                );
+            }
 
-        if fq.seq().len() < config.linker_spec.linker_length() + config.min_insert {
-            config.short_file.write_record(&fq)?;
-            tooshort += 1;
-        } else {
-            let split = config.linker_spec.split_record(&fq).ok_or_else(|| {
-                failure::err_msg(format!(
-                    "Split failed on \"{}\"",
-                    str::from_utf8(fq.seq()).unwrap_or("???")
-                ))
-            })?;
-            let mut sample = config.sample_map.get_mut(split.sample_index())?;
-            sample.handle_split_read(&fq, &split)?;
-        }
-
-        if config.progress.map_or(false, |nprog| total % nprog == 0) {
-            print!(
-                "{:7} reads from {}\n",
-                total,
-                input_name.as_ref().to_str().unwrap_or("???")
-            );
+            Ok(!dispatcher.is_finished())
+        },
+    )
+}
+
+pub fn split_file_pair(
+    config: &mut Config,
+    input1: FastxInput,
+    input2: FastxInput,
+    index1: Option<IndexReadRecords>,
+    index2: Option<IndexReadRecords>,
+) -> Result<(), failure::Error> {
+    let input_name = input1.name().to_path_buf();
+    match input1.format() {
+        FastxFormat::Fastq => split_record_pairs(
+            config,
+            &input_name,
+            IndexedRecords::new(
+                MatePairs::new(
+                    FastqRecords::new(input1.into_reader()),
+                    FastqRecords::new(input2.into_reader()),
+                ),
+                index1,
+                index2,
+            ),
+        ),
+        FastxFormat::Fasta => split_record_pairs(
+            config,
+            &input_name,
+            IndexedRecords::new(
+                MatePairs::new(
+                    FastaRecords::new(input1.into_reader()),
+                    FastaRecords::new(input2.into_reader()),
+                ),
+                index1,
+                index2,
+            ),
+        ),
+    }
+}
+
+// Read pairs are handled as for single-end reads. Both mates of
+// every pair have been parsed, because an unparsed mate ends the
+// input with an error.
+fn split_record_pairs<R, I>(
+    config: &mut Config,
+    input_name: &Path,
+    pairs: I,
+) -> Result<(), failure::Error>
+where
+    R: FastxRecord + Send,
+    I: Iterator<Item = Result<((R, R), IndexReads), failure::Error>> + Send,
+{
+    let processor = config.dispatcher.demux().shared_processor();
+    let dispatcher = &mut config.dispatcher;
+    let progress = config.progress;
+    let mut file_total = 0;
+
+    run_pipeline(
+        config.threads,
+        pairs,
+        |&((ref fq1, ref fq2), ref index_reads)| processor.indexed_pair_fate(fq1, fq2, index_reads),
+        |((fq1, fq2), _), analysis| {
+            let (fate, barcode) = analysis?;
+            dispatcher.handle_pair(&fq1, &fq2, fate, &barcode)?;
+
+            file_total += 1;
+            if progress.map_or(false, |nprog| file_total % nprog == 0) {
+                print!(
+                    "{:7} read pairs from {}\n",
+                    file_total,
+                    input_name.to_str().unwrap_or("???")
+                );
+            }
+
+            Ok(!dispatcher.is_finished())
+        },
+    )
+}
+
+// Fate of a read that could not be parsed, which is never used
+fn unparseable_fate() -> (ReadFate, Barcode) {
+    (
+        ReadFate::Malformed(MalformedReason::Unparseable),
+        Barcode::default(),
+    )
+}
+
+/// Writes the collapsed records for each sample, when identical
+/// reads are collapsed, along with `<name>_collapsed.txt` giving the
+/// multiplicity of each record.
+pub fn write_collapsed(config: &Config) -> Result<(), failure::Error> {
+    for sample_rc in config.dispatcher.sample_map().things() {
+        let mut sample = sample_rc.try_borrow_mut()?;
+        if let Some(table) = sample.write_collapsed()? {
+            let mut collapsed_path = config.output_dir.clone();
+            collapsed_path.push(format!("{}_collapsed.txt", sample.name()));
+            fs::write(&collapsed_path, table)?;
         }
     }
+    Ok(())
+}
 
-    Ok((total, tooshort))
+// Writes one line of `fates.txt` with the count of reads, or read
+// pairs, and their percentage of the total
+fn write_fate<W: Write>(
+    fates: &mut W,
+    name: &str,
+    index: &str,
+    count: usize,
+    total: usize,
+) -> io::Result<()> {
+    write!(
+        fates,
+        "{}\t{}\t{}\t{:.2}%\n",
+        name,
+        index,
+        count,
+        100.0 * (count as f64) / (total as f64)
+    )
 }
 
-pub fn write_stats(config: &Config, total: usize, tooshort: usize) -> Result<(), failure::Error> {
+pub fn write_stats(config: &Config) -> Result<(), failure::Error> {
+    let counts = config.dispatcher.counts();
+    let total = counts.total();
+
     let mut fates_path = config.output_dir.clone();
     fates_path.push("fates.txt");
     let mut fates = fs::File::create(&fates_path)?;
 
-    for sample_rc in config.sample_map.things() {
+    for sample_rc in config.dispatcher.sample_map().things() {
         let sample = sample_rc.try_borrow()?;
-        let mut stats_path = config.output_dir.clone();
-        stats_path.push(format!("{}_stats.txt", sample.name()));
-        fs::write(&stats_path, sample.stats_table())?;
-
-        let fract = 100.0 * (sample.total() as f64) / (total as f64);
-        write!(
-            fates,
-            "{}\t{}\t{}\t{:.2}%\n",
-            sample.name(),
-            str::from_utf8(sample.index())?,
-            sample.total(),
-            fract
+        if sample.umi_length() <= MAX_UMI_TABLE_LENGTH {
+            let mut stats_path = config.output_dir.clone();
+            stats_path.push(format!("{}_stats.txt", sample.name()));
+            fs::write(&stats_path, sample.stats_table())?;
+        }
+
+        let mut umi_stats_path = config.output_dir.clone();
+        umi_stats_path.push(format!("{}_umi_stats.txt", sample.name()));
+        fs::write(&umi_stats_path, sample.umi_stats().table())?;
+
+        let mut lengths_path = config.output_dir.clone();
+        lengths_path.push(format!("{}_lengths.txt", sample.name()));
+        fs::write(&lengths_path, sample.insert_length_table())?;
+
+        let index = str::from_utf8(sample.index())?;
+        write_fate(&mut fates, sample.name(), index, sample.total(), total)?;
+
+        if config.dispatcher.processor().contaminants().is_some() {
+            let name = format!("{}_contam", sample.name());
+            write_fate(&mut fates, &name, index, sample.contaminants(), total)?;
+        }
+    }
+
+    // Dual index rows break down the reads in UnknownIndex
+    let dual_stats = dual_index_stats(config)?;
+    if let Some(ref dual_stats) = dual_stats {
+        for (name, reads) in [
+            ("index1_failed", dual_stats.failed_index1()),
+            ("index2_failed", dual_stats.failed_index2()),
+            ("both_failed", dual_stats.failed_both()),
+            ("hopped", dual_stats.hopped()),
+        ]
+        .iter()
+        {
+            write_fate(&mut fates, name, "N/A", *reads, total)?;
+        }
+
+        let mut hopping_path = config.output_dir.clone();
+        hopping_path.push("index_hopping.txt");
+        fs::write(&hopping_path, dual_stats.matrix_table())?;
+    }
+
+    write_fate(&mut fates, "short", "N/A", counts.tooshort(), total)?;
+
+    if config.dispatcher.processor().adapter().is_some() {
+        write_fate(&mut fates, "noadapter", "N/A", counts.noadapter(), total)?;
+    }
+
+    if config.dispatcher.checks_constants() {
+        write_fate(&mut fates, "badlinker", "N/A", counts.badlinker(), total)?;
+    }
+
+    if config.dispatcher.demux().subsampler().is_some() {
+        write_fate(&mut fates, "subsampled", "N/A", counts.subsampled(), total)?;
+    }
+
+    if config.dispatcher.caps_samples() {
+        write_fate(
+            &mut fates,
+            "capped",
+            "N/A",
+            config.dispatcher.capped(),
+            total,
         )?;
     }
 
-    write!(
-        fates,
-        "short\tN/A\t{}\t{:.2}%\n",
-        tooshort,
-        100.0 * (tooshort as f64) / (total as f64)
-    )?;
+    // Malformed records abort the run unless they are skipped or rejected
+    if config.dispatcher.malformed_policy() != MalformedPolicy::Abort {
+        for reason in MalformedReason::all() {
+            let name = reason.to_string();
+            write_fate(&mut fates, &name, "N/A", counts.malformed(*reason), total)?;
+        }
+    }
+
+    let sample_rcs = config.dispatcher.sample_map().things();
+    let samples = sample_rcs
+        .iter()
+        .map(|sample_rc| sample_rc.try_borrow())
+        .collect::<Result<Vec<_>, _>>()?;
+    let sample_refs: Vec<&Sample> = samples.iter().map(|sample| &**sample).collect();
+    let mut matrix_path = config.output_dir.clone();
+    matrix_path.push("insert_length_matrix.txt");
+    fs::write(&matrix_path, insert_length_matrix(&sample_refs))?;
+
+    if config.dispatcher.processor().trimmer().is_active() {
+        let mut trim_path = config.output_dir.clone();
+        trim_path.push("trimming.txt");
+        fs::write(&trim_path, trim_table(&sample_refs))?;
+    }
+
+    Ok(())
+}
+
+/// Writes the report of the most common unassigned index sequences
+/// to `unknown_indices.txt`, and the run summary as JSON to
+/// `summary.json`. Likely sample sheet mistakes are also reported on
+/// standard error.
+pub fn write_summary(config: &Config) -> Result<(), failure::Error> {
+    let unknowns = unknown_indices(config)?;
+    let total = config.dispatcher.counts().total();
+
+    for unknown in unknowns.iter() {
+        for mistake in unknown.mistakes() {
+            eprintln!(
+                "Unassigned index {} ({} reads) matches sample {} after {} correction",
+                str::from_utf8(unknown.index())?,
+                unknown.reads(),
+                mistake.sample_name(),
+                mistake.kind()
+            );
+        }
+    }
+
+    let mut unknown_path = config.output_dir.clone();
+    unknown_path.push("unknown_indices.txt");
+    fs::write(&unknown_path, unknown_index_table(&unknowns, total))?;
 
+    let mut summary_path = config.output_dir.clone();
+    summary_path.push("summary.json");
+    let mut summary = serde_json::to_string_pretty(&run_summary(config, &unknowns)?)?;
+    summary.push('\n');
+    fs::write(&summary_path, summary)?;
     Ok(())
 }
 
 pub fn fastx_split(mut config: Config) -> Result<(), failure::Error> {
-    let mut total = 0;
-    let mut tooshort = 0;
+    let fastx_inputs = mem::replace(&mut config.fastx_inputs, Vec::new());
+    let mate2_inputs = mem::replace(&mut config.mate2_inputs, Vec::new());
+    let mut index1_inputs = mem::replace(&mut config.index1_inputs, Vec::new()).into_iter();
+    let mut index2_inputs = mem::replace(&mut config.index2_inputs, Vec::new()).into_iter();
+
+    if mate2_inputs.is_empty() {
+        for input in fastx_inputs {
+            if config.dispatcher.is_finished() {
+                break;
+            }
+            let index1 = index1_inputs.next().map(index_read_records);
+            let index2 = index2_inputs.next().map(index_read_records);
+            split_file(&mut config, input, index1, index2)?;
+        }
+    } else {
+        for (input1, input2) in fastx_inputs.into_iter().zip(mate2_inputs.into_iter()) {
+            if config.dispatcher.is_finished() {
+                break;
+            }
+            let index1 = index1_inputs.next().map(index_read_records);
+            let index2 = index2_inputs.next().map(index_read_records);
+            split_file_pair(&mut config, input1, input2, index1, index2)?;
+        }
+    }
 
-    for input_name in config.fastx_inputs.to_vec() {
-        let (file_total, file_tooshort) = split_file(&mut config, input_name)?;
-        total += file_total;
-        tooshort += file_tooshort;
+    if config.dispatcher.is_finished() {
+        eprintln!("All samples reached their maximum read count, stopped early");
     }
 
-    write_stats(&config, total, tooshort)?;
+    write_collapsed(&config)?;
+    config.dispatcher.finish_outputs()?;
+    write_stats(&config)?;
+    write_summary(&config)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io;
+    use std::process;
+
+    // Creates a configuration that splits reads into one sample,
+    // with an "N" linker on mate 2 of paired-end reads
+    fn split_config(output_dir: &Path, policy: MalformedPolicy, paired: bool) -> Config {
+        fs::create_dir_all(output_dir).unwrap();
+        let output_files = OutputFiles::new(
+            output_dir,
+            FastxFormat::Fastq,
+            OutputCompression::Plain,
+            false,
+        );
+
+        let new_sample = |name: &str, index: &[u8]| {
+            if paired {
+                Sample::new_paired(
+                    name.to_string(),
+                    index.to_vec(),
+                    FastxFormat::Fastq,
+                    UmiEncoding::Hash,
+                    io::sink(),
+                    io::sink(),
+                )
+            } else {
+                Sample::new(
+                    name.to_string(),
+                    index.to_vec(),
+                    FastxFormat::Fastq,
+                    UmiEncoding::Hash,
+                    io::sink(),
+                )
+            }
+        };
+        let mut sample_map = SampleMap::new(2, new_sample("UnknownIndex", b"NN"));
+        sample_map
+            .insert(b"AC".to_vec(), 0, new_sample("one", b"AC"))
+            .unwrap();
+
+        let linker_spec = LinkerSpec::new("NNII", "").unwrap();
+        let paired_spec = if paired {
+            Some(PairedLinkerSpec::new(
+                linker_spec.clone(),
+                LinkerSpec::new("N", "").unwrap(),
+            ))
+        } else {
+            None
+        };
+        let processor = ReadProcessor::new(linker_spec, paired_spec, None, 3);
+        let rejected_file = if policy == MalformedPolicy::Reject {
+            Some(FateOutput::create(&output_files, "rejected", paired).unwrap())
+        } else {
+            None
+        };
+        let dispatcher = Dispatcher::new(
+            Demultiplexer::with_processor(processor, sample_map),
+            FateOutput::create(&output_files, "tooshort", paired).unwrap(),
+            None,
+            None,
+            policy,
+            rejected_file,
+        );
+
+        Config {
+            fastx_inputs: Vec::new(),
+            mate2_inputs: Vec::new(),
+            index1_inputs: Vec::new(),
+            index2_inputs: Vec::new(),
+            input_names: Vec::new(),
+            mate2_names: Vec::new(),
+            index1_names: Vec::new(),
+            index2_names: Vec::new(),
+            contaminants_name: None,
+            output_dir: output_dir.to_path_buf(),
+            unaligned_bam: false,
+            max_reads: None,
+            dispatcher: dispatcher,
+            threads: 1,
+            progress: None,
+        }
+    }
+
+    // Splits single-end reads from a FastQ input into one sample,
+    // returning the counts and any rejected records
+    fn split_fastq(
+        name: &str,
+        input: &[u8],
+        policy: MalformedPolicy,
+    ) -> Result<(SplitCounts, Option<Vec<u8>>), failure::Error> {
+        let mut output_dir = env::temp_dir();
+        output_dir.push(format!("fastx_split_{}_{}", name, process::id()));
+        let mut config = split_config(&output_dir, policy, false);
+
+        let input_name = output_dir.join("reads.fastq");
+        fs::write(&input_name, input).unwrap();
+        let input = FastxInput::open(&input_name).unwrap();
+        let res = split_file(&mut config, input, None, None)
+            .and_then(|()| config.dispatcher.finish_outputs());
+        let rejected = fs::read(output_dir.join("rejected.fastq")).ok();
+        fs::remove_dir_all(&output_dir).unwrap();
+
+        res.map(|()| (config.dispatcher.counts().clone(), rejected))
+    }
+
+    // Splits read pairs from mate 1 and mate 2 FastQ inputs into one
+    // sample, returning the counts
+    fn split_fastq_pair(
+        name: &str,
+        input1: &[u8],
+        input2: &[u8],
+        policy: MalformedPolicy,
+    ) -> Result<SplitCounts, failure::Error> {
+        let mut output_dir = env::temp_dir();
+        output_dir.push(format!("fastx_split_{}_{}", name, process::id()));
+        let mut config = split_config(&output_dir, policy, true);
+
+        let input1_name = output_dir.join("reads_R1.fastq");
+        let input2_name = output_dir.join("reads_R2.fastq");
+        fs::write(&input1_name, input1).unwrap();
+        fs::write(&input2_name, input2).unwrap();
+        let input1 = FastxInput::open(&input1_name).unwrap();
+        let input2 = FastxInput::open(&input2_name).unwrap();
+        let res = split_file_pair(&mut config, input1, input2, None, None)
+            .and_then(|()| config.dispatcher.finish_outputs());
+        fs::remove_dir_all(&output_dir).unwrap();
+
+        res.map(|()| config.dispatcher.counts().clone())
+    }
+
+    #[test]
+    fn unparseable_records() {
+        // The second record is cut short, and the records after it are
+        // still split
+        let corrupt: &[u8] = b"@r1\nACGGGATTACA\n+\nIIIIIIIIIII\n\
+                               @r2\nACGG\n\
+                               @r3\nACGGGATTACA\n+\nIIIIIIIIIII\n\
+                               @r4\nACTTGATTACA\n+\nIIIIIIIIIII\n";
+
+        let (counts, rejected) =
+            split_fastq("unparseable_skip", corrupt, MalformedPolicy::Skip).unwrap();
+        assert!(counts.total() == 4);
+        assert!(counts.malformed(MalformedReason::Unparseable) == 1);
+        assert!(counts.tooshort() == 0);
+        assert!(rejected.is_none());
+
+        let (counts, rejected) =
+            split_fastq("unparseable_reject", corrupt, MalformedPolicy::Reject).unwrap();
+        assert!(counts.total() == 4);
+        assert!(counts.malformed(MalformedReason::Unparseable) == 1);
+        assert!(rejected == Some(b"@r2\nACGG\n".to_vec()));
+
+        match split_fastq("unparseable_abort", corrupt, MalformedPolicy::Abort) {
+            Err(err) => {
+                assert!(err.to_string() == "Unparseable record at line 5: truncated record")
+            }
+            Ok(_) => panic!("Expected unparseable record to abort"),
+        }
+    }
+
+    #[test]
+    fn unparseable_mate() {
+        // Mate 1 of r2 has no separator and its quality line starts
+        // with '@', so mate 1 has one more unparsed record than mate 2
+        // and the run stops rather than pairing later mates wrongly
+        let mate1: &[u8] = b"@r1\nACGGGATTACA\n+\nIIIIIIIIIII\n\
+                             @r2\nACGT\n@III\n\
+                             @r3\nACGGGATTACA\n+\nIIIIIIIIIII\n";
+        let mate2: &[u8] = b"@r1\nTGGATTACA\n+\nIIIIIIIII\n\
+                             @r2\nTGGATTACA\n+\nIIIIIIIII\n\
+                             @r3\nTGGATTACA\n+\nIIIIIIIII\n";
+
+        for &policy in [MalformedPolicy::Skip, MalformedPolicy::Reject].iter() {
+            let name = format!("unparseable_mate_{}", policy);
+            match split_fastq_pair(&name, mate1, mate2, policy) {
+                Err(err) => assert!(
+                    err.to_string() == "Unparseable mate 1 record at line 5: truncated record"
+                ),
+                Ok(_) => panic!("Expected unparseable mate to stop the run"),
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use failure;
+
+use fastx_split::compress::*;
+
+/// Number of reads handed to a worker thread at once
+const CHUNK_SIZE: usize = 4096;
+
+/// Runs a two-stage pipeline over a sequence of items. The `analyze`
+/// function is applied to every item, in parallel worker threads
+/// when `threads` is more than 1. The `apply` function is then
+/// called on the calling thread for each item along with its
+/// analysis, in the original item order. Results are therefore
+/// identical to running both functions serially. The pipeline stops
+/// early, without error, when `apply` returns `false`.
+///
+/// Items are read on a dedicated reader thread and passed to the
+/// workers in chunks.
+///
+/// # Arguments
+///
+/// * `threads` is the number of worker threads
+///
+/// * `items` yields the items, or an error that stops the pipeline
+///
+/// * `analyze` is the per-item analysis, run in worker threads
+///
+/// * `apply` handles each item and its analysis in order, and
+///   returns whether further items are wanted
+///
+/// # Errors
+///
+/// An error variant is returned for the first error in `items` or in
+/// `apply`, after which no further items are handled.
+pub fn run_pipeline<T, U, I, F, A>(
+    threads: usize,
+    items: I,
+    analyze: F,
+    mut apply: A,
+) -> Result<(), failure::Error>
+where
+    T: Send,
+    U: Send,
+    I: Iterator<Item = Result<T, failure::Error>> + Send,
+    F: Fn(&T) -> U + Sync,
+    A: FnMut(T, U) -> Result<bool, failure::Error>,
+{
+    if threads <= 1 {
+        for item_res in items {
+            let item = item_res?;
+            let analysis = analyze(&item);
+            if !apply(item, analysis)? {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let (chunk_tx, chunk_rx) =
+        mpsc::sync_channel::<(usize, Result<Vec<T>, failure::Error>)>(2 * threads);
+    let (result_tx, result_rx) =
+        mpsc::sync_channel::<(usize, Result<Vec<(T, U)>, failure::Error>)>(2 * threads);
+
+    // Workers share the chunk receiver, which is dropped once every
+    // worker exits. This unblocks the reader if the pipeline stops early.
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let analyze = &analyze;
+
+    thread::scope(move |scope| -> Result<(), failure::Error> {
+        scope.spawn(move || read_chunks(items, chunk_tx));
+
+        for _ in 0..threads {
+            let chunk_rx = chunk_rx.clone();
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let next = chunk_rx.lock().map(|rx| rx.recv());
+                let (serial, chunk) = match next {
+                    Ok(Ok(serial_chunk)) => serial_chunk,
+                    _ => break,
+                };
+                let result = chunk.map(|chunk_items| {
+                    chunk_items
+                        .into_iter()
+                        .map(|item| {
+                            let analysis = analyze(&item);
+                            (item, analysis)
+                        })
+                        .collect()
+                });
+                if result_tx.send((serial, result)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        // Only the workers hold result senders now, so the results
+        // end when the last worker finishes.
+        mem::drop(result_tx);
+        mem::drop(chunk_rx);
+
+        // Chunks can finish out of order and wait here until all
+        // earlier chunks have been handled. Returning early drops
+        // the result receiver, which stops the workers and the reader.
+        let mut pending = BTreeMap::new();
+        let mut next_serial = 0;
+        for (serial, result) in result_rx.iter() {
+            pending.insert(serial, result);
+            while let Some(result) = pending.remove(&next_serial) {
+                for (item, analysis) in result? {
+                    if !apply(item, analysis)? {
+                        return Ok(());
+                    }
+                }
+                next_serial += 1;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+fn read_chunks<T, I>(
+    mut items: I,
+    chunk_tx: mpsc::SyncSender<(usize, Result<Vec<T>, failure::Error>)>,
+) where
+    I: Iterator<Item = Result<T, failure::Error>>,
+{
+    let mut serial = 0;
+
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+
+        while chunk.len() < CHUNK_SIZE {
+            match items.next() {
+                Some(Ok(item)) => chunk.push(item),
+                Some(Err(e)) => {
+                    let _ = chunk_tx.send((serial, Ok(chunk)));
+                    let _ = chunk_tx.send((serial + 1, Err(e)));
+                    return;
+                }
+                None => break,
+            }
+        }
+
+        let last = chunk.len() < CHUNK_SIZE;
+        if chunk_tx.send((serial, Ok(chunk))).is_err() || last {
+            return;
+        }
+        serial += 1;
+    }
+}
+
+/// Size of the data blocks passed to the writer thread
+const WRITER_BLOCK_SIZE: usize = 1 << 16;
+
+/// Number of data blocks that can wait for the writer thread
+const WRITER_QUEUE: usize = 16;
+
+/// Output writer that passes data to a dedicated thread, which
+/// performs the underlying write along with any compression. Data is
+/// written in the same order it is received. An error in the writer
+/// thread is reported by the next write, flush or finish. Dropping
+/// the `ThreadedWriter` finishes it as well, but discards any error.
+pub struct ThreadedWriter {
+    block: Vec<u8>,
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,
+    handle: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl ThreadedWriter {
+    /// Starts a writer thread for an underlying output writer, which
+    /// is finished by the thread once all data is written.
+    pub fn new(mut inner: Box<FinishWrite + Send>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(WRITER_QUEUE);
+        let handle = thread::spawn(move || {
+            for block in receiver.iter() {
+                inner.write_all(&block)?;
+            }
+            inner.finish()
+        });
+
+        ThreadedWriter {
+            block: Vec::with_capacity(WRITER_BLOCK_SIZE),
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let block = mem::replace(&mut self.block, Vec::with_capacity(WRITER_BLOCK_SIZE));
+        let sent = self.sender.as_ref().map(|sender| sender.send(block));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            _ => Err(self.thread_error()),
+        }
+    }
+
+    // The writer thread only stops early after a write error, which
+    // is recovered by joining it.
+    fn thread_error(&mut self) -> io::Error {
+        self.sender = None;
+        match self.handle.take().map(|handle| handle.join()) {
+            Some(Ok(Err(e))) => e,
+            Some(Err(_)) => Self::panic_error(),
+            _ => io::Error::new(io::ErrorKind::BrokenPipe, "Output writer thread stopped"),
+        }
+    }
+
+    fn panic_error() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "Output writer thread panicked")
+    }
+}
+
+impl Write for ThreadedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.block.extend_from_slice(buf);
+        if self.block.len() >= WRITER_BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()
+    }
+}
+
+impl FinishWrite for ThreadedWriter {
+    /// Sends all remaining data to the writer thread and waits for
+    /// it to finish the underlying output.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned for any error in the writer thread.
+    fn finish(&mut self) -> io::Result<()> {
+        self.send_block()?;
+        self.sender = None;
+        match self.handle.take() {
+            Some(handle) => handle.join().unwrap_or_else(|_| Err(Self::panic_error())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ThreadedWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(n: usize) -> Vec<Result<usize, failure::Error>> {
+        (0..n).map(Ok).collect()
+    }
+
+    #[test]
+    fn pipeline_order() {
+        for threads in 1..5 {
+            let mut seen = Vec::new();
+            run_pipeline(
+                threads,
+                numbers(3 * CHUNK_SIZE + 17).into_iter(),
+                |x| x * x,
+                |x, sq| {
+                    seen.push((x, sq));
+                    Ok(true)
+                },
+            )
+            .unwrap();
+
+            assert!(seen.len() == 3 * CHUNK_SIZE + 17);
+            assert!(seen
+                .iter()
+                .enumerate()
+                .all(|(i, &(x, sq))| x == i && sq == i * i));
+        }
+    }
+
+    #[test]
+    fn pipeline_errors() {
+        for threads in 1..5 {
+            let mut items = numbers(2 * CHUNK_SIZE);
+            items.push(Err(failure::err_msg("bad item")));
+            items.extend(numbers(10));
+            let mut seen = 0;
+            let res = run_pipeline(
+                threads,
+                items.into_iter(),
+                |x| *x,
+                |_, _| {
+                    seen += 1;
+                    Ok(true)
+                },
+            );
+            assert!(res.is_err());
+            assert!(seen == 2 * CHUNK_SIZE);
+
+            let res = run_pipeline(
+                threads,
+                numbers(5 * CHUNK_SIZE).into_iter(),
+                |x| *x,
+                |x, _| {
+                    if x == 10 {
+                        Err(failure::err_msg("bad apply"))
+                    } else {
+                        Ok(true)
+                    }
+                },
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn pipeline_stop() {
+        for threads in 1..5 {
+            let mut seen = Vec::new();
+            run_pipeline(
+                threads,
+                numbers(5 * CHUNK_SIZE).into_iter(),
+                |x| *x,
+                |x, _| {
+                    seen.push(x);
+                    Ok(x + 1 < CHUNK_SIZE + 10)
+                },
+            )
+            .unwrap();
+            assert!(seen.len() == CHUNK_SIZE + 10);
+            assert!(seen.iter().enumerate().all(|(i, &x)| x == i));
+        }
+    }
+
+    #[test]
+    fn threaded_writer() {
+        let shared = SharedBuffer::new();
+        let mut expected = Vec::new();
+
+        {
+            let mut writer = ThreadedWriter::new(Box::new(shared.clone()));
+            for i in 0..50000 {
+                let line = format!("line {}\n", i);
+                writer.write_all(line.as_bytes()).unwrap();
+                expected.extend_from_slice(line.as_bytes());
+            }
+            writer.finish().unwrap();
+        }
+
+        assert!(shared.contents() == expected);
+    }
+}
@@ -0,0 +1,700 @@
+use std::fmt;
+use std::str;
+
+use failure;
+
+use fastx_split::adapter::*;
+use fastx_split::contaminant::*;
+use fastx_split::fastx::*;
+use fastx_split::index_reads::*;
+use fastx_split::linkers::*;
+use fastx_split::trim::*;
+
+/// Reason that a record, or a read pair, is malformed
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub enum MalformedReason {
+    /// The sequence contains characters other than letters
+    BadSequence,
+    /// The quality string and the sequence differ in length
+    BadQuality,
+    /// The mate 1 and mate 2 records, or a read and its index read,
+    /// have different names
+    MateMismatch,
+    /// The index read or read description index is missing or too
+    /// short
+    BadIndex,
+    /// The record could not be split into linker and insert
+    SplitFailed,
+    /// The record could not be parsed
+    Unparseable,
+}
+
+impl MalformedReason {
+    /// Returns all reasons, in the order they are reported
+    pub fn all() -> &'static [MalformedReason] {
+        &[
+            MalformedReason::BadSequence,
+            MalformedReason::BadQuality,
+            MalformedReason::MateMismatch,
+            MalformedReason::BadIndex,
+            MalformedReason::SplitFailed,
+            MalformedReason::Unparseable,
+        ]
+    }
+}
+
+impl fmt::Display for MalformedReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MalformedReason::BadSequence => "badsequence",
+            MalformedReason::BadQuality => "badquality",
+            MalformedReason::MateMismatch => "matemismatch",
+            MalformedReason::BadIndex => "badindex",
+            MalformedReason::SplitFailed => "splitfailed",
+            MalformedReason::Unparseable => "unparseable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Fate of a read, or a read pair, determined from the read sequence
+/// alone and without reference to the samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReadFate {
+    /// The record is malformed
+    Malformed(MalformedReason),
+    /// No 3' adapter was found
+    NoAdapter,
+    /// The insert, after any 3' trimming, is shorter than the minimum
+    /// insert length, using the first `end` bases of the read (or of
+    /// mate 1 for read pairs)
+    TooShort(usize),
+    /// The read can be split, using the first `end` bases of the
+    /// read (or of mate 1 for read pairs)
+    Split(usize),
+    /// The read can be split, as for `Split`, but its insert (or the
+    /// mate 1 insert for read pairs) matches the contaminant sequences
+    Contaminant(usize),
+}
+
+/// Read processing parameters that do not depend on the samples. A
+/// `ReadProcessor` holds no mutable state and so it can be shared
+/// between worker threads.
+#[derive(Debug, Clone)]
+pub struct ReadProcessor {
+    linker_spec: LinkerSpec,
+    paired_spec: Option<PairedLinkerSpec>,
+    adapter: Option<Adapter>,
+    min_insert: usize,
+    max_constant_mismatch: usize,
+    external_index: Option<ExternalIndex>,
+    contaminants: Option<ContaminantIndex>,
+    trimmer: InsertTrimmer,
+}
+
+impl ReadProcessor {
+    /// Creates a new read processor
+    ///
+    /// # Arguments
+    ///
+    /// * `linker_spec` is the linker specification for single-end reads
+    ///
+    /// * `paired_spec` is the linker specification for read pairs, if
+    /// the reads are paired-end
+    ///
+    /// * `adapter` is the 3' adapter, if reads should be trimmed at the adapter
+    ///
+    /// * `min_insert` is the minimum insert length after linker removal
+    pub fn new(
+        linker_spec: LinkerSpec,
+        paired_spec: Option<PairedLinkerSpec>,
+        adapter: Option<Adapter>,
+        min_insert: usize,
+    ) -> Self {
+        ReadProcessor {
+            linker_spec: linker_spec,
+            paired_spec: paired_spec,
+            adapter: adapter,
+            min_insert: min_insert,
+            max_constant_mismatch: 0,
+            external_index: None,
+            contaminants: None,
+            trimmer: InsertTrimmer::new(),
+        }
+    }
+
+    /// Returns the maximum number of mismatches allowed in the
+    /// constant linker bases
+    pub fn max_constant_mismatch(&self) -> usize {
+        self.max_constant_mismatch
+    }
+
+    /// Sets the maximum number of mismatches allowed in the constant
+    /// linker bases, over both mates for read pairs
+    pub fn set_max_constant_mismatch(&mut self, max_constant_mismatch: usize) {
+        self.max_constant_mismatch = max_constant_mismatch;
+    }
+
+    /// Returns the specification for separately sequenced indices, if any
+    pub fn external_index(&self) -> Option<&ExternalIndex> {
+        self.external_index.as_ref()
+    }
+
+    /// Sets the specification for separately sequenced indices, whose
+    /// bases are placed before the sample index and UMI bases from
+    /// the linker
+    pub fn set_external_index(&mut self, external_index: Option<ExternalIndex>) {
+        self.external_index = external_index;
+    }
+
+    /// Returns the contaminant k-mer index, if inserts are filtered
+    pub fn contaminants(&self) -> Option<&ContaminantIndex> {
+        self.contaminants.as_ref()
+    }
+
+    /// Sets the contaminant k-mer index. Reads that can be split and
+    /// whose insert matches the index have the fate
+    /// `ReadFate::Contaminant` rather than `ReadFate::Split`.
+    pub fn set_contaminants(&mut self, contaminants: Option<ContaminantIndex>) {
+        self.contaminants = contaminants;
+    }
+
+    /// True when an insert matches the contaminant sequences, and
+    /// false when inserts are not filtered.
+    pub fn is_contaminant(&self, insert: &[u8]) -> bool {
+        self.contaminants
+            .as_ref()
+            .map_or(false, |contaminants| contaminants.matches(insert))
+    }
+
+    /// Returns the trimming of the 3' end of inserts
+    pub fn trimmer(&self) -> &InsertTrimmer {
+        &self.trimmer
+    }
+
+    /// Sets the trimming of the 3' end of inserts, which is applied
+    /// after linker removal and before the minimum insert length is
+    /// checked. Both mates of read pairs are quality trimmed, but only
+    /// mate 1 has poly(A) and poly(N) tails stripped.
+    pub fn set_trimmer(&mut self, trimmer: InsertTrimmer) {
+        self.trimmer = trimmer;
+    }
+
+    /// Returns the linker specification for single-end reads
+    pub fn linker_spec(&self) -> &LinkerSpec {
+        &self.linker_spec
+    }
+
+    /// Returns the linker specification for read pairs, if the reads
+    /// are paired-end
+    pub fn paired_spec(&self) -> Option<&PairedLinkerSpec> {
+        self.paired_spec.as_ref()
+    }
+
+    /// Returns the minimum insert length
+    pub fn min_insert(&self) -> usize {
+        self.min_insert
+    }
+
+    /// Returns the 3' adapter, if any
+    pub fn adapter(&self) -> Option<&Adapter> {
+        self.adapter.as_ref()
+    }
+
+    /// Returns the length of the sample index, including any bases
+    /// from separately sequenced indices
+    pub fn sample_index_length(&self) -> usize {
+        let linker_length = self
+            .paired_spec
+            .as_ref()
+            .map_or(self.linker_spec.sample_index_length(), |spec| {
+                spec.sample_index_length()
+            });
+        linker_length
+            + self
+                .external_index
+                .as_ref()
+                .map_or(0, ExternalIndex::sample_index_length)
+    }
+
+    /// Returns the lengths of the separately sequenced parts of the
+    /// sample index: the first and second index reads, if any, and
+    /// then mate 1 and mate 2 of read pairs or the prefix and suffix
+    /// of single-end reads
+    pub fn index_segments(&self) -> Vec<usize> {
+        let mut segments = self
+            .external_index
+            .as_ref()
+            .map_or(Vec::new(), ExternalIndex::index_segments);
+        match self.paired_spec {
+            Some(ref spec) => {
+                segments.push(spec.mate1().sample_index_length());
+                segments.push(spec.mate2().sample_index_length());
+            }
+            None => segments.extend(self.linker_spec.index_segments()),
+        }
+        segments
+    }
+
+    /// Extracts the sample index and UMI bases from separately
+    /// sequenced indices, returning an empty barcode when there are
+    /// none.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq` is the read, or mate 1 of a read pair
+    ///
+    /// * `index_reads` are the index reads accompanying the read
+    ///
+    /// # Errors
+    ///
+    /// The reason that the read is malformed is returned when the
+    /// index sequences cannot be found.
+    pub fn barcode<R: FastxRecord>(
+        &self,
+        fq: &R,
+        index_reads: &IndexReads,
+    ) -> Result<Barcode, MalformedReason> {
+        match self.external_index {
+            Some(ref external_index) => external_index.barcode(fq, index_reads),
+            None => Ok(Barcode::default()),
+        }
+    }
+
+    /// Determines the fate of a single-end read along with the
+    /// barcode from its separately sequenced indices
+    ///
+    /// # Errors
+    ///
+    /// No error is returned for single-end reads. The `Result` matches
+    /// `indexed_pair_fate`, so that reads and read pairs are handled
+    /// alike.
+    pub fn indexed_read_fate<R: FastxRecord>(
+        &self,
+        fq: &R,
+        index_reads: &IndexReads,
+    ) -> Result<(ReadFate, Barcode), failure::Error> {
+        Ok(match self.barcode(fq, index_reads) {
+            Ok(barcode) => (self.read_fate(fq), barcode),
+            Err(reason) => (ReadFate::Malformed(reason), Barcode::default()),
+        })
+    }
+
+    /// Determines the fate of a read pair along with the barcode from
+    /// its separately sequenced indices
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the processor has no paired
+    /// linker specification.
+    pub fn indexed_pair_fate<R: FastxRecord>(
+        &self,
+        fq1: &R,
+        fq2: &R,
+        index_reads: &IndexReads,
+    ) -> Result<(ReadFate, Barcode), failure::Error> {
+        Ok(match self.barcode(fq1, index_reads) {
+            Ok(barcode) => (self.pair_fate(fq1, fq2)?, barcode),
+            Err(reason) => (ReadFate::Malformed(reason), Barcode::default()),
+        })
+    }
+
+    /// Determines the fate of a single-end read
+    pub fn read_fate<R: FastxRecord>(&self, fq: &R) -> ReadFate {
+        if let Some(reason) = Self::check_record(fq) {
+            return ReadFate::Malformed(reason);
+        }
+
+        match self.insert_end(fq) {
+            None => ReadFate::NoAdapter,
+            Some(end) if end < self.linker_spec.linker_length() + self.min_insert => {
+                ReadFate::TooShort(end)
+            }
+            Some(end) if !self.splits_for_fate() => ReadFate::Split(end),
+            Some(end) => match self.linker_spec.split_record_at(fq, end) {
+                Some(mut split) => {
+                    self.trim_read(&mut split);
+                    self.split_fate(end, &[split.sequence()])
+                }
+                None => ReadFate::Split(end),
+            },
+        }
+    }
+
+    /// Determines the fate of a read pair. The 3' adapter is located
+    /// only in mate 1. Both mates must have the same name, apart from
+    /// any `/1` or `/2` suffix.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the processor has no paired
+    /// linker specification.
+    pub fn pair_fate<R: FastxRecord>(&self, fq1: &R, fq2: &R) -> Result<ReadFate, failure::Error> {
+        let paired_spec = self.paired_spec_or_err()?;
+        let min_length1 = paired_spec.mate1().linker_length() + self.min_insert;
+        let min_length2 = paired_spec.mate2().linker_length() + self.min_insert;
+
+        if let Some(reason) = Self::check_record(fq1).or_else(|| Self::check_record(fq2)) {
+            return Ok(ReadFate::Malformed(reason));
+        }
+
+        if mate_base_id(fq1.id()) != mate_base_id(fq2.id()) {
+            return Ok(ReadFate::Malformed(MalformedReason::MateMismatch));
+        }
+
+        Ok(match self.insert_end(fq1) {
+            None => ReadFate::NoAdapter,
+            Some(end1) if end1 < min_length1 || fq2.seq().len() < min_length2 => {
+                ReadFate::TooShort(end1)
+            }
+            Some(end1) if !self.splits_for_fate() => ReadFate::Split(end1),
+            Some(end1) => match paired_spec.split_pair_at(fq1, end1, fq2) {
+                Some(mut split) => {
+                    self.trim_pair(&mut split);
+                    self.split_fate(end1, &[split.mate1().sequence(), split.mate2().sequence()])
+                }
+                None => ReadFate::Split(end1),
+            },
+        })
+    }
+
+    // Reads must be split using the default linker to find their fate
+    // only when inserts are trimmed or checked against contaminants
+    fn splits_for_fate(&self) -> bool {
+        self.trimmer.is_active() || self.contaminants.is_some()
+    }
+
+    // Split fate for a read that is long enough to split before
+    // trimming, given the insert of the read, or of each mate, from
+    // the default linker after trimming. Only the first insert is
+    // checked against any contaminants.
+    fn split_fate(&self, end: usize, inserts: &[&[u8]]) -> ReadFate {
+        if inserts.iter().any(|insert| insert.len() < self.min_insert) {
+            ReadFate::TooShort(end)
+        } else if self.is_contaminant(inserts[0]) {
+            ReadFate::Contaminant(end)
+        } else {
+            ReadFate::Split(end)
+        }
+    }
+
+    /// Trims the 3' end of the insert of a single-end read
+    pub fn trim_read(&self, split: &mut LinkerSplit) {
+        if self.trimmer.is_active() {
+            let insert_trim = self.trimmer.trim(split.sequence(), split.quality(), true);
+            split.trim_insert(insert_trim);
+        }
+    }
+
+    /// Trims the 3' ends of the inserts of a read pair, stripping
+    /// tails only from mate 1
+    pub fn trim_pair(&self, split: &mut PairedSplit) {
+        if self.trimmer.is_active() {
+            let insert_trim1 =
+                self.trimmer
+                    .trim(split.mate1().sequence(), split.mate1().quality(), true);
+            let insert_trim2 =
+                self.trimmer
+                    .trim(split.mate2().sequence(), split.mate2().quality(), false);
+            split.trim_inserts(insert_trim1, insert_trim2);
+        }
+    }
+
+    /// Splits a single-end read whose fate is `ReadFate::Split(end)`,
+    /// placing the barcode from any separately sequenced indices
+    /// before the linker bases.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the read cannot be split.
+    pub fn split_read<'a, R: FastxRecord>(
+        &self,
+        fq: &'a R,
+        end: usize,
+        barcode: &Barcode,
+    ) -> Result<LinkerSplit<'a>, failure::Error> {
+        self.try_split_read(fq, end, barcode).ok_or_else(|| {
+            failure::err_msg(format!(
+                "Split failed on \"{}\"",
+                str::from_utf8(fq.seq()).unwrap_or("???")
+            ))
+        })
+    }
+
+    /// Splits a single-end read using its first `end` bases, as for
+    /// `split_read`, returning `None` when the read is too short to
+    /// split. This is used to find the sample for reads with a short
+    /// insert. The insert is trimmed at its 3' end.
+    pub fn try_split_read<'a, R: FastxRecord>(
+        &self,
+        fq: &'a R,
+        end: usize,
+        barcode: &Barcode,
+    ) -> Option<LinkerSplit<'a>> {
+        let mut split = self.linker_spec.split_record_at(fq, end)?;
+        split.prepend_barcode(barcode);
+        self.trim_read(&mut split);
+        Some(split)
+    }
+
+    /// Splits a read pair whose fate is `ReadFate::Split(end1)`, as
+    /// for single-end reads.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the pair cannot be split.
+    pub fn split_pair<'a, R: FastxRecord>(
+        &self,
+        fq1: &'a R,
+        end1: usize,
+        fq2: &'a R,
+        barcode: &Barcode,
+    ) -> Result<PairedSplit<'a>, failure::Error> {
+        self.paired_spec_or_err()?;
+        self.try_split_pair(fq1, end1, fq2, barcode).ok_or_else(|| {
+            failure::err_msg(format!(
+                "Split failed on \"{}\" / \"{}\"",
+                str::from_utf8(fq1.seq()).unwrap_or("???"),
+                str::from_utf8(fq2.seq()).unwrap_or("???")
+            ))
+        })
+    }
+
+    /// Splits a read pair using the first `end1` bases of mate 1, as
+    /// for `split_pair`, returning `None` when either mate is too
+    /// short to split or the processor has no paired linker
+    /// specification.
+    pub fn try_split_pair<'a, R: FastxRecord>(
+        &self,
+        fq1: &'a R,
+        end1: usize,
+        fq2: &'a R,
+        barcode: &Barcode,
+    ) -> Option<PairedSplit<'a>> {
+        let mut split = self.paired_spec.as_ref()?.split_pair_at(fq1, end1, fq2)?;
+        split.prepend_barcode(barcode);
+        self.trim_pair(&mut split);
+        Some(split)
+    }
+
+    /// Splits a single-end read whose fate is `ReadFate::Split(end)`
+    /// using a sample-specific linker specification, returning
+    /// `None` when the insert is too short under that specification,
+    /// either before or after 3' trimming.
+    ///
+    /// # Arguments
+    ///
+    /// * `linker_spec` is the linker specification for the sample
+    ///
+    /// * `fq` is the read
+    ///
+    /// * `end` is the end of the read before any 3' adapter
+    ///
+    /// * `barcode` holds the bases from separately sequenced indices
+    pub fn resplit_read<'a, R: FastxRecord>(
+        &self,
+        linker_spec: &LinkerSpec,
+        fq: &'a R,
+        end: usize,
+        barcode: &Barcode,
+    ) -> Option<LinkerSplit<'a>> {
+        if end < linker_spec.linker_length() + self.min_insert {
+            None
+        } else {
+            let mut split = linker_spec.split_record_at(fq, end)?;
+            split.prepend_barcode(barcode);
+            self.trim_read(&mut split);
+            if split.sequence().len() < self.min_insert {
+                None
+            } else {
+                Some(split)
+            }
+        }
+    }
+
+    /// Splits a read pair whose fate is `ReadFate::Split(end1)` using
+    /// a sample-specific linker specification, returning `None` when
+    /// either insert is too short under that specification, either
+    /// before or after 3' trimming.
+    ///
+    /// # Arguments
+    ///
+    /// * `paired_spec` is the linker specification for the sample
+    ///
+    /// * `fq1` is the mate 1 read
+    ///
+    /// * `end1` is the end of mate 1 before any 3' adapter
+    ///
+    /// * `fq2` is the mate 2 read
+    ///
+    /// * `barcode` holds the bases from separately sequenced indices
+    pub fn resplit_pair<'a, R: FastxRecord>(
+        &self,
+        paired_spec: &PairedLinkerSpec,
+        fq1: &'a R,
+        end1: usize,
+        fq2: &'a R,
+        barcode: &Barcode,
+    ) -> Option<PairedSplit<'a>> {
+        if end1 < paired_spec.mate1().linker_length() + self.min_insert
+            || fq2.seq().len() < paired_spec.mate2().linker_length() + self.min_insert
+        {
+            None
+        } else {
+            let mut split = paired_spec.split_pair_at(fq1, end1, fq2)?;
+            split.prepend_barcode(barcode);
+            self.trim_pair(&mut split);
+            if split.mate1().sequence().len() < self.min_insert
+                || split.mate2().sequence().len() < self.min_insert
+            {
+                None
+            } else {
+                Some(split)
+            }
+        }
+    }
+
+    // FastA records have no quality, which is not an error, but a
+    // FastQ record with an empty quality line is malformed
+    fn check_record<R: FastxRecord>(fq: &R) -> Option<MalformedReason> {
+        if !fq.seq().iter().all(u8::is_ascii_alphabetic) {
+            Some(MalformedReason::BadSequence)
+        } else if fq.has_qual() && fq.qual().len() != fq.seq().len() {
+            Some(MalformedReason::BadQuality)
+        } else {
+            None
+        }
+    }
+
+    fn insert_end<R: FastxRecord>(&self, fq: &R) -> Option<usize> {
+        match self.adapter {
+            Some(ref adapter) => adapter.find(fq.seq()),
+            None => Some(fq.seq().len()),
+        }
+    }
+
+    fn paired_spec_or_err(&self) -> Result<&PairedLinkerSpec, failure::Error> {
+        self.paired_spec
+            .as_ref()
+            .ok_or_else(|| failure::err_msg("No mate 2 linker specification"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::io::{fasta, fastq};
+
+    #[test]
+    fn malformed() {
+        let processor = ReadProcessor::new(LinkerSpec::new("NI", "").unwrap(), None, None, 2);
+
+        let good = fastq::Record::with_attrs("good", None, b"ACGTA", &[40; 5]);
+        assert!(processor.read_fate(&good) == ReadFate::Split(5));
+
+        let short = fastq::Record::with_attrs("short", None, b"ACG", &[40; 3]);
+        assert!(processor.read_fate(&short) == ReadFate::TooShort(3));
+
+        let bad_seq = fastq::Record::with_attrs("bad_seq", None, b"AC-TA", &[40; 5]);
+        assert!(processor.read_fate(&bad_seq) == ReadFate::Malformed(MalformedReason::BadSequence));
+
+        let bad_qual = fastq::Record::with_attrs("bad_qual", None, b"ACGTA", &[40; 4]);
+        assert!(processor.read_fate(&bad_qual) == ReadFate::Malformed(MalformedReason::BadQuality));
+
+        let no_qual = fastq::Record::with_attrs("no_qual", None, b"ACGTA", b"");
+        assert!(processor.read_fate(&no_qual) == ReadFate::Malformed(MalformedReason::BadQuality));
+
+        let fasta = fasta::Record::with_attrs("fasta", None, b"ACGTA");
+        assert!(processor.read_fate(&fasta) == ReadFate::Split(5));
+    }
+
+    #[test]
+    fn malformed_pair() {
+        let paired_spec = PairedLinkerSpec::new(
+            LinkerSpec::new("NI", "").unwrap(),
+            LinkerSpec::new("N", "").unwrap(),
+        );
+        let processor = ReadProcessor::new(paired_spec.mate1().clone(), Some(paired_spec), None, 1);
+
+        let fq1 = fastq::Record::with_attrs("pair/1", None, b"ACGT", &[40; 4]);
+        let fq2 = fastq::Record::with_attrs("pair/2", None, b"TTGC", &[40; 4]);
+        assert!(processor.pair_fate(&fq1, &fq2).unwrap() == ReadFate::Split(4));
+
+        let other2 = fastq::Record::with_attrs("other/2", None, b"TTGC", &[40; 4]);
+        assert!(
+            processor.pair_fate(&fq1, &other2).unwrap()
+                == ReadFate::Malformed(MalformedReason::MateMismatch)
+        );
+    }
+
+    #[test]
+    fn external_index() {
+        let mut processor = ReadProcessor::new(LinkerSpec::new("NI", "").unwrap(), None, None, 2);
+        processor.set_external_index(Some(
+            ExternalIndex::new(IndexSource::Header, "IIN", "I").unwrap(),
+        ));
+        assert!(processor.sample_index_length() == 4);
+        assert!(processor.index_segments() == vec![2, 1, 1, 0]);
+
+        let fq = fastq::Record::with_attrs("read", Some("1:N:0:ACG+TT"), b"ACGTA", &[40; 5]);
+        let (fate, barcode) = processor
+            .indexed_read_fate(&fq, &IndexReads::default())
+            .unwrap();
+        assert!(fate == ReadFate::Split(5));
+        let split = processor.split_read(&fq, 5, &barcode).unwrap();
+        assert!(split.sample_index() == b"ACTC");
+        assert!(split.umi() == b"GA");
+
+        let no_index = fastq::Record::with_attrs("read", None, b"ACGTA", &[40; 5]);
+        let (fate, _) = processor
+            .indexed_read_fate(&no_index, &IndexReads::default())
+            .unwrap();
+        assert!(fate == ReadFate::Malformed(MalformedReason::BadIndex));
+    }
+
+    #[test]
+    fn contaminant() {
+        let mut processor = ReadProcessor::new(LinkerSpec::new("NI", "").unwrap(), None, None, 2);
+        let mut contaminants = ContaminantIndex::new(4, 0.5).unwrap();
+        contaminants.add_sequence(b"ACGTACCGATGG");
+        processor.set_contaminants(Some(contaminants));
+
+        let contam = fastq::Record::with_attrs("contam", None, b"TTCGTACCGA", &[40; 10]);
+        assert!(processor.read_fate(&contam) == ReadFate::Contaminant(10));
+
+        let good = fastq::Record::with_attrs("good", None, b"TTGGGGGGGG", &[40; 10]);
+        assert!(processor.read_fate(&good) == ReadFate::Split(10));
+    }
+
+    #[test]
+    fn trimmed_insert() {
+        let mut processor = ReadProcessor::new(LinkerSpec::new("NI", "").unwrap(), None, None, 4);
+        let mut trimmer = InsertTrimmer::new();
+        trimmer
+            .set_quality_trim(QualityTrimMode::Bwa, 20, 1)
+            .unwrap();
+        trimmer.set_poly_a(3);
+        processor.set_trimmer(trimmer);
+
+        let poly_a = fastq::Record::with_attrs("poly_a", None, b"ACGTCAAAAA", b"IIIIIIIIII");
+        assert!(processor.read_fate(&poly_a) == ReadFate::TooShort(10));
+
+        let tail = fastq::Record::with_attrs("tail", None, b"ACGTCGAAAA", b"IIIIIIIIII");
+        assert!(processor.read_fate(&tail) == ReadFate::Split(10));
+        let split = processor
+            .split_read(&tail, 10, &Barcode::default())
+            .unwrap();
+        assert!(split.sequence() == b"GTCG");
+        assert!(split.insert_trim().tail() == 4);
+
+        let low_qual = fastq::Record::with_attrs("low_qual", None, b"ACGTCGATGC", b"IIIIIIII##");
+        assert!(processor.read_fate(&low_qual) == ReadFate::Split(10));
+        let split = processor
+            .split_read(&low_qual, 10, &Barcode::default())
+            .unwrap();
+        assert!(split.sequence() == b"GTCGAT");
+        assert!(split.quality() == b"IIIIII");
+        assert!(split.insert_trim().quality() == 2);
+    }
+}
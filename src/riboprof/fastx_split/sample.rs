@@ -1,21 +1,37 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::io;
 use std::str;
 
 use failure;
 
-use bio::io::fastq;
-
+use fastx_split::collapse::*;
+#[cfg(test)]
+use fastx_split::compress::*;
+use fastx_split::demux::*;
+use fastx_split::fastx::*;
 use fastx_split::linkers::*;
+use fastx_split::sample_writer::*;
+use fastx_split::trim::*;
+use fastx_split::umi_stats::*;
+use umi::*;
 
 /// Collected information about one particular sample
 pub struct Sample {
     name: String,
     index: Vec<u8>,
-    dest: fastq::Writer<Box<io::Write>>,
+    dest: SampleWriter,
+    contam_dest: Option<SampleWriter>,
+    umi_encoding: UmiEncoding,
+    collapser: Option<Collapser>,
+    description: Option<String>,
+    linker_spec: Option<LinkerSpec>,
+    paired_spec: Option<PairedLinkerSpec>,
     total: usize,
+    contaminants: usize,
     umi_count: HashMap<Vec<u8>, usize>,
+    insert_lengths: Vec<usize>,
+    trim_counts: TrimCounts,
+    max_reads: Option<usize>,
 }
 
 impl Sample {
@@ -27,54 +43,319 @@ impl Sample {
     ///
     /// * `index` is the sample index sequence
     ///
-    /// * `dest` is the output writer for processed fastq records for this sample
-    pub fn new<W: io::Write + 'static>(name: String, index: Vec<u8>, dest: W) -> Self {
+    /// * `format` is the sequence format for processed records
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs in processed records
+    ///
+    /// * `dest` is the output writer for processed records for this sample
+    #[cfg(test)]
+    pub fn new<W: FinishWrite + 'static>(
+        name: String,
+        index: Vec<u8>,
+        format: FastxFormat,
+        umi_encoding: UmiEncoding,
+        dest: W,
+    ) -> Self {
+        Sample::with_writer(name, index, umi_encoding, SampleWriter::fastx(format, dest))
+    }
+
+    /// Creates new sample information for paired-end reads
+    ///
+    /// # Arguments
+    ///
+    /// * `name` is the display name for the sample
+    ///
+    /// * `index` is the combined sample index sequence from both mates
+    ///
+    /// * `format` is the sequence format for processed records
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs in processed records
+    ///
+    /// * `dest1` is the output writer for processed mate 1 records
+    ///
+    /// * `dest2` is the output writer for processed mate 2 records
+    #[cfg(test)]
+    pub fn new_paired<W1, W2>(
+        name: String,
+        index: Vec<u8>,
+        format: FastxFormat,
+        umi_encoding: UmiEncoding,
+        dest1: W1,
+        dest2: W2,
+    ) -> Self
+    where
+        W1: FinishWrite + 'static,
+        W2: FinishWrite + 'static,
+    {
+        Sample::with_writer(
+            name,
+            index,
+            umi_encoding,
+            SampleWriter::fastx_paired(format, dest1, dest2),
+        )
+    }
+
+    /// Creates new sample information with any sample output, such as
+    /// unaligned BAM
+    ///
+    /// # Arguments
+    ///
+    /// * `name` is the display name for the sample
+    ///
+    /// * `index` is the sample index sequence
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs in
+    /// FastA and FastQ records
+    ///
+    /// * `dest` is the output for processed records for this sample
+    pub fn with_writer(
+        name: String,
+        index: Vec<u8>,
+        umi_encoding: UmiEncoding,
+        dest: SampleWriter,
+    ) -> Self {
         Sample {
             name: name,
             index: index,
-            dest: fastq::Writer::new(Box::new(dest)),
+            dest: dest,
+            contam_dest: None,
+            umi_encoding: umi_encoding,
+            collapser: None,
+            description: None,
+            linker_spec: None,
+            paired_spec: None,
             total: 0,
+            contaminants: 0,
             umi_count: HashMap::new(),
+            insert_lengths: Vec::new(),
+            trim_counts: TrimCounts::new(),
+            max_reads: None,
         }
     }
 
-    /// Handle a fastq record after linker trimming. This function
-    /// will write a new fastq record to the sample output writer,
+    /// Handle a fastq or fasta record after linker trimming. This function
+    /// will write a new record to the sample output writer,
     /// using the trimmed sequence and quality. The UMI will be
-    /// appended to the record `id`, after a `#` character. This
-    /// function does not check the sample index in the `LinkerSplit`
+    /// recorded in the record `id` or description according to the
+    /// sample's `UmiEncoding`, or in SAM tags for unaligned BAM
+    /// output. This function does not check the sample index in the `LinkerSplit`
     /// result.
     ///
     /// The `Sample` also collects statistics on the total number of
-    /// reads, and the number of reads per UMI.
+    /// reads, the number of reads per UMI, the insert lengths, and the
+    /// bases trimmed from the 3' end of the insert.
+    ///
+    /// When identical reads are collapsed, the record is held until
+    /// `write_collapsed` rather than written immediately.
     ///
     /// # Arguments
     ///
-    /// * `fq` is an input fastq record
+    /// * `fq` is an input fastq or fasta record
     ///
     /// * `split` contains the results of linker trimming and processing
     ///
     /// # Errors
     ///
     /// An error variant is returned when problems arise in writing
-    /// the processed fastq record to the output file.
-    pub fn handle_split_read(
+    /// the processed record to the output file.
+    pub fn handle_split_read<R: FastxRecord>(
         &mut self,
-        fq: &fastq::Record,
+        fq: &R,
         split: &LinkerSplit,
     ) -> Result<(), failure::Error> {
-        let umi_id = format!("{}#{}", fq.id(), str::from_utf8(split.umi())?);
-        let splitfq = fastq::Record::with_attrs(
-            umi_id.as_str(),
+        self.total += 1;
+        *self.umi_count.entry(split.umi().to_vec()).or_insert(0) += 1;
+        self.tally_insert_length(split.sequence().len());
+        self.trim_counts.tally(split.insert_trim());
+
+        if let Some(ref mut collapser) = self.collapser {
+            collapser.insert(
+                split.barcode(),
+                &[(fq.id(), fq.desc(), split.sequence(), split.quality())],
+            );
+            return Ok(());
+        }
+
+        self.dest.write(
+            self.umi_encoding,
+            Mate::Single,
+            fq.id(),
             fq.desc(),
             split.sequence(),
             split.quality(),
-        );
+            split.barcode(),
+        )
+    }
 
+    /// Handle a read pair after linker trimming of both mates. Each
+    /// mate is written to its own output using the trimmed sequence
+    /// and quality, and the combined UMI from both mates is recorded
+    /// with each mate according to the sample's `UmiEncoding`. Any
+    /// `/1` or `/2` mate suffix on the `id` is removed, so that both
+    /// mates share the same name.
+    ///
+    /// Statistics are collected for each pair, as for single reads,
+    /// with the mate 1 insert length as the insert length.
+    ///
+    /// # Arguments
+    ///
+    /// * `fq1` is the input mate 1 record
+    ///
+    /// * `fq2` is the input mate 2 record
+    ///
+    /// * `split` contains the results of linker trimming and processing
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the sample has no mate 2
+    /// output or when problems arise in writing the processed records.
+    pub fn handle_split_pair<R: FastxRecord>(
+        &mut self,
+        fq1: &R,
+        fq2: &R,
+        split: &PairedSplit,
+    ) -> Result<(), failure::Error> {
         self.total += 1;
         *self.umi_count.entry(split.umi().to_vec()).or_insert(0) += 1;
+        self.tally_insert_length(split.mate1().sequence().len());
+        self.trim_counts.tally(split.mate1().insert_trim());
+        self.trim_counts.tally(split.mate2().insert_trim());
+
+        if let Some(ref mut collapser) = self.collapser {
+            collapser.insert(
+                split.barcode(),
+                &[
+                    (
+                        mate_base_id(fq1.id()),
+                        fq1.desc(),
+                        split.mate1().sequence(),
+                        split.mate1().quality(),
+                    ),
+                    (
+                        mate_base_id(fq2.id()),
+                        fq2.desc(),
+                        split.mate2().sequence(),
+                        split.mate2().quality(),
+                    ),
+                ],
+            );
+            return Ok(());
+        }
+
+        write_pair(&mut self.dest, self.umi_encoding, fq1, fq2, split)
+    }
 
-        self.dest.write_record(&splitfq)?;
+    /// Handle a fastq or fasta record whose insert matches the
+    /// contaminant sequences. The record is written to the
+    /// contaminant output after linker trimming, as for
+    /// `handle_split_read`, and it is counted as a contaminant and in
+    /// the insert length distribution.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the sample has no
+    /// contaminant output or when problems arise in writing the
+    /// processed record.
+    pub fn handle_contaminant_read<R: FastxRecord>(
+        &mut self,
+        fq: &R,
+        split: &LinkerSplit,
+    ) -> Result<(), failure::Error> {
+        self.contaminants += 1;
+        self.tally_insert_length(split.sequence().len());
+
+        let name = &self.name;
+        let contam_dest = self
+            .contam_dest
+            .as_mut()
+            .ok_or_else(|| format_err!("No contaminant output for sample {}", name))?;
+        contam_dest.write(
+            self.umi_encoding,
+            Mate::Single,
+            fq.id(),
+            fq.desc(),
+            split.sequence(),
+            split.quality(),
+            split.barcode(),
+        )
+    }
+
+    /// Handle a read pair whose mate 1 insert matches the contaminant
+    /// sequences, writing each mate to its contaminant output as for
+    /// `handle_split_pair`. The mate 1 insert length is tallied, as
+    /// for `handle_contaminant_read`.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the sample has no
+    /// contaminant outputs or when problems arise in writing the
+    /// processed records.
+    pub fn handle_contaminant_pair<R: FastxRecord>(
+        &mut self,
+        fq1: &R,
+        fq2: &R,
+        split: &PairedSplit,
+    ) -> Result<(), failure::Error> {
+        self.contaminants += 1;
+        self.tally_insert_length(split.mate1().sequence().len());
+
+        let name = &self.name;
+        let contam_dest = self
+            .contam_dest
+            .as_mut()
+            .ok_or_else(|| format_err!("No contaminant output for sample {}", name))?;
+        write_pair(contam_dest, self.umi_encoding, fq1, fq2, split)
+    }
+
+    /// Sets the output for reads, or read pairs, whose insert matches
+    /// the contaminant sequences.
+    pub fn set_contam_dest(&mut self, dest: SampleWriter) {
+        self.contam_dest = Some(dest);
+    }
+
+    /// Collapses identical reads, or read pairs, with the same UMI
+    /// into one record carrying the number of identical reads. The
+    /// collapsed records are written by `write_collapsed`.
+    pub fn collapse_identical(&mut self) {
+        self.collapser = Some(Collapser::new());
+    }
+
+    /// Returns the number of distinct records after collapsing
+    /// identical reads, or `None` when reads are not collapsed
+    pub fn collapsed(&self) -> Option<usize> {
+        self.collapser.as_ref().map(Collapser::len)
+    }
+
+    /// Writes the collapsed records to the sample outputs, returning
+    /// a table of the read name, UMI, and multiplicity of each
+    /// record, or `None` when reads are not collapsed.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the records.
+    pub fn write_collapsed(&mut self) -> Result<Option<String>, failure::Error> {
+        let collapser = match self.collapser {
+            Some(ref collapser) => collapser,
+            None => return Ok(None),
+        };
+
+        let table = collapser.write_all(self.umi_encoding, &mut self.dest)?;
+        Ok(Some(table))
+    }
+
+    /// Finishes the sample outputs, including any contaminant
+    /// outputs, after all records have been written.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in completing
+    /// the outputs.
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        self.dest.finish()?;
+        if let Some(ref mut contam_dest) = self.contam_dest {
+            contam_dest.finish()?;
+        }
         Ok(())
     }
 
@@ -88,12 +369,113 @@ impl Sample {
         &self.index
     }
 
+    /// Returns the sample description, if any
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(String::as_str)
+    }
+
+    /// Sets the sample description
+    pub fn set_description(&mut self, description: Option<&str>) {
+        self.description = description.map(str::to_string);
+    }
+
+    /// Sets a linker specification for single-end reads from this
+    /// sample, replacing the one used to find the sample index when
+    /// reads are split.
+    pub fn set_linker_spec(&mut self, linker_spec: LinkerSpec) {
+        self.linker_spec = Some(linker_spec);
+    }
+
+    /// Sets a linker specification for read pairs from this sample,
+    /// replacing the one used to find the sample index when read
+    /// pairs are split.
+    pub fn set_paired_spec(&mut self, paired_spec: PairedLinkerSpec) {
+        self.paired_spec = Some(paired_spec);
+    }
+
     /// Returns the total number of reads handled for the sample
     pub fn total(&self) -> usize {
         self.total
     }
 
-    /// Returns a table of the number of reads per UMI
+    /// Returns the maximum number of reads handled for the sample, if
+    /// the sample is capped
+    pub fn max_reads(&self) -> Option<usize> {
+        self.max_reads
+    }
+
+    /// Sets the maximum number of reads handled for the sample. Once
+    /// the sample is full, further reads should not be handled.
+    pub fn set_max_reads(&mut self, max_reads: Option<usize>) {
+        self.max_reads = max_reads;
+    }
+
+    /// Returns true when the sample has reached its maximum number of
+    /// reads
+    pub fn is_full(&self) -> bool {
+        self.max_reads
+            .map_or(false, |max_reads| self.total >= max_reads)
+    }
+
+    /// Returns the number of reads whose insert matches the
+    /// contaminant sequences
+    pub fn contaminants(&self) -> usize {
+        self.contaminants
+    }
+
+    /// Returns the number of distinct UMIs observed
+    pub fn distinct_umis(&self) -> usize {
+        self.umi_count.len()
+    }
+
+    /// Tallies an insert length for the sample. Reads written to the
+    /// sample are tallied automatically, and reads with an insert
+    /// that is too short are tallied here as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` is the insert length after linker and adapter removal
+    pub fn tally_insert_length(&mut self, length: usize) {
+        if self.insert_lengths.len() <= length {
+            self.insert_lengths.resize(length + 1, 0);
+        }
+        self.insert_lengths[length] += 1;
+    }
+
+    /// Returns the number of reads for each insert length, indexed by
+    /// length, up to the longest insert observed
+    pub fn insert_lengths(&self) -> &[usize] {
+        &self.insert_lengths
+    }
+
+    /// Returns a table of the number of reads for each insert length,
+    /// with a header line, from length 0 up to the longest insert
+    pub fn insert_length_table(&self) -> String {
+        let mut table = "length\treads\n".to_string();
+        for (length, count) in self.insert_lengths.iter().enumerate() {
+            table.push_str(&format!("{}\t{}\n", length, count));
+        }
+        table
+    }
+
+    /// Returns the counts of inserts and bases trimmed from the 3' end
+    /// of reads written to the sample
+    pub fn trim_counts(&self) -> &TrimCounts {
+        &self.trim_counts
+    }
+
+    /// Returns a summary of UMI diversity and saturation
+    pub fn umi_stats(&self) -> UmiStats {
+        UmiStats::new(&self.umi_count)
+    }
+
+    /// Returns the length of the UMIs observed, or 0 if there are none
+    pub fn umi_length(&self) -> usize {
+        self.umi_count.keys().next().map_or(0, Vec::len)
+    }
+
+    /// Returns a table of the number of reads per UMI, listing every
+    /// possible UMI including those with `N`
     pub fn stats_table(&self) -> String {
         let umi_length = self.umi_count.keys().next().map_or(0, |umi| umi.len());
         let mut table = String::new();
@@ -129,6 +511,106 @@ impl Sample {
     }
 }
 
+// Writes both mates of a read pair, sharing the name without any
+// `/1` or `/2` mate suffix and the combined barcode from both mates
+fn write_pair<R: FastxRecord>(
+    dest: &mut SampleWriter,
+    umi_encoding: UmiEncoding,
+    fq1: &R,
+    fq2: &R,
+    split: &PairedSplit,
+) -> Result<(), failure::Error> {
+    dest.write(
+        umi_encoding,
+        Mate::First,
+        mate_base_id(fq1.id()),
+        fq1.desc(),
+        split.mate1().sequence(),
+        split.mate1().quality(),
+        split.barcode(),
+    )?;
+    dest.write(
+        umi_encoding,
+        Mate::Second,
+        mate_base_id(fq2.id()),
+        fq2.desc(),
+        split.mate2().sequence(),
+        split.mate2().quality(),
+        split.barcode(),
+    )
+}
+
+/// Returns a matrix of insert length counts with one row per sample
+/// and one column per insert length, from length 0 up to the longest
+/// insert in any sample, with a header line naming the lengths.
+///
+/// # Arguments
+///
+/// * `samples` are the samples, in the order of the rows
+pub fn insert_length_matrix(samples: &[&Sample]) -> String {
+    let max_length = samples
+        .iter()
+        .map(|sample| sample.insert_lengths().len())
+        .max()
+        .unwrap_or(0);
+
+    let mut matrix = "sample".to_string();
+    for length in 0..max_length {
+        matrix.push_str(&format!("\t{}", length));
+    }
+    matrix.push('\n');
+
+    for sample in samples.iter() {
+        matrix.push_str(sample.name());
+        for length in 0..max_length {
+            let count = sample.insert_lengths().get(length).unwrap_or(&0);
+            matrix.push_str(&format!("\t{}", count));
+        }
+        matrix.push('\n');
+    }
+
+    matrix
+}
+
+/// Returns a table of the inserts and bases trimmed from the 3' end of
+/// the reads in each sample, with a header line. Each mate of a read
+/// pair is counted separately.
+///
+/// # Arguments
+///
+/// * `samples` are the samples, in the order of the rows
+pub fn trim_table(samples: &[&Sample]) -> String {
+    let mut table =
+        "sample\treads\tquality_trimmed\tquality_bases\ttail_trimmed\ttail_bases\n".to_string();
+    for sample in samples.iter() {
+        let counts = sample.trim_counts();
+        table.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            sample.name(),
+            sample.total(),
+            counts.quality_inserts(),
+            counts.quality_bases(),
+            counts.tail_inserts(),
+            counts.tail_bases()
+        ));
+    }
+    table
+}
+
+impl DemuxSample for Sample {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn linker_spec(&self) -> Option<&LinkerSpec> {
+        self.linker_spec.as_ref()
+    }
+
+    fn paired_spec(&self) -> Option<&PairedLinkerSpec> {
+        self.paired_spec.as_ref()
+    }
+}
+
 impl fmt::Display for Sample {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.name)
@@ -139,36 +621,26 @@ impl fmt::Display for Sample {
 mod tests {
     use super::*;
 
-    use std::cell::*;
+    use std::io;
     use std::ops::*;
-    use std::rc::*;
 
-    use fastx_split::linkers::*;
-
-    struct TestWriter {
-        dest: Rc<RefCell<Vec<u8>>>,
-    }
+    use bio::io::{fasta, fastq};
 
-    impl io::Write for TestWriter {
-        fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-            self.dest.borrow_mut().append(&mut buf.to_vec());
-            Ok(buf.len())
-        }
-
-        fn flush(&mut self) -> Result<(), io::Error> {
-            Ok(())
-        }
-    }
+    use fastx_split::linkers::*;
 
     #[test]
     fn sample_output() {
-        let outbuf = Rc::new(RefCell::new(Vec::new()));
+        let outbuf = SharedBuffer::new();
 
         {
-            let writer = TestWriter {
-                dest: outbuf.clone(),
-            };
-            let mut sample = Sample::new("One".to_string(), b"ACGT".to_vec(), writer);
+            let writer = outbuf.clone();
+            let mut sample = Sample::new(
+                "One".to_string(),
+                b"ACGT".to_vec(),
+                FastxFormat::Fastq,
+                UmiEncoding::Hash,
+                writer,
+            );
 
             let linker_spec = LinkerSpec::new("NN", "NNIIII").unwrap();
 
@@ -189,14 +661,111 @@ mod tests {
         let mut exp2 = b"@another#TGTC\nTGCGAGCTAG\n+\n%%%%%%%%%%\n".to_vec();
         exp.append(&mut exp2);
 
-        assert!(outbuf.borrow().as_slice() == exp.as_slice());
+        assert!(outbuf.contents().as_slice() == exp.as_slice());
+    }
+
+    #[test]
+    fn sample_fasta_output() {
+        let outbuf = SharedBuffer::new();
+
+        {
+            let writer = outbuf.clone();
+            let mut sample = Sample::new(
+                "One".to_string(),
+                b"ACGT".to_vec(),
+                FastxFormat::Fasta,
+                UmiEncoding::Hash,
+                writer,
+            );
+
+            let linker_spec = LinkerSpec::new("NN", "NNIIII").unwrap();
+
+            let rec1 = fasta::Record::with_attrs("test_record", None, b"ACGTACGTACGTACGT");
+            let spl1 = linker_spec.split_record(&rec1).unwrap();
+            sample.handle_split_read(&rec1, &spl1).unwrap();
+            assert!(sample.total() == 1);
+        }
+
+        assert!(outbuf.contents().as_slice() == b">test_record#ACGT\nGTACGTAC\n");
+    }
+
+    #[test]
+    fn sample_umi_encoding() {
+        let outbuf = SharedBuffer::new();
+
+        {
+            let linker_spec = LinkerSpec::new("NN", "NNIIII").unwrap();
+            let rec = fastq::Record::with_attrs(
+                "test_record",
+                Some("1:N:0:ACGT"),
+                b"ACGTACGTACGTACGT",
+                &vec![40; 16],
+            );
+            let spl = linker_spec.split_record(&rec).unwrap();
+
+            for encoding in [UmiEncoding::Underscore, UmiEncoding::SamTag].iter() {
+                let writer = outbuf.clone();
+                let mut sample = Sample::new(
+                    "One".to_string(),
+                    b"ACGT".to_vec(),
+                    FastxFormat::Fasta,
+                    *encoding,
+                    writer,
+                );
+                sample.handle_split_read(&rec, &spl).unwrap();
+            }
+        }
+
+        let mut exp = b">test_record_ACGT 1:N:0:ACGT\nGTACGTAC\n".to_vec();
+        exp.append(&mut b">test_record RX:Z:ACGT\nGTACGTAC\n".to_vec());
+
+        assert!(outbuf.contents().as_slice() == exp.as_slice());
+    }
+
+    #[test]
+    fn sample_paired_output() {
+        let outbuf1 = SharedBuffer::new();
+        let outbuf2 = SharedBuffer::new();
+
+        {
+            let writer1 = outbuf1.clone();
+            let writer2 = outbuf2.clone();
+            let mut sample = Sample::new_paired(
+                "Pair".to_string(),
+                b"ACGT".to_vec(),
+                FastxFormat::Fastq,
+                UmiEncoding::Hash,
+                writer1,
+                writer2,
+            );
+
+            let linker_spec = PairedLinkerSpec::new(
+                LinkerSpec::new("NNII", "").unwrap(),
+                LinkerSpec::new("IIN", "").unwrap(),
+            );
+
+            let rec1 = fastq::Record::with_attrs("pair/1", None, b"ACGTACGT", &vec![40; 8]);
+            let rec2 = fastq::Record::with_attrs("pair/2", None, b"TTGCCAA", &vec![37; 7]);
+            let spl = linker_spec.split_pair(&rec1, &rec2).unwrap();
+            sample.handle_split_pair(&rec1, &rec2, &spl).unwrap();
+            assert!(sample.total() == 1);
+        }
+
+        assert!(outbuf1.contents().as_slice() == b"@pair#ACG\nACGT\n+\n((((\n");
+        assert!(outbuf2.contents().as_slice() == b"@pair#ACG\nCCAA\n+\n%%%%\n");
     }
 
     #[test]
     fn sample_umi_counts() {
         let linker_spec = LinkerSpec::new("", "NN").unwrap();
 
-        let mut sample = Sample::new("Two".to_string(), Vec::new(), io::sink());
+        let mut sample = Sample::new(
+            "Two".to_string(),
+            Vec::new(),
+            FastxFormat::Fastq,
+            UmiEncoding::Hash,
+            io::sink(),
+        );
 
         for nt1 in b"ACCGGGTTTT" {
             for nt2 in b"AAAACCCGGT" {
@@ -217,4 +786,38 @@ mod tests {
 
         assert!(sample.stats_table() == exp);
     }
+
+    #[test]
+    fn sample_insert_lengths() {
+        let linker_spec = LinkerSpec::new("II", "").unwrap();
+        let mut one = Sample::new(
+            "One".to_string(),
+            b"AC".to_vec(),
+            FastxFormat::Fastq,
+            UmiEncoding::Hash,
+            io::sink(),
+        );
+        let mut two = Sample::new(
+            "Two".to_string(),
+            b"GT".to_vec(),
+            FastxFormat::Fastq,
+            UmiEncoding::Hash,
+            io::sink(),
+        );
+
+        for seq in [&b"ACGTA"[..], &b"ACGTAC"[..], &b"ACGTA"[..]].iter() {
+            let rec = fastq::Record::with_attrs("read", None, seq, &vec![40; seq.len()]);
+            let spl = linker_spec.split_record(&rec).unwrap();
+            one.handle_split_read(&rec, &spl).unwrap();
+        }
+        one.tally_insert_length(1);
+        two.tally_insert_length(0);
+
+        assert!(one.insert_lengths() == &[0, 1, 0, 2, 1]);
+        assert!(one.insert_length_table() == "length\treads\n0\t0\n1\t1\n2\t0\n3\t2\n4\t1\n");
+        assert!(
+            insert_length_matrix(&[&one, &two])
+                == "sample\t0\t1\t2\t3\t4\nOne\t0\t1\t0\t2\t1\nTwo\t1\t0\t0\t0\t0\n"
+        );
+    }
 }
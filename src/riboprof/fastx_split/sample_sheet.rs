@@ -6,6 +6,7 @@ use std::fmt;
 use std::rc::Rc;
 use std::str;
 
+use csv;
 use failure;
 
 #[derive(Debug, Clone)]
@@ -14,6 +15,8 @@ pub struct SampleMap<T> {
     index_map: HashMap<Vec<u8>, SampleEntry<T>>,
     unknown: SampleEntry<T>,
     entries: Vec<SampleEntry<T>>,
+    index1_length: Option<usize>,
+    part_maps: Vec<HashMap<Vec<u8>, Vec<Vec<u8>>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -47,38 +50,247 @@ impl<T> SampleMap<T> {
             index_map: HashMap::new(),
             unknown: SampleEntry::new(&unknown_index, &unknown_rcrc),
             entries: vec![SampleEntry::new(&unknown_index, &unknown_rcrc)],
+            index1_length: None,
+            part_maps: Vec::new(),
         }
     }
 
+    /// Creates a sample map for dual indices, where each sample index
+    /// is the first index followed by the second index. Each index
+    /// has its own mismatch tolerance, and unassigned indices can be
+    /// diagnosed with `index_failure`. Only the exact combinations of
+    /// dual indices are stored, and each index of a read is resolved
+    /// to the true indices within tolerance before the combination is
+    /// looked up.
+    pub fn new_dual(index1_length: usize, index2_length: usize, unknown: T) -> Self {
+        let mut sample_map = SampleMap::new(index1_length + index2_length, unknown);
+        sample_map.index1_length = Some(index1_length);
+        sample_map.part_maps = vec![HashMap::new(), HashMap::new()];
+        sample_map
+    }
+
+    /// Returns the length of sample indices
+    pub fn index_length(&self) -> usize {
+        self.index_length
+    }
+
+    /// Returns the length of the first index for dual indices
+    pub fn index1_length(&self) -> Option<usize> {
+        self.index1_length
+    }
+
+    /// Inserts a sample, which will be found by any index sequence
+    /// within `max_mismatch` substitutions of its true index, or of
+    /// each of its dual indices. An `N` in the read index counts as a
+    /// mismatch.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the index has the wrong
+    /// length or when any index sequence within the mismatch
+    /// tolerance is already assigned to another sample.
     pub fn insert(
         &mut self,
         index: Vec<u8>,
-        allow_mismatch: bool,
+        max_mismatch: usize,
+        thing: T,
+    ) -> Result<Rc<RefCell<T>>, failure::Error> {
+        let tolerances = vec![max_mismatch; self.part_count()];
+        self.insert_with_tolerances(index, &tolerances, thing)
+    }
+
+    /// Inserts a sample with a separate mismatch tolerance for each
+    /// part of its index, which is the whole index or each of the
+    /// dual indices. The sample will be found by any index sequence
+    /// where every part is within its tolerance.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned as for `insert`, and when the
+    /// number of tolerances does not match the number of index parts.
+    pub fn insert_with_tolerances(
+        &mut self,
+        index: Vec<u8>,
+        max_mismatch: &[usize],
         thing: T,
     ) -> Result<Rc<RefCell<T>>, failure::Error> {
         if index.len() != self.index_length {
             return Err(SampleError::IndexBadLength(self.index_length, index).into());
         }
+        if max_mismatch.len() != self.part_count() {
+            return Err(format_err!(
+                "{} mismatch tolerances for {} index parts",
+                max_mismatch.len(),
+                self.part_count()
+            ));
+        }
+
+        if self.part_maps.is_empty() {
+            let mut neighbors = vec![index.clone()];
+            neighbors.extend(hamming_neighbors(&index, max_mismatch[0]));
+
+            // Check for clashes before changing anything, so that a
+            // failed insert leaves the map as it was
+            if let Some(clash) = neighbors
+                .iter()
+                .find(|neighbor| self.index_map.contains_key(neighbor.as_slice()))
+            {
+                return Err(SampleError::IndexClash(clash.clone()).into());
+            }
+
+            let rcrc = Rc::new(RefCell::new(thing));
+            for index_mut in neighbors.into_iter() {
+                self.insert_index(index_mut, SampleEntry::new(index.as_slice(), &rcrc))?;
+            }
+            self.entries.push(SampleEntry::new(index.as_slice(), &rcrc));
+            return Ok(rcrc);
+        }
+
+        let parts = self.parts(&index);
+        let part_variants: Vec<Vec<Vec<u8>>> = parts
+            .iter()
+            .zip(max_mismatch.iter())
+            .map(|(part, tolerance)| {
+                let mut variants = vec![part.to_vec()];
+                variants.extend(hamming_neighbors(part, *tolerance));
+                variants
+            })
+            .collect();
+
+        if let Some(clash) = self.dual_clash(&part_variants) {
+            return Err(SampleError::IndexClash(clash).into());
+        }
 
         let rcrc = Rc::new(RefCell::new(thing));
 
+        for ((part, variants), part_map) in parts
+            .into_iter()
+            .zip(part_variants.into_iter())
+            .zip(self.part_maps.iter_mut())
+        {
+            for variant in variants.into_iter() {
+                let true_parts = part_map.entry(variant).or_insert_with(Vec::new);
+                if !true_parts
+                    .iter()
+                    .any(|true_part| true_part.as_slice() == part)
+                {
+                    true_parts.push(part.to_vec());
+                }
+            }
+        }
+
         self.insert_index(index.clone(), SampleEntry::new(index.as_slice(), &rcrc))?;
+        self.entries.push(SampleEntry::new(index.as_slice(), &rcrc));
+
+        Ok(rcrc)
+    }
 
-        if allow_mismatch {
-            for mm in 0..index.len() {
-                for nt in [b'A', b'C', b'G', b'T'].iter() {
-                    if index[mm] != *nt {
-                        let mut index_mut = index.clone();
-                        index_mut[mm] = *nt;
-                        self.insert_index(index_mut, SampleEntry::new(index.as_slice(), &rcrc))?;
+    // Returns the true index of a sample whose dual indices would
+    // both match some read index along with a new sample, given the
+    // variants of each index of the new sample within its tolerance.
+    // The true indices that share a variant are found for each index
+    // separately, and a clash needs a sample with both of them.
+    fn dual_clash(&self, part_variants: &[Vec<Vec<u8>>]) -> Option<Vec<u8>> {
+        let overlaps: Vec<Vec<&[u8]>> = part_variants
+            .iter()
+            .zip(self.part_maps.iter())
+            .map(|(variants, part_map)| {
+                let mut true_parts: Vec<&[u8]> = Vec::new();
+                for shared in variants.iter().filter_map(|variant| part_map.get(variant)) {
+                    for true_part in shared.iter() {
+                        if !true_parts.contains(&true_part.as_slice()) {
+                            true_parts.push(true_part.as_slice());
+                        }
                     }
                 }
+                true_parts
+            })
+            .collect();
+
+        for true1 in overlaps[0].iter() {
+            for true2 in overlaps[1].iter() {
+                let true_index = [*true1, *true2].concat();
+                if self.index_map.contains_key(&true_index) {
+                    return Some(true_index);
+                }
             }
         }
+        None
+    }
 
-        self.entries.push(SampleEntry::new(index.as_slice(), &rcrc));
+    // Returns the entry for an index sequence. With dual indices,
+    // each index is resolved to the true indices within tolerance,
+    // and at most one combination of those is assigned to a sample
+    // because clashes are refused on insert.
+    fn entry(&self, index: &[u8]) -> Option<&SampleEntry<T>> {
+        if self.part_maps.is_empty() {
+            return self.index_map.get(index);
+        }
+        if index.len() != self.index_length {
+            return None;
+        }
 
-        Ok(rcrc)
+        let true_parts: Vec<&Vec<Vec<u8>>> = self
+            .parts(index)
+            .into_iter()
+            .zip(self.part_maps.iter())
+            .map(|(part, part_map)| part_map.get(part))
+            .collect::<Option<Vec<_>>>()?;
+
+        for true1 in true_parts[0].iter() {
+            for true2 in true_parts[1].iter() {
+                let true_index = [true1.as_slice(), true2.as_slice()].concat();
+                if let Some(entry) = self.index_map.get(&true_index) {
+                    return Some(entry);
+                }
+            }
+        }
+        None
+    }
+
+    /// Diagnoses an index sequence that is not assigned to any
+    /// sample, for dual indices, according to which of the indices
+    /// match a sample within their mismatch tolerance. An index that
+    /// is within the tolerance of two different true indices does
+    /// not match. Returns `None` for single indices and for index
+    /// sequences that are assigned to a sample or have the wrong
+    /// length.
+    pub fn index_failure(&self, index: &[u8]) -> Option<IndexFailure> {
+        if self.part_maps.len() != 2
+            || index.len() != self.index_length
+            || self.entry(index).is_some()
+        {
+            return None;
+        }
+
+        let matches: Vec<Option<Vec<u8>>> = self
+            .parts(index)
+            .into_iter()
+            .zip(self.part_maps.iter())
+            .map(|(part, part_map)| match part_map.get(part) {
+                Some(true_parts) if true_parts.len() == 1 => Some(true_parts[0].clone()),
+                _ => None,
+            })
+            .collect();
+
+        Some(match (&matches[0], &matches[1]) {
+            (Some(true1), Some(true2)) => IndexFailure::Hopped(true1.clone(), true2.clone()),
+            (Some(_), None) => IndexFailure::Index2,
+            (None, Some(_)) => IndexFailure::Index1,
+            (None, None) => IndexFailure::Both,
+        })
+    }
+
+    fn part_count(&self) -> usize {
+        self.part_maps.len().max(1)
+    }
+
+    // The dual indices making up an index, or else the whole index
+    fn parts<'a>(&self, index: &'a [u8]) -> Vec<&'a [u8]> {
+        match self.index1_length {
+            Some(index1_length) => vec![&index[..index1_length], &index[index1_length..]],
+            None => vec![index],
+        }
     }
 
     fn insert_index(
@@ -99,19 +311,67 @@ impl<T> SampleMap<T> {
             return Err(SampleError::IndexBadLength(self.index_length, index.to_vec()).into());
         }
 
-        let entry = self.index_map.get(index).unwrap_or(&self.unknown);
+        let entry = self.entry(index).unwrap_or(&self.unknown);
         let thing = entry.thing.try_borrow()?;
         Ok(thing)
     }
 
-    pub fn get_mut(&mut self, index: &[u8]) -> Result<RefMut<T>, failure::Error> {
+    /// Returns the thing assigned to an index sequence, or the thing
+    /// for unknown indices when the index is not assigned to any
+    /// sample.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the index has the wrong length.
+    pub fn lookup(&self, index: &[u8]) -> Result<Rc<RefCell<T>>, failure::Error> {
         if index.len() != self.index_length {
             return Err(SampleError::IndexBadLength(self.index_length, index.to_vec()).into());
         }
 
-        let entry = self.index_map.get(index).unwrap_or(&self.unknown);
-        let thing = entry.thing.try_borrow_mut()?;
-        Ok(thing)
+        let entry = self.entry(index).unwrap_or(&self.unknown);
+        Ok(entry.thing.clone())
+    }
+
+    /// Returns the thing assigned to an index sequence, or `None`
+    /// when the index is not assigned to any sample.
+    pub fn find(&self, index: &[u8]) -> Option<Rc<RefCell<T>>> {
+        self.entry(index).map(|entry| entry.thing.clone())
+    }
+
+    /// Returns the sample whose true index is closest to an index
+    /// sequence, along with the Hamming distance between them. Ties
+    /// go to the sample inserted first, and `None` is returned when
+    /// there are no samples.
+    pub fn closest(&self, index: &[u8]) -> Option<(Rc<RefCell<T>>, usize)> {
+        let mut closest: Option<(&SampleEntry<T>, usize)> = None;
+
+        for entry in self.entries.iter() {
+            if Rc::ptr_eq(&entry.thing, &self.unknown.thing) {
+                continue;
+            }
+            let distance = hamming_distance(index, &entry.true_index);
+            if closest.map_or(true, |(_, best)| distance < best) {
+                closest = Some((entry, distance));
+            }
+        }
+
+        closest.map(|(entry, distance)| (entry.thing.clone(), distance))
+    }
+
+    /// Returns the thing for unknown indices
+    pub fn unknown(&self) -> Rc<RefCell<T>> {
+        self.unknown.thing.clone()
+    }
+
+    /// Returns the true index and the thing for each sample, in the
+    /// order they were inserted, excluding the thing for unknown
+    /// indices
+    pub fn sample_indices(&self) -> Vec<(&[u8], Rc<RefCell<T>>)> {
+        self.entries
+            .iter()
+            .filter(|entry| !Rc::ptr_eq(&entry.thing, &self.unknown.thing))
+            .map(|entry| (entry.true_index.as_slice(), entry.thing.clone()))
+            .collect()
     }
 
     pub fn things(&self) -> Vec<Rc<RefCell<T>>> {
@@ -124,6 +384,9 @@ impl<T> SampleMap<T> {
 }
 
 impl<T: fmt::Display> SampleMap<T> {
+    /// Returns a table of each index sequence assigned to a sample,
+    /// the sample, and its true index. With dual indices, only the
+    /// true index combinations are listed.
     pub fn mapping_table(&self) -> String {
         let mut table = String::new();
         for (index, entry) in self.index_map.iter() {
@@ -138,32 +401,528 @@ impl<T: fmt::Display> SampleMap<T> {
     }
 }
 
-pub fn parse_sample_sheet(sheet: &str) -> Result<Vec<(String, String)>, failure::Error> {
-    sheet.lines().map(parse_sample_line).collect()
+/// Returns every sequence at Hamming distance 1 through
+/// `max_mismatch` from `index`, with substitutions drawn from `ACGTN`.
+pub fn hamming_neighbors(index: &[u8], max_mismatch: usize) -> Vec<Vec<u8>> {
+    let mut neighbors = Vec::new();
+    let mut frontier = vec![(index.to_vec(), 0)];
+
+    // Each round substitutes one more position, always to the right
+    // of the previous substitution so that no sequence is repeated.
+    for _ in 0..max_mismatch {
+        let mut next_frontier = Vec::new();
+        for (seq, first_pos) in frontier.into_iter() {
+            for pos in first_pos..seq.len() {
+                for nt in b"ACGTN".iter() {
+                    if index[pos] != *nt {
+                        let mut seq_mut = seq.clone();
+                        seq_mut[pos] = *nt;
+                        next_frontier.push((seq_mut, pos + 1));
+                    }
+                }
+            }
+        }
+        neighbors.extend(next_frontier.iter().map(|(seq, _)| seq.clone()));
+        frontier = next_frontier;
+    }
+
+    neighbors
+}
+
+/// Returns the number of positions where two equal-length index
+/// sequences differ.
+pub fn hamming_distance(index1: &[u8], index2: &[u8]) -> usize {
+    index1
+        .iter()
+        .zip(index2.iter())
+        .filter(|(nt1, nt2)| nt1 != nt2)
+        .count()
+}
+
+/// Reason that an index sequence is not assigned to any sample with
+/// dual indices
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IndexFailure {
+    /// The first index matches no sample, but the second does
+    Index1,
+    /// The second index matches no sample, but the first does
+    Index2,
+    /// Neither index matches a sample
+    Both,
+    /// Each index matches a sample, giving these true indices, but no
+    /// sample has the combination, typically due to index hopping
+    Hopped(Vec<u8>, Vec<u8>),
+}
+
+impl IndexFailure {
+    /// Returns a short description of the failure
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IndexFailure::Index1 => "index1",
+            IndexFailure::Index2 => "index2",
+            IndexFailure::Both => "both",
+            IndexFailure::Hopped(_, _) => "hopped",
+        }
+    }
+}
+
+impl fmt::Display for IndexFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind())
+    }
+}
+
+/// A pair of samples whose indices are too similar to be resolved
+/// with the requested mismatch tolerance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexConflict {
+    name1: String,
+    index1: String,
+    name2: String,
+    index2: String,
+    distances: Vec<usize>,
+}
+
+impl IndexConflict {
+    /// Returns the names of the two conflicting samples
+    pub fn names(&self) -> (&str, &str) {
+        (&self.name1, &self.name2)
+    }
+
+    /// Returns the Hamming distance between the two sample indices
+    pub fn distance(&self) -> usize {
+        self.distances.iter().sum()
+    }
+
+    /// Returns the Hamming distance between each part of the two
+    /// sample indices, i.e., the whole index or each of the dual
+    /// indices
+    pub fn part_distances(&self) -> &[usize] {
+        &self.distances
+    }
+}
+
+impl fmt::Display for IndexConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) and {} ({}) differ at {} position{}",
+            self.name1,
+            self.index1,
+            self.name2,
+            self.index2,
+            self.distance(),
+            if self.distance() == 1 { "" } else { "s" }
+        )
+    }
+}
+
+/// Finds all pairs of samples whose indices cannot be resolved with
+/// their mismatch tolerances, i.e., those where some read index would
+/// lie within the tolerance of both. With dual indices, this requires
+/// each index to lie within its own tolerance of both.
+///
+/// # Arguments
+///
+/// * `samples` lists the samples from the sample sheet
+///
+/// * `max_mismatch` is the mismatch tolerance for each part of the
+/// index of each sample, as for `SampleSpec::index_parts`
+pub fn index_conflicts(samples: &[SampleSpec], max_mismatch: &[Vec<usize>]) -> Vec<IndexConflict> {
+    let mut conflicts = Vec::new();
+
+    for (i, spec1) in samples.iter().enumerate() {
+        for (j, spec2) in samples.iter().enumerate().skip(i + 1) {
+            let distances: Vec<usize> = spec1
+                .index_parts()
+                .iter()
+                .zip(spec2.index_parts().iter())
+                .map(|(part1, part2)| hamming_distance(part1.as_bytes(), part2.as_bytes()))
+                .collect();
+            let resolved = distances
+                .iter()
+                .enumerate()
+                .any(|(k, distance)| *distance > max_mismatch[i][k] + max_mismatch[j][k]);
+            if !resolved {
+                conflicts.push(IndexConflict {
+                    name1: spec1.name().to_string(),
+                    index1: spec1.index_parts().join("+"),
+                    name2: spec2.name().to_string(),
+                    index2: spec2.index_parts().join("+"),
+                    distances: distances,
+                });
+            }
+        }
+    }
+
+    conflicts
+}
+
+/// Lowers the mismatch tolerance for samples involved in index
+/// conflicts so that every pair of samples can be resolved. Each
+/// sample gets the largest tolerance, up to its requested tolerance,
+/// that is less than half the distance to every conflicting index.
+/// With dual indices, this applies to each index that differs
+/// between the samples.
+///
+/// # Arguments
+///
+/// * `samples` lists the samples from the sample sheet
+///
+/// * `max_mismatch` is the requested mismatch tolerance for each
+/// part of the index of each sample
+///
+/// * `conflicts` are the index conflicts among `samples`
+///
+/// # Errors
+///
+/// An error variant is returned when two samples have identical
+/// indices, which cannot be resolved at any tolerance.
+pub fn reduced_tolerances(
+    samples: &[SampleSpec],
+    max_mismatch: &[Vec<usize>],
+    conflicts: &[IndexConflict],
+) -> Result<Vec<Vec<usize>>, failure::Error> {
+    let mut tolerances = max_mismatch.to_vec();
+
+    for conflict in conflicts.iter() {
+        if conflict.distance() == 0 {
+            return Err(SampleError::IndexConflicts(vec![conflict.clone()]).into());
+        }
+
+        let (name1, name2) = conflict.names();
+        for (k, distance) in conflict.part_distances().iter().enumerate() {
+            if *distance == 0 {
+                continue;
+            }
+            let tolerance = (distance - 1) / 2;
+            for (i, spec) in samples.iter().enumerate() {
+                if spec.name() == name1 || spec.name() == name2 {
+                    tolerances[i][k] = tolerances[i][k].min(tolerance);
+                }
+            }
+        }
+    }
+
+    Ok(tolerances)
+}
+
+/// One sample from the sample sheet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleSpec {
+    line: usize,
+    name: String,
+    index: String,
+    max_mismatch: Option<usize>,
+    index2: Option<String>,
+    max_mismatch2: Option<usize>,
+    output: Option<String>,
+    description: Option<String>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+    prefix2: Option<String>,
+    suffix2: Option<String>,
+    max_reads: Option<usize>,
+}
+
+impl SampleSpec {
+    /// Returns the sample sheet line number for the sample
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the sample name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the sample index sequence
+    pub fn index(&self) -> &str {
+        &self.index
+    }
+
+    /// Returns the mismatch tolerance for the sample, if it differs
+    /// from the default
+    pub fn max_mismatch(&self) -> Option<usize> {
+        self.max_mismatch
+    }
+
+    /// Returns the second index sequence for dual indices, if any
+    pub fn index2(&self) -> Option<&str> {
+        self.index2.as_ref().map(String::as_str)
+    }
+
+    /// Returns the mismatch tolerance for the second index, if it
+    /// differs from the default
+    pub fn max_mismatch2(&self) -> Option<usize> {
+        self.max_mismatch2
+    }
+
+    /// Returns the index sequence, or both dual indices
+    pub fn index_parts(&self) -> Vec<&str> {
+        let mut parts = vec![self.index()];
+        parts.extend(self.index2());
+        parts
+    }
+
+    /// Returns the full sample index, with the second index following
+    /// the first for dual indices
+    pub fn full_index(&self) -> String {
+        self.index_parts().concat()
+    }
+
+    /// Returns the base name for sample output files, which is the
+    /// sample name unless an output name is given
+    pub fn output_name(&self) -> &str {
+        self.output.as_ref().unwrap_or(&self.name)
+    }
+
+    /// Returns the sample description, if any
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(String::as_str)
+    }
+
+    /// Returns the linker prefix specification for the sample, if it
+    /// overrides the default
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_ref().map(String::as_str)
+    }
+
+    /// Returns the linker suffix specification for the sample, if it
+    /// overrides the default
+    pub fn suffix(&self) -> Option<&str> {
+        self.suffix.as_ref().map(String::as_str)
+    }
+
+    /// Returns the mate 2 linker prefix specification for the sample,
+    /// if it overrides the default
+    pub fn prefix2(&self) -> Option<&str> {
+        self.prefix2.as_ref().map(String::as_str)
+    }
+
+    /// Returns the mate 2 linker suffix specification for the sample,
+    /// if it overrides the default
+    pub fn suffix2(&self) -> Option<&str> {
+        self.suffix2.as_ref().map(String::as_str)
+    }
+
+    /// Returns the maximum number of reads written for the sample, if
+    /// it differs from the default
+    pub fn max_reads(&self) -> Option<usize> {
+        self.max_reads
+    }
+
+    fn from_record(
+        record: &csv::StringRecord,
+        columns: &[String],
+        line: usize,
+    ) -> Result<Self, SampleError> {
+        let bad_line = |msg: String| SampleError::BadSheetLine(line, msg);
+
+        let mut spec = SampleSpec {
+            line: line,
+            name: String::new(),
+            index: String::new(),
+            max_mismatch: None,
+            index2: None,
+            max_mismatch2: None,
+            output: None,
+            description: None,
+            prefix: None,
+            suffix: None,
+            prefix2: None,
+            suffix2: None,
+            max_reads: None,
+        };
+
+        for (column, field) in columns.iter().zip(record.iter()) {
+            if field.is_empty() {
+                continue;
+            }
+
+            match column.as_str() {
+                "name" => spec.name = field.to_string(),
+                "index" => spec.index = field.to_string(),
+                "mismatches" => {
+                    spec.max_mismatch = Some(field.parse().map_err(|err| {
+                        bad_line(format!("bad mismatches \"{}\": {}", field, err))
+                    })?)
+                }
+                "index2" => spec.index2 = Some(field.to_string()),
+                "mismatches2" => {
+                    spec.max_mismatch2 = Some(field.parse().map_err(|err| {
+                        bad_line(format!("bad mismatches2 \"{}\": {}", field, err))
+                    })?)
+                }
+                "output" => {
+                    if field.contains('/') {
+                        return Err(bad_line(format!("bad output name \"{}\"", field)));
+                    }
+                    spec.output = Some(field.to_string())
+                }
+                "description" => spec.description = Some(field.to_string()),
+                "prefix" => spec.prefix = Some(field.to_string()),
+                "suffix" => spec.suffix = Some(field.to_string()),
+                "prefix2" => spec.prefix2 = Some(field.to_string()),
+                "suffix2" => spec.suffix2 = Some(field.to_string()),
+                "max_reads" => {
+                    spec.max_reads =
+                        Some(field.parse().map_err(|err| {
+                            bad_line(format!("bad max_reads \"{}\": {}", field, err))
+                        })?)
+                }
+                _ => return Err(bad_line(format!("unknown column \"{}\"", column))),
+            }
+        }
+
+        if spec.name.is_empty() {
+            Err(bad_line("no sample name".to_string()))
+        } else if spec.index.is_empty() {
+            Err(bad_line("no sample index".to_string()))
+        } else if spec.index2.is_none() && spec.max_mismatch2.is_some() {
+            Err(bad_line("mismatches2 without index2".to_string()))
+        } else {
+            Ok(spec)
+        }
+    }
+}
+
+/// Sample sheet columns, in the order used for sheets with no header
+const SHEET_COLUMNS: &[&str] = &[
+    "name",
+    "index",
+    "mismatches",
+    "output",
+    "description",
+    "prefix",
+    "suffix",
+    "prefix2",
+    "suffix2",
+    "max_reads",
+    "index2",
+    "mismatches2",
+];
+
+/// Parses a CSV sample sheet with one sample per line. Blank lines
+/// and lines starting with `#` are ignored. The first line is a
+/// header when it has both a `name` and an `index` field, and it
+/// assigns the columns listed in `SHEET_COLUMNS`, in any order, of
+/// which only `name` and `index` are required. Without a header, the
+/// first two columns are the sample name and index and any others
+/// are ignored.
+/// Dual indices give the second index in `index2` for every sample.
+///
+/// # Errors
+///
+/// An error variant is returned, with the sample sheet line number,
+/// for malformed lines, for duplicate sample or output names, and
+/// when only some samples have a second index.
+pub fn parse_sample_sheet(sheet: &str) -> Result<Vec<SampleSpec>, failure::Error> {
+    let mut columns: Vec<String> = SHEET_COLUMNS[..2].iter().map(|c| c.to_string()).collect();
+    let mut header = false;
+    let mut samples: Vec<SampleSpec> = Vec::new();
+
+    for (lineidx, text) in sheet.lines().enumerate() {
+        let line = lineidx + 1;
+        if text.trim().is_empty() || text.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let record = parse_sheet_record(text, line)?;
+
+        let has_field = |name: &str| record.iter().any(|f| f.eq_ignore_ascii_case(name));
+        if !header && samples.is_empty() && has_field("name") && has_field("index") {
+            columns = parse_sheet_header(&record, line)?;
+            header = true;
+            continue;
+        }
+
+        if header && record.len() > columns.len() {
+            return Err(SampleError::BadSheetLine(
+                line,
+                format!("{} fields but {} columns", record.len(), columns.len()),
+            )
+            .into());
+        }
+
+        let spec = SampleSpec::from_record(&record, &columns, line)?;
+
+        if samples.first().map_or(false, |first| {
+            first.index2.is_some() != spec.index2.is_some()
+        }) {
+            return Err(SampleError::BadSheetLine(
+                line,
+                "index2 must be given for all samples or none".to_string(),
+            )
+            .into());
+        }
+
+        for other in samples.iter() {
+            if other.name() == spec.name() || other.output_name() == spec.output_name() {
+                return Err(SampleError::BadSheetLine(
+                    line,
+                    format!("duplicate of line {}", other.line()),
+                )
+                .into());
+            }
+        }
+
+        samples.push(spec);
+    }
+
+    Ok(samples)
+}
+
+// Each line is parsed as its own CSV record, so that errors report
+// line numbers in the sample sheet itself.
+fn parse_sheet_record(text: &str, line: usize) -> Result<csv::StringRecord, SampleError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+
+    match reader.records().next() {
+        Some(Ok(record)) => Ok(record),
+        Some(Err(err)) => Err(SampleError::BadSheetLine(line, err.to_string())),
+        None => Err(SampleError::BadSheetLine(line, "no fields".to_string())),
+    }
 }
 
-fn parse_sample_line(line: &str) -> Result<(String, String), failure::Error> {
-    let mut field_iter = line.split(',');
-    let name = field_iter
-        .next()
-        .ok_or_else(|| SampleError::BadSheetLine(line.to_string()))?;
-    let idx = field_iter
-        .next()
-        .ok_or_else(|| SampleError::BadSheetLine(line.to_string()))?;
-    Ok((name.to_string(), idx.to_string()))
+fn parse_sheet_header(record: &csv::StringRecord, line: usize) -> Result<Vec<String>, SampleError> {
+    let columns: Vec<String> = record.iter().map(|c| c.to_lowercase()).collect();
+
+    for (i, column) in columns.iter().enumerate() {
+        if !SHEET_COLUMNS.contains(&column.as_str()) {
+            return Err(SampleError::BadSheetLine(
+                line,
+                format!("unknown column \"{}\"", column),
+            ));
+        }
+        if columns[..i].contains(column) {
+            return Err(SampleError::BadSheetLine(
+                line,
+                format!("duplicate column \"{}\"", column),
+            ));
+        }
+    }
+
+    Ok(columns)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum SampleError {
-    BadSheetLine(String),
+pub enum SampleError {
+    BadSheetLine(usize, String),
     IndexBadLength(usize, Vec<u8>),
     IndexClash(Vec<u8>),
+    IndexConflicts(Vec<IndexConflict>),
 }
 
 impl fmt::Display for SampleError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            SampleError::BadSheetLine(line) => write!(f, "Bad sample sheet line: \"{}\"", line),
+            SampleError::BadSheetLine(line, msg) => {
+                write!(f, "Bad sample sheet line {}: {}", line, msg)
+            }
             SampleError::IndexBadLength(ilen, idx) => write!(
                 f,
                 "Index length wrong: index \"{}\" but length {}",
@@ -175,8 +934,246 @@ impl fmt::Display for SampleError {
                 "Index clash: index {}",
                 str::from_utf8(idx).unwrap_or("???")
             ),
+            SampleError::IndexConflicts(conflicts) => {
+                write!(f, "Sample indices too similar for mismatch tolerance:")?;
+                for conflict in conflicts.iter() {
+                    write!(f, "\n  {}", conflict)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl error::Error for SampleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(indices: &[&str]) -> Vec<SampleSpec> {
+        let sheet: Vec<String> = indices
+            .iter()
+            .enumerate()
+            .map(|(i, index)| format!("s{},{}\n", i, index))
+            .collect();
+        parse_sample_sheet(&sheet.concat()).unwrap()
+    }
+
+    #[test]
+    fn neighbors() {
+        assert!(hamming_neighbors(b"ACG", 0).is_empty());
+
+        let one = hamming_neighbors(b"ACG", 1);
+        assert!(one.len() == 3 * 4);
+        assert!(one.contains(&b"NCG".to_vec()));
+        assert!(one.iter().all(|seq| hamming_distance(seq, b"ACG") == 1));
+
+        let two = hamming_neighbors(b"ACG", 2);
+        assert!(two.len() == 3 * 4 + 3 * 4 * 4);
+        assert!(two.contains(&b"ANN".to_vec()));
+        assert!(two.iter().all(|seq| hamming_distance(seq, b"ACG") <= 2));
+    }
+
+    #[test]
+    fn sample_map_mismatch() {
+        let mut sample_map = SampleMap::new(4, "unknown");
+        sample_map.insert(b"AAAA".to_vec(), 2, "first").unwrap();
+        sample_map.insert(b"CCCC".to_vec(), 0, "second").unwrap();
+
+        assert!(*sample_map.get(b"AAAA").unwrap() == "first");
+        assert!(*sample_map.get(b"ANAT").unwrap() == "first");
+        assert!(*sample_map.get(b"ANNT").unwrap() == "unknown");
+        assert!(*sample_map.get(b"CCCC").unwrap() == "second");
+        assert!(*sample_map.get(b"CCCA").unwrap() == "unknown");
+
+        assert!(sample_map.insert(b"AACC".to_vec(), 0, "third").is_err());
+    }
+
+    #[test]
+    fn conflicts() {
+        let sheet = samples(&["AAAAAA", "AAAACC", "CCCCCC", "GGGGGG"]);
+
+        let tol = |tolerances: &[usize]| -> Vec<Vec<usize>> {
+            tolerances.iter().map(|t| vec![*t]).collect()
+        };
+
+        assert!(index_conflicts(&sheet, &tol(&[0; 4])).is_empty());
+        assert!(index_conflicts(&sheet, &tol(&[1; 4])).len() == 1);
+        assert!(index_conflicts(&sheet, &tol(&[1; 4]))[0].names() == ("s0", "s1"));
+        assert!(index_conflicts(&sheet, &tol(&[2; 4])).len() == 2);
+        assert!(index_conflicts(&sheet, &tol(&[0, 1, 0, 0])).is_empty());
+        assert!(index_conflicts(&sheet, &tol(&[0, 2, 0, 0])).len() == 1);
+
+        let conflicts = index_conflicts(&sheet, &tol(&[2; 4]));
+        let tolerances = reduced_tolerances(&sheet, &tol(&[2; 4]), &conflicts).unwrap();
+        assert!(tolerances == tol(&[0, 0, 1, 2]));
+
+        let dup_sheet = samples(&["AAAAAA", "AAAAAA"]);
+        let dup_conflicts = index_conflicts(&dup_sheet, &tol(&[1; 2]));
+        assert!(reduced_tolerances(&dup_sheet, &tol(&[1; 2]), &dup_conflicts).is_err());
+    }
+
+    #[test]
+    fn dual_conflicts() {
+        let sheet = parse_sample_sheet(
+            "name,index,index2\n\
+             s0,AAAA,CCCC\n\
+             s1,AAAA,CCGG\n\
+             s2,AAAT,GGGG\n",
+        )
+        .unwrap();
+
+        // Shared first indices are resolved by the second index
+        assert!(index_conflicts(&sheet, &[vec![1, 0], vec![1, 0], vec![1, 0]]).is_empty());
+
+        let requested = vec![vec![1, 1]; 3];
+        let conflicts = index_conflicts(&sheet, &requested);
+        assert!(conflicts.len() == 1);
+        assert!(conflicts[0].names() == ("s0", "s1"));
+        assert!(conflicts[0].part_distances() == &[0, 2]);
+
+        let tolerances = reduced_tolerances(&sheet, &requested, &conflicts).unwrap();
+        assert!(tolerances == vec![vec![1, 0], vec![1, 0], vec![1, 1]]);
+    }
+
+    #[test]
+    fn dual_index_failure() {
+        let mut sample_map = SampleMap::new_dual(4, 4, "unknown");
+        sample_map
+            .insert_with_tolerances(b"AAAACCCC".to_vec(), &[1, 0], "first")
+            .unwrap();
+        sample_map
+            .insert_with_tolerances(b"GGGGTTTT".to_vec(), &[1, 0], "second")
+            .unwrap();
+
+        // A clash with a neighbor of the first sample leaves the first
+        // index parts as they were
+        assert!(sample_map
+            .insert_with_tolerances(b"AAATCCCC".to_vec(), &[0, 0], "third")
+            .is_err());
+
+        assert!(*sample_map.get(b"AAATCCCC").unwrap() == "first");
+        assert!(*sample_map.get(b"AAAACCCA").unwrap() == "unknown");
+        assert!(sample_map.index_failure(b"AAATCCCC") == None);
+
+        assert!(sample_map.index_failure(b"AAAACCCA") == Some(IndexFailure::Index2));
+        assert!(sample_map.index_failure(b"ACGTCCCC") == Some(IndexFailure::Index1));
+        assert!(sample_map.index_failure(b"ACGTACGT") == Some(IndexFailure::Both));
+        assert!(
+            sample_map.index_failure(b"AAATTTTT")
+                == Some(IndexFailure::Hopped(b"AAAA".to_vec(), b"TTTT".to_vec()))
+        );
+
+        let single = SampleMap::new(4, "unknown");
+        assert!(single.index_failure(b"ACGT") == None);
+    }
+
+    #[test]
+    fn dual_index_lookup() {
+        let mut sample_map = SampleMap::new_dual(4, 4, "unknown");
+        sample_map
+            .insert_with_tolerances(b"AAAACCCC".to_vec(), &[1, 1], "first")
+            .unwrap();
+        sample_map
+            .insert_with_tolerances(b"AAAAGGGG".to_vec(), &[1, 1], "second")
+            .unwrap();
+
+        // The first indices are within tolerance of each other, and
+        // the second index tells the samples apart
+        sample_map
+            .insert_with_tolerances(b"AAATTTTT".to_vec(), &[1, 1], "third")
+            .unwrap();
+
+        assert!(*sample_map.get(b"AAAACCCC").unwrap() == "first");
+        assert!(*sample_map.get(b"NAAACCCA").unwrap() == "first");
+        assert!(*sample_map.get(b"AAAAGGGN").unwrap() == "second");
+        assert!(*sample_map.get(b"AAAATTTT").unwrap() == "third");
+        assert!(*sample_map.get(b"AAAGTTTA").unwrap() == "third");
+        assert!(*sample_map.get(b"ANNACCCC").unwrap() == "unknown");
+        assert!(sample_map.find(b"AAAAAAAA").is_none());
+
+        // Only the true index combinations are stored
+        assert!(sample_map.mapping_table().lines().count() == 3);
+
+        // Both indices of the new sample overlap those of the first
+        assert!(sample_map
+            .insert_with_tolerances(b"AAACCCCG".to_vec(), &[0, 1], "fourth")
+            .is_err());
+        assert!(sample_map
+            .insert_with_tolerances(b"AAAACCCC".to_vec(), &[0, 0], "copy")
+            .is_err());
+        assert!(sample_map.sample_indices().len() == 3);
+    }
+
+    #[test]
+    fn sheet_header() {
+        let sheet = "# Sample sheet\n\nName,Index,Mismatches,Output,Description,Max_Reads\n\
+                     one,ACGT,0,one_out,\"First, best\",1000\n\
+                     \n\
+                     # Skipped\n\
+                     two , TGCA\n";
+        let specs = parse_sample_sheet(sheet).unwrap();
+        assert!(specs.len() == 2);
+
+        assert!(specs[0].name() == "one");
+        assert!(specs[0].index() == "ACGT");
+        assert!(specs[0].max_mismatch() == Some(0));
+        assert!(specs[0].output_name() == "one_out");
+        assert!(specs[0].description() == Some("First, best"));
+        assert!(specs[0].max_reads() == Some(1000));
+        assert!(specs[0].line() == 4);
+
+        assert!(specs[1].name() == "two");
+        assert!(specs[1].index() == "TGCA");
+        assert!(specs[1].max_mismatch() == None);
+        assert!(specs[1].output_name() == "two");
+        assert!(specs[1].prefix() == None);
+        assert!(specs[1].suffix2() == None);
+        assert!(specs[1].max_reads() == None);
+        assert!(specs[1].line() == 7);
+    }
+
+    #[test]
+    fn sheet_column_order() {
+        let sheet = "index,name,description\nACGT,one,First\nTGCA,two,\n";
+        let specs = parse_sample_sheet(sheet).unwrap();
+        assert!(specs.len() == 2);
+        assert!(specs[0].name() == "one");
+        assert!(specs[0].index() == "ACGT");
+        assert!(specs[0].description() == Some("First"));
+        assert!(specs[0].line() == 2);
+        assert!(specs[1].name() == "two");
+        assert!(specs[1].index() == "TGCA");
+        assert!(specs[1].description() == None);
+    }
+
+    #[test]
+    fn sheet_legacy() {
+        let specs = parse_sample_sheet("one,ACGT,extra\ntwo,TGCA\n").unwrap();
+        assert!(specs.len() == 2);
+        assert!(specs[0].name() == "one");
+        assert!(specs[0].max_mismatch() == None);
+        assert!(specs[1].index() == "TGCA");
+    }
+
+    #[test]
+    fn sheet_errors() {
+        let line_err = |sheet: &str| match parse_sample_sheet(sheet)
+            .unwrap_err()
+            .downcast::<SampleError>()
+        {
+            Ok(SampleError::BadSheetLine(line, _)) => line,
+            _ => panic!("Expected BadSheetLine"),
+        };
+
+        assert!(line_err("name,index,color\none,ACGT,red\n") == 1);
+        assert!(line_err("name,index\none,ACGT\n\ntwo\n") == 4);
+        assert!(line_err("name,index,mismatches\none,ACGT,x\n") == 2);
+        assert!(line_err("name,index,max_reads\none,ACGT,-1\n") == 2);
+        assert!(line_err("one,ACGT\n# Comment\none,TGCA\n") == 3);
+        assert!(line_err("name,index\none,ACGT,0\n") == 2);
+        assert!(line_err("name,index,index2\none,ACGT,TT\ntwo,TGCA\n") == 3);
+        assert!(line_err("name,index,mismatches2\none,ACGT,1\n") == 2);
+    }
+}
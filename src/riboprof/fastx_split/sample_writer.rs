@@ -0,0 +1,134 @@
+use std::str;
+
+use failure;
+
+use fastx_split::compress::*;
+use fastx_split::fastx::*;
+use fastx_split::linkers::*;
+use fastx_split::ubam::*;
+use umi::*;
+
+/// Position of a record within a read pair, or a single-end read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mate {
+    /// Single-end read
+    Single,
+    /// Mate 1 of a read pair
+    First,
+    /// Mate 2 of a read pair
+    Second,
+}
+
+/// Output for the processed records of one sample, either FastA or
+/// FastQ with the UMI recorded according to a `UmiEncoding`, or
+/// unaligned BAM with the UMI and sample index in SAM tags.
+pub enum SampleWriter {
+    /// FastA or FastQ output, with a second output for mate 2 of
+    /// paired-end reads
+    Fastx(FastxWriter, Option<FastxWriter>),
+    /// Unaligned BAM output holding both mates of paired-end reads
+    Bam(UnalignedBamWriter),
+}
+
+impl SampleWriter {
+    /// Creates a FastA or FastQ output for single-end reads
+    ///
+    /// # Arguments
+    ///
+    /// * `format` is the sequence format for processed records
+    ///
+    /// * `dest` is the output writer for processed records
+    pub fn fastx<W: FinishWrite + 'static>(format: FastxFormat, dest: W) -> Self {
+        SampleWriter::Fastx(FastxWriter::new(format, Box::new(dest)), None)
+    }
+
+    /// Creates FastA or FastQ outputs for paired-end reads
+    ///
+    /// # Arguments
+    ///
+    /// * `format` is the sequence format for processed records
+    ///
+    /// * `dest1` is the output writer for processed mate 1 records
+    ///
+    /// * `dest2` is the output writer for processed mate 2 records
+    pub fn fastx_paired<W1, W2>(format: FastxFormat, dest1: W1, dest2: W2) -> Self
+    where
+        W1: FinishWrite + 'static,
+        W2: FinishWrite + 'static,
+    {
+        SampleWriter::Fastx(
+            FastxWriter::new(format, Box::new(dest1)),
+            Some(FastxWriter::new(format, Box::new(dest2))),
+        )
+    }
+
+    /// Writes one processed record. FastA and FastQ records carry
+    /// the UMI according to `umi_encoding`, while unaligned BAM
+    /// records carry it in the `RX` tag and ignore the description.
+    ///
+    /// # Arguments
+    ///
+    /// * `umi_encoding` is the convention for recording UMIs in
+    /// FastA and FastQ records
+    ///
+    /// * `mate` is the position of the record in a read pair
+    ///
+    /// * `id` is the read name without the UMI
+    ///
+    /// * `desc` is the read description, if any
+    ///
+    /// * `seq` and `qual` are the insert sequence and quality
+    ///
+    /// * `barcode` holds the UMI and sample index of the read
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when a mate 2 record is written
+    /// without a mate 2 output or when problems arise in writing the
+    /// record.
+    pub fn write(
+        &mut self,
+        umi_encoding: UmiEncoding,
+        mate: Mate,
+        id: &str,
+        desc: Option<&str>,
+        seq: &[u8],
+        qual: &[u8],
+        barcode: &Barcode,
+    ) -> Result<(), failure::Error> {
+        match self {
+            SampleWriter::Fastx(ref mut dest1, ref mut dest2) => {
+                let dest = if mate == Mate::Second {
+                    dest2
+                        .as_mut()
+                        .ok_or_else(|| failure::err_msg("No mate 2 output"))?
+                } else {
+                    dest1
+                };
+                let (umi_id, umi_desc) =
+                    umi_encoding.encode(id, desc, str::from_utf8(barcode.umi())?);
+                dest.write(&umi_id, umi_desc.as_ref().map(String::as_str), seq, qual)
+            }
+            SampleWriter::Bam(ref mut dest) => dest.write(mate, id, seq, qual, barcode),
+        }
+    }
+
+    /// Writes all buffered records and finishes the FastA or FastQ
+    /// outputs. Unaligned BAM output is closed by `htslib` only when
+    /// the writer is dropped, and errors in closing it are not
+    /// reported.
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing
+    /// the remaining records or completing the outputs.
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        if let SampleWriter::Fastx(ref mut dest1, ref mut dest2) = self {
+            dest1.finish()?;
+            if let Some(dest2) = dest2.as_mut() {
+                dest2.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use failure;
+
+/// Small pseudo-random number generator (SplitMix64). The sequence of
+/// values depends only on the seed, so a subsample can be reproduced
+/// exactly on any platform.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Creates a generator whose sequence of values is set by `seed`
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    /// Returns the next 64-bit value
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns the next value, uniform on [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Random selection of a fraction of the input reads. Each read is
+/// kept independently, so the number kept varies around the expected
+/// fraction. Reads must be offered in input order for the same reads
+/// to be kept with the same seed.
+#[derive(Debug, Clone)]
+pub struct Subsampler {
+    fraction: f64,
+    seed: u64,
+    rng: SplitMix64,
+}
+
+impl Subsampler {
+    /// Creates a new subsampler
+    ///
+    /// # Arguments
+    ///
+    /// * `fraction` is the probability of keeping each read
+    ///
+    /// * `seed` is the seed for the random number generator
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when `fraction` is not in (0, 1].
+    pub fn new(fraction: f64, seed: u64) -> Result<Self, failure::Error> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(format_err!(
+                "Subsampling fraction {} is not in (0, 1]",
+                fraction
+            ));
+        }
+
+        Ok(Subsampler {
+            fraction: fraction,
+            seed: seed,
+            rng: SplitMix64::new(seed),
+        })
+    }
+
+    /// Returns the fraction of reads kept
+    pub fn fraction(&self) -> f64 {
+        self.fraction
+    }
+
+    /// Returns the random number seed
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns true when the next read should be kept
+    pub fn keep(&mut self) -> bool {
+        self.rng.next_f64() < self.fraction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitmix_values() {
+        let mut rng = SplitMix64::new(1234567);
+        assert!(rng.next_u64() == 6457827717110365317);
+        assert!(rng.next_u64() == 3203168211198807973);
+
+        let mut rng = SplitMix64::new(0);
+        assert!((0..1000)
+            .map(|_| rng.next_f64())
+            .all(|x| x >= 0.0 && x < 1.0));
+    }
+
+    #[test]
+    fn subsample_fraction() {
+        assert!(Subsampler::new(0.0, 1).is_err());
+        assert!(Subsampler::new(1.5, 1).is_err());
+
+        let mut all = Subsampler::new(1.0, 1).unwrap();
+        assert!((0..1000).all(|_| all.keep()));
+
+        let mut tenth = Subsampler::new(0.1, 7).unwrap();
+        let kept: Vec<bool> = (0..10000).map(|_| tenth.keep()).collect();
+        let nkept = kept.iter().filter(|k| **k).count();
+        assert!(nkept > 900 && nkept < 1100);
+
+        let mut again = Subsampler::new(0.1, 7).unwrap();
+        assert!(kept.iter().all(|k| *k == again.keep()));
+    }
+}
@@ -0,0 +1,470 @@
+use std::path::Path;
+use std::rc::Rc;
+use std::str;
+
+use failure;
+use serde_json;
+
+use fastx_split::dispatch::*;
+use fastx_split::index_hopping::*;
+use fastx_split::linkers::*;
+use fastx_split::processor::*;
+use fastx_split::sample::*;
+use fastx_split::sample_sheet::*;
+use fastx_split::subsample::*;
+use fastx_split::unknown_index::*;
+use fastx_split::Config;
+
+/// Number of unassigned index sequences listed in the run summary
+const TOP_UNASSIGNED: usize = 20;
+
+/// Diagnoses the most common unassigned index sequences
+///
+/// # Errors
+///
+/// An error variant is returned when a sample cannot be borrowed.
+pub fn unknown_indices(config: &Config) -> Result<Vec<UnknownIndex>, failure::Error> {
+    let sample_map = config.dispatcher.sample_map();
+    // Dual indices from the sample sheet are the segments that may be
+    // swapped or reverse complemented, wherever they are read from
+    let segments = match sample_map.index1_length() {
+        Some(index1_length) => vec![index1_length, sample_map.index_length() - index1_length],
+        None => config.dispatcher.processor().index_segments(),
+    };
+    let counts = config.dispatcher.counts();
+
+    let mut unknowns = Vec::new();
+    for (index, count) in counts.top_unknown_indices(TOP_UNASSIGNED) {
+        unknowns.push(UnknownIndex::diagnose(index, count, sample_map, &segments)?);
+    }
+    Ok(unknowns)
+}
+
+/// Tallies index hopping and index failures, when the sample sheet
+/// has dual indices
+///
+/// # Errors
+///
+/// An error variant is returned when a sample cannot be borrowed.
+pub fn dual_index_stats(config: &Config) -> Result<Option<DualIndexStats>, failure::Error> {
+    DualIndexStats::new(
+        config.dispatcher.sample_map(),
+        config.dispatcher.counts().unknown_indices(),
+        Sample::total,
+    )
+}
+
+/// Builds a JSON summary of a fastx-split run, covering the inputs,
+/// the read processing parameters, the read counts for each fate and
+/// each sample along with its UMI diversity, and the diagnosed
+/// unassigned index sequences.
+///
+/// # Arguments
+///
+/// * `config` is the run configuration, after all reads are handled
+///
+/// * `unknowns` are the diagnosed unassigned index sequences
+///
+/// # Errors
+///
+/// An error variant is returned when a sample cannot be borrowed.
+pub fn run_summary(
+    config: &Config,
+    unknowns: &[UnknownIndex],
+) -> Result<serde_json::Value, failure::Error> {
+    let processor = config.dispatcher.processor();
+    let counts = config.dispatcher.counts();
+    let total = counts.total();
+
+    let unknown_rc = config.dispatcher.sample_map().unknown();
+
+    let mut samples = Vec::new();
+    for sample_rc in config.dispatcher.sample_map().things() {
+        if !Rc::ptr_eq(&sample_rc, &unknown_rc) {
+            samples.push(sample_summary(&sample_rc.try_borrow()?, total)?);
+        }
+    }
+
+    let unknown = unknown_rc.try_borrow()?;
+    let mut top_indices = Vec::new();
+    for unknown_index in unknowns.iter() {
+        let mistakes: Vec<serde_json::Value> = unknown_index
+            .mistakes()
+            .iter()
+            .map(|mistake| json!({"kind": mistake.kind(), "sample": mistake.sample_name()}))
+            .collect();
+        top_indices.push(json!({
+            "index": str::from_utf8(unknown_index.index())?,
+            "reads": unknown_index.reads(),
+            "fraction": fraction(unknown_index.reads(), total),
+            "closest_sample": unknown_index.closest().map(|(name, _)| name),
+            "closest_distance": unknown_index.closest().map(|(_, distance)| distance),
+            "failure": config
+                .dispatcher
+                .sample_map()
+                .index_failure(unknown_index.index())
+                .as_ref()
+                .map(IndexFailure::kind),
+            "mistakes": mistakes,
+        }));
+    }
+
+    let linker = match processor.paired_spec() {
+        Some(paired_spec) => json!({
+            "mate1": linker_summary(paired_spec.mate1()),
+            "mate2": linker_summary(paired_spec.mate2()),
+        }),
+        None => linker_summary(processor.linker_spec()),
+    };
+
+    let external_index = processor.external_index().map(|external_index| {
+        json!({
+            "source": external_index.source().to_string(),
+            "index1": external_index.index1().prefix_spec(),
+            "index2": external_index.index2().prefix_spec(),
+            "index1_inputs": path_strings(&config.index1_names),
+            "index2_inputs": path_strings(&config.index2_names),
+        })
+    });
+
+    let contaminants = processor.contaminants().map(|contaminants| {
+        json!({
+            "file": config
+                .contaminants_name
+                .as_ref()
+                .map(|name| name.to_string_lossy().into_owned()),
+            "kmer_length": contaminants.kmer_length(),
+            "min_fraction": contaminants.min_fraction(),
+            "sequences": contaminants.sequences(),
+        })
+    });
+
+    let trimmer = processor.trimmer();
+    let trimming = if trimmer.is_active() {
+        Some(json!({
+            "quality": trimmer.quality_trim().map(|(mode, threshold)| json!({
+                "mode": mode.to_string(),
+                "threshold": threshold,
+                "window": trimmer.window(),
+            })),
+            "poly_a": trimmer.poly_a(),
+            "poly_n": trimmer.poly_n(),
+        }))
+    } else {
+        None
+    };
+
+    let subsampler = config.dispatcher.demux().subsampler();
+    let subsampling = json!({
+        "fraction": subsampler.map(Subsampler::fraction),
+        "seed": subsampler.map(Subsampler::seed),
+        "max_reads": config.max_reads,
+        "subsampled": counts.subsampled(),
+        "capped": config.dispatcher.capped(),
+        "stopped_early": config.dispatcher.is_finished(),
+    });
+
+    let dual_index = match dual_index_stats(config)? {
+        Some(stats) => {
+            let hopped: Vec<serde_json::Value> = stats
+                .hopped_combinations()
+                .iter()
+                .map(|(index1, index2, reads)| {
+                    json!({
+                        "index1": String::from_utf8_lossy(index1),
+                        "index2": String::from_utf8_lossy(index2),
+                        "reads": reads,
+                    })
+                })
+                .collect();
+            Some(json!({
+                "index1_failed": stats.failed_index1(),
+                "index2_failed": stats.failed_index2(),
+                "both_failed": stats.failed_both(),
+                "hopped": stats.hopped(),
+                "hopped_fraction": fraction(stats.hopped(), total),
+                "hopped_combinations": hopped,
+            }))
+        }
+        None => None,
+    };
+
+    let mut malformed = serde_json::Map::new();
+    if config.dispatcher.malformed_policy() != MalformedPolicy::Abort {
+        for reason in MalformedReason::all() {
+            malformed.insert(reason.to_string(), json!(counts.malformed(*reason)));
+        }
+    }
+
+    Ok(json!({
+        "inputs": path_strings(&config.input_names),
+        "mate2_inputs": path_strings(&config.mate2_names),
+        "paired": processor.paired_spec().is_some(),
+        // Paired-end counts are read pairs, not individual reads
+        "count_unit": if processor.paired_spec().is_some() { "pairs" } else { "reads" },
+        "linker": linker,
+        "external_index": external_index,
+        "adapter": processor.adapter().map(|adapter| adapter.to_string()),
+        "min_insert": processor.min_insert(),
+        "max_constant_mismatch": processor.max_constant_mismatch(),
+        "contaminants": contaminants,
+        "trimming": trimming,
+        "unaligned_bam": config.unaligned_bam,
+        "subsampling": subsampling,
+        "total": total,
+        "short": counts.tooshort(),
+        "noadapter": counts.noadapter(),
+        "badlinker": counts.badlinker(),
+        "malformed": malformed,
+        "samples": samples,
+        "dual_index": dual_index,
+        "unassigned": {
+            "reads": unknown.total(),
+            "fraction": fraction(unknown.total(), total),
+            "top_indices": top_indices,
+        },
+    }))
+}
+
+fn sample_summary(sample: &Sample, total: usize) -> Result<serde_json::Value, failure::Error> {
+    let umi_stats = sample.umi_stats();
+    let umi_composition: Vec<serde_json::Value> = umi_stats
+        .composition()
+        .iter()
+        .map(|counts| {
+            json!({
+                "A": counts[0],
+                "C": counts[1],
+                "G": counts[2],
+                "T": counts[3],
+                "N": counts[4],
+            })
+        })
+        .collect();
+    let reads_per_umi: Vec<serde_json::Value> = umi_stats
+        .reads_per_umi()
+        .iter()
+        .map(|(reads, umis)| json!({"reads": reads, "umis": umis}))
+        .collect();
+    let trim_counts = sample.trim_counts();
+    Ok(json!({
+        "name": sample.name(),
+        "index": str::from_utf8(sample.index())?,
+        "description": sample.description(),
+        "reads": sample.total(),
+        "max_reads": sample.max_reads(),
+        "fraction": fraction(sample.total(), total),
+        "contaminants": sample.contaminants(),
+        "collapsed": sample.collapsed(),
+        "trimmed": {
+            "quality_inserts": trim_counts.quality_inserts(),
+            "quality_bases": trim_counts.quality_bases(),
+            "tail_inserts": trim_counts.tail_inserts(),
+            "tail_bases": trim_counts.tail_bases(),
+        },
+        "distinct_umis": sample.distinct_umis(),
+        "umi_diversity": {
+            "umi_length": umi_stats.umi_length(),
+            "reads": umi_stats.reads(),
+            "distinct_umis": umi_stats.distinct(),
+            "distinct_called_umis": umi_stats.distinct_called(),
+            "entropy_bits": umi_stats.entropy(),
+            "saturation": umi_stats.saturation(),
+            "expected_collision_rate": umi_stats.expected_collision_rate(),
+            "composition": umi_composition,
+            "reads_per_umi": reads_per_umi,
+        },
+    }))
+}
+
+fn linker_summary(linker_spec: &LinkerSpec) -> serde_json::Value {
+    json!({
+        "prefix": linker_spec.prefix_spec(),
+        "suffix": linker_spec.suffix_spec(),
+    })
+}
+
+fn path_strings<P: AsRef<Path>>(paths: &[P]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.as_ref().to_string_lossy().into_owned())
+        .collect()
+}
+
+// A run with no reads has a fraction of 0 rather than NaN, which is
+// not valid JSON
+fn fraction(count: usize, total: usize) -> f64 {
+    if total > 0 {
+        (count as f64) / (total as f64)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+
+    use bio::io::fastq;
+
+    use fastx_split::compress::*;
+    use fastx_split::demux::*;
+    use fastx_split::fastx::*;
+    use fastx_split::index_reads::*;
+    use umi::*;
+
+    // Runs single-end reads through samples that discard their
+    // records, with the short read output in a scratch directory
+    fn run_reads(name: &str, samples: &[(&str, &str)], reads: &[(&str, &[u8])]) -> Config {
+        let mut output_dir = env::temp_dir();
+        output_dir.push(format!("fastx_split_{}_{}", name, process::id()));
+        fs::create_dir_all(&output_dir).unwrap();
+        let output_files = OutputFiles::new(
+            &output_dir,
+            FastxFormat::Fastq,
+            OutputCompression::Plain,
+            false,
+        );
+
+        let unknown = Sample::new(
+            "UnknownIndex".to_string(),
+            b"NN".to_vec(),
+            FastxFormat::Fastq,
+            UmiEncoding::Hash,
+            io::sink(),
+        );
+        let mut sample_map = SampleMap::new(2, unknown);
+        for (sample_name, index) in samples.iter() {
+            let sample = Sample::new(
+                sample_name.to_string(),
+                index.as_bytes().to_vec(),
+                FastxFormat::Fastq,
+                UmiEncoding::Hash,
+                io::sink(),
+            );
+            sample_map
+                .insert(index.as_bytes().to_vec(), 0, sample)
+                .unwrap();
+        }
+
+        let processor = ReadProcessor::new(LinkerSpec::new("NNII", "").unwrap(), None, None, 3);
+        let dispatcher = Dispatcher::new(
+            Demultiplexer::with_processor(processor, sample_map),
+            FateOutput::create(&output_files, "tooshort", false).unwrap(),
+            None,
+            None,
+            MalformedPolicy::Skip,
+            None,
+        );
+
+        let mut config = Config {
+            fastx_inputs: Vec::new(),
+            mate2_inputs: Vec::new(),
+            index1_inputs: Vec::new(),
+            index2_inputs: Vec::new(),
+            input_names: vec![PathBuf::from("reads.fastq")],
+            mate2_names: Vec::new(),
+            index1_names: Vec::new(),
+            index2_names: Vec::new(),
+            contaminants_name: None,
+            output_dir: output_dir.clone(),
+            unaligned_bam: false,
+            max_reads: None,
+            dispatcher: dispatcher,
+            threads: 1,
+            progress: None,
+        };
+
+        let processor = config.dispatcher.demux().shared_processor();
+        for (id, seq) in reads.iter() {
+            let fq = fastq::Record::with_attrs(id, None, seq, &vec![b'I'; seq.len()]);
+            let (fate, barcode) = processor
+                .indexed_read_fate(&fq, &IndexReads::default())
+                .unwrap();
+            config.dispatcher.handle_read(&fq, fate, &barcode).unwrap();
+        }
+        config.dispatcher.finish_outputs().unwrap();
+        fs::remove_dir_all(&output_dir).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn summary_counts() {
+        let config = run_reads(
+            "summary_counts",
+            &[("one", "AC"), ("two", "GT")],
+            &[
+                ("r1", b"AAACGATTACA"),
+                ("r2", b"CCACGATTACA"),
+                ("r3", b"AAGTGATTACA"),
+                ("r4", b"GGTTGATTACA"),
+                ("r5", b"GGTTCCCCAAA"),
+                ("r6", b"AAACG"),
+                ("r7", b"AATTG"),
+            ],
+        );
+        let unknowns = unknown_indices(&config).unwrap();
+        let summary = run_summary(&config, &unknowns).unwrap();
+
+        assert!(summary["total"] == 7);
+        assert!(summary["short"] == 2);
+        assert!(summary["paired"] == false);
+        assert!(summary["count_unit"] == "reads");
+        assert!(summary["inputs"][0] == "reads.fastq");
+
+        let samples = summary["samples"].as_array().unwrap();
+        assert!(samples.len() == 2);
+        assert!(samples[0]["name"] == "one");
+        assert!(samples[0]["reads"] == 2);
+        assert!((samples[0]["fraction"].as_f64().unwrap() - 2.0 / 7.0).abs() < 1e-9);
+        assert!(samples[1]["name"] == "two");
+        assert!(samples[1]["reads"] == 1);
+        assert!((samples[1]["fraction"].as_f64().unwrap() - 1.0 / 7.0).abs() < 1e-9);
+
+        // UMIs AA and CC, one read each, among 16 possible 2-base UMIs
+        let umis = &samples[0]["umi_diversity"];
+        assert!(umis["umi_length"] == 2);
+        assert!(umis["reads"] == 2);
+        assert!(umis["distinct_umis"] == 2);
+        assert!(umis["distinct_called_umis"] == 2);
+        assert!((umis["entropy_bits"].as_f64().unwrap() - 1.0).abs() < 1e-9);
+        assert!((umis["saturation"].as_f64().unwrap() - 2.0 / 16.0).abs() < 1e-9);
+        assert!(umis["composition"][0] == json!({"A": 1, "C": 1, "G": 0, "T": 0, "N": 0}));
+        assert!(umis["composition"][1] == json!({"A": 1, "C": 1, "G": 0, "T": 0, "N": 0}));
+        assert!(umis["reads_per_umi"] == json!([{"reads": 1, "umis": 2}]));
+
+        // The short read with index TT is counted among the
+        // unassigned indices, though not as an unassigned read
+        let unassigned = &summary["unassigned"];
+        assert!(unassigned["reads"] == 2);
+        assert!((unassigned["fraction"].as_f64().unwrap() - 2.0 / 7.0).abs() < 1e-9);
+        let top_indices = unassigned["top_indices"].as_array().unwrap();
+        assert!(top_indices.len() == 1);
+        assert!(top_indices[0]["index"] == "TT");
+        assert!(top_indices[0]["reads"] == 3);
+        assert!(top_indices[0]["closest_sample"] == "two");
+        assert!(top_indices[0]["closest_distance"] == 1);
+        assert!(top_indices[0]["mistakes"] == json!([]));
+        assert!(summary["dual_index"].is_null());
+    }
+
+    #[test]
+    fn summary_without_reads() {
+        let config = run_reads("summary_without_reads", &[("one", "AC")], &[]);
+        let unknowns = unknown_indices(&config).unwrap();
+        assert!(unknowns.is_empty());
+
+        let summary = run_summary(&config, &unknowns).unwrap();
+        assert!(summary["total"] == 0);
+        assert!(summary["samples"][0]["fraction"] == 0.0);
+        assert!(summary["samples"][0]["umi_diversity"]["distinct_umis"] == 0);
+        assert!(summary["unassigned"]["top_indices"] == json!([]));
+    }
+}
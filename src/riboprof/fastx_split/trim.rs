@@ -0,0 +1,392 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure;
+
+use fastx_split::fastx::*;
+
+/// Algorithm for trimming low-quality bases from the 3' end of an
+/// insert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QualityTrimMode {
+    /// Trim to the position that maximizes the sum of the threshold
+    /// minus the base quality over the trimmed bases, as in BWA
+    Bwa,
+    /// Trim bases from the 3' end until the window of bases ending at
+    /// the new 3' end has a mean quality at or above the threshold
+    Window,
+}
+
+impl QualityTrimMode {
+    /// Returns the name strings accepted by `from_str`
+    pub fn names() -> &'static [&'static str] {
+        &["bwa", "window"]
+    }
+}
+
+impl FromStr for QualityTrimMode {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bwa" => Ok(QualityTrimMode::Bwa),
+            "window" => Ok(QualityTrimMode::Window),
+            _ => Err(format_err!(
+                "Unknown quality trimming mode \"{}\", expected one of {}",
+                name,
+                QualityTrimMode::names().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for QualityTrimMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            QualityTrimMode::Bwa => "bwa",
+            QualityTrimMode::Window => "window",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Number of bases removed from the 3' end of an insert by quality
+/// trimming and by tail stripping
+#[derive(Debug, Clone, Copy, Default, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct InsertTrim {
+    quality: usize,
+    tail: usize,
+}
+
+impl InsertTrim {
+    /// Returns the number of low-quality bases removed
+    pub fn quality(&self) -> usize {
+        self.quality
+    }
+
+    /// Returns the number of poly(A) and poly(N) tail bases removed
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    /// Returns the total number of bases removed
+    pub fn total(&self) -> usize {
+        self.quality + self.tail
+    }
+}
+
+/// Trimming of the 3' end of inserts after linker removal. Low-quality
+/// bases are trimmed first, and then a poly(N) tail followed by a
+/// poly(A) tail are stripped from what remains.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertTrimmer {
+    quality_mode: QualityTrimMode,
+    quality_threshold: Option<u8>,
+    window: usize,
+    poly_a_min: Option<usize>,
+    poly_n: bool,
+}
+
+impl InsertTrimmer {
+    /// Creates an insert trimmer that does no trimming
+    pub fn new() -> Self {
+        InsertTrimmer {
+            quality_mode: QualityTrimMode::Bwa,
+            quality_threshold: None,
+            window: 1,
+            poly_a_min: None,
+            poly_n: false,
+        }
+    }
+
+    /// Returns true when the trimmer removes any bases
+    pub fn is_active(&self) -> bool {
+        self.quality_threshold.is_some() || self.poly_a_min.is_some() || self.poly_n
+    }
+
+    /// Sets 3' quality trimming
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` is the quality trimming algorithm
+    ///
+    /// * `threshold` is the Phred quality threshold
+    ///
+    /// * `window` is the window length for `QualityTrimMode::Window`
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the window length is zero.
+    pub fn set_quality_trim(
+        &mut self,
+        mode: QualityTrimMode,
+        threshold: u8,
+        window: usize,
+    ) -> Result<(), failure::Error> {
+        if window == 0 {
+            return Err(failure::err_msg("Quality trimming window length is zero"));
+        }
+        self.quality_mode = mode;
+        self.quality_threshold = Some(threshold);
+        self.window = window;
+        Ok(())
+    }
+
+    /// Sets poly(A) tail stripping, removing a run of `A` bases at the
+    /// 3' end when it is at least `min_length` long
+    pub fn set_poly_a(&mut self, min_length: usize) {
+        self.poly_a_min = Some(min_length.max(1));
+    }
+
+    /// Sets poly(N) tail stripping, removing any run of `N` bases at
+    /// the 3' end
+    pub fn set_poly_n(&mut self, poly_n: bool) {
+        self.poly_n = poly_n;
+    }
+
+    /// Returns the quality trimming algorithm and Phred threshold, if
+    /// inserts are quality trimmed
+    pub fn quality_trim(&self) -> Option<(QualityTrimMode, u8)> {
+        self.quality_threshold
+            .map(|threshold| (self.quality_mode, threshold))
+    }
+
+    /// Returns the quality trimming window length
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Returns the minimum poly(A) tail length, if poly(A) tails are
+    /// stripped
+    pub fn poly_a(&self) -> Option<usize> {
+        self.poly_a_min
+    }
+
+    /// Returns true when poly(N) tails are stripped
+    pub fn poly_n(&self) -> bool {
+        self.poly_n
+    }
+
+    /// Determines the number of bases to remove from the 3' end of an
+    /// insert. Inserts without qualities, from FastA input, are not
+    /// quality trimmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` is the insert sequence
+    ///
+    /// * `qual` is the insert quality string
+    ///
+    /// * `strip_tail` is true when poly(A) and poly(N) tails should be
+    /// stripped
+    pub fn trim(&self, seq: &[u8], qual: &[u8], strip_tail: bool) -> InsertTrim {
+        let mut end = seq.len();
+
+        if let Some(threshold) = self.quality_threshold {
+            if qual.len() == seq.len() {
+                end = match self.quality_mode {
+                    QualityTrimMode::Bwa => bwa_trim_end(qual, threshold),
+                    QualityTrimMode::Window => window_trim_end(qual, threshold, self.window),
+                };
+            }
+        }
+        let quality = seq.len() - end;
+
+        if strip_tail {
+            if self.poly_n {
+                end -= tail_length(&seq[..end], b'N');
+            }
+            if let Some(min_length) = self.poly_a_min {
+                let poly_a = tail_length(&seq[..end], b'A');
+                if poly_a >= min_length {
+                    end -= poly_a;
+                }
+            }
+        }
+
+        InsertTrim {
+            quality: quality,
+            tail: seq.len() - quality - end,
+        }
+    }
+}
+
+impl Default for InsertTrimmer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts of inserts, and of bases, trimmed from the 3' end of the
+/// reads in one sample. Each mate of a read pair is counted
+/// separately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrimCounts {
+    quality_inserts: usize,
+    quality_bases: usize,
+    tail_inserts: usize,
+    tail_bases: usize,
+}
+
+impl TrimCounts {
+    /// Creates trimming counts with no reads tallied
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tallies the bases trimmed from one insert
+    pub fn tally(&mut self, insert_trim: InsertTrim) {
+        if insert_trim.quality() > 0 {
+            self.quality_inserts += 1;
+            self.quality_bases += insert_trim.quality();
+        }
+        if insert_trim.tail() > 0 {
+            self.tail_inserts += 1;
+            self.tail_bases += insert_trim.tail();
+        }
+    }
+
+    /// Returns the number of inserts with low-quality bases trimmed
+    pub fn quality_inserts(&self) -> usize {
+        self.quality_inserts
+    }
+
+    /// Returns the total number of low-quality bases trimmed
+    pub fn quality_bases(&self) -> usize {
+        self.quality_bases
+    }
+
+    /// Returns the number of inserts with a tail stripped
+    pub fn tail_inserts(&self) -> usize {
+        self.tail_inserts
+    }
+
+    /// Returns the total number of tail bases stripped
+    pub fn tail_bases(&self) -> usize {
+        self.tail_bases
+    }
+}
+
+// Phred quality of a FastQ quality character
+fn phred(qual: u8) -> i64 {
+    i64::from(qual.saturating_sub(PHRED_OFFSET))
+}
+
+// BWA-style trimming: the new end maximizes the sum of the threshold
+// minus the quality over the bases after it, scanning from the 3'
+// end until the running sum drops below zero
+fn bwa_trim_end(qual: &[u8], threshold: u8) -> usize {
+    let mut end = qual.len();
+    let mut sum = 0;
+    let mut max_sum = 0;
+    for pos in (0..qual.len()).rev() {
+        sum += i64::from(threshold) - phred(qual[pos]);
+        if sum < 0 {
+            break;
+        }
+        if sum > max_sum {
+            max_sum = sum;
+            end = pos;
+        }
+    }
+    end
+}
+
+// Sliding window trimming: the new end is the end of the last window
+// whose mean quality reaches the threshold, using the whole insert as
+// the window when it is shorter than the window length
+fn window_trim_end(qual: &[u8], threshold: u8, window: usize) -> usize {
+    let window = window.min(qual.len());
+    if window == 0 {
+        return 0;
+    }
+
+    let min_sum = i64::from(threshold) * (window as i64);
+    let mut sum: i64 = qual[(qual.len() - window)..]
+        .iter()
+        .map(|&q| phred(q))
+        .sum();
+    let mut end = qual.len();
+    while sum < min_sum {
+        if end == window {
+            return 0;
+        }
+        end -= 1;
+        sum += phred(qual[end - window]) - phred(qual[end]);
+    }
+    end
+}
+
+// Length of the run of `nt`, in either case, at the end of `seq`
+fn tail_length(seq: &[u8], nt: u8) -> usize {
+    seq.iter()
+        .rev()
+        .take_while(|&&seq_nt| seq_nt.to_ascii_uppercase() == nt)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bwa_trim() {
+        // Phred 30 30 30 30 10 30 5 2
+        let qual = b"????+?&#";
+        assert!(bwa_trim_end(qual, 20) == 6);
+        assert!(bwa_trim_end(qual, 3) == 7);
+        assert!(bwa_trim_end(qual, 1) == 8);
+        assert!(bwa_trim_end(b"", 20) == 0);
+        assert!(bwa_trim_end(b"####", 20) == 0);
+    }
+
+    #[test]
+    fn window_trim() {
+        // Phred 30 30 30 30 10 30 5 2
+        let qual = b"????+?&#";
+        assert!(window_trim_end(qual, 20, 2) == 6);
+        assert!(window_trim_end(qual, 20, 3) == 6);
+        assert!(window_trim_end(qual, 20, 1) == 6);
+        assert!(window_trim_end(qual, 25, 1) == 6);
+        assert!(window_trim_end(qual, 20, 20) == 8);
+        assert!(window_trim_end(qual, 25, 20) == 0);
+        assert!(window_trim_end(b"?????", 20, 20) == 5);
+        assert!(window_trim_end(b"", 20, 2) == 0);
+    }
+
+    #[test]
+    fn insert_trim() {
+        let mut trimmer = InsertTrimmer::new();
+        assert!(!trimmer.is_active());
+        assert!(trimmer.trim(b"ACGTAAAA", b"IIIIIIII", true) == InsertTrim::default());
+
+        trimmer.set_poly_a(3);
+        trimmer.set_poly_n(true);
+        assert!(trimmer.is_active());
+        let trim = trimmer.trim(b"ACGTaaaann", b"IIIIIIIIII", true);
+        assert!(trim.quality() == 0 && trim.tail() == 6);
+        assert!(trimmer.trim(b"ACGTaaaann", b"IIIIIIIIII", false) == InsertTrim::default());
+        assert!(trimmer.trim(b"ACGTCAAN", b"", true).tail() == 1);
+
+        trimmer
+            .set_quality_trim(QualityTrimMode::Bwa, 20, 1)
+            .unwrap();
+        let trim = trimmer.trim(b"ACGTAAAAGC", b"IIIIIIII##", true);
+        assert!(trim.quality() == 2 && trim.tail() == 4 && trim.total() == 6);
+        let trim = trimmer.trim(b"ACGTAAAAGC", b"", true);
+        assert!(trim.quality() == 0 && trim.tail() == 0);
+
+        assert!(trimmer
+            .set_quality_trim(QualityTrimMode::Window, 20, 0)
+            .is_err());
+
+        let mut counts = TrimCounts::new();
+        counts.tally(trimmer.trim(b"ACGTAAAAGC", b"IIIIIIII##", true));
+        counts.tally(trimmer.trim(b"ACGTCAAAAA", b"IIIIIIIIII", true));
+        counts.tally(InsertTrim::default());
+        assert!(counts.quality_inserts() == 1 && counts.quality_bases() == 2);
+        assert!(counts.tail_inserts() == 2 && counts.tail_bases() == 9);
+        assert!("window".parse::<QualityTrimMode>().unwrap() == QualityTrimMode::Window);
+        assert!("sliding".parse::<QualityTrimMode>().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::path::Path;
+
+use failure;
+
+use rust_htslib::bam;
+use rust_htslib::bam::header::HeaderRecord;
+use rust_htslib::bam::record::{Aux, CigarString};
+
+use fastx_split::fastx::*;
+use fastx_split::linkers::*;
+use fastx_split::sample_writer::*;
+
+/// BAM quality byte for bases with no quality information
+const MISSING_QUALITY: u8 = 0xff;
+
+/// Read group for the records of one sample in unaligned BAM output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadGroup {
+    id: String,
+    sample_index: Vec<u8>,
+    description: Option<String>,
+}
+
+impl ReadGroup {
+    /// Creates a read group for a sample
+    ///
+    /// # Arguments
+    ///
+    /// * `id` is the sample name, used as the read group ID and as
+    /// the sample (`SM`) field
+    ///
+    /// * `sample_index` is the sample index sequence, reported in the
+    /// barcode (`BC`) field when not empty
+    ///
+    /// * `description` is the sample sheet description, reported in
+    /// the description (`DS`) field when present
+    pub fn new(id: &str, sample_index: &[u8], description: Option<&str>) -> Self {
+        ReadGroup {
+            id: id.to_string(),
+            sample_index: sample_index.to_vec(),
+            description: description.map(|desc| desc.to_string()),
+        }
+    }
+
+    /// Returns the read group ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn header(&self) -> bam::Header {
+        let mut header = bam::Header::new();
+        header.push_record(
+            HeaderRecord::new(b"HD")
+                .push_tag(b"VN", &"1.6")
+                .push_tag(b"SO", &"unsorted"),
+        );
+
+        let mut rg = HeaderRecord::new(b"RG");
+        rg.push_tag(b"ID", &self.id).push_tag(b"SM", &self.id);
+        if !self.sample_index.is_empty() {
+            rg.push_tag(b"BC", &String::from_utf8_lossy(&self.sample_index));
+        }
+        if let Some(ref desc) = self.description {
+            rg.push_tag(b"DS", desc);
+        }
+        header.push_record(&rg);
+
+        header.push_record(
+            HeaderRecord::new(b"PG")
+                .push_tag(b"ID", &"fastx-split")
+                .push_tag(b"PN", &"fastx-split"),
+        );
+        header
+    }
+}
+
+/// Writes processed reads as unaligned BAM records. The UMI is placed
+/// in the `RX` tag, its quality in the `QX` tag, the sample index in
+/// the `BC` tag, and the read group in the `RG` tag, leaving the read
+/// name unchanged.
+pub struct UnalignedBamWriter {
+    writer: bam::Writer,
+    read_group: ReadGroup,
+}
+
+impl UnalignedBamWriter {
+    /// Creates an unaligned BAM output whose header declares a single
+    /// read group
+    ///
+    /// # Arguments
+    ///
+    /// * `path` is the name of the BAM output file
+    ///
+    /// * `read_group` is the read group for all records
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when the output file cannot be
+    /// created.
+    pub fn from_path<P: AsRef<Path>>(
+        path: P,
+        read_group: ReadGroup,
+    ) -> Result<Self, failure::Error> {
+        let writer = bam::Writer::from_path(path.as_ref(), &read_group.header())?;
+        Ok(UnalignedBamWriter {
+            writer: writer,
+            read_group: read_group,
+        })
+    }
+
+    /// Writes one unaligned record. Records from FastA input, with no
+    /// quality, are written with missing base qualities. The `QX` tag
+    /// is omitted when some UMI bases have no quality.
+    ///
+    /// # Arguments
+    ///
+    /// * `mate` is the position of the record in a read pair
+    ///
+    /// * `name` is the read name
+    ///
+    /// * `seq` and `qual` are the insert sequence and its FastQ
+    /// quality string
+    ///
+    /// * `barcode` holds the UMI and sample index of the read
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when problems arise in writing the
+    /// record.
+    pub fn write(
+        &mut self,
+        mate: Mate,
+        name: &str,
+        seq: &[u8],
+        qual: &[u8],
+        barcode: &Barcode,
+    ) -> Result<(), failure::Error> {
+        let bam_qual: Vec<u8> = if qual.len() == seq.len() {
+            qual.iter()
+                .map(|q| q.saturating_sub(PHRED_OFFSET))
+                .collect()
+        } else {
+            vec![MISSING_QUALITY; seq.len()]
+        };
+
+        let mut rec = bam::Record::new();
+        rec.set(name.as_bytes(), &CigarString(Vec::new()), seq, &bam_qual);
+        rec.set_tid(-1);
+        rec.set_pos(-1);
+        rec.set_mtid(-1);
+        rec.set_mpos(-1);
+        rec.set_unmapped();
+        match mate {
+            Mate::Single => (),
+            Mate::First => {
+                rec.set_paired();
+                rec.set_mate_unmapped();
+                rec.set_first_in_template();
+            }
+            Mate::Second => {
+                rec.set_paired();
+                rec.set_mate_unmapped();
+                rec.set_last_in_template();
+            }
+        }
+
+        if !barcode.umi().is_empty() {
+            rec.push_aux(b"RX", &Aux::String(barcode.umi()))?;
+            if barcode.umi_quality().len() == barcode.umi().len() {
+                rec.push_aux(b"QX", &Aux::String(barcode.umi_quality()))?;
+            }
+        }
+        if !barcode.sample_index().is_empty() {
+            rec.push_aux(b"BC", &Aux::String(barcode.sample_index()))?;
+        }
+        rec.push_aux(b"RG", &Aux::String(self.read_group.id().as_bytes()))?;
+
+        self.writer.write(&rec)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use rust_htslib::bam::Read as BamRead;
+
+    use umi::*;
+
+    // Reads back the header text and records of a BAM file
+    fn read_bam(path: &Path) -> (String, Vec<bam::Record>) {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let header = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+        let records = reader.records().map(Result::unwrap).collect();
+        (header, records)
+    }
+
+    #[test]
+    fn unaligned_bam() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("fastx_split_ubam_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let linker_spec = LinkerSpec::new("IINN", "").unwrap();
+        let fastq_barcode = linker_spec.barcode(b"ACGT", b"II+5").unwrap();
+        let fasta_barcode = linker_spec.barcode(b"ACGT", b"").unwrap();
+
+        let paired_path = dir.join("paired.bam");
+        {
+            let read_group = ReadGroup::new("one", b"AC", Some("first sample"));
+            let mut writer =
+                SampleWriter::Bam(UnalignedBamWriter::from_path(&paired_path, read_group).unwrap());
+            for &(mate, seq) in [(Mate::First, b"GGAT"), (Mate::Second, b"TTCA")].iter() {
+                writer
+                    .write(
+                        UmiEncoding::Hash,
+                        mate,
+                        "pair",
+                        Some("1:N:0:AC"),
+                        seq,
+                        b"I5+#",
+                        &fastq_barcode,
+                    )
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        let (header, records) = read_bam(&paired_path);
+        assert!(header.contains("@RG\tID:one\tSM:one\tBC:AC\tDS:first sample\n"));
+        assert!(records.len() == 2);
+        for rec in records.iter() {
+            assert!(rec.qname() == b"pair");
+            assert!(rec.is_unmapped() && rec.is_paired() && rec.is_mate_unmapped());
+            assert!(rec.qual() == &[40, 20, 10, 2]);
+            assert!(rec.aux(b"RX") == Some(Aux::String(b"GT")));
+            assert!(rec.aux(b"QX") == Some(Aux::String(b"+5")));
+            assert!(rec.aux(b"BC") == Some(Aux::String(b"AC")));
+            assert!(rec.aux(b"RG") == Some(Aux::String(b"one")));
+        }
+        assert!(records[0].seq().as_bytes() == b"GGAT");
+        assert!(records[0].is_first_in_template() && !records[0].is_last_in_template());
+        assert!(records[1].seq().as_bytes() == b"TTCA");
+        assert!(records[1].is_last_in_template() && !records[1].is_first_in_template());
+
+        // FastA input has no base or UMI qualities, and an empty
+        // sample index or description is left out of the read group
+        let fasta_path = dir.join("fasta.bam");
+        {
+            let read_group = ReadGroup::new("two", b"", None);
+            let mut writer = UnalignedBamWriter::from_path(&fasta_path, read_group).unwrap();
+            writer
+                .write(Mate::Single, "single", b"GGAT", b"", &fasta_barcode)
+                .unwrap();
+        }
+
+        let (header, records) = read_bam(&fasta_path);
+        assert!(header.contains("@RG\tID:two\tSM:two\n"));
+        assert!(records.len() == 1);
+        assert!(records[0].qual() == &[MISSING_QUALITY; 4]);
+        assert!(!records[0].is_paired());
+        assert!(records[0].aux(b"RX") == Some(Aux::String(b"GT")));
+        assert!(records[0].aux(b"QX").is_none());
+        assert!(records[0].aux(b"RG") == Some(Aux::String(b"two")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Nucleotides reported in the per-position UMI composition. Any
+/// other character is counted as `N`.
+const UMI_NTS: &[u8] = b"ACGTN";
+
+/// Summary of UMI diversity and saturation for the reads of one
+/// sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UmiStats {
+    umi_length: usize,
+    reads: usize,
+    distinct: usize,
+    distinct_called: usize,
+    entropy: f64,
+    composition: Vec<[usize; 5]>,
+    reads_per_umi: BTreeMap<usize, usize>,
+}
+
+impl UmiStats {
+    /// Summarizes UMI read counts
+    ///
+    /// # Arguments
+    ///
+    /// * `umi_count` maps each observed UMI to its number of reads
+    pub fn new(umi_count: &HashMap<Vec<u8>, usize>) -> Self {
+        let umi_length = umi_count.keys().map(Vec::len).max().unwrap_or(0);
+        let reads: usize = umi_count.values().sum();
+
+        let mut composition = vec![[0; 5]; umi_length];
+        let mut reads_per_umi = BTreeMap::new();
+        let mut entropy = 0.0;
+        let mut distinct_called = 0;
+
+        for (umi, count) in umi_count.iter() {
+            if umi.iter().all(|nt| UMI_NTS[..4].contains(nt)) {
+                distinct_called += 1;
+            }
+
+            for (pos, nt) in umi.iter().enumerate() {
+                let nt_idx = UMI_NTS.iter().position(|ch| ch == nt).unwrap_or(4);
+                composition[pos][nt_idx] += *count;
+            }
+
+            *reads_per_umi.entry(*count).or_insert(0) += 1;
+
+            if *count > 0 {
+                let p = (*count as f64) / (reads as f64);
+                entropy -= p * p.log2();
+            }
+        }
+
+        UmiStats {
+            umi_length: umi_length,
+            reads: reads,
+            distinct: umi_count.len(),
+            distinct_called: distinct_called,
+            entropy: entropy,
+            composition: composition,
+            reads_per_umi: reads_per_umi,
+        }
+    }
+
+    /// Returns the UMI length
+    pub fn umi_length(&self) -> usize {
+        self.umi_length
+    }
+
+    /// Returns the number of reads with a UMI
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Returns the number of distinct UMIs observed
+    pub fn distinct(&self) -> usize {
+        self.distinct
+    }
+
+    /// Returns the Shannon entropy, in bits, of the UMI distribution
+    /// over reads
+    pub fn entropy(&self) -> f64 {
+        self.entropy
+    }
+
+    /// Returns the largest possible entropy, in bits, for the UMI
+    /// length, when all UMIs without `N` are equally common
+    pub fn max_entropy(&self) -> f64 {
+        2.0 * (self.umi_length as f64)
+    }
+
+    /// Returns the number of possible UMIs without `N` for the UMI
+    /// length
+    pub fn umi_space(&self) -> f64 {
+        4.0f64.powi(self.umi_length as i32)
+    }
+
+    /// Returns the number of distinct UMIs observed without `N` or
+    /// any other uncalled base
+    pub fn distinct_called(&self) -> usize {
+        self.distinct_called
+    }
+
+    /// Returns the fraction of possible UMIs without `N` that are
+    /// observed
+    pub fn saturation(&self) -> f64 {
+        (self.distinct_called as f64) / self.umi_space()
+    }
+
+    /// Returns the expected fraction of molecules that share their UMI
+    /// with another molecule, when as many molecules as distinct
+    /// UMIs observed are labeled with uniformly random UMIs. This is
+    /// a lower bound, as colliding molecules are observed as one
+    /// distinct UMI.
+    pub fn expected_collision_rate(&self) -> f64 {
+        if self.distinct == 0 {
+            return 0.0;
+        }
+
+        let space = self.umi_space();
+        let molecules = self.distinct as f64;
+        // Expected distinct UMIs among n molecules, space * (1 - (1 - 1/space)^n),
+        // computed stably for large spaces
+        let unseen = (molecules * (-1.0 / space).ln_1p()).exp();
+        let expected_distinct = -space * (unseen - 1.0);
+        1.0 - expected_distinct / molecules
+    }
+
+    /// Returns the number of reads with each nucleotide, in the order
+    /// A, C, G, T, N, at each UMI position
+    pub fn composition(&self) -> &[[usize; 5]] {
+        &self.composition
+    }
+
+    /// Returns the number of UMIs observed with each read count
+    pub fn reads_per_umi(&self) -> &BTreeMap<usize, usize> {
+        &self.reads_per_umi
+    }
+
+    /// Returns a text report of the UMI summary with three
+    /// tab-separated sections: summary statistics, the per-position
+    /// nucleotide composition, and the reads-per-UMI histogram.
+    pub fn table(&self) -> String {
+        let mut table = String::new();
+        table.push_str(&format!("reads\t{}\n", self.reads));
+        table.push_str(&format!("distinct_umis\t{}\n", self.distinct));
+        table.push_str(&format!("distinct_called_umis\t{}\n", self.distinct_called));
+        table.push_str(&format!("umi_length\t{}\n", self.umi_length));
+        table.push_str(&format!("umi_space\t{}\n", self.umi_space()));
+        table.push_str(&format!("saturation\t{:.6}\n", self.saturation()));
+        table.push_str(&format!("entropy_bits\t{:.4}\n", self.entropy));
+        table.push_str(&format!("max_entropy_bits\t{:.4}\n", self.max_entropy()));
+        table.push_str(&format!(
+            "expected_collision_rate\t{:.6}\n",
+            self.expected_collision_rate()
+        ));
+
+        table.push_str("\nposition\tA\tC\tG\tT\tN\n");
+        for (pos, counts) in self.composition.iter().enumerate() {
+            table.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                pos + 1,
+                counts[0],
+                counts[1],
+                counts[2],
+                counts[3],
+                counts[4]
+            ));
+        }
+
+        table.push_str("\nreads_per_umi\tumis\n");
+        for (reads, umis) in self.reads_per_umi.iter() {
+            table.push_str(&format!("{}\t{}\n", reads, umis));
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn umi_stats() {
+        let mut umi_count = HashMap::new();
+        umi_count.insert(b"AC".to_vec(), 2);
+        umi_count.insert(b"AG".to_vec(), 1);
+        umi_count.insert(b"TN".to_vec(), 1);
+
+        let stats = UmiStats::new(&umi_count);
+        assert!(stats.umi_length() == 2);
+        assert!(stats.reads() == 4);
+        assert!(stats.distinct() == 3);
+        assert!(stats.distinct_called() == 2);
+        assert!(stats.saturation() == 2.0 / 16.0);
+        assert!((stats.entropy() - 1.5).abs() < 1e-9);
+        assert!(stats.max_entropy() == 4.0);
+        assert!(stats.umi_space() == 16.0);
+        assert!(stats.composition() == &[[3, 0, 0, 1, 0], [0, 2, 1, 0, 1]]);
+        assert!(stats.reads_per_umi().iter().collect::<Vec<_>>() == vec![(&1, &2), (&2, &1)]);
+
+        // 3 molecules among 16 UMIs: 16 * (1 - (15/16)^3) distinct expected
+        let expected = 1.0 - 16.0 * (1.0 - (15.0f64 / 16.0).powi(3)) / 3.0;
+        assert!((stats.expected_collision_rate() - expected).abs() < 1e-9);
+
+        let table = stats.table();
+        assert!(table.starts_with(
+            "reads\t4\ndistinct_umis\t3\ndistinct_called_umis\t2\numi_length\t2\numi_space\t16\n"
+        ));
+        assert!(table.contains("\nsaturation\t0.125000\n"));
+        assert!(table.contains("\nposition\tA\tC\tG\tT\tN\n1\t3\t0\t0\t1\t0\n2\t0\t2\t1\t0\t1\n"));
+        assert!(table.ends_with("\nreads_per_umi\tumis\n1\t2\n2\t1\n"));
+
+        let empty = UmiStats::new(&HashMap::new());
+        assert!(empty.distinct() == 0);
+        assert!(empty.expected_collision_rate() == 0.0);
+    }
+}
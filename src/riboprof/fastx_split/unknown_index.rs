@@ -0,0 +1,238 @@
+use std::fmt;
+use std::str;
+
+use failure;
+
+use bio::alphabets::dna;
+
+use fastx_split::sample_sheet::*;
+
+/// Likely sample sheet mistake that would explain an unknown index
+/// sequence, naming the sample that the corrected index matches.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IndexMistake {
+    /// The whole index is reverse complemented
+    ReverseComplement(String),
+    /// Each index segment is reverse complemented in place
+    SegmentReverseComplement(String),
+    /// The two index segments are swapped
+    SwappedSegments(String),
+}
+
+impl IndexMistake {
+    /// Returns the name of the sample matched by the corrected index
+    pub fn sample_name(&self) -> &str {
+        match self {
+            IndexMistake::ReverseComplement(name) => name,
+            IndexMistake::SegmentReverseComplement(name) => name,
+            IndexMistake::SwappedSegments(name) => name,
+        }
+    }
+
+    /// Returns a short description of the mistake
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IndexMistake::ReverseComplement(_) => "revcomp",
+            IndexMistake::SegmentReverseComplement(_) => "segment_revcomp",
+            IndexMistake::SwappedSegments(_) => "swapped",
+        }
+    }
+}
+
+impl fmt::Display for IndexMistake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind(), self.sample_name())
+    }
+}
+
+/// Diagnosis of an index sequence that is not assigned to any sample
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownIndex {
+    index: Vec<u8>,
+    reads: usize,
+    closest: Option<(String, usize)>,
+    mistakes: Vec<IndexMistake>,
+}
+
+impl UnknownIndex {
+    /// Diagnoses an unknown index sequence by finding the closest
+    /// sample index and any likely sample sheet mistakes. A mistake
+    /// is reported when the corrected index is assigned to a sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` is the unknown index sequence
+    ///
+    /// * `reads` is the number of reads with the unknown index
+    ///
+    /// * `sample_map` maps index sequences to samples
+    ///
+    /// * `segments` gives the lengths of the separate parts of the
+    /// index, e.g., the mate 1 and mate 2 index bases
+    ///
+    /// # Errors
+    ///
+    /// An error variant is returned when a sample cannot be borrowed.
+    pub fn diagnose<T: fmt::Display>(
+        index: &[u8],
+        reads: usize,
+        sample_map: &SampleMap<T>,
+        segments: &[usize],
+    ) -> Result<Self, failure::Error> {
+        let closest = match sample_map.closest(index) {
+            Some((thing, distance)) => Some((thing.try_borrow()?.to_string(), distance)),
+            None => None,
+        };
+
+        let mut mistakes = Vec::new();
+
+        if let Some(thing) = sample_map.find(&dna::revcomp(index)) {
+            mistakes.push(IndexMistake::ReverseComplement(
+                thing.try_borrow()?.to_string(),
+            ));
+        }
+
+        let parts = Self::segments(index, segments);
+        if parts.len() == 2 {
+            let segment_revcomp: Vec<u8> =
+                parts.iter().flat_map(|part| dna::revcomp(*part)).collect();
+            if let Some(thing) = sample_map.find(&segment_revcomp) {
+                mistakes.push(IndexMistake::SegmentReverseComplement(
+                    thing.try_borrow()?.to_string(),
+                ));
+            }
+
+            let swapped: Vec<u8> = parts[1].iter().chain(parts[0].iter()).cloned().collect();
+            if let Some(thing) = sample_map.find(&swapped) {
+                mistakes.push(IndexMistake::SwappedSegments(
+                    thing.try_borrow()?.to_string(),
+                ));
+            }
+        }
+
+        Ok(UnknownIndex {
+            index: index.to_vec(),
+            reads: reads,
+            closest: closest,
+            mistakes: mistakes,
+        })
+    }
+
+    // Non-empty segments of the index, or the whole index if the
+    // segment lengths do not add up
+    fn segments<'a>(index: &'a [u8], segments: &[usize]) -> Vec<&'a [u8]> {
+        if segments.iter().sum::<usize>() != index.len() {
+            return vec![index];
+        }
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for len in segments.iter().filter(|&len| *len > 0) {
+            parts.push(&index[start..(start + len)]);
+            start += len;
+        }
+        parts
+    }
+
+    /// Returns the unknown index sequence
+    pub fn index(&self) -> &[u8] {
+        &self.index
+    }
+
+    /// Returns the number of reads with the unknown index
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Returns the name of the closest sample and the Hamming
+    /// distance to its index, if there are any samples
+    pub fn closest(&self) -> Option<(&str, usize)> {
+        self.closest
+            .as_ref()
+            .map(|(name, distance)| (name.as_str(), *distance))
+    }
+
+    /// Returns the likely sample sheet mistakes explaining the index
+    pub fn mistakes(&self) -> &[IndexMistake] {
+        &self.mistakes
+    }
+}
+
+/// Returns a tab-separated table of unknown index diagnoses with a
+/// header line. Mistakes are listed in the last column, separated by
+/// commas.
+///
+/// # Arguments
+///
+/// * `unknowns` are the diagnosed unknown indices
+///
+/// * `total` is the total number of reads, for computing fractions
+pub fn unknown_index_table(unknowns: &[UnknownIndex], total: usize) -> String {
+    let mut table = "index\treads\tfraction\tclosest\tdistance\tmistakes\n".to_string();
+
+    for unknown in unknowns.iter() {
+        let (closest, distance) = match unknown.closest() {
+            Some((name, distance)) => (name.to_string(), distance.to_string()),
+            None => ("N/A".to_string(), "N/A".to_string()),
+        };
+        let mistakes: Vec<String> = unknown.mistakes().iter().map(|m| m.to_string()).collect();
+
+        table.push_str(&format!(
+            "{}\t{}\t{:.4}\t{}\t{}\t{}\n",
+            str::from_utf8(unknown.index()).unwrap_or("???"),
+            unknown.reads(),
+            if total > 0 {
+                (unknown.reads() as f64) / (total as f64)
+            } else {
+                0.0
+            },
+            closest,
+            distance,
+            if mistakes.is_empty() {
+                "N/A".to_string()
+            } else {
+                mistakes.join(",")
+            }
+        ));
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnose() {
+        let mut sample_map = SampleMap::new(6, "unknown");
+        sample_map.insert(b"AACCGT".to_vec(), 1, "first").unwrap();
+        sample_map.insert(b"GGGTTT".to_vec(), 0, "second").unwrap();
+
+        let revcomp = UnknownIndex::diagnose(b"ACGGTT", 10, &sample_map, &[6]).unwrap();
+        assert!(revcomp.closest() == Some(("second", 3)));
+        assert!(revcomp.mistakes() == &[IndexMistake::ReverseComplement("first".to_string())]);
+
+        let swapped = UnknownIndex::diagnose(b"TTTGGG", 5, &sample_map, &[3, 3]).unwrap();
+        assert!(swapped.closest() == Some(("first", 5)));
+        assert!(swapped.mistakes() == &[IndexMistake::SwappedSegments("second".to_string())]);
+
+        let seg_revcomp = UnknownIndex::diagnose(b"GTTACG", 5, &sample_map, &[3, 3]).unwrap();
+        assert!(
+            seg_revcomp.mistakes()
+                == &[IndexMistake::SegmentReverseComplement("first".to_string())]
+        );
+
+        let typo = UnknownIndex::diagnose(b"AAGGGT", 3, &sample_map, &[6]).unwrap();
+        assert!(typo.closest() == Some(("first", 2)));
+        assert!(typo.mistakes().is_empty());
+
+        let table = unknown_index_table(&[revcomp, typo], 20);
+        assert!(
+            table
+                == "index\treads\tfraction\tclosest\tdistance\tmistakes\n\
+                    ACGGTT\t10\t0.5000\tsecond\t3\trevcomp:first\n\
+                    AAGGGT\t3\t0.1500\tfirst\t2\tN/A\n"
+        );
+    }
+}
@@ -16,6 +16,7 @@ use rust_htslib::bam::Read as BamRead;
 
 use bam_utils::*;
 use transcript::*;
+use umi::*;
 
 mod framing;
 mod stats;
@@ -33,6 +34,7 @@ pub struct CLI {
     pub lengths: String,
     pub count_multi: bool,
     pub annotate: Option<String>,
+    pub collapsed: bool,
 }
 
 pub struct Config {
@@ -44,6 +46,7 @@ pub struct Config {
     lengths: Range<usize>,
     count_multi: bool,
     annotate: Option<PathBuf>,
+    collapsed: bool,
 }
 
 impl Config {
@@ -64,6 +67,7 @@ impl Config {
                 .annotate
                 .as_ref()
                 .map(|ann| Path::new(&ann).to_path_buf()),
+            collapsed: cli.collapsed,
         })
     }
 
@@ -149,7 +153,7 @@ pub fn run_fp_framing(config: Config) -> Result<(), failure::Error> {
             config.count_multi,
         )?;
 
-        framing_stats.tally_bam_frame(&res);
+        framing_stats.tally_bam_frame(&res, read_multiplicity(config.collapsed, &rec));
 
         if let Some(ref mut ann_writer) = &mut annotate {
             rec.push_aux(b"ZF", &bam::record::Aux::String(&res.aux()))?;
@@ -189,3 +193,65 @@ impl fmt::Display for FpFramingError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio_types::annot::spliced::Spliced;
+    use bio_types::strand::ReqStrand;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    #[test]
+    fn collapsed_read_count() {
+        let mut rec = bam::Record::new();
+        rec.set(
+            b"fp_x3",
+            &CigarString(vec![Cigar::Match(4)]),
+            b"ACGT",
+            &[30; 4],
+        );
+
+        // [87261..87387) [87387..87500) [87500..87822)
+        // CDS is 24..378
+        let bed_str = "chr01\t87261\t87822\tYAL030W\t0\t+\t87285\t87752\t0\t2\t126,322,\t0,239,\n";
+        let bed_rec = bed::Reader::new(bed_str.as_bytes())
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mut refids: RefIDSet<Rc<String>> = RefIDSet::new();
+        let trx = Transcript::from_bed12(&bed_rec, &mut refids).unwrap();
+        let fp: Spliced<Rc<String>, ReqStrand> = "chr01:87361-87387(+)".parse().unwrap();
+        let gene_frame = match gene_framing(&(15, -15), &[&trx], &fp) {
+            GeneFrameResult::Good(gene_frame) => gene_frame,
+            _ => panic!("Expected good gene framing"),
+        };
+
+        // The collapsed read adds 3 to each table
+        let mut framing_stats = FramingStats::new(&(20..40), &(-300..300));
+        let res = BamFrameResult::Fp(FpFrameResult::Gene(GeneFrameResult::Good(
+            gene_frame.clone(),
+        )));
+        framing_stats.tally_bam_frame(&res, read_multiplicity(true, &rec));
+
+        let fp_length = gene_frame.fp_length();
+        let frame = gene_frame.frame().unwrap() as isize;
+        assert!(*framing_stats.frame_length().get(fp_length).get(frame) == 3);
+        assert!(
+            framing_stats
+                .around_start()
+                .get(gene_frame.vs_cds_start().unwrap())
+                .map(|vs_start| *vs_start.get(fp_length))
+                == Some(3)
+        );
+        assert!(
+            framing_stats
+                .around_end()
+                .get(gene_frame.vs_cds_end().unwrap())
+                .map(|vs_end| *vs_end.get(fp_length))
+                == Some(3)
+        );
+        assert!(framing_stats.align_stats().good_total() == 3);
+    }
+}
@@ -45,36 +45,36 @@ impl FramingStats {
         &mut self.align_stats
     }
 
-    pub fn tally_frame_length(&mut self, frame: isize, fp_length: usize) {
-        *self.frame_length.get_mut(fp_length).get_mut(frame) += 1
+    pub fn tally_frame_length(&mut self, frame: isize, fp_length: usize, count: usize) {
+        *self.frame_length.get_mut(fp_length).get_mut(frame) += count
     }
 
-    pub fn tally_around_start(&mut self, start_offset: isize, fp_length: usize) {
+    pub fn tally_around_start(&mut self, start_offset: isize, fp_length: usize, count: usize) {
         self.around_start
             .get_mut(start_offset)
-            .map(|vs_start| *vs_start.get_mut(fp_length) += 1);
+            .map(|vs_start| *vs_start.get_mut(fp_length) += count);
     }
 
-    pub fn tally_around_end(&mut self, end_offset: isize, fp_length: usize) {
+    pub fn tally_around_end(&mut self, end_offset: isize, fp_length: usize, count: usize) {
         self.around_end
             .get_mut(end_offset)
-            .map(|vs_end| *vs_end.get_mut(fp_length) += 1);
+            .map(|vs_end| *vs_end.get_mut(fp_length) += count);
     }
 
-    pub fn tally_bam_frame(&mut self, bam_frame: &BamFrameResult) {
-        self.align_stats_mut().tally_bam_frame(bam_frame);
+    pub fn tally_bam_frame(&mut self, bam_frame: &BamFrameResult, count: usize) {
+        self.align_stats_mut().tally_bam_frame(bam_frame, count);
 
         match bam_frame {
             BamFrameResult::Fp(FpFrameResult::Gene(GeneFrameResult::Good(gene_frame))) => {
                 gene_frame
                     .frame()
-                    .map(|fr| self.tally_frame_length(fr as isize, gene_frame.fp_length()));
+                    .map(|fr| self.tally_frame_length(fr as isize, gene_frame.fp_length(), count));
                 gene_frame.vs_cds_start().map(|start_offset| {
-                    self.tally_around_start(start_offset, gene_frame.fp_length())
+                    self.tally_around_start(start_offset, gene_frame.fp_length(), count)
                 });
                 gene_frame
                     .vs_cds_end()
-                    .map(|end_offset| self.tally_around_end(end_offset, gene_frame.fp_length()));
+                    .map(|end_offset| self.tally_around_end(end_offset, gene_frame.fp_length(), count));
             }
             _ => (),
         };
@@ -199,15 +199,15 @@ impl AnnotStats {
         self.good
     }
 
-    pub fn tally_fp_frame(&mut self, fp_frame: &FpFrameResult) {
+    pub fn tally_fp_frame(&mut self, fp_frame: &FpFrameResult, count: usize) {
         match fp_frame {
-            FpFrameResult::NoGene => self.no_gene += 1,
-            FpFrameResult::NoncodingOnly => self.noncoding += 1,
-            FpFrameResult::NoncodingOverlap => self.noncoding_overlap += 1,
-            FpFrameResult::MultiCoding => self.multi_coding += 1,
-            FpFrameResult::Gene(GeneFrameResult::NoCompatible) => self.incompatible += 1,
-            FpFrameResult::Gene(GeneFrameResult::Ambig) => self.ambig += 1,
-            FpFrameResult::Gene(GeneFrameResult::Good(_)) => self.good += 1,
+            FpFrameResult::NoGene => self.no_gene += count,
+            FpFrameResult::NoncodingOnly => self.noncoding += count,
+            FpFrameResult::NoncodingOverlap => self.noncoding_overlap += count,
+            FpFrameResult::MultiCoding => self.multi_coding += count,
+            FpFrameResult::Gene(GeneFrameResult::NoCompatible) => self.incompatible += count,
+            FpFrameResult::Gene(GeneFrameResult::Ambig) => self.ambig += count,
+            FpFrameResult::Gene(GeneFrameResult::Good(_)) => self.good += count,
         }
     }
 
@@ -314,13 +314,13 @@ impl AlignStats {
         self.multi_hit
     }
 
-    pub fn tally_bam_frame(&mut self, bam_frame: &BamFrameResult) {
+    pub fn tally_bam_frame(&mut self, bam_frame: &BamFrameResult, count: usize) {
         match bam_frame {
-            BamFrameResult::NoHit => self.unmapped += 1,
-            BamFrameResult::MultiHit => self.multi_hit += 1,
-            BamFrameResult::TooShort => self.short += 1,
-            BamFrameResult::TooLong => self.long += 1,
-            BamFrameResult::Fp(ffr) => self.annot_stats.tally_fp_frame(ffr),
+            BamFrameResult::NoHit => self.unmapped += count,
+            BamFrameResult::MultiHit => self.multi_hit += count,
+            BamFrameResult::TooShort => self.short += count,
+            BamFrameResult::TooLong => self.long += count,
+            BamFrameResult::Fp(ffr) => self.annot_stats.tally_fp_frame(ffr, count),
         }
     }
 
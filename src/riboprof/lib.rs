@@ -1,7 +1,11 @@
+extern crate csv;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate itertools;
 extern crate regex;
+#[macro_use]
+extern crate serde_json;
 
 extern crate bio;
 extern crate bio_types;
@@ -14,3 +18,4 @@ pub mod fastx_split;
 pub mod fp_framing;
 pub mod metagene;
 pub mod transcript;
+pub mod umi;
@@ -0,0 +1,312 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure;
+
+use rust_htslib::bam;
+
+/// Convention for recording a unique molecular identifier (UMI) with
+/// a sequencing read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UmiEncoding {
+    /// UMI at the end of the read name after the first `#`, as in
+    /// `read#ACGT`
+    Hash,
+    /// UMI at the end of the read name after the last `_`, as used by
+    /// UMI-tools
+    Underscore,
+    /// UMI as the last `:`-delimited field of the read name, as in
+    /// Illumina read names
+    Colon,
+    /// UMI in an `RX:Z:` SAM tag in the read description. Aligners
+    /// that copy the description into the SAM record turn this into
+    /// an `RX` tag on the alignment.
+    SamTag,
+}
+
+/// Name of the SAM tag holding the UMI for `UmiEncoding::SamTag`
+pub const UMI_SAM_TAG: &[u8] = b"RX";
+
+impl UmiEncoding {
+    /// Returns the name strings accepted by `from_str`
+    pub fn names() -> &'static [&'static str] {
+        &["hash", "underscore", "colon", "sam"]
+    }
+
+    /// Returns the delimiter separating the UMI from the rest of the
+    /// read name, or `None` when the UMI is not in the read name.
+    pub fn delimiter(&self) -> Option<u8> {
+        match self {
+            UmiEncoding::Hash => Some(b'#'),
+            UmiEncoding::Underscore => Some(b'_'),
+            UmiEncoding::Colon => Some(b':'),
+            UmiEncoding::SamTag => None,
+        }
+    }
+
+    /// Returns the read name and description for a read carrying a
+    /// UMI. The SAM tag encoding replaces any existing description,
+    /// because aligners that copy the description into the SAM
+    /// record require it to consist of valid SAM tags.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` is the read name without the UMI
+    ///
+    /// * `desc` is the read description, if any
+    ///
+    /// * `umi` is the UMI sequence
+    pub fn encode(&self, id: &str, desc: Option<&str>, umi: &str) -> (String, Option<String>) {
+        match self.delimiter() {
+            Some(delim) => (
+                format!("{}{}{}", id, delim as char, umi),
+                desc.map(str::to_string),
+            ),
+            None => (
+                id.to_string(),
+                Some(format!(
+                    "{}:Z:{}",
+                    String::from_utf8_lossy(UMI_SAM_TAG),
+                    umi
+                )),
+            ),
+        }
+    }
+
+    /// Extracts the UMI from a read name, returning `None` when the
+    /// name has no UMI or the UMI is not stored in the read name.
+    ///
+    /// Illumina read names contain several `:`-delimited fields
+    /// whether or not a UMI is present, so the colon encoding only
+    /// accepts a final field made up of nucleotides, optionally
+    /// joined by `+` for dual UMIs.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` is the read name
+    pub fn name_umi<'a>(&self, name: &'a [u8]) -> Option<&'a [u8]> {
+        let delim_pos = match self {
+            UmiEncoding::Hash => name.iter().position(|&ch| ch == b'#'),
+            UmiEncoding::Underscore => name.iter().rposition(|&ch| ch == b'_'),
+            UmiEncoding::Colon => name.iter().rposition(|&ch| ch == b':'),
+            UmiEncoding::SamTag => None,
+        }?;
+        let umi = name.split_at(delim_pos + 1).1;
+
+        if *self == UmiEncoding::Colon && !is_umi_field(umi) {
+            None
+        } else {
+            Some(umi)
+        }
+    }
+}
+
+/// Marker placed between the read name and the multiplicity of a
+/// collapsed read, as in `read_x12#ACGT`
+pub const MULTIPLICITY_MARKER: &[u8] = b"_x";
+
+/// Returns the read name for a collapsed read standing for `count`
+/// identical reads, before any UMI is added by a `UmiEncoding`.
+///
+/// # Arguments
+///
+/// * `id` is the name of the first of the identical reads
+///
+/// * `count` is the number of identical reads
+pub fn collapsed_id(id: &str, count: usize) -> String {
+    format!(
+        "{}{}{}",
+        id,
+        String::from_utf8_lossy(MULTIPLICITY_MARKER),
+        count
+    )
+}
+
+/// Extracts the multiplicity of a collapsed read from its name,
+/// returning `None` for reads that were not collapsed. The
+/// multiplicity follows the last `_x` in the name and runs to the end
+/// of the name or to a UMI delimiter, so it is found with any
+/// `UmiEncoding`.
+///
+/// # Arguments
+///
+/// * `name` is the read name
+pub fn name_multiplicity(name: &[u8]) -> Option<usize> {
+    let marker_len = MULTIPLICITY_MARKER.len();
+    (0..(name.len() + 1).saturating_sub(marker_len))
+        .rev()
+        .filter(|&start| &name[start..(start + marker_len)] == MULTIPLICITY_MARKER)
+        .filter_map(|start| {
+            let count = &name[(start + marker_len)..];
+            let count_end = count
+                .iter()
+                .position(|ch| !ch.is_ascii_digit())
+                .unwrap_or(count.len());
+            let is_terminated = count_end == count.len()
+                || [
+                    UmiEncoding::Hash,
+                    UmiEncoding::Underscore,
+                    UmiEncoding::Colon,
+                ]
+                .iter()
+                .any(|encoding| encoding.delimiter() == Some(count[count_end]));
+            if count_end > 0 && is_terminated {
+                String::from_utf8_lossy(&count[..count_end]).parse().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Returns the number of reads that a record stands for: the
+/// multiplicity in the read name for collapsed reads, or 1 otherwise.
+///
+/// # Arguments
+///
+/// * `collapsed` is true when identical reads were collapsed
+///
+/// * `rec` is the aligned record
+pub fn read_multiplicity(collapsed: bool, rec: &bam::Record) -> usize {
+    if collapsed {
+        name_multiplicity(rec.qname()).unwrap_or(1)
+    } else {
+        1
+    }
+}
+
+fn is_umi_field(field: &[u8]) -> bool {
+    !field.is_empty()
+        && field.iter().any(|&ch| ch != b'+')
+        && field.iter().all(|&ch| {
+            ch == b'A' || ch == b'C' || ch == b'G' || ch == b'T' || ch == b'N' || ch == b'+'
+        })
+}
+
+impl FromStr for UmiEncoding {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "hash" => Ok(UmiEncoding::Hash),
+            "underscore" => Ok(UmiEncoding::Underscore),
+            "colon" => Ok(UmiEncoding::Colon),
+            "sam" => Ok(UmiEncoding::SamTag),
+            _ => Err(format_err!(
+                "Unknown UMI encoding \"{}\", expected one of {}",
+                name,
+                UmiEncoding::names().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for UmiEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            UmiEncoding::Hash => "hash",
+            UmiEncoding::Underscore => "underscore",
+            UmiEncoding::Colon => "colon",
+            UmiEncoding::SamTag => "sam",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_htslib::bam::record::CigarString;
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            UmiEncoding::Hash.encode("read", Some("1:N:0"), "ACGT"),
+            ("read#ACGT".to_string(), Some("1:N:0".to_string()))
+        );
+        assert_eq!(
+            UmiEncoding::Underscore.encode("read", None, "ACGT"),
+            ("read_ACGT".to_string(), None)
+        );
+        assert_eq!(
+            UmiEncoding::Colon.encode("M1:12:FC:1:1101:155:133", None, "ACGT"),
+            ("M1:12:FC:1:1101:155:133:ACGT".to_string(), None)
+        );
+        assert_eq!(
+            UmiEncoding::SamTag.encode("read", Some("1:N:0"), "ACGT"),
+            ("read".to_string(), Some("RX:Z:ACGT".to_string()))
+        );
+    }
+
+    #[test]
+    fn name_umi() {
+        assert_eq!(
+            UmiEncoding::Hash.name_umi(b"read#AC#GT"),
+            Some(&b"AC#GT"[..])
+        );
+        assert_eq!(UmiEncoding::Hash.name_umi(b"read"), None);
+        assert_eq!(
+            UmiEncoding::Underscore.name_umi(b"read_1_ACGT"),
+            Some(&b"ACGT"[..])
+        );
+        assert_eq!(
+            UmiEncoding::Colon.name_umi(b"M1:12:FC:1:1101:155:133:ACGT"),
+            Some(&b"ACGT"[..])
+        );
+        assert_eq!(
+            UmiEncoding::Colon.name_umi(b"M1:12:FC:1:1101:155:AC+GT"),
+            Some(&b"AC+GT"[..])
+        );
+        assert_eq!(
+            UmiEncoding::Colon.name_umi(b"M1:12:FC:1:1101:155:133"),
+            None
+        );
+        assert_eq!(UmiEncoding::SamTag.name_umi(b"read#ACGT"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        for encoding in [
+            UmiEncoding::Hash,
+            UmiEncoding::Underscore,
+            UmiEncoding::Colon,
+        ]
+        .iter()
+        {
+            let (id, _) = encoding.encode("M1:12:FC:1:1101:155:133", None, "GATTACA");
+            assert_eq!(encoding.name_umi(id.as_bytes()), Some(&b"GATTACA"[..]));
+        }
+
+        for name in UmiEncoding::names() {
+            let encoding: UmiEncoding = name.parse().unwrap();
+            assert_eq!(encoding.to_string(), *name);
+        }
+        assert!("pound".parse::<UmiEncoding>().is_err());
+    }
+
+    #[test]
+    fn multiplicity() {
+        assert_eq!(collapsed_id("read", 12), "read_x12");
+        for encoding in [
+            UmiEncoding::Hash,
+            UmiEncoding::Underscore,
+            UmiEncoding::Colon,
+            UmiEncoding::SamTag,
+        ]
+        .iter()
+        {
+            let (id, _) = encoding.encode(&collapsed_id("M1:12:FC_x9", 7), None, "ACGT");
+            assert_eq!(name_multiplicity(id.as_bytes()), Some(7));
+        }
+        assert_eq!(name_multiplicity(b"read_x3y#ACGT"), None);
+        assert_eq!(name_multiplicity(b"read_x#ACGT"), None);
+        assert_eq!(name_multiplicity(b"read#ACGT"), None);
+        assert_eq!(name_multiplicity(b"x"), None);
+
+        let mut rec = bam::Record::new();
+        rec.set(b"fp_x3", &CigarString(Vec::new()), b"ACGT", &[30; 4]);
+        assert_eq!(read_multiplicity(true, &rec), 3);
+        assert_eq!(read_multiplicity(false, &rec), 1);
+    }
+}